@@ -0,0 +1,230 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use rand::Rng;
+
+use std::time::Duration;
+
+use in_mem_cached::cache::TtlCache;
+use in_mem_cached::config::Config;
+use in_mem_cached::deferred_drop::DeferredDrop;
+
+mod common;
+
+use common::keys;
+use common::rng;
+use common::FixedTime;
+
+const TTL_SECS: u64 = 60;
+
+fn config() -> Config {
+    Config {
+        ttl: Duration::from_secs(TTL_SECS),
+        capacity: None,
+        ..Config::default()
+    }
+}
+
+fn filled_cache<'a>(time: &'a FixedTime, keys: &[String]) -> TtlCache<'a, FixedTime> {
+    let mut cache = TtlCache::new(config(), time);
+    for k in keys {
+        cache.set(k.clone(), b"value".to_vec()).unwrap();
+    }
+    cache
+}
+
+fn set_new_keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_new_keys");
+    for n in [1_000, 100_000] {
+        let time = FixedTime::new();
+        let keys = keys(n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &keys, |b, keys| {
+            b.iter_batched(
+                || (TtlCache::new(config(), &time), keys.clone()),
+                |(mut cache, keys)| {
+                    for k in keys {
+                        cache.set(k, b"value".to_vec()).unwrap();
+                    }
+                    cache
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// filling a cold cache, with the map pre-sized and growing on demand
+fn warm_up(c: &mut Criterion) {
+    let mut group = c.benchmark_group("warm_up");
+    let n = 100_000;
+    let keys = keys(n);
+    for initial_capacity in [None, Some(n)] {
+        let time = FixedTime::new();
+        let config = Config {
+            initial_capacity,
+            ..config()
+        };
+        let name = if initial_capacity.is_some() {
+            "presized"
+        } else {
+            "growing"
+        };
+        group.bench_with_input(BenchmarkId::new(name, n), &keys, |b, keys| {
+            b.iter_batched(
+                || (TtlCache::new(config.clone(), &time), keys.clone()),
+                |(mut cache, keys)| {
+                    for k in keys {
+                        cache.set(k, b"value".to_vec()).unwrap();
+                    }
+                    cache
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for n in [1_000, 100_000] {
+        let time = FixedTime::new();
+        let keys = keys(n);
+        let mut cache = filled_cache(&time, &keys);
+        let mut rng = rng();
+
+        group.bench_function(BenchmarkId::new("hit", n), |b| {
+            b.iter(|| cache.get(&keys[rng.gen_range(0..n)]))
+        });
+        group.bench_function(BenchmarkId::new("miss", n), |b| {
+            b.iter(|| cache.get("missing"))
+        });
+    }
+    group.finish();
+}
+
+fn overwrite(c: &mut Criterion) {
+    let time = FixedTime::new();
+    let keys = keys(10_000);
+    let mut cache = filled_cache(&time, &keys);
+    let mut rng = rng();
+
+    c.bench_function("overwrite", |b| {
+        b.iter(|| {
+            let k = keys[rng.gen_range(0..keys.len())].clone();
+            cache.set(k, b"other value".to_vec()).unwrap()
+        })
+    });
+}
+
+fn evict_expired(c: &mut Criterion) {
+    let mut group = c.benchmark_group("evict_expired");
+    let n = 10_000;
+    for expired_pct in [10, 50, 90] {
+        let keys = keys(n);
+        let expired = n * expired_pct / 100;
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{}%", expired_pct)),
+            &keys,
+            |b, keys| {
+                let time = FixedTime::new();
+                b.iter_batched(
+                    || {
+                        // first batch of keys is older and expires by the time eviction runs
+                        time.set_secs(0);
+                        let mut cache = filled_cache(&time, &keys[..expired]);
+                        time.set_secs(TTL_SECS / 2 + 1);
+                        for k in &keys[expired..] {
+                            cache.set(k.clone(), b"value".to_vec()).unwrap();
+                        }
+                        time.set_secs(TTL_SECS + 1);
+                        cache
+                    },
+                    |mut cache| {
+                        cache.evict_expired();
+                        cache
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+// deletes of large values, freed in the loop or handed over to the dropping thread, see
+// `deferred_drop_min_bytes`, the time of a delete is what requests queued behind it wait
+fn delete_large_values(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete_large_values");
+    let keys = keys(16);
+    let value_bytes = 8 * 1024 * 1024;
+    for deferred_drop_min_bytes in [None, Some(1024 * 1024)] {
+        let time = FixedTime::new();
+        let config = Config {
+            deferred_drop_min_bytes,
+            ..config()
+        };
+        let deferred_drop = DeferredDrop::start(&config);
+        let name = if deferred_drop.is_some() {
+            "deferred"
+        } else {
+            "inline"
+        };
+        group.bench_with_input(BenchmarkId::new(name, value_bytes), &keys, |b, keys| {
+            b.iter_batched(
+                || {
+                    let mut cache = TtlCache::new(config.clone(), &time);
+                    if let Some(deferred_drop) = &deferred_drop {
+                        cache.set_deferred_drop(deferred_drop.clone());
+                    }
+                    for k in keys {
+                        // touched so that pages are actually mapped before they are freed
+                        cache.set(k.clone(), vec![1; value_bytes]).unwrap();
+                    }
+                    cache
+                },
+                |mut cache| {
+                    for k in keys {
+                        cache.delete(k);
+                    }
+                    cache
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn mixed_read_write(c: &mut Criterion) {
+    let time = FixedTime::new();
+    let keys = keys(10_000);
+    let mut cache = filled_cache(&time, &keys);
+    let mut rng = rng();
+
+    c.bench_function("mixed_90_read_10_write", |b| {
+        b.iter(|| {
+            let k = &keys[rng.gen_range(0..keys.len())];
+            if rng.gen_ratio(1, 10) {
+                cache.set(k.clone(), b"other value".to_vec()).unwrap();
+            } else {
+                cache.get(k);
+            }
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    set_new_keys,
+    warm_up,
+    get,
+    overwrite,
+    evict_expired,
+    delete_large_values,
+    mixed_read_write
+);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use in_mem_cached::time::Time;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+pub const SEED: u64 = 42;
+
+// clock that only moves when told to, so runs are comparable
+pub struct FixedTime {
+    start: Instant,
+    secs_passed: AtomicU64,
+}
+
+impl FixedTime {
+    #[allow(dead_code)]
+    pub fn new() -> FixedTime {
+        FixedTime {
+            start: Instant::now(),
+            secs_passed: AtomicU64::new(0),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_secs(&self, secs: u64) {
+        self.secs_passed.store(secs, Ordering::SeqCst);
+    }
+}
+
+impl Time for FixedTime {
+    fn get_time(&self) -> Instant {
+        self.start + Duration::from_secs(self.secs_passed.load(Ordering::SeqCst))
+    }
+}
+
+pub fn rng() -> StdRng {
+    StdRng::seed_from_u64(SEED)
+}
+
+pub fn keys(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!("key:{}", i)).collect()
+}
@@ -0,0 +1,115 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use rand::Rng;
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use in_mem_cached::config::Config;
+use in_mem_cached::service::service_queue;
+use in_mem_cached::service::ServiceFlags;
+use in_mem_cached::service::ServiceMessage;
+use in_mem_cached::service::ServiceQueue;
+use in_mem_cached::service::TtlCacheService;
+use tokio::runtime::Builder;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+mod common;
+
+use common::keys;
+use common::rng;
+use common::FixedTime;
+
+fn spawn_service(runtime: &Runtime) -> ServiceQueue {
+    let (tx, rx) = service_queue();
+    let config = Config::default();
+    let flags = Arc::new(ServiceFlags::new(&config));
+    let time: &'static FixedTime = Box::leak(Box::new(FixedTime::new()));
+
+    runtime.spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+    tx
+}
+
+// messages go through the mpsc channel to the service loop, no http involved
+fn service_loop(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let tx = spawn_service(&runtime);
+    let keys = keys(10_000);
+    let mut rng = rng();
+
+    runtime.block_on(async {
+        for k in &keys {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(k.clone(), b"value".to_vec(), cb).into())
+                .unwrap();
+            res.await.unwrap().unwrap();
+        }
+    });
+
+    c.bench_function("service_mixed_90_read_10_write", |b| {
+        b.to_async(&runtime).iter(|| {
+            let k = keys[rng.gen_range(0..keys.len())].clone();
+            let write = rng.gen_ratio(1, 10);
+            let tx = tx.clone();
+            async move {
+                if write {
+                    let (cb, res) = oneshot::channel();
+                    tx.send(ServiceMessage::Write(k, b"other value".to_vec(), cb).into())
+                        .unwrap();
+                    res.await.unwrap().unwrap();
+                } else {
+                    let (cb, res) = oneshot::channel();
+                    tx.send(ServiceMessage::Read(k, cb).into()).unwrap();
+                    res.await.unwrap();
+                }
+            }
+        })
+    });
+}
+
+// latency of a single read queued behind a burst of writes, service runs on the same thread
+// so that the whole burst is queued before it gets to it and scheduling does not add noise
+fn read_during_write_flood(c: &mut Criterion) {
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let tx = spawn_service(&runtime);
+    let keys = keys(10_000);
+
+    c.bench_function("service_read_behind_10k_writes", |b| {
+        b.to_async(&runtime).iter_custom(|iters| {
+            let tx = tx.clone();
+            let keys = keys.clone();
+            async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let writes: Vec<_> = keys
+                        .iter()
+                        .map(|k| {
+                            let (cb, res) = oneshot::channel();
+                            tx.send(ServiceMessage::Write(k.clone(), b"value".to_vec(), cb).into())
+                                .unwrap();
+                            res
+                        })
+                        .collect();
+
+                    let started = Instant::now();
+                    let (cb, res) = oneshot::channel();
+                    tx.send(ServiceMessage::Read(keys[0].clone(), cb).into())
+                        .unwrap();
+                    res.await.unwrap();
+                    total += started.elapsed();
+
+                    for res in writes {
+                        res.await.unwrap().unwrap();
+                    }
+                }
+                total
+            }
+        })
+    });
+}
+
+criterion_group!(benches, service_loop, read_during_write_flood);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use rand::Rng;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use in_mem_cached::config::SnapshotFormat;
+use in_mem_cached::snapshot;
+
+mod common;
+
+use common::keys;
+use common::rng;
+
+const ENTRIES: usize = 1_000_000;
+
+// binary values, where JSON arrays of numbers cost the most
+fn entries() -> Vec<(String, Vec<u8>)> {
+    let mut rng = rng();
+    keys(ENTRIES)
+        .into_iter()
+        .map(|k| (k, (0..32).map(|_| rng.gen()).collect()))
+        .collect()
+}
+
+fn snapshot_path(format: SnapshotFormat) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "in-mem-cached-bench-{:?}-{}.snapshot",
+        format,
+        std::process::id()
+    ))
+}
+
+fn dump_and_load(c: &mut Criterion) {
+    let entries = entries();
+    let mut group = c.benchmark_group("snapshot_1m_keys");
+    group.sample_size(10);
+
+    for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+        let path = snapshot_path(format);
+        let write = || {
+            snapshot::write(
+                &path,
+                format,
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_slice(), Duration::from_secs(60))),
+            )
+            .unwrap()
+        };
+
+        group.bench_function(format!("dump_{:?}", format), |b| b.iter(write));
+        write();
+        println!(
+            "{:?} snapshot of {} keys: {} bytes",
+            format,
+            ENTRIES,
+            fs::metadata(&path).unwrap().len()
+        );
+        group.bench_function(format!("load_{:?}", format), |b| {
+            b.iter(|| snapshot::read(&path).unwrap())
+        });
+        fs::remove_file(&path).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dump_and_load);
+criterion_main!(benches);
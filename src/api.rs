@@ -1,219 +1,7755 @@
+use crate::bulk;
+use crate::bulk::BulkLoadReport;
+use crate::bulk::BulkWrite;
+use crate::bulk::Lines;
+use crate::cache::CacheError;
+use crate::cache::ConditionalDelete;
+use crate::cache::ConditionalRead;
+use crate::cache::DatedRead;
+use crate::cache::EntryMeta;
+use crate::cache::Explanation;
+use crate::cache::Item;
+use crate::cache::LockedRead;
+use crate::cache::TagInvalidation;
+use crate::cache::TtlHistogram;
+use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::config::KeyCanonicalization;
+use crate::config::MissStatus;
+use crate::config::SnapshotFormat;
+use crate::duration::parse_duration;
+use crate::duration::ACCEPTED_FORMATS;
+use crate::duration::MAX_DURATION;
+use crate::encoding;
+use crate::events::EventFilter;
+use crate::events::EventItem;
+use crate::events::Subscription;
+use crate::maintenance::MaintenanceStatus;
+use crate::metrics;
+use crate::metrics::HttpMetrics;
+use crate::metrics::InFlight;
+use crate::openapi;
+use crate::openapi::Body;
+use crate::openapi::RouteDoc;
+use crate::openapi::RouteDocs;
+use crate::selftest;
+use crate::selftest::BenchParams;
+use crate::server::ClientAddr;
+use crate::service::CacheStats;
+use crate::service::CompactReport;
+use crate::service::DumpEntry;
+use crate::service::EvictionReport;
+use crate::service::FlushReport;
+use crate::service::InflightWrite;
+use crate::service::ServiceFlags;
 use crate::service::ServiceMessage;
 use crate::service::ServiceQueue;
+use crate::service::SnapshotReport;
+use crate::service::WriteOptions;
+use crate::slowlog::SlowOperation;
+use crate::snapshot;
+use crate::snapshot::SnapshotError;
+use crate::txn::TxnOp;
+use crate::txn::TxnResult;
+use crate::upstream::is_hop_by_hop;
+use crate::upstream::BreakerSnapshot;
+use crate::upstream::FetchError;
+use crate::upstream::Forwarded;
+use crate::upstream::Upstream;
 
+use std::collections::TryReserveError;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::UNIX_EPOCH;
+
+use base64::Engine;
+use ipnet::IpNet;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::AsciiSet;
+use percent_encoding::CONTROLS;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+use warp::http::header::CONTENT_LENGTH;
+use warp::http::header::CONTENT_TYPE;
 use warp::http::status::StatusCode;
+use warp::http::HeaderMap;
+use warp::http::HeaderValue;
+use warp::http::Method;
+use warp::hyper::body::Buf;
+use warp::path::FullPath;
+use warp::reply::Response;
 use warp::Filter;
+use warp::Reply;
+
+use tokio::sync::oneshot;
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn json_error(error: String, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(&ErrorBody { error }), status).into_response()
+}
+
+fn read_only_response() -> Response {
+    warp::reply::with_header(
+        json_error(
+            format!("{}", CacheError::ReadOnly),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        "X-Read-Only",
+        "true",
+    )
+    .into_response()
+}
+
+// writes already waiting hold `max_inflight_write_bytes`, retried once they are answered
+fn inflight_writes_response() -> Response {
+    warp::reply::with_header(
+        json_error(
+            String::from("too many bytes of writes in flight"),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        "Retry-After",
+        "1",
+    )
+    .into_response()
+}
+
+fn deadline_exceeded_response() -> Response {
+    json_error(
+        String::from("deadline exceeded"),
+        StatusCode::GATEWAY_TIMEOUT,
+    )
+}
+
+fn is_past(deadline: Option<Instant>) -> bool {
+    deadline.map(|d| d <= Instant::now()).unwrap_or(false)
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+struct ReadOnly;
+
+impl warp::reject::Reject for ReadOnly {}
+
+// client is outside of `write_allowlist`
+#[derive(Debug)]
+struct WriteForbidden;
+
+impl warp::reject::Reject for WriteForbidden {}
+
+// reads and writes are shed, see `latency_budget`
+#[derive(Debug)]
+struct Overloaded;
+
+impl warp::reject::Reject for Overloaded {}
+
+// declared body does not fit in `max_inflight_write_bytes`
+#[derive(Debug)]
+struct InflightWritesFull;
+
+impl warp::reject::Reject for InflightWritesFull {}
+
+#[derive(Debug)]
+struct KeyNotAllowed(String);
+
+impl warp::reject::Reject for KeyNotAllowed {}
+
+#[derive(Debug)]
+struct KeyTooLong(usize);
+
+impl warp::reject::Reject for KeyTooLong {}
+
+// query param that `parse_duration` does not accept, or zero where it means nothing
+#[derive(Debug)]
+struct InvalidDuration {
+    param: &'static str,
+    error: String,
+}
+
+impl warp::reject::Reject for InvalidDuration {}
+
+// none of the duration params has a meaning for zero, there are no entries without expiry
+fn duration_param(
+    param: &'static str,
+    value: Option<&str>,
+) -> Result<Option<Duration>, warp::Rejection> {
+    let invalid = |error: String| warp::reject::custom(InvalidDuration { param, error });
+    match value.map(parse_duration) {
+        Some(Ok(duration)) if duration.is_zero() => Err(invalid(String::from("must not be zero"))),
+        Some(Ok(duration)) if duration > MAX_DURATION => Err(invalid(format!(
+            "must not be longer than {}s",
+            MAX_DURATION.as_secs()
+        ))),
+        Some(Ok(duration)) => Ok(Some(duration)),
+        Some(Err(e)) => Err(invalid(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug)]
+struct ValueTooLarge(usize);
+
+impl warp::reject::Reject for ValueTooLarge {}
+
+#[derive(Debug)]
+struct InsufficientStorage;
+
+impl warp::reject::Reject for InsufficientStorage {}
+
+#[derive(Debug)]
+struct GenerationMismatch(u64);
+
+impl warp::reject::Reject for GenerationMismatch {}
+
+// checks keys against `allowed_key_prefixes` once canonicalized, as the cache would see them
+#[derive(Clone)]
+struct KeyRules {
+    canonicalization: KeyCanonicalization,
+    allowed_prefixes: Arc<Vec<String>>,
+    // applies to keys in the path, see `key_param`, and to `/bulk-load` lines
+    max_len: Option<usize>,
+}
+
+impl KeyRules {
+    fn check<'a>(&self, mut keys: impl Iterator<Item = &'a str>) -> Result<(), warp::Rejection> {
+        match keys.find(|key| !self.allows(key)) {
+            Some(key) => Err(warp::reject::custom(KeyNotAllowed(key.to_string()))),
+            None => Ok(()),
+        }
+    }
+
+    fn allows(&self, key: &str) -> bool {
+        if self.allowed_prefixes.is_empty() {
+            return true;
+        }
+        let key = self.canonicalization.canonicalize(key.to_string());
+        self.allowed_prefixes
+            .iter()
+            .any(|p| key.starts_with(p.as_str()))
+    }
+}
+
+// key path segment, rejected when longer than `max_key_len` or outside of allowed prefixes
+fn key_param(rules: KeyRules) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::path::param::<String>().and_then(move |key: String| {
+        let checked = match rules.max_len.filter(|max| key.len() > *max) {
+            Some(max) => Err(warp::reject::custom(KeyTooLong(max))),
+            None => rules.check(std::iter::once(key.as_str())),
+        };
+        async move { checked.map(|_| key) }
+    })
+}
+
+// JSON array of keys, rejected when any of them is outside of allowed prefixes
+fn keys_body(
+    rules: KeyRules,
+) -> impl Filter<Extract = (Vec<String>,), Error = warp::Rejection> + Clone {
+    warp::body::json::<Vec<String>>().and_then(move |keys: Vec<String>| {
+        let checked = rules.check(keys.iter().map(|k| k.as_str()));
+        async move { checked.map(|_| keys) }
+    })
+}
+
+// how `/get` responds, taken from config
+#[derive(Clone)]
+struct ReadOptions {
+    miss_status: MissStatus,
+    compress_min_bytes: usize,
+    // keys missing locally are fetched from here, see `replica_of`
+    primary: Option<Arc<Upstream>>,
+    // see `enable_last_modified`
+    last_modified: bool,
+}
+
+// primary was not asked for a missing key because its circuit breaker is open
+struct OriginBypassed;
+
+// fetches key missing locally from the primary and stores it for the following reads,
+// primary being unavailable is treated as a miss
+async fn refresh_from_primary(
+    queue: &ServiceQueue,
+    primary: &Upstream,
+    key: String,
+) -> Result<Option<Vec<u8>>, OriginBypassed> {
+    let value = match primary.fetch(&key).await {
+        Ok(Some(value)) => value,
+        Ok(None) => return Ok(None),
+        Err(FetchError::Bypassed) => return Err(OriginBypassed),
+        Err(FetchError::Failed(e)) => {
+            tracing::error!("[replica] failed to fetch key {} from primary: {}", key, e);
+            return Ok(None);
+        }
+    };
+
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+    if queue
+        .send(ServiceMessage::Write(key, value.clone(), tx).into())
+        .is_ok()
+    {
+        if let Ok(Err(e)) = rx.await {
+            tracing::warn!(
+                "[replica] failed to store value fetched from primary: {}",
+                e
+            );
+        }
+    }
+    Ok(Some(value))
+}
+
+// relays response of the primary, local copies of written keys are dropped afterwards
+// so that the next read fetches them back instead of serving the old value
+async fn relay_forwarded(
+    queue: ServiceQueue,
+    keys: Vec<String>,
+    forwarded: Result<Forwarded, String>,
+) -> Response {
+    let forwarded = match forwarded {
+        Ok(forwarded) => forwarded,
+        Err(e) => {
+            return json_error(
+                format!("primary is unavailable: {}", e),
+                StatusCode::BAD_GATEWAY,
+            )
+        }
+    };
+
+    if forwarded.status.is_success() {
+        let mut deleted = Vec::with_capacity(keys.len());
+        for key in keys {
+            let (tx, rx) = oneshot::channel::<Result<ConditionalDelete, CacheError>>();
+            if queue
+                .send(ServiceMessage::Delete(key, None, tx).into())
+                .is_ok()
+            {
+                deleted.push(rx);
+            }
+        }
+        // reads are served ahead of queued writes, wait until the old values are gone
+        for rx in deleted {
+            let _ = rx.await;
+        }
+    }
+
+    forwarded_response(forwarded)
+}
+
+// characters a path segment cannot hold as they are, `%` is not one of them, keys are
+// taken from the path without decoding them
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/');
+
+// path of a write of the key forwarded to the primary, with query parameters encoded
+fn forwarded_path(route: &str, key: &str, query: &[(&str, String)]) -> String {
+    let path = format!("/{}/{}", route, utf8_percent_encode(key, PATH_SEGMENT));
+    match serde_urlencoded::to_string(query) {
+        Ok(query) if !query.is_empty() => format!("{}?{}", path, query),
+        _ => path,
+    }
+}
+
+// headers describing body of a forwarded write
+fn forwarded_headers(
+    content_type: Option<&'static str>,
+    content_encoding: Option<String>,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(content_type) = content_type {
+        headers.insert("content-type", HeaderValue::from_static(content_type));
+    }
+    if let Some(encoding) = content_encoding.and_then(|e| HeaderValue::from_str(&e).ok()) {
+        headers.insert("content-encoding", encoding);
+    }
+    headers
+}
+
+// status, headers and body of the upstream response as they were
+fn forwarded_response(forwarded: Forwarded) -> Response {
+    let mut response = Response::new(forwarded.body.into());
+    *response.status_mut() = forwarded.status;
+    for (name, value) in forwarded.headers.iter() {
+        if !is_hop_by_hop(name.as_str()) {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    response
+}
+
+// endpoints addressing a single key, those are passed to the peer owning the key
+// in cluster mode, the rest is answered locally
+const ROUTED_ENDPOINTS: [&str; 12] = [
+    "get", "set", "del", "ringpush", "peek", "item", "meta", "strlen", "explain", "pin", "unpin",
+    "access",
+];
+
+fn routed_key(path: &str, api_version: &str) -> Option<String> {
+    let path = path.trim_start_matches('/');
+    let path = path
+        .strip_prefix(api_version)
+        .and_then(|p| p.strip_prefix('/'))
+        .unwrap_or(path);
+    let mut segments = path.split('/');
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (Some(endpoint), Some(key), None, _)
+            if ROUTED_ENDPOINTS.contains(&endpoint) && !key.is_empty() =>
+        {
+            Some(key.to_string())
+        }
+        // `/access/<key>/reset`
+        (Some("access"), Some(key), Some("reset"), None) if !key.is_empty() => {
+            Some(key.to_string())
+        }
+        _ => None,
+    }
+}
+
+// route label of the request in http metrics, see `metrics::ROUTES`
+fn route_label(path: &str, api_version: &str) -> &'static str {
+    let path = path.trim_start_matches('/');
+    let path = path
+        .strip_prefix(api_version)
+        .and_then(|p| p.strip_prefix('/'))
+        .unwrap_or(path);
+    match path.split('/').next() {
+        Some("get") | Some("item") | Some("mget") | Some("mttl") => "get",
+        Some("set") | Some("ringpush") | Some("txn") | Some("tx") | Some("swap")
+        | Some("rename") | Some("pin") | Some("unpin") | Some("bulk-load") => "set",
+        Some("del") | Some("tags") | Some("flush") => "del",
+        Some("admin") => "admin",
+        _ => "other",
+    }
+}
+
+// request is counted as in flight on its route until the reply is ready or it is rejected
+fn tracked(
+    http_metrics: Arc<HttpMetrics>,
+    api_version: &'static str,
+) -> impl Filter<Extract = (InFlight,), Error = std::convert::Infallible> + Clone {
+    warp::path::full()
+        .map(move |path: FullPath| http_metrics.start(route_label(path.as_str(), api_version)))
+}
+
+// the client address the router sees is appended to `X-Forwarded-For`, so the peer
+// can check its own `write_allowlist` against it
+#[allow(clippy::too_many_arguments)]
+async fn route_to_peer(
+    cluster: Arc<Cluster>,
+    key: String,
+    method: Method,
+    path: FullPath,
+    query: String,
+    mut headers: HeaderMap,
+    client: Option<IpAddr>,
+    body: warp::hyper::body::Bytes,
+) -> Result<Response, std::convert::Infallible> {
+    let path_and_query = if query.is_empty() {
+        path.as_str().to_string()
+    } else {
+        format!("{}?{}", path.as_str(), query)
+    };
+    if let Some(client) = client {
+        let forwarded = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(chain) => format!("{}, {}", chain, client),
+            None => client.to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded) {
+            headers.insert("x-forwarded-for", value);
+        }
+    }
+
+    match cluster
+        .route(&key, method, &path_and_query, headers, body)
+        .await
+    {
+        Ok(forwarded) => Ok(forwarded_response(forwarded)),
+        Err(e) => Ok(json_error(e, StatusCode::BAD_GATEWAY)),
+    }
+}
+
+async fn read(
+    queue: ServiceQueue,
+    key: String,
+    options: ReadOptions,
+    accept: Option<String>,
+    value_headers: ValueHeaders,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<Vec<u8>>>();
+
+    // todo: warp does not allow any types apart from Infallible and Rejection
+    // thus it is a big ugly instead of using much more ergonomic '?' op
+    match queue.send(ServiceMessage::Read(key.clone(), tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(v) => {
+                let v = match (v, &options.primary) {
+                    (None, Some(primary)) => refresh_from_primary(&queue, primary, key).await,
+                    (v, _) => Ok(v),
+                };
+                match v {
+                    Ok(Some(vv)) => Ok(value_headers.respond(vv, options.compress_min_bytes).await),
+                    Ok(None) => Ok(miss_response(options.miss_status, accept)),
+                    Err(OriginBypassed) => Ok(bypassed_response(options.miss_status, accept)),
+                }
+            }
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(warp::reply::with_status(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()),
+        },
+        Err(e) => Ok(
+            warp::reply::with_status(format!("{}", e), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response(),
+        ),
+    }
+}
+
+// `/get` answered as json envelope or base64 text, plain reads only
+async fn read_formatted(
+    queue: ServiceQueue,
+    key: String,
+    format: ValueFormat,
+    options: ReadOptions,
+    accept: Option<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<(Vec<u8>, EntryMeta)>>();
+
+    match queue.send(ServiceMessage::ReadWithMeta(key.clone(), tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(read) => {
+                let read = match (read, &options.primary) {
+                    (None, Some(primary)) => refresh_from_primary(&queue, primary, key.clone())
+                        .await
+                        .map(|value| value.map(|value| (value, None))),
+                    (read, _) => Ok(read.map(|(value, meta)| (value, Some(meta.ttl_remaining_ms)))),
+                };
+                let response = match read {
+                    Ok(Some((value, ttl_remaining_ms))) => {
+                        formatted_value(key, value, format, ttl_remaining_ms)
+                    }
+                    Ok(None) => miss_response(options.miss_status, accept),
+                    Err(OriginBypassed) => bypassed_response(options.miss_status, accept),
+                };
+                Ok(warp::reply::with_header(response, "Vary", "Accept").into_response())
+            }
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+fn formatted_value(
+    key: String,
+    value: Vec<u8>,
+    format: ValueFormat,
+    ttl_remaining_ms: Option<u128>,
+) -> Response {
+    let base64 = base64::engine::general_purpose::STANDARD;
+    match format {
+        ValueFormat::Json => {
+            let (value, encoding) = match String::from_utf8(value) {
+                Ok(value) => (value, "utf8"),
+                Err(e) => (base64.encode(e.into_bytes()), "base64"),
+            };
+            warp::reply::json(&ValueEnvelope {
+                key,
+                value,
+                encoding,
+                ttl_remaining_ms,
+            })
+            .into_response()
+        }
+        ValueFormat::Base64 => warp::reply::with_header(
+            base64.encode(value),
+            "content-type",
+            "text/plain; charset=utf-8",
+        )
+        .into_response(),
+        ValueFormat::Raw => Response::new(value.into()),
+    }
+}
+
+// value of the key within the namespace, misses are answered the same as those of `/get`
+async fn namespace_read(
+    queue: ServiceQueue,
+    namespace: String,
+    key: String,
+    miss_status: MissStatus,
+    accept: Option<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<Vec<u8>>>();
+
+    match queue.send(ServiceMessage::NsRead(namespace, key, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Some(value)) => Ok(Response::new(value.into())),
+            Ok(None) => Ok(miss_response(miss_status, accept)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// value without counting the read nor removing an expired entry, see `TtlCache::peek`
+async fn peek(
+    queue: ServiceQueue,
+    key: String,
+    miss_status: MissStatus,
+    accept: Option<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<Vec<u8>>>();
+
+    match queue.send(ServiceMessage::Peek(key, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Some(value)) => Ok(Response::new(value.into())),
+            Ok(None) => Ok(miss_response(miss_status, accept)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn namespace_write(
+    queue: ServiceQueue,
+    namespace: String,
+    key: String,
+    value: warp::hyper::body::Bytes,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+
+    match queue
+        .send(ServiceMessage::NsWrite(namespace, key, value[..].into(), tx).with_deadline(deadline))
+    {
+        Ok(_) => match rx.await {
+            Ok(Ok(())) => Ok(empty_response(StatusCode::OK)),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::NamespaceReadOnly(_))) => {
+                Ok(json_error(format!("{}", e), StatusCode::CONFLICT))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn namespace_detach(
+    queue: ServiceQueue,
+    namespace: String,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+
+    match queue.send(ServiceMessage::NsDetach(namespace, tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(Ok(())) => Ok(empty_response(StatusCode::OK)),
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND)),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// namespace in the format of a snapshot file, can be restored as one
+async fn namespace_dump(
+    queue: ServiceQueue,
+    namespace: String,
+    format: SnapshotFormat,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<Vec<DumpEntry>, CacheError>>();
+
+    match queue.send(ServiceMessage::NsDump(namespace, tx).into()) {
+        Ok(_) => match rx.await {
+            // encoded here rather than by the service, which goes on with other requests
+            Ok(Ok(entries)) => match tokio::task::spawn_blocking(move || {
+                let mut dump = Vec::new();
+                let entries = entries
+                    .iter()
+                    .map(|(key, value, ttl)| (key.as_str(), &value[..], *ttl));
+                snapshot::dump(&mut dump, format, entries).map(|_| dump)
+            })
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+            {
+                Ok(dump) => {
+                    let content_type = match format {
+                        SnapshotFormat::Json => "application/x-ndjson",
+                        SnapshotFormat::Binary => "application/octet-stream",
+                    };
+                    Ok(warp::reply::with_header(dump, CONTENT_TYPE, content_type).into_response())
+                }
+                Err(e) => Ok(json_error(
+                    format!("{}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )),
+            },
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND)),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn slowlog(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Vec<SlowOperation>>();
+
+    match queue.send(ServiceMessage::SlowLog(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(operations) => Ok(warp::reply::json(&operations).into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct SlowLogResetResponse {
+    removed: usize,
+}
+
+async fn reset_slowlog(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<usize>();
+
+    match queue.send(ServiceMessage::ResetSlowLog(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(removed) => Ok(warp::reply::json(&SlowLogResetResponse { removed }).into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct NamespaceDropResponse {
+    removed: usize,
+}
+
+async fn namespace_drop(
+    queue: ServiceQueue,
+    namespace: String,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<usize, CacheError>>();
+
+    match queue.send(ServiceMessage::NsDrop(namespace, tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(Ok(removed)) => {
+                Ok(warp::reply::json(&NamespaceDropResponse { removed }).into_response())
+            }
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND)),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// misses tell the client whether it got to refresh the key with `X-Refresh` header
+async fn read_or_lock(
+    queue: ServiceQueue,
+    key: String,
+    lock_ttl: Duration,
+    options: ReadOptions,
+    accept: Option<String>,
+    accept_encoding: Option<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<LockedRead>();
+
+    let refresh = |header: &'static str| {
+        warp::reply::with_header(
+            miss_response(options.miss_status, accept.clone()),
+            "X-Refresh",
+            header,
+        )
+        .into_response()
+    };
+    match queue.send(ServiceMessage::ReadOrLock(key, lock_ttl, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(LockedRead::Value(v)) => {
+                Ok(encode_value(v, accept_encoding, options.compress_min_bytes).await)
+            }
+            Ok(LockedRead::Granted) => Ok(refresh("granted")),
+            Ok(LockedRead::Wait) => Ok(refresh("wait")),
+            Ok(LockedRead::Missing) => Ok(miss_response(options.miss_status, accept)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn read_many(
+    queue: ServiceQueue,
+    keys: Vec<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Vec<Option<Vec<u8>>>>();
+
+    match queue.send(ServiceMessage::ReadMany(keys, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(values) => {
+                // values set through the api are utf-8
+                let values: Vec<Option<String>> = values
+                    .into_iter()
+                    .map(|v| v.map(|v| String::from_utf8_lossy(&v).into_owned()))
+                    .collect();
+                Ok(warp::reply::json(&values).into_response())
+            }
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn ttl_many(
+    queue: ServiceQueue,
+    keys: Vec<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Vec<Option<Duration>>>();
+
+    match queue.send(ServiceMessage::MultiTtl(keys, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(ttls) => {
+                let ttls_ms: Vec<Option<u128>> =
+                    ttls.into_iter().map(|t| t.map(|t| t.as_millis())).collect();
+                Ok(warp::reply::json(&ttls_ms).into_response())
+            }
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn read_if_modified_since(
+    queue: ServiceQueue,
+    key: String,
+    since: u64,
+    options: ReadOptions,
+    accept: Option<String>,
+    accept_encoding: Option<String>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<ConditionalRead>();
+
+    match queue.send(ServiceMessage::ReadIfModifiedSince(key, since, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(ConditionalRead::Value(v)) => {
+                Ok(encode_value(v, accept_encoding, options.compress_min_bytes).await)
+            }
+            Ok(ConditionalRead::NotModified) => Ok(warp::reply::with_status(
+                String::new(),
+                StatusCode::NOT_MODIFIED,
+            )
+            .into_response()),
+            Ok(ConditionalRead::Missing) => Ok(miss_response(options.miss_status, accept)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(warp::reply::with_status(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()),
+        },
+        Err(e) => Ok(
+            warp::reply::with_status(format!("{}", e), StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response(),
+        ),
+    }
+}
+
+fn with_last_modified(mut response: Response, created_at: Option<u64>) -> Response {
+    if let Some(created_at) = created_at {
+        let date = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(created_at));
+        if let Ok(date) = HeaderValue::from_str(&date) {
+            response
+                .headers_mut()
+                .insert(warp::http::header::LAST_MODIFIED, date);
+        }
+    }
+    response
+}
+
+// HTTP dates have second precision, so does the comparison, dates that do not parse
+// are ignored as if the header was not sent
+async fn read_last_modified(
+    queue: ServiceQueue,
+    key: String,
+    if_modified_since: Option<String>,
+    options: ReadOptions,
+    accept: Option<String>,
+    value_headers: ValueHeaders,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let since = if_modified_since
+        .and_then(|date| httpdate::parse_http_date(&date).ok())
+        .and_then(|date| date.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+    let (tx, rx) = oneshot::channel::<DatedRead>();
+
+    match queue.send(ServiceMessage::ReadDated(key.clone(), since, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(DatedRead {
+                read: ConditionalRead::Value(v),
+                created_at,
+            }) => Ok(with_last_modified(
+                value_headers.respond(v, options.compress_min_bytes).await,
+                created_at,
+            )),
+            Ok(DatedRead {
+                read: ConditionalRead::NotModified,
+                created_at,
+            }) => Ok(with_last_modified(
+                empty_response(StatusCode::NOT_MODIFIED),
+                created_at,
+            )),
+            // value fetched from the primary was not created here, it is sent without a date
+            Ok(DatedRead {
+                read: ConditionalRead::Missing,
+                ..
+            }) => match &options.primary {
+                Some(primary) => match refresh_from_primary(&queue, primary, key).await {
+                    Ok(Some(v)) => Ok(value_headers.respond(v, options.compress_min_bytes).await),
+                    Ok(None) => Ok(miss_response(options.miss_status, accept)),
+                    Err(OriginBypassed) => Ok(bypassed_response(options.miss_status, accept)),
+                },
+                None => Ok(miss_response(options.miss_status, accept)),
+            },
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+fn wants_json(accept: &Option<String>) -> bool {
+    accept
+        .as_deref()
+        .map(|a| a.contains("application/json"))
+        .unwrap_or(false)
+}
+
+// explicit length, so that an empty body is never sent chunked
+fn empty_response(status: StatusCode) -> Response {
+    warp::reply::with_header(
+        warp::reply::with_status(String::new(), status),
+        "Content-Length",
+        "0",
+    )
+    .into_response()
+}
+
+// header tells key misses apart from 404s of unknown routes
+fn miss_response(miss_status: MissStatus, accept: Option<String>) -> Response {
+    let response = match miss_status {
+        MissStatus::NotFound if wants_json(&accept) => {
+            json_error(String::from("Not found"), StatusCode::NOT_FOUND)
+        }
+        MissStatus::NotFound => empty_response(StatusCode::NOT_FOUND),
+        MissStatus::OkEmpty => empty_response(StatusCode::OK),
+    };
+    warp::reply::with_header(response, "X-Cache-Result", "miss").into_response()
+}
+
+// value given with `?default=` in place of a miss, not stored, headers of the miss such as
+// `X-Cache-Result` or `X-Refresh` are kept
+fn default_on_miss(response: Response, default: String) -> Response {
+    let is_miss = response
+        .headers()
+        .get("x-cache-result")
+        .map(|v| v.as_bytes() == b"miss")
+        .unwrap_or(false);
+    if !is_miss {
+        return response;
+    }
+    let (mut parts, _) = response.into_parts();
+    parts.status = StatusCode::OK;
+    parts.headers.remove(CONTENT_TYPE);
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, default.into())
+}
+
+// miss of a replica that did not ask the primary, see `origin_failure_threshold`
+fn bypassed_response(miss_status: MissStatus, accept: Option<String>) -> Response {
+    warp::reply::with_header(
+        miss_response(miss_status, accept),
+        "X-Cache",
+        "ORIGIN-BYPASSED",
+    )
+    .into_response()
+}
+
+// values this large are compressed on the blocking pool instead of a runtime worker
+const BLOCKING_COMPRESS_MIN_BYTES: usize = 64 * 1024;
+
+async fn gzip_value(value: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if value.len() < BLOCKING_COMPRESS_MIN_BYTES {
+        return encoding::gzip(&value);
+    }
+    tokio::task::spawn_blocking(move || encoding::gzip(&value))
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+// compresses value longer than `min_bytes` when client accepts gzip, falls back to identity
+// if compression fails, so the response depends on `Accept-Encoding` either way
+async fn encode_value(
+    value: Vec<u8>,
+    accept_encoding: Option<String>,
+    min_bytes: usize,
+) -> Response {
+    let response = if value.len() > min_bytes
+        && accept_encoding
+            .map(|a| encoding::accepts(&a, encoding::GZIP))
+            .unwrap_or(false)
+    {
+        // compression may run on another thread, a copy is kept for the fallback
+        match gzip_value(value.clone()).await {
+            Ok(compressed) => {
+                Ok(
+                    warp::reply::with_header(compressed, "Content-Encoding", encoding::GZIP)
+                        .into_response(),
+                )
+            }
+            Err(e) => {
+                tracing::error!("[read] failed to compress value: {}", e);
+                Err(value)
+            }
+        }
+    } else {
+        Err(value)
+    };
+
+    // values set through the api are utf-8, raw bytes can only come from a transform
+    let response = response.unwrap_or_else(|value| match String::from_utf8(value) {
+        Ok(value) => warp::reply::with_status(value, StatusCode::OK).into_response(),
+        Err(e) => warp::reply::with_status(e.into_bytes(), StatusCode::OK).into_response(),
+    });
+    warp::reply::with_header(response, "Vary", "Accept-Encoding").into_response()
+}
+
+// headers of a plain `/get` deciding how a found value is sent
+struct ValueHeaders {
+    accept_encoding: Option<String>,
+    range: Option<String>,
+}
+
+impl ValueHeaders {
+    // a range is sent as is, compression only applies to whole values
+    async fn respond(self, value: Vec<u8>, compress_min_bytes: usize) -> Response {
+        match self.range {
+            Some(range) => range_response(value, &range),
+            None => encode_value(value, self.accept_encoding, compress_min_bytes).await,
+        }
+    }
+}
+
+// single `bytes=` range of a value of given length as inclusive bounds, `None` when it is
+// malformed, not satisfiable or lists several ranges
+fn byte_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let last = len.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // last `n` bytes
+        ("", suffix) => match suffix.parse::<usize>().ok()? {
+            0 => return None,
+            n => (len.saturating_sub(n), last),
+        },
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<usize>().ok()?.min(last)),
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn range_response(value: Vec<u8>, range: &str) -> Response {
+    match byte_range(range, value.len()) {
+        Some((start, end)) => {
+            let content_range = format!("bytes {}-{}/{}", start, end, value.len());
+            let mut value = value;
+            value.truncate(end + 1);
+            value.drain(..start);
+            warp::reply::with_header(
+                warp::reply::with_status(value, StatusCode::PARTIAL_CONTENT),
+                "Content-Range",
+                content_range,
+            )
+            .into_response()
+        }
+        None => warp::reply::with_header(
+            empty_response(StatusCode::RANGE_NOT_SATISFIABLE),
+            "Content-Range",
+            format!("bytes */{}", value.len()),
+        )
+        .into_response(),
+    }
+}
+
+// `max_value_bytes` is checked again once decoded, `value_body` only sees the body as sent
+fn decode_body(
+    content_encoding: Option<String>,
+    body: warp::hyper::body::Bytes,
+    max_value_bytes: Option<usize>,
+) -> Result<Vec<u8>, (String, StatusCode)> {
+    match content_encoding {
+        None => Ok(body.to_vec()),
+        Some(e) if e.eq_ignore_ascii_case("identity") => Ok(body.to_vec()),
+        Some(e) if e.eq_ignore_ascii_case(encoding::GZIP) => {
+            match encoding::gunzip(&body, max_value_bytes) {
+                Ok(decoded) => match max_value_bytes {
+                    Some(max) if decoded.len() > max => Err((
+                        format!("value is larger than {} bytes", max),
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    )),
+                    _ => Ok(decoded),
+                },
+                Err(e) => Err((
+                    format!("Could not decode gzip body: {}", e),
+                    StatusCode::BAD_REQUEST,
+                )),
+            }
+        }
+        Some(e) => Err((
+            format!("Unsupported content encoding: {}", e),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct SetQuery {
+    // comma separated, e.g. `user:42,org:7`
+    tags: Option<String>,
+    // ttl of the entry instead of the cache one, see `duration_param`
+    ttl: Option<String>,
+}
+
+impl SetQuery {
+    fn ttl(&self) -> Result<Option<Duration>, warp::Rejection> {
+        duration_param("ttl", self.ttl.as_deref())
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct WriteLimits {
+    max_value_bytes: Option<usize>,
+    // replica stores nothing itself, room is for the primary to judge
+    probe_room: bool,
+}
+
+// cheap checks of a write done before its body is read, hyper sends `100 Continue` only
+// once the body is read, so a client waiting for it is turned away without uploading,
+// the probe for room is advisory, the write itself is checked again
+async fn check_write(
+    queue: ServiceQueue,
+    key: &str,
+    content_length: Option<u64>,
+    expect: Option<String>,
+    limits: WriteLimits,
+) -> Result<(), warp::Rejection> {
+    if let (Some(len), Some(max)) = (content_length, limits.max_value_bytes) {
+        if len > max as u64 {
+            return Err(warp::reject::custom(ValueTooLarge(max)));
+        }
+    }
+
+    let expects_continue = expect
+        .map(|e| e.eq_ignore_ascii_case("100-continue"))
+        .unwrap_or(false);
+    if let (true, true, Some(len)) = (expects_continue, limits.probe_room, content_length) {
+        let (tx, rx) = oneshot::channel::<bool>();
+        if queue
+            .send(ServiceMessage::CanWrite(key.to_string(), len as usize, tx).into())
+            .is_ok()
+            && rx.await == Ok(false)
+        {
+            return Err(warp::reject::custom(InsufficientStorage));
+        }
+    }
+    Ok(())
+}
+
+// request body of a write, also rejected over `max_value_bytes` when no length was declared
+fn value_body(
+    max_value_bytes: Option<usize>,
+) -> impl Filter<Extract = (warp::hyper::body::Bytes,), Error = warp::Rejection> + Clone {
+    warp::body::bytes().and_then(move |body: warp::hyper::body::Bytes| async move {
+        match max_value_bytes {
+            Some(max) if body.len() > max => Err(warp::reject::custom(ValueTooLarge(max))),
+            _ => Ok(body),
+        }
+    })
+}
+
+// room in the in-flight write bytes for the declared body, taken before the body is read so
+// that a client is turned away without uploading it, see `ServiceQueue::reserve_write`,
+// handlers grow it to the actual size with `ServiceQueue::grow_write`
+fn inflight_reserved(
+    queue: ServiceQueue,
+) -> impl Filter<Extract = (InflightWrite,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("content-length").and_then(move |len: Option<u64>| {
+        let reserved = usize::try_from(len.unwrap_or(0))
+            .ok()
+            .and_then(|len| queue.reserve_write(len));
+        async move { reserved.ok_or_else(|| warp::reject::custom(InflightWritesFull)) }
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn write(
+    queue: ServiceQueue,
+    key: String,
+    options: WriteOptions,
+    mut inflight: InflightWrite,
+    value: warp::hyper::body::Bytes,
+    content_encoding: Option<String>,
+    max_value_bytes: Option<usize>,
+    deadline: Option<Instant>,
+    primary: Option<Arc<Upstream>>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    // replica does not store writes itself, primary decodes and validates the body
+    if let Some(primary) = primary {
+        let mut query = Vec::new();
+        if !options.tags.is_empty() {
+            query.push(("tags", options.tags.join(",")));
+        }
+        if let Some(ttl) = options.ttl {
+            query.push(("ttl", format!("{}ms", ttl.as_millis().max(1))));
+        }
+        let path = forwarded_path("set", &key, &query);
+        let mut headers = forwarded_headers(None, content_encoding);
+        if let Some(version) = options.expected_version {
+            headers.insert("x-expect-version", HeaderValue::from(version));
+        }
+        let forwarded = primary.forward(Method::POST, &path, headers, value).await;
+        return Ok(relay_forwarded(queue, vec![key], forwarded).await);
+    }
+
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+
+    let value = match decode_body(content_encoding, value, max_value_bytes) {
+        Ok(v) => v,
+        Err((e, status)) => return Ok(warp::reply::with_status(e, status).into_response()),
+    };
+
+    // held until the write is answered, the value stays buffered until then
+    if !queue.grow_write(&mut inflight, value.len()) {
+        return Ok(inflight_writes_response());
+    }
+
+    match std::str::from_utf8(&value) {
+        Ok(_) => match queue.send(
+            if options.tags.is_empty()
+                && options.ttl.is_none()
+                && options.expected_version.is_none()
+            {
+                ServiceMessage::Write(key, value, tx)
+            } else {
+                ServiceMessage::WriteTagged(key, value, Box::new(options), tx)
+            }
+            .with_deadline(deadline),
+        ) {
+            Ok(_) => match rx.await {
+                Ok(res) => match res {
+                    Ok(_) => {
+                        Ok(warp::reply::with_status(String::new(), StatusCode::OK).into_response())
+                    }
+                    // read-only mode could have been switched on after the request passed the filter
+                    Err(CacheError::ReadOnly) => Ok(read_only_response()),
+                    Err(e @ CacheError::Flushed) => Ok(warp::reply::with_status(
+                        format!("{}", e),
+                        StatusCode::CONFLICT,
+                    )
+                    .into_response()),
+                    // current version lets the client retry without reading the meta again
+                    Err(CacheError::VersionMismatch(key, current)) => Ok(warp::reply::with_header(
+                        warp::reply::with_status(
+                            format!("{}", CacheError::VersionMismatch(key, current)),
+                            StatusCode::CONFLICT,
+                        ),
+                        "X-Cache-Version",
+                        current.to_string(),
+                    )
+                    .into_response()),
+                    Err(e) => Ok(warp::reply::with_status(
+                        format!("{}", e),
+                        StatusCode::BAD_REQUEST,
+                    )
+                    .into_response()),
+                },
+                Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+                Err(e) => Ok(warp::reply::with_status(
+                    format!("{}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into_response()),
+            },
+            Err(e) => Ok(warp::reply::with_status(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()),
+        },
+
+        Err(e) => Ok(warp::reply::with_status(
+            format!("Could not decode utf-8: {}", e),
+            StatusCode::BAD_REQUEST,
+        )
+        .into_response()),
+    }
+}
+
+async fn txn(
+    queue: ServiceQueue,
+    ops: Vec<TxnOp>,
+    deadline: Option<Instant>,
+    primary: Option<Arc<Upstream>>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if let Some(primary) = primary {
+        let body = match serde_json::to_vec(&ops) {
+            Ok(body) => body,
+            Err(e) => {
+                return Ok(json_error(
+                    format!("{}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        };
+        let keys = ops.iter().map(|op| op.key().to_string()).collect();
+        let forwarded = primary
+            .forward(
+                Method::POST,
+                "/txn",
+                forwarded_headers(Some("application/json"), None),
+                body.into(),
+            )
+            .await;
+        return Ok(relay_forwarded(queue, keys, forwarded).await);
+    }
+
+    let (tx, rx) = oneshot::channel::<Result<TxnResult, CacheError>>();
+
+    match queue.send(ServiceMessage::Txn(ops, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(result)) if result.committed => Ok(warp::reply::json(&result).into_response()),
+            // per-operation results tell which one failed
+            Ok(Ok(result)) => Ok(warp::reply::with_status(
+                warp::reply::json(&result),
+                StatusCode::CONFLICT,
+            )
+            .into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::Flushed)) => {
+                Ok(json_error(format!("{}", e), StatusCode::CONFLICT))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn invalidate_tag(
+    queue: ServiceQueue,
+    tag: String,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<TagInvalidation, CacheError>>();
+
+    match queue.send(ServiceMessage::InvalidateTag(tag, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(result)) => Ok(warp::reply::json(&result).into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkLoadQuery {
+    // only validates the lines, nothing is written
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Clone)]
+struct BulkLoadOptions {
+    key_rules: KeyRules,
+    max_value_bytes: Option<usize>,
+    batch_size: usize,
+}
+
+// writes lines of a bulk load in batches, a batch is sent once the previous one was applied
+struct BulkLoader {
+    queue: ServiceQueue,
+    options: BulkLoadOptions,
+    dry_run: bool,
+    report: BulkLoadReport,
+    // numbers of the lines in `writes`
+    line_numbers: Vec<usize>,
+    writes: Vec<BulkWrite>,
+}
+
+impl BulkLoader {
+    async fn line(&mut self, n: usize, line: Result<Vec<u8>, String>) -> Result<(), String> {
+        let write = match line.and_then(|line| match line.is_empty() {
+            true => Ok(None),
+            false => bulk::parse_line(&line, self.options.max_value_bytes).map(Some),
+        }) {
+            Ok(Some(write)) => write,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                self.report.skip(n, e);
+                return Ok(());
+            }
+        };
+        if let Some(max) = self
+            .options
+            .key_rules
+            .max_len
+            .filter(|max| write.key.len() > *max)
+        {
+            self.report
+                .skip(n, format!("key is longer than {} bytes", max));
+            return Ok(());
+        }
+        if !self.options.key_rules.allows(&write.key) {
+            self.report.skip(
+                n,
+                format!("key {} is outside of allowed prefixes", write.key),
+            );
+            return Ok(());
+        }
+        if self.dry_run {
+            self.report.loaded += 1;
+            return Ok(());
+        }
+        self.line_numbers.push(n);
+        self.writes.push(write);
+        if self.writes.len() >= self.options.batch_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), String> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let writes = std::mem::take(&mut self.writes);
+        let (tx, rx) = oneshot::channel::<Vec<Result<(), CacheError>>>();
+        self.queue
+            .send(ServiceMessage::WriteBatch(writes, tx).into())
+            .map_err(|e| format!("{}", e))?;
+        let results = rx.await.map_err(|e| format!("{}", e))?;
+        for (n, result) in self.line_numbers.drain(..).zip(results) {
+            match result {
+                Ok(()) => self.report.loaded += 1,
+                Err(e) => self.report.skip(n, format!("{}", e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_tsv(content_type: &Option<String>) -> bool {
+    content_type
+        .as_deref()
+        .and_then(|c| c.split(';').next())
+        .map(|c| c.trim().eq_ignore_ascii_case("text/tab-separated-values"))
+        .unwrap_or(false)
+}
+
+// body is parsed as it arrives, only the line being read and the current batch are buffered
+async fn bulk_load<B: Buf>(
+    queue: ServiceQueue,
+    content_type: Option<String>,
+    query: BulkLoadQuery,
+    body: impl tokio_stream::Stream<Item = Result<B, warp::Error>>,
+    options: BulkLoadOptions,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if !is_tsv(&content_type) {
+        return Ok(json_error(
+            String::from("expected content-type text/tab-separated-values"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ));
+    }
+
+    // a line is at most a key, a value and a ttl, anything longer is skipped unread
+    let max_line = match (options.key_rules.max_len, options.max_value_bytes) {
+        (Some(key), Some(value)) => Some(key + value + bulk::LINE_OVERHEAD),
+        _ => None,
+    };
+    let mut loader = BulkLoader {
+        queue,
+        options,
+        dry_run: query.dry_run,
+        report: BulkLoadReport::default(),
+        line_numbers: Vec::new(),
+        writes: Vec::new(),
+    };
+    let mut lines = Lines::new(max_line);
+    tokio::pin!(body);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return Ok(json_error(
+                    format!("failed to read body: {}", e),
+                    StatusCode::BAD_REQUEST,
+                ))
+            }
+        };
+        let chunk = chunk.copy_to_bytes(chunk.remaining());
+        for (n, line) in lines.push(&chunk) {
+            if let Err(e) = loader.line(n, line).await {
+                return Ok(json_error(e, StatusCode::INTERNAL_SERVER_ERROR));
+            }
+        }
+    }
+    if let Some((n, line)) = lines.finish() {
+        if let Err(e) = loader.line(n, line).await {
+            return Ok(json_error(e, StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    }
+    if let Err(e) = loader.flush().await {
+        return Ok(json_error(e, StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    Ok(warp::reply::json(&loader.report).into_response())
+}
+
+#[derive(Deserialize)]
+struct FlushQuery {
+    #[serde(default)]
+    fence: bool,
+}
+
+async fn flush(
+    queue: ServiceQueue,
+    query: FlushQuery,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<FlushReport, CacheError>>();
+
+    match queue.send(ServiceMessage::Flush(query.fence, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(report)) => Ok(warp::reply::json(&report).into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct SwapQuery {
+    a: String,
+    b: String,
+}
+
+async fn swap(
+    queue: ServiceQueue,
+    query: SwapQuery,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+
+    match queue.send(ServiceMessage::Swap(query.a, query.b, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(())) => Ok(warp::reply().into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::NotFound(_))) => {
+                Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct RenameQuery {
+    #[serde(default)]
+    overwrite: bool,
+}
+
+async fn rename(
+    queue: ServiceQueue,
+    from: String,
+    to: String,
+    query: RenameQuery,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+
+    match queue.send(ServiceMessage::Rename(from, to, query.overwrite, tx).with_deadline(deadline))
+    {
+        Ok(_) => match rx.await {
+            Ok(Ok(())) => Ok(warp::reply().into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::NotFound(_))) => {
+                Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND))
+            }
+            Ok(Err(e @ CacheError::Conflict(_))) => {
+                Ok(json_error(format!("{}", e), StatusCode::CONFLICT))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn pin(
+    queue: ServiceQueue,
+    key: String,
+    pinned: bool,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<(), CacheError>>();
+
+    match queue.send(ServiceMessage::Pin(key, pinned, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(())) => Ok(warp::reply().into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::NotFound(_))) => {
+                Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct AccessCountResponse {
+    key: String,
+    // reads since the entry was set or the count was last reset
+    count: u64,
+}
+
+async fn access_count(
+    queue: ServiceQueue,
+    key: String,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<EntryMeta>>();
+
+    match queue.send(ServiceMessage::Meta(key.clone(), tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Some(meta)) => Ok(warp::reply::json(&AccessCountResponse {
+                key,
+                count: meta.hits,
+            })
+            .into_response()),
+            Ok(None) => Ok(json_error(String::from("Not found"), StatusCode::NOT_FOUND)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// responds with the count before the reset
+async fn reset_access_count(
+    queue: ServiceQueue,
+    key: String,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<u64, CacheError>>();
+
+    match queue.send(ServiceMessage::ResetHits(key.clone(), tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(count)) => {
+                Ok(warp::reply::json(&AccessCountResponse { key, count }).into_response())
+            }
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::NotFound(_))) => {
+                Ok(json_error(format!("{}", e), StatusCode::NOT_FOUND))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct RingPushQuery {
+    max: usize,
+}
+
+#[derive(Serialize)]
+struct RingPushResponse {
+    lines: usize,
+}
+
+async fn ring_push(
+    queue: ServiceQueue,
+    key: String,
+    max: usize,
+    line: warp::hyper::body::Bytes,
+    deadline: Option<Instant>,
+    primary: Option<Arc<Upstream>>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if let Some(primary) = primary {
+        let path = forwarded_path("ringpush", &key, &[("max", max.to_string())]);
+        let forwarded = primary
+            .forward(Method::POST, &path, forwarded_headers(None, None), line)
+            .await;
+        return Ok(relay_forwarded(queue, vec![key], forwarded).await);
+    }
+
+    if max == 0 {
+        return Ok(json_error(
+            String::from("max has to be at least 1"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    match std::str::from_utf8(&line) {
+        Ok(s) if s.contains('\n') => {
+            return Ok(json_error(
+                String::from("line must not contain newlines"),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+        Ok(_) => (),
+        Err(e) => return Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+    }
+
+    let (tx, rx) = oneshot::channel::<Result<usize, CacheError>>();
+
+    match queue.send(ServiceMessage::RingPush(key, line.to_vec(), max, tx).with_deadline(deadline))
+    {
+        Ok(_) => match rx.await {
+            Ok(Ok(lines)) => Ok(warp::reply::json(&RingPushResponse { lines }).into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e @ CacheError::Flushed)) => {
+                Ok(json_error(format!("{}", e), StatusCode::CONFLICT))
+            }
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct DeleteResponse {
+    deleted: bool,
+}
+
+#[derive(Serialize)]
+struct CreatedAfterResponse {
+    error: String,
+    created_at_ms: u64,
+}
+
+async fn delete(
+    queue: ServiceQueue,
+    key: String,
+    created_before: Option<u64>,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<ConditionalDelete, CacheError>>();
+
+    match queue.send(ServiceMessage::Delete(key, created_before, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Ok(ConditionalDelete::Deleted)) => {
+                Ok(warp::reply::json(&DeleteResponse { deleted: true }).into_response())
+            }
+            Ok(Ok(ConditionalDelete::Missing)) => {
+                Ok(json_error(String::from("Not found"), StatusCode::NOT_FOUND))
+            }
+            Ok(Ok(ConditionalDelete::CreatedAfter(created_at_ms))) => Ok(warp::reply::with_status(
+                warp::reply::json(&CreatedAfterResponse {
+                    error: String::from("entry is not older than X-If-Created-Before"),
+                    created_at_ms,
+                }),
+                StatusCode::PRECONDITION_FAILED,
+            )
+            .into_response()),
+            Ok(Err(CacheError::ReadOnly)) => Ok(read_only_response()),
+            Ok(Err(e)) => Ok(json_error(format!("{}", e), StatusCode::BAD_REQUEST)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn meta(
+    queue: ServiceQueue,
+    key: String,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<EntryMeta>>();
+
+    match queue.send(ServiceMessage::Meta(key, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Some(meta)) => Ok(warp::reply::json(&meta).into_response()),
+            Ok(None) => Ok(json_error(String::from("Not found"), StatusCode::NOT_FOUND)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// `/stats` of the service along with what only the frontend knows of
+#[derive(Serialize)]
+struct StatsReply {
+    #[serde(flatten)]
+    cache: CacheStats,
+    // circuit breaker of a replica fetching from its primary, see `origin_failure_threshold`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    origin_breaker: Option<BreakerSnapshot>,
+}
+
+async fn stats(
+    queue: ServiceQueue,
+    primary: Option<Arc<Upstream>>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<CacheStats>();
+
+    match queue.send(ServiceMessage::Stats(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(cache) => Ok(warp::reply::json(&StatsReply {
+                cache,
+                origin_breaker: primary.and_then(|primary| primary.breaker()),
+            })
+            .into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn maintenance_status(
+    queue: ServiceQueue,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<MaintenanceStatus>();
+
+    match queue.send(ServiceMessage::MaintenanceStatus(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(status) => Ok(warp::reply::json(&status).into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn metrics(
+    queue: ServiceQueue,
+    http_metrics: Arc<HttpMetrics>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<CacheStats>();
+
+    match queue.send(ServiceMessage::Stats(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(stats) => Ok(warp::reply::with_header(
+                metrics::render(&stats, &http_metrics),
+                "content-type",
+                metrics::CONTENT_TYPE,
+            )
+            .into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn ttl_histogram(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<TtlHistogram>();
+
+    match queue.send(ServiceMessage::TtlHistogram(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(histogram) => Ok(warp::reply::json(&histogram).into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct GetQuery {
+    // unix time in seconds
+    since: Option<u64>,
+    // how long the refresh lock is held for when key is missing, see `duration_param`
+    refresh_lock: Option<String>,
+    format: Option<ValueFormat>,
+    // answered with 200 instead of a miss, see `default_on_miss`
+    default: Option<String>,
+}
+
+impl GetQuery {
+    fn refresh_lock(&self) -> Result<Option<Duration>, warp::Rejection> {
+        duration_param("refresh_lock", self.refresh_lock.as_deref())
+    }
+
+    // explicit `format` wins over `Accept`, raw bytes unless the client asks for json
+    fn format(&self, accept: &Option<String>) -> ValueFormat {
+        self.format.unwrap_or(if wants_json(accept) {
+            ValueFormat::Json
+        } else {
+            ValueFormat::Raw
+        })
+    }
+}
+
+// representation of a value returned by `/get`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ValueFormat {
+    Raw,
+    // `ValueEnvelope`, for clients that only speak json
+    Json,
+    // value as base64 text, for binary values
+    Base64,
+}
+
+#[derive(Serialize)]
+struct ValueEnvelope {
+    key: String,
+    // as is when valid utf-8, base64 otherwise
+    value: String,
+    encoding: &'static str,
+    // not known for values just fetched from the primary
+    ttl_remaining_ms: Option<u128>,
+}
+
+#[derive(Serialize)]
+struct ItemResponse {
+    // as is when valid utf-8, base64 otherwise
+    value: String,
+    encoding: &'static str,
+    // null for pinned entries
+    ttl_secs: Option<u64>,
+    created_at: u64,
+    version: u64,
+}
+
+// value with its metadata in one response, never read through from the primary
+async fn read_item(
+    queue: ServiceQueue,
+    key: String,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<Item>>();
+
+    match queue.send(ServiceMessage::ReadItem(key, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Some(item)) => {
+                let (value, encoding) = match String::from_utf8(item.value) {
+                    Ok(value) => (value, "utf8"),
+                    Err(e) => (
+                        base64::engine::general_purpose::STANDARD.encode(e.into_bytes()),
+                        "base64",
+                    ),
+                };
+                Ok(warp::reply::json(&ItemResponse {
+                    value,
+                    encoding,
+                    ttl_secs: item.ttl_secs,
+                    created_at: item.created_at,
+                    version: item.version,
+                })
+                .into_response())
+            }
+            Ok(None) => Ok(json_error(String::from("Not found"), StatusCode::NOT_FOUND)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+struct ValueLenResponse {
+    len: usize,
+}
+
+async fn value_len(
+    queue: ServiceQueue,
+    key: String,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Option<usize>>();
+
+    match queue.send(ServiceMessage::ValueLen(key, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(Some(len)) => Ok(warp::reply::json(&ValueLenResponse { len }).into_response()),
+            Ok(None) => Ok(json_error(String::from("Not found"), StatusCode::NOT_FOUND)),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExplainQuery {
+    // explain a write of a value of this size as well
+    value_size: Option<usize>,
+}
+
+async fn explain(
+    queue: ServiceQueue,
+    key: String,
+    query: ExplainQuery,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Explanation>();
+
+    match queue.send(ServiceMessage::Explain(key, query.value_size, tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(explanation) => Ok(warp::reply::json(&explanation).into_response()),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct CountQuery {
+    // glob, `*` matches any sequence of characters and `?` a single one
+    pattern: String,
+}
+
+#[derive(Serialize)]
+struct CountResponse {
+    pattern: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct PingResponse {
+    latency_us: u128,
+}
+
+async fn ping(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<()>();
+
+    let started = Instant::now();
+    match queue.send(ServiceMessage::Ping(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(()) => Ok(warp::reply::json(&PingResponse {
+                latency_us: started.elapsed().as_micros(),
+            })
+            .into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn count(
+    queue: ServiceQueue,
+    query: CountQuery,
+    deadline: Option<Instant>,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<usize>();
+
+    match queue.send(ServiceMessage::Count(query.pattern.clone(), tx).with_deadline(deadline)) {
+        Ok(_) => match rx.await {
+            Ok(count) => Ok(warp::reply::json(&CountResponse {
+                pattern: query.pattern,
+                count,
+            })
+            .into_response()),
+            Err(_) if is_past(deadline) => Ok(deadline_exceeded_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    prefix: Option<String>,
+    // comma separated list of event kinds
+    kinds: Option<String>,
+}
+
+// events carry their seq as `id`, a client reconnecting with `Last-Event-ID` gets events
+// it missed replayed, or a `gap` event first when some of them are no longer kept
+async fn events(
+    queue: ServiceQueue,
+    query: EventsQuery,
+    last_event_id: Option<u64>,
+    keepalive: Duration,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let filter = match EventFilter::parse(query.prefix, query.kinds.as_deref()) {
+        Ok(filter) => filter,
+        Err(e) => return Ok(json_error(e, StatusCode::BAD_REQUEST)),
+    };
+
+    let (tx, rx) = oneshot::channel::<Result<Subscription, CacheError>>();
+
+    match queue.send(ServiceMessage::Subscribe(Box::new(filter), last_event_id, tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(Ok(subscription)) => {
+                let stream = subscription.matching_events().map(|item| match item {
+                    EventItem::Event(event) => warp::sse::Event::default()
+                        .id(event.seq.to_string())
+                        .event(event.kind.as_str())
+                        .json_data(&event),
+                    EventItem::Gap(gap) => warp::sse::Event::default().event("gap").json_data(&gap),
+                });
+                Ok(
+                    warp::sse::reply(warp::sse::keep_alive().interval(keepalive).stream(stream))
+                        .into_response(),
+                )
+            }
+            Ok(Err(e)) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn set_read_only(
+    flags: Arc<ServiceFlags>,
+    value: warp::hyper::body::Bytes,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    match std::str::from_utf8(&value).map(|v| v.trim().parse::<bool>()) {
+        Ok(Ok(read_only)) => {
+            flags.set_read_only(read_only);
+            tracing::info!("[admin] read-only mode set to {}", read_only);
+            Ok(warp::reply::with_status(format!("{}", read_only), StatusCode::OK).into_response())
+        }
+        _ => Ok(json_error(
+            String::from("expected 'true' or 'false'"),
+            StatusCode::BAD_REQUEST,
+        )),
+    }
+}
+
+async fn compact(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<CompactReport>();
+
+    match queue.send(ServiceMessage::Compact(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(report) => Ok(warp::reply::json(&report).into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReserveQuery {
+    additional: usize,
+}
+
+// most keys `/admin/reserve` grows the map by at once
+const MAX_RESERVE: usize = 1 << 24;
+
+async fn reserve(
+    query: ReserveQuery,
+    queue: ServiceQueue,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if query.additional > MAX_RESERVE {
+        return Ok(json_error(
+            format!("additional must be at most {}", MAX_RESERVE),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let (tx, rx) = oneshot::channel::<Result<CompactReport, TryReserveError>>();
+
+    match queue.send(ServiceMessage::Reserve(query.additional, tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(Ok(report)) => Ok(warp::reply::json(&report).into_response()),
+            Ok(Err(e)) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INSUFFICIENT_STORAGE,
+            )),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn evict(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<EvictionReport>();
+
+    match queue.send(ServiceMessage::Evict(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(report) => Ok(warp::reply::json(&report).into_response()),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// benchmark is refused while the service is this busy, it would skew live latencies
+// as much as the results
+const SELFTEST_MAX_QUEUED: usize = 64;
+// time between two benchmarks
+const SELFTEST_COOLDOWN: Duration = Duration::from_secs(10);
+
+struct SelftestState {
+    config: Config,
+    last_run: std::sync::Mutex<Option<Instant>>,
+}
+
+async fn run_selftest(
+    queue: ServiceQueue,
+    state: Arc<SelftestState>,
+    params: BenchParams,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    if let Err(e) = params.validate() {
+        return Ok(json_error(e, StatusCode::BAD_REQUEST));
+    }
+
+    let (tx, rx) = oneshot::channel::<CacheStats>();
+    let queued = match queue.send(ServiceMessage::Stats(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(stats) => stats.queued.reads + stats.queued.writes,
+            Err(e) => {
+                return Ok(json_error(
+                    format!("{}", e),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ))
+            }
+        },
+        Err(e) => {
+            return Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    };
+    if queued > SELFTEST_MAX_QUEUED {
+        return Ok(json_error(
+            format!("service is busy, {} requests queued", queued),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    {
+        let mut last_run = state.last_run.lock().unwrap();
+        if let Some(wait) = last_run.map(|t| SELFTEST_COOLDOWN.saturating_sub(t.elapsed())) {
+            if !wait.is_zero() {
+                return Ok(warp::reply::with_header(
+                    json_error(
+                        String::from("selftest ran recently"),
+                        StatusCode::TOO_MANY_REQUESTS,
+                    ),
+                    "Retry-After",
+                    wait.as_secs().max(1).to_string(),
+                )
+                .into_response());
+            }
+        }
+        *last_run = Some(Instant::now());
+    }
+
+    tracing::info!("[admin] selftest {:?}", params);
+    match tokio::task::spawn_blocking(move || selftest::bench(&state.config, &params)).await {
+        Ok(report) => Ok(warp::reply::json(&report).into_response()),
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn set_eviction_paused(
+    flags: Arc<ServiceFlags>,
+    paused: bool,
+) -> Result<impl warp::Reply, std::convert::Infallible> {
+    flags.set_eviction_paused(paused);
+    tracing::info!("[admin] eviction paused: {}", paused);
+    Ok(warp::reply::with_status(
+        String::from(if paused { "paused" } else { "resumed" }),
+        StatusCode::OK,
+    ))
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<Response, warp::Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(json_error(
+            String::from("unauthorized"),
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else if err.find::<ReadOnly>().is_some() {
+        Ok(read_only_response())
+    } else if err.find::<WriteForbidden>().is_some() {
+        Ok(json_error(
+            String::from("client is not allowed to write"),
+            StatusCode::FORBIDDEN,
+        ))
+    } else if err.find::<InflightWritesFull>().is_some() {
+        Ok(inflight_writes_response())
+    } else if err.find::<Overloaded>().is_some() {
+        Ok(warp::reply::with_header(
+            json_error(
+                String::from("service is overloaded"),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            "Retry-After",
+            "1",
+        )
+        .into_response())
+    } else if let Some(KeyNotAllowed(key)) = err.find::<KeyNotAllowed>() {
+        Ok(json_error(
+            format!("key {} is outside of allowed prefixes", key),
+            StatusCode::BAD_REQUEST,
+        ))
+    } else if let Some(KeyTooLong(max)) = err.find::<KeyTooLong>() {
+        Ok(json_error(
+            format!("key is longer than {} bytes", max),
+            StatusCode::URI_TOO_LONG,
+        ))
+    } else if let Some(InvalidDuration { param, error }) = err.find::<InvalidDuration>() {
+        Ok(json_error(
+            format!(
+                "invalid {}: {}, expected {}",
+                param, error, ACCEPTED_FORMATS
+            ),
+            StatusCode::BAD_REQUEST,
+        ))
+    } else if let Some(ValueTooLarge(max)) = err.find::<ValueTooLarge>() {
+        Ok(json_error(
+            format!("value is larger than {} bytes", max),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ))
+    } else if let Some(GenerationMismatch(generation)) = err.find::<GenerationMismatch>() {
+        Ok(json_error(
+            format!("cache generation is {}", generation),
+            StatusCode::PRECONDITION_FAILED,
+        ))
+    } else if err.find::<InsufficientStorage>().is_some() {
+        Ok(json_error(
+            format!("{}", CacheError::OutOfCapacity(None)),
+            StatusCode::INSUFFICIENT_STORAGE,
+        ))
+    } else if err.is_not_found() || err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        // no `X-Cache-Result` header here, unlike key misses, method mismatches of
+        // routes that did not match the path either end up here too, resources
+        // answer with 405 themselves, see `method_not_allowed`
+        Ok(empty_response(StatusCode::NOT_FOUND))
+    } else {
+        Err(err)
+    }
+}
+
+fn with_primary(
+    primary: Option<Arc<Upstream>>,
+) -> impl Filter<Extract = (Option<Arc<Upstream>>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || primary.clone())
+}
+
+// 405 for `/<resource>/<key>` requested with a method other than `allowed`, to be tried
+// after the routes of the resource so that their own rejections are not masked
+fn method_not_allowed(
+    resource: &'static str,
+    allowed: &'static [&'static str],
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::path(resource)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::method())
+        .and_then(move |_key: String, method: Method| async move {
+            if allowed.contains(&method.as_str()) {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(warp::reply::with_header(
+                    json_error(
+                        format!("method {} not allowed", method),
+                        StatusCode::METHOD_NOT_ALLOWED,
+                    ),
+                    "Allow",
+                    allowed.join(", "),
+                )
+                .into_response())
+            }
+        })
+}
+
+// key of the request when it is to be passed to a peer, rejects otherwise
+// so that local routes handle it, the key is canonicalized so that its spellings
+// hash to the same peer
+fn cluster_routed(
+    cluster: Option<Arc<Cluster>>,
+    api_version: &'static str,
+    canonicalization: KeyCanonicalization,
+) -> impl Filter<Extract = (Arc<Cluster>, String), Error = warp::Rejection> + Clone {
+    warp::path::full()
+        .and_then(move |path: FullPath| {
+            let key = routed_key(path.as_str(), api_version)
+                .map(|key| canonicalization.canonicalize(key));
+            let routed = cluster.clone().zip(key);
+            async move { routed.ok_or_else(warp::reject::not_found) }
+        })
+        .untuple_one()
+}
+
+// address of the connected client, the one the listener recorded or the remote one
+fn client_addr(
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = std::convert::Infallible> + Clone {
+    warp::ext::optional::<ClientAddr>()
+        .and(warp::addr::remote())
+        .map(|client: Option<ClientAddr>, addr: Option<SocketAddr>| {
+            client.map(|c| c.0).or(addr).map(|a| a.ip())
+        })
+}
+
+fn with_cache_tx(
+    tx: ServiceQueue,
+) -> impl Filter<Extract = (ServiceQueue,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || tx.clone())
+}
+
+// for mutating routes, requests sent through it carry the client address for the audit log
+fn with_client_tx(
+    tx: ServiceQueue,
+) -> impl Filter<Extract = (ServiceQueue,), Error = std::convert::Infallible> + Clone {
+    warp::ext::optional::<ClientAddr>()
+        .and(warp::addr::remote())
+        .map(
+            move |client: Option<ClientAddr>, addr: Option<SocketAddr>| {
+                tx.for_client(client.map(|c| c.0).or(addr).map(|a| a.ip()))
+            },
+        )
+}
+
+// time budget from `X-Request-Deadline-Ms` header, capped by `request_timeout`
+fn deadline(
+    request_timeout: Option<Duration>,
+) -> impl Filter<Extract = (Option<Instant>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("x-request-deadline-ms").map(move |budget_ms: Option<u64>| {
+        let budget = match (budget_ms.map(Duration::from_millis), request_timeout) {
+            (Some(budget), Some(timeout)) => Some(budget.min(timeout)),
+            (budget, timeout) => budget.or(timeout),
+        };
+        budget.map(|b| Instant::now() + b)
+    })
+}
+
+fn with_flags(
+    flags: Arc<ServiceFlags>,
+) -> impl Filter<Extract = (Arc<ServiceFlags>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || flags.clone())
+}
+
+// rejects mutating requests early while the cache is in read-only mode
+fn writable(
+    flags: Arc<ServiceFlags>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let read_only = flags.is_read_only();
+            async move {
+                if read_only {
+                    Err(warp::reject::custom(ReadOnly))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// mutating and admin routes, every client passes without `write_allowlist`
+fn write_allowed(
+    allowlist: Option<Arc<Vec<IpNet>>>,
+    trust_proxy: bool,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::ext::optional::<ClientAddr>()
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(
+            move |client: Option<ClientAddr>,
+                  addr: Option<SocketAddr>,
+                  forwarded: Option<String>| {
+                let allowed = match &allowlist {
+                    Some(allowlist) => allows_write(
+                        allowlist,
+                        client.map(|c| c.0).or(addr).map(|a| a.ip()),
+                        forwarded.as_deref().filter(|_| trust_proxy),
+                    ),
+                    None => true,
+                };
+                async move {
+                    if allowed {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::custom(WriteForbidden))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+// forwarded address is only taken from an allowlisted peer and has to be allowlisted itself,
+// its last entry is the one the proxy added, those before it come from the client
+fn allows_write(allowlist: &[IpNet], peer: Option<IpAddr>, forwarded: Option<&str>) -> bool {
+    let listed = |ip: IpAddr| allowlist.iter().any(|net| net.contains(&ip.to_canonical()));
+    match peer {
+        Some(peer) if listed(peer) => match forwarded.and_then(|f| f.rsplit(',').next()) {
+            Some(client) => client.trim().parse().map(listed).unwrap_or(false),
+            None => true,
+        },
+        _ => false,
+    }
+}
+
+// routed requests other than reads have to pass `write_allowed` before they reach a peer
+fn routed_write_allowed(
+    allowlist: Option<Arc<Vec<IpNet>>>,
+    trust_proxy: bool,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and_then(|method: Method| async move {
+            if method == Method::GET || method == Method::HEAD {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .or(write_allowed(allowlist, trust_proxy))
+        .unify()
+}
+
+// reads and writes of entries, including the ones labelled as "other" in http metrics,
+// e.g. `/peek`, `/strlen` or those within a namespace
+fn is_key_access(path: &str, api_version: &str) -> bool {
+    if matches!(route_label(path, api_version), "get" | "set" | "del") {
+        return true;
+    }
+    let path = path.trim_start_matches('/');
+    let path = path
+        .strip_prefix(api_version)
+        .and_then(|p| p.strip_prefix('/'))
+        .unwrap_or(path);
+    let mut segments = path.split('/');
+    match (segments.next(), segments.nth(1)) {
+        (Some("ns"), Some(op)) => op == "get" || op == "set",
+        (Some(endpoint), _) => matches!(
+            endpoint,
+            "peek" | "meta" | "access" | "strlen" | "explain" | "count"
+        ),
+        _ => false,
+    }
+}
+
+// reads and writes are rejected before they are queued while the service is overloaded,
+// everything else, e.g. `/stats` or admin endpoints, is still served
+fn not_overloaded(
+    flags: Arc<ServiceFlags>,
+    api_version: &'static str,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::path::full()
+        .and_then(move |path: FullPath| {
+            let shed = flags.is_overloaded() && is_key_access(path.as_str(), api_version);
+            async move {
+                if shed {
+                    Err(warp::reject::custom(Overloaded))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// `X-Expect-Generation` precondition, checked before the request is served so a flush racing
+// with it is not detected
+fn expected_generation(
+    flags: Arc<ServiceFlags>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<u64>("x-expect-generation")
+        .and_then(move |expected: Option<u64>| {
+            let generation = flags.generation();
+            async move {
+                match expected {
+                    Some(expected) if expected != generation => {
+                        Err(warp::reject::custom(GenerationMismatch(generation)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    api_version: &'static str,
+    generation: u64,
+    read_only: bool,
+}
+
+// served at `/` with `enable_dashboard`, `stats` is relative so that the page keeps
+// working under the api version prefix
+const DASHBOARD_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>in-mem-cached</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+td { padding: 0.3em 1em; }
+td:last-child { text-align: right; font-family: monospace; }
+</style>
+</head>
+<body>
+<h1>in-mem-cached</h1>
+<table>
+<tr><td>keys</td><td id="keys">-</td></tr>
+<tr><td>hit ratio</td><td id="hit_ratio">-</td></tr>
+<tr><td>memory, bytes</td><td id="bytes">-</td></tr>
+</table>
+<p id="error"></p>
+<script>
+async function refresh() {
+  try {
+    const stats = await (await fetch("stats")).json();
+    const lookups = stats.process.hits + stats.process.misses;
+    document.getElementById("keys").textContent = stats.keys_total;
+    document.getElementById("hit_ratio").textContent =
+      lookups > 0 ? (100 * stats.process.hits / lookups).toFixed(1) + "%" : "-";
+    document.getElementById("bytes").textContent = stats.bytes_total;
+    document.getElementById("error").textContent = "";
+  } catch (e) {
+    document.getElementById("error").textContent = "failed to load stats: " + e;
+  }
+}
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"#;
+
+// passes only when the switch is on, as if the route did not exist otherwise
+fn enabled(on: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if on {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+fn admin_auth(token: Option<String>) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let authorized = match (&token, header) {
+                (Some(token), Some(header)) => header == format!("Bearer {}", token),
+                _ => false,
+            };
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn snapshot(queue: ServiceQueue) -> Result<impl warp::Reply, std::convert::Infallible> {
+    let (tx, rx) = oneshot::channel::<Result<SnapshotReport, SnapshotError>>();
+
+    match queue.send(ServiceMessage::Snapshot(tx).into()) {
+        Ok(_) => match rx.await {
+            Ok(Ok(report)) => Ok(warp::reply::json(&report).into_response()),
+            Ok(Err(e @ SnapshotError::NotConfigured)) => {
+                Ok(json_error(format!("{}", e), StatusCode::CONFLICT))
+            }
+            Ok(Err(e)) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+            Err(e) => Ok(json_error(
+                format!("{}", e),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+        },
+        Err(e) => Ok(json_error(
+            format!("{}", e),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+// other nodes requests are passed on to, built once on startup, see `replica_of` and
+// `cluster_peers`
+pub struct Upstreams {
+    primary: Option<Arc<Upstream>>,
+    cluster: Option<Arc<Cluster>>,
+}
+
+impl Upstreams {
+    // fails on invalid urls and origin settings, see `Upstream::origin` and `Cluster::new`
+    pub fn new(config: &Config) -> Result<Upstreams, String> {
+        let primary = config
+            .replica_of
+            .as_deref()
+            .map(|url| Upstream::origin(url, config).map(Arc::new))
+            .transpose()?;
+        let cluster = if config.cluster_peers.is_empty() {
+            None
+        } else {
+            Some(Arc::new(Cluster::new(config)?))
+        };
+        Ok(Upstreams { primary, cluster })
+    }
+}
+
+pub fn make_api(
+    tx: ServiceQueue,
+    config: &Config,
+    flags: Arc<ServiceFlags>,
+    http_metrics: Arc<HttpMetrics>,
+    upstreams: Upstreams,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let mut docs = RouteDocs::default();
+    docs.add(RouteDoc::new("get", "/health-check", "Liveness").response(Body::Text));
+    let hello = warp::get().and(warp::path("health-check")).map(|| "Ok");
+
+    // unlike health-check goes through the service queue, so it reflects how long requests wait
+    docs.add(
+        RouteDoc::new("get", "/ping", "Round trip through the service queue")
+            .response(Body::Json("Object")),
+    );
+    let ping = warp::get()
+        .and(warp::path("ping"))
+        .and(warp::path::end())
+        .and(with_cache_tx(tx.clone()))
+        .and_then(ping);
+
+    let Upstreams { primary, cluster } = upstreams;
+
+    let write_allowlist = config.write_allowlist.clone().map(Arc::new);
+
+    let routed = cluster_routed(
+        cluster.clone(),
+        config.api_version,
+        config.key_canonicalization.clone(),
+    )
+    .and(routed_write_allowed(
+        write_allowlist.clone(),
+        config.trust_proxy,
+    ))
+    .and(warp::method())
+    .and(warp::path::full())
+    .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+    .and(warp::header::headers_cloned())
+    .and(client_addr())
+    .and(warp::body::bytes())
+    .and_then(route_to_peer)
+    // boxed to keep the filter type of `routes` manageable
+    .boxed();
+
+    docs.add(
+        RouteDoc::new("get", "/cluster/status", "Peers and their health")
+            .response(Body::Json("Object")),
+    );
+    let cluster_status = warp::get()
+        .and(warp::path!("cluster" / "status"))
+        .and(warp::any().map(move || cluster.clone()))
+        .map(|cluster: Option<Arc<Cluster>>| match cluster {
+            Some(cluster) => warp::reply::json(&cluster.status()).into_response(),
+            None => json_error(
+                String::from("not running in cluster mode"),
+                StatusCode::NOT_FOUND,
+            ),
+        });
+
+    // shared by every request, see `ServiceQueue::reserve_write`
+    let tx = tx.limit_inflight_writes(config.max_inflight_write_bytes);
+
+    let key_rules = KeyRules {
+        canonicalization: config.key_canonicalization.clone(),
+        allowed_prefixes: Arc::new(config.allowed_key_prefixes.clone()),
+        max_len: config.max_key_len,
+    };
+
+    let write_limits = WriteLimits {
+        max_value_bytes: config.max_value_bytes,
+        probe_room: primary.is_none(),
+    };
+    docs.add(
+        RouteDoc::new("post", "/set/{key}", "Write a value")
+            .query(&[
+                openapi::optional("ttl", "string"),
+                openapi::optional("tags", "string"),
+            ])
+            .request(Body::Raw),
+    );
+    let set = warp::path("set")
+        .and(key_param(key_rules.clone()))
+        .and(warp::post())
+        .and(warp::query::<SetQuery>())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::header::optional::<String>("expect"))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            move |key: String,
+                  query: SetQuery,
+                  content_length: Option<u64>,
+                  expect: Option<String>,
+                  tx: ServiceQueue| async move {
+                let ttl = query.ttl()?;
+                check_write(tx, &key, content_length, expect, write_limits)
+                    .await
+                    .map(|_| (key, query, ttl))
+            },
+        )
+        .untuple_one()
+        .and(inflight_reserved(tx.clone()))
+        .and(value_body(config.max_value_bytes))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(deadline(config.request_timeout))
+        .and(warp::header::optional::<u64>("x-expect-version"))
+        .and(with_client_tx(tx.clone()))
+        .and(with_primary(primary.clone()))
+        .and_then(
+            move |key: String,
+                  query: SetQuery,
+                  ttl: Option<Duration>,
+                  inflight: InflightWrite,
+                  value: warp::hyper::body::Bytes,
+                  content_encoding: Option<String>,
+                  deadline: Option<Instant>,
+                  expected_version: Option<u64>,
+                  tx: ServiceQueue,
+                  primary: Option<Arc<Upstream>>| async move {
+                let options = WriteOptions {
+                    tags: query.tags(),
+                    ttl,
+                    expected_version,
+                };
+                write(
+                    tx.clone(),
+                    key,
+                    options,
+                    inflight,
+                    value,
+                    content_encoding,
+                    write_limits.max_value_bytes,
+                    deadline,
+                    primary,
+                )
+                .await
+            },
+        )
+        // checked in several steps before the body is read, boxed to keep the filter type manageable
+        .boxed();
+
+    // body is a single line appended to the value, only the last `max` lines are kept
+    docs.add(
+        RouteDoc::new(
+            "post",
+            "/ringpush/{key}",
+            "Append a line, keeping the last `max`",
+        )
+        .query(&[openapi::required("max", "integer")])
+        .request(Body::Raw),
+    );
+    let ring_push = warp::path("ringpush")
+        .and(key_param(key_rules.clone()))
+        .and(warp::post())
+        .and(warp::query::<RingPushQuery>())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(inflight_reserved(tx.clone()))
+        .and(warp::body::bytes())
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and(with_primary(primary.clone()))
+        .and_then(
+            |key: String,
+             query: RingPushQuery,
+             mut inflight: InflightWrite,
+             line: warp::hyper::body::Bytes,
+             deadline: Option<Instant>,
+             tx: ServiceQueue,
+             primary: Option<Arc<Upstream>>| async move {
+                if !tx.grow_write(&mut inflight, line.len()) {
+                    return Ok(inflight_writes_response());
+                }
+                ring_push(tx, key, query.max, line, deadline, primary)
+                    .await
+                    .map(Reply::into_response)
+            },
+        );
+
+    // repeat while `more` is true to invalidate tags carried by many keys
+    docs.add(
+        RouteDoc::new("delete", "/tags/{tag}", "Delete entries carrying a tag")
+            .response(Body::Json("Object")),
+    );
+    let invalidate_tag = warp::delete()
+        .and(warp::path("tags"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |tag: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                invalidate_tag(tx, tag, deadline).await
+            },
+        );
+
+    // with `X-If-Created-Before: <unix ms>` only deletes entries created strictly before then
+    docs.add(
+        RouteDoc::new("delete", "/del/{key}", "Delete an entry").response(Body::Json("Object")),
+    );
+    let delete = warp::path("del")
+        .and(key_param(key_rules.clone()))
+        .and(warp::delete())
+        .and(warp::header::optional::<u64>("x-if-created-before"))
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |key: String,
+             created_before: Option<u64>,
+             deadline: Option<Instant>,
+             tx: ServiceQueue| async move {
+                delete(tx, key, created_before, deadline).await
+            },
+        );
+
+    // with `fence=true` writes sent before the flush are rejected instead of applied after it
+    docs.add(
+        RouteDoc::new("post", "/flush", "Delete all entries")
+            .query(&[openapi::optional("fence", "boolean")])
+            .response(Body::Json("Object")),
+    );
+    let flush = warp::post()
+        .and(warp::path("flush"))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(warp::query::<FlushQuery>())
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |query: FlushQuery, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                flush(tx, query, deadline).await
+            },
+        );
+
+    // both keys have to exist, entries are exchanged together with their ttl
+    docs.add(
+        RouteDoc::new("post", "/swap", "Exchange two entries").query(&[
+            openapi::required("a", "string"),
+            openapi::required("b", "string"),
+        ]),
+    );
+    let swap = warp::post()
+        .and(warp::path("swap"))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(warp::query::<SwapQuery>())
+        .and_then({
+            let key_rules = key_rules.clone();
+            move |query: SwapQuery| {
+                let checked = key_rules.check([query.a.as_str(), query.b.as_str()].iter().copied());
+                async move { checked.map(|_| query) }
+            }
+        })
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |query: SwapQuery, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                swap(tx, query, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new(
+            "post",
+            "/rename/{from}/{to}",
+            "Move an entry to another key",
+        )
+        .query(&[openapi::optional("overwrite", "boolean")]),
+    );
+    let rename = warp::post()
+        .and(warp::path("rename"))
+        .and(key_param(key_rules.clone()))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(warp::query::<RenameQuery>())
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |from: String,
+             to: String,
+             query: RenameQuery,
+             deadline: Option<Instant>,
+             tx: ServiceQueue| async move { rename(tx, from, to, query, deadline).await },
+        );
+
+    docs.add(RouteDoc::new(
+        "post",
+        "/pin/{key}",
+        "Exempt an entry from eviction",
+    ));
+    docs.add(RouteDoc::new(
+        "post",
+        "/unpin/{key}",
+        "Make an entry evictable again",
+    ));
+    let pin = warp::post()
+        .and(
+            warp::path("pin")
+                .map(|| true)
+                .or(warp::path("unpin").map(|| false))
+                .unify(),
+        )
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |pinned: bool, key: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                pin(tx, key, pinned, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("get", "/access/{key}", "Reads of an entry").response(Body::Json("Object")),
+    );
+    docs.add(
+        RouteDoc::new("post", "/access/{key}/reset", "Reset reads of an entry")
+            .response(Body::Json("Object")),
+    );
+    let access = warp::path("access").and(key_param(key_rules.clone()));
+    let access_count = warp::get()
+        .and(access.clone())
+        .and(warp::path::end())
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |key: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                access_count(tx, key, deadline).await
+            },
+        );
+    let reset_access_count = warp::post()
+        .and(access)
+        .and(warp::path("reset"))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |key: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                reset_access_count(tx, key, deadline).await
+            },
+        );
+
+    let bulk_options = BulkLoadOptions {
+        key_rules: key_rules.clone(),
+        max_value_bytes: config.max_value_bytes,
+        batch_size: config.bulk_load_batch_size.max(1),
+    };
+    // applied locally, neither forwarded to the primary nor routed to cluster peers
+    docs.add(
+        RouteDoc::new("post", "/bulk-load", "Write entries, one per line")
+            .query(&[openapi::optional("dry_run", "boolean")])
+            .request(Body::Raw)
+            .response(Body::Json("Object")),
+    );
+    let bulk_load = warp::post()
+        .and(warp::path("bulk-load"))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::query::<BulkLoadQuery>())
+        .and(warp::body::stream())
+        .and(with_client_tx(tx.clone()))
+        .and(warp::any().map(move || bulk_options.clone()))
+        .and_then(
+            |content_type: Option<String>,
+             query: BulkLoadQuery,
+             body,
+             tx: ServiceQueue,
+             options: BulkLoadOptions| async move {
+                bulk_load(tx, content_type, query, body, options).await
+            },
+        );
+
+    for path in ["/txn", "/tx"].iter() {
+        docs.add(
+            RouteDoc::new("post", path, "Apply writes all or nothing")
+                .request(Body::Json("TxnOps"))
+                .response(Body::Json("Object")),
+        );
+    }
+    let txn = warp::post()
+        .and(warp::path("txn").or(warp::path("tx")).unify())
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(inflight_reserved(tx.clone()))
+        .and(warp::body::json::<Vec<TxnOp>>())
+        .and_then({
+            let key_rules = key_rules.clone();
+            move |inflight: InflightWrite, ops: Vec<TxnOp>| {
+                let checked = key_rules.check(ops.iter().map(|op| op.key()));
+                async move { checked.map(|_| (inflight, ops)) }
+            }
+        })
+        .untuple_one()
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and(with_primary(primary.clone()))
+        .and_then(
+            |mut inflight: InflightWrite,
+             ops: Vec<TxnOp>,
+             deadline: Option<Instant>,
+             tx: ServiceQueue,
+             primary: Option<Arc<Upstream>>| async move {
+                // values are what stays buffered of a body sent without `Content-Length`
+                let len = ops
+                    .iter()
+                    .map(|op| match op {
+                        TxnOp::Set { value, .. } => value.len(),
+                        _ => 0,
+                    })
+                    .sum();
+                if !tx.grow_write(&mut inflight, len) {
+                    return Ok(inflight_writes_response());
+                }
+                txn(tx, ops, deadline, primary)
+                    .await
+                    .map(Reply::into_response)
+            },
+        );
+
+    let read_options = ReadOptions {
+        primary: primary.clone(),
+        miss_status: config.miss_status,
+        compress_min_bytes: config.compress_response_min_bytes,
+        last_modified: config.enable_last_modified,
+    };
+    for method in ["get", "head"].iter() {
+        docs.add(
+            RouteDoc::new(method, "/get/{key}", "Read a value")
+                .query(&[
+                    openapi::optional("since", "integer"),
+                    openapi::optional("refresh_lock", "string"),
+                    openapi::optional("format", "string"),
+                    openapi::optional("default", "string"),
+                ])
+                .response(Body::Raw),
+        );
+    }
+    let get = warp::path("get")
+        .and(key_param(key_rules.clone()))
+        .and(warp::get().or(warp::head()).unify())
+        .and(
+            warp::query::<GetQuery>()
+                .and_then(|query: GetQuery| async move {
+                    query.refresh_lock().map(|lock| (query, lock))
+                })
+                .untuple_one(),
+        )
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("range"))
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and(warp::any().map(move || read_options.clone()))
+        .and_then(
+            |key: String,
+             query: GetQuery,
+             refresh_lock: Option<Duration>,
+             accept: Option<String>,
+             accept_encoding: Option<String>,
+             if_modified_since: Option<String>,
+             range: Option<String>,
+             deadline: Option<Instant>,
+             tx: ServiceQueue,
+             read_options: ReadOptions| async move {
+                let format = query.format(&accept);
+                let response = match (query.since, refresh_lock) {
+                    (Some(since), _) => read_if_modified_since(
+                        tx,
+                        key,
+                        since,
+                        read_options,
+                        accept,
+                        accept_encoding,
+                        deadline,
+                    )
+                    .await
+                    .map(|r| r.into_response()),
+                    (None, Some(lock)) => read_or_lock(
+                        tx,
+                        key,
+                        lock,
+                        read_options,
+                        accept,
+                        accept_encoding,
+                        deadline,
+                    )
+                    .await
+                    .map(|r| r.into_response()),
+                    (None, None) if format != ValueFormat::Raw => {
+                        read_formatted(tx, key, format, read_options, accept, deadline)
+                            .await
+                            .map(|r| r.into_response())
+                    }
+                    (None, None) if read_options.last_modified => read_last_modified(
+                        tx,
+                        key,
+                        if_modified_since,
+                        read_options,
+                        accept,
+                        ValueHeaders {
+                            accept_encoding,
+                            range,
+                        },
+                        deadline,
+                    )
+                    .await
+                    .map(|r| r.into_response()),
+                    (None, None) => read(
+                        tx,
+                        key,
+                        read_options,
+                        accept,
+                        ValueHeaders {
+                            accept_encoding,
+                            range,
+                        },
+                        deadline,
+                    )
+                    .await
+                    .map(|r| r.into_response()),
+                };
+                response.map(|response| match query.default {
+                    Some(default) => default_on_miss(response, default),
+                    None => response,
+                })
+            },
+        )
+        // boxed for the same reason as `set`
+        .boxed();
+
+    docs.add(
+        RouteDoc::new("post", "/mget", "Read several values")
+            .request(Body::Json("Keys"))
+            .response(Body::Json("Values")),
+    );
+    let mget = warp::post()
+        .and(warp::path("mget"))
+        .and(warp::path::end())
+        .and(keys_body(key_rules.clone()))
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |keys: Vec<String>, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                read_many(tx, keys, deadline).await
+            },
+        );
+
+    // keyspace of its own per namespace, created by the first write to it
+    docs.add(
+        RouteDoc::new(
+            "post",
+            "/ns/{namespace}/set/{key}",
+            "Write a value in a namespace",
+        )
+        .request(Body::Raw),
+    );
+    let namespace_set = warp::post()
+        .and(warp::path("ns"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("set"))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(writable(flags.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(inflight_reserved(tx.clone()))
+        .and(warp::body::bytes())
+        .and(deadline(config.request_timeout))
+        .and(with_client_tx(tx.clone()))
+        .and_then(
+            |namespace: String,
+             key: String,
+             mut inflight: InflightWrite,
+             value: warp::hyper::body::Bytes,
+             deadline: Option<Instant>,
+             tx: ServiceQueue| async move {
+                if !tx.grow_write(&mut inflight, value.len()) {
+                    return Ok(inflight_writes_response());
+                }
+                namespace_write(tx, namespace, key, value, deadline)
+                    .await
+                    .map(Reply::into_response)
+            },
+        );
+
+    let miss_status = config.miss_status;
+    docs.add(
+        RouteDoc::new(
+            "get",
+            "/ns/{namespace}/get/{key}",
+            "Read a value in a namespace",
+        )
+        .response(Body::Raw),
+    );
+    let namespace_get = warp::get()
+        .and(warp::path("ns"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("get"))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("accept"))
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            move |namespace: String,
+                  key: String,
+                  accept: Option<String>,
+                  deadline: Option<Instant>,
+                  tx: ServiceQueue| async move {
+                namespace_read(tx, namespace, key, miss_status, accept, deadline).await
+            },
+        );
+
+    // never forwarded to the primary nor read through, so that probes have no side effects
+    docs.add(
+        RouteDoc::new("get", "/peek/{key}", "Read a value without side effects")
+            .response(Body::Raw),
+    );
+    let peek = warp::get()
+        .and(warp::path("peek"))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("accept"))
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            move |key: String,
+                  accept: Option<String>,
+                  deadline: Option<Instant>,
+                  tx: ServiceQueue| async move {
+                peek(tx, key, miss_status, accept, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("get", "/item/{key}", "Read a value with its metadata")
+            .response(Body::Json("Item")),
+    );
+    let item = warp::get()
+        .and(warp::path("item"))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |key: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                read_item(tx, key, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("post", "/mttl", "Remaining ttl of several entries")
+            .request(Body::Json("Keys"))
+            .response(Body::Json("Ttls")),
+    );
+    let mttl = warp::post()
+        .and(warp::path("mttl"))
+        .and(warp::path::end())
+        .and(keys_body(key_rules.clone()))
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |keys: Vec<String>, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                ttl_many(tx, keys, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("get", "/meta/{key}", "Metadata of an entry").response(Body::Json("Object")),
+    );
+    let meta = warp::get()
+        .and(warp::path("meta"))
+        .and(key_param(key_rules.clone()))
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |key: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                meta(tx, key, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("get", "/strlen/{key}", "Length of a value").response(Body::Json("Object")),
+    );
+    let strlen = warp::get()
+        .and(warp::path("strlen"))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |key: String, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                value_len(tx, key, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new(
+            "get",
+            "/explain/{key}",
+            "How an entry would be read, written and evicted",
+        )
+        .query(&[openapi::optional("value_size", "integer")])
+        .response(Body::Json("Object")),
+    );
+    let explain = warp::get()
+        .and(warp::path("explain"))
+        .and(key_param(key_rules.clone()))
+        .and(warp::path::end())
+        .and(warp::query::<ExplainQuery>())
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |key: String, query: ExplainQuery, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                explain(tx, key, query, deadline).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("get", "/count", "Keys matching a glob")
+            .query(&[openapi::required("pattern", "string")])
+            .response(Body::Json("Object")),
+    );
+    let count = warp::get()
+        .and(warp::path("count"))
+        .and(warp::path::end())
+        .and(warp::query::<CountQuery>())
+        .and(deadline(config.request_timeout))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            |query: CountQuery, deadline: Option<Instant>, tx: ServiceQueue| async move {
+                count(tx, query, deadline).await
+            },
+        );
+
+    if config.enable_dashboard {
+        docs.add(RouteDoc::new("get", "/", "Dashboard").response(Body::Html));
+    }
+    let dashboard = warp::get()
+        .and(warp::path::end())
+        .and(enabled(config.enable_dashboard))
+        .map(|| warp::reply::html(DASHBOARD_HTML));
+
+    docs.add(RouteDoc::new("get", "/stats", "Cache statistics").response(Body::Json("Object")));
+    let stats = warp::get()
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(with_cache_tx(tx.clone()))
+        .and(with_primary(primary.clone()))
+        .and_then(stats);
+
+    // operations over `slowlog_threshold`, most recent first
+    docs.add(
+        RouteDoc::new("get", "/slowlog", "Slowest recent operations")
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    docs.add(
+        RouteDoc::new("delete", "/slowlog", "Clear the slowlog")
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    // keys of the operations are listed, admin only like the other introspection of entries
+    let slowlog = warp::path("slowlog")
+        .and(warp::path::end())
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(
+            warp::get()
+                .and(with_cache_tx(tx.clone()))
+                .and_then(slowlog)
+                .or(warp::delete()
+                    .and(with_client_tx(tx.clone()))
+                    .and_then(reset_slowlog)),
+        );
+
+    // answered without going through the service queue
+    docs.add(RouteDoc::new("get", "/info", "Version and state").response(Body::Json("Info")));
+    let info = warp::get()
+        .and(warp::path("info"))
+        .and(warp::path::end())
+        .map({
+            let flags = flags.clone();
+            let api_version = config.api_version;
+            move || {
+                warp::reply::json(&Info {
+                    version: env!("CARGO_PKG_VERSION"),
+                    api_version,
+                    generation: flags.generation(),
+                    read_only: flags.is_read_only(),
+                })
+            }
+        });
+
+    // what of eviction passes and snapshots is running, waiting and ran recently
+    docs.add(
+        RouteDoc::new("get", "/stats/maintenance", "Eviction passes and snapshots")
+            .response(Body::Json("Object")),
+    );
+    let maintenance = warp::get()
+        .and(warp::path("stats"))
+        .and(warp::path("maintenance"))
+        .and(warp::path::end())
+        .and(with_cache_tx(tx.clone()))
+        .and_then(maintenance_status);
+
+    docs.add(RouteDoc::new("get", "/metrics", "Prometheus metrics").response(Body::Text));
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(with_cache_tx(tx.clone()))
+        .and(warp::any().map({
+            let http_metrics = http_metrics.clone();
+            move || http_metrics.clone()
+        }))
+        .and_then(metrics);
+
+    docs.add(
+        RouteDoc::new("get", "/stats/ttl-histogram", "Remaining ttl of entries")
+            .response(Body::Json("Object")),
+    );
+    let ttl_histogram = warp::get()
+        .and(warp::path!("stats" / "ttl-histogram"))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(ttl_histogram);
+
+    let keepalive = config.sse_keepalive;
+    docs.add(
+        RouteDoc::new("get", "/events", "Stream of cache events")
+            .query(&[
+                openapi::optional("prefix", "string"),
+                openapi::optional("kinds", "string"),
+            ])
+            .response(Body::EventStream),
+    );
+    let events = warp::get()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::query::<EventsQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(
+            move |query: EventsQuery, last_event_id: Option<u64>, tx: ServiceQueue| async move {
+                events(tx, query, last_event_id, keepalive).await
+            },
+        );
+
+    docs.add(
+        RouteDoc::new("put", "/admin/read-only", "Switch read-only mode")
+            .request(Body::Text)
+            .admin(),
+    );
+    let read_only = warp::put()
+        .and(warp::path!("admin" / "read-only"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_flags(flags.clone()))
+        .and(warp::body::bytes())
+        .and_then(set_read_only);
+
+    docs.add(RouteDoc::new("post", "/admin/eviction/pause", "Pause eviction").admin());
+    let pause_eviction = warp::post()
+        .and(warp::path!("admin" / "eviction" / "pause"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_flags(flags.clone()))
+        .and_then(|flags| set_eviction_paused(flags, true));
+
+    docs.add(RouteDoc::new("post", "/admin/eviction/resume", "Resume eviction").admin());
+    let resume_eviction = warp::post()
+        .and(warp::path!("admin" / "eviction" / "resume"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_flags(flags.clone()))
+        .and_then(|flags| set_eviction_paused(flags, false));
+
+    docs.add(
+        RouteDoc::new("post", "/admin/compact", "Shrink the map")
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    let compact = warp::post()
+        .and(warp::path!("admin" / "compact"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(compact);
+
+    // pays for the rehash of growing the map at a quiet time instead of in a write
+    docs.add(
+        RouteDoc::new("post", "/admin/reserve", "Grow the map ahead of writes")
+            .query(&[openapi::required("additional", "integer")])
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    let reserve = warp::post()
+        .and(warp::path!("admin" / "reserve"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(warp::query::<ReserveQuery>())
+        .and(with_cache_tx(tx.clone()))
+        .and_then(reserve);
+
+    docs.add(
+        RouteDoc::new("post", "/admin/evict", "Run an eviction pass")
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    let evict = warp::post()
+        .and(warp::path!("admin" / "evict"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(evict);
+
+    let selftest_state = Arc::new(SelftestState {
+        config: config.clone(),
+        last_run: std::sync::Mutex::new(None),
+    });
+    docs.add(
+        RouteDoc::new("post", "/admin/selftest", "Benchmark a scratch cache")
+            .request(Body::Json("Object"))
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    let selftest = warp::post()
+        .and(warp::path!("admin" / "selftest"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_cache_tx(tx.clone()))
+        .and(warp::any().map(move || selftest_state.clone()))
+        .and(warp::body::json::<BenchParams>())
+        .and_then(run_selftest);
+
+    docs.add(
+        RouteDoc::new("post", "/admin/snapshot", "Write a snapshot")
+            .response(Body::Json("SnapshotReport"))
+            .admin(),
+    );
+    let snapshot = warp::post()
+        .and(warp::path!("admin" / "snapshot"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(snapshot);
+
+    // retiring a namespace: detach it, export it, drop it
+    docs.add(
+        RouteDoc::new(
+            "post",
+            "/ns/{namespace}/detach",
+            "Stop writes to a namespace",
+        )
+        .admin(),
+    );
+    let namespace_detach = warp::post()
+        .and(warp::path!("ns" / String / "detach"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(|namespace, tx| namespace_detach(tx, namespace));
+
+    let snapshot_format = config.snapshot_format;
+    docs.add(
+        RouteDoc::new("get", "/ns/{namespace}/dump", "Export a namespace")
+            .response(Body::Raw)
+            .admin(),
+    );
+    let namespace_dump = warp::get()
+        .and(warp::path!("ns" / String / "dump"))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_cache_tx(tx.clone()))
+        .and_then(move |namespace, tx| namespace_dump(tx, namespace, snapshot_format));
+
+    docs.add(
+        RouteDoc::new("delete", "/ns/{namespace}", "Drop a namespace")
+            .response(Body::Json("Object"))
+            .admin(),
+    );
+    let namespace_drop = warp::delete()
+        .and(warp::path!("ns" / String))
+        .and(admin_auth(config.admin_token.clone()))
+        .and(write_allowed(write_allowlist.clone(), config.trust_proxy))
+        .and(with_client_tx(tx))
+        .and_then(|namespace, tx| namespace_drop(tx, namespace));
+
+    docs.add(
+        RouteDoc::new("get", "/openapi.json", "This document").response(Body::Json("OpenApi")),
+    );
+    // built once, routes do not change after startup
+    let document = Arc::new(docs.document(config.api_version, config.admin_token.is_some()));
+    let openapi = warp::get()
+        .and(warp::path("openapi.json"))
+        .and(warp::path::end())
+        .map(move || warp::reply::json(&*document));
+
+    // boxed on their own, the whole chain of routes is too deep a type otherwise
+    let admin = read_only
+        .or(pause_eviction)
+        .or(resume_eviction)
+        .or(compact)
+        .or(reserve)
+        .or(evict)
+        .or(snapshot)
+        .or(selftest)
+        .or(namespace_detach)
+        .or(namespace_dump)
+        .or(namespace_drop)
+        .map(Reply::into_response)
+        .boxed();
+
+    // entry inspection, grouped and boxed to keep the filter type of `routes` manageable
+    let inspect = meta
+        .or(strlen)
+        .or(explain)
+        .or(access_count)
+        .or(reset_access_count)
+        .map(Reply::into_response)
+        .boxed();
+
+    let routes = routed
+        .or(hello)
+        .or(ping)
+        .or(cluster_status)
+        .or(get.or(method_not_allowed("get", &["GET", "HEAD"])))
+        .or(peek)
+        .or(item)
+        .or(mget)
+        .or(mttl)
+        .or(namespace_get)
+        .or(namespace_set)
+        .or(set.or(method_not_allowed("set", &["POST"])))
+        .or(ring_push)
+        .or(invalidate_tag)
+        .or(delete)
+        .or(swap)
+        .or(rename)
+        .or(pin)
+        .or(flush)
+        .or(txn)
+        .or(bulk_load)
+        .or(inspect)
+        .or(count)
+        .or(stats)
+        .or(slowlog)
+        .or(info)
+        .or(maintenance)
+        .or(dashboard)
+        .or(metrics)
+        .or(ttl_histogram)
+        .or(events)
+        .or(openapi)
+        .or(admin)
+        // mounted twice below, boxed to keep the filter type manageable
+        .map(Reply::into_response)
+        .boxed();
+
+    let versioned = warp::path(config.api_version)
+        .and(routes.clone())
+        .or(routes
+            .map(|reply| warp::reply::with_header(reply, "Deprecation", "true").into_response()))
+        .unify();
+
+    tracked(http_metrics, config.api_version)
+        .and(expected_generation(flags.clone()))
+        .and(not_overloaded(flags.clone(), config.api_version))
+        .and(versioned)
+        .map(|_: InFlight, reply: Response| reply)
+        .recover(handle_rejection)
+        // read once the request is served, so a flush response carries the new generation
+        .map(move |reply| {
+            warp::reply::with_header(reply, "X-Cache-Generation", flags.generation().to_string())
+        })
+}
+
+#[cfg(test)]
+mod api_tests {
+    use crate::api::make_api;
+    use crate::api::Upstreams;
+    use crate::cluster::Ring;
+    use crate::config::Config;
+    use crate::config::KeyCanonicalization;
+    use crate::config::MissStatus;
+    use crate::config::CASE_INSENSITIVE_KEYS;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::encoding;
+    use crate::metrics::HttpMetrics;
+    use crate::service::service_queue;
+    use crate::service::ServiceFlags;
+    use crate::service::ServiceMessage;
+    use crate::service::TtlCacheService;
+    use crate::snapshot::snapshot_tests::corrupt_value;
+    use crate::snapshot::SnapshotEntry;
+    use crate::time::time_fixtures::TestTime;
+    use crate::time::Time;
+
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::sync::Mutex;
+
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    impl Time for Arc<Mutex<TestTime>> {
+        fn get_time(&self) -> std::time::Instant {
+            loop {
+                if let Ok(inner) = self.try_lock() {
+                    break inner.get_time();
+                }
+            }
+        }
+    }
+
+    fn init() -> (
+        Arc<Mutex<TestTime>>,
+        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+    ) {
+        init_with_config(TEST_CONFIG_SINGLE_ITEM)
+    }
+
+    fn init_with_config(
+        config: Config,
+    ) -> (
+        Arc<Mutex<TestTime>>,
+        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+    ) {
+        let (tx, rx) = service_queue();
+
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let api = make_api(
+            tx,
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+
+        let time_for_svc = time.clone();
+        tokio::spawn(async move {
+            TtlCacheService::new(config, rx, flags, &time_for_svc)
+                .run()
+                .await
+        });
+
+        (time, api)
+    }
+
+    fn api_set_request(key: &str, value: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path(format!("/set/{}", key).as_str())
+            .body(value)
+    }
+
+    fn api_get_request(key: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("GET")
+            .path(format!("/get/{}", key).as_str())
+    }
+
+    const TEST_ADMIN_TOKEN: &str = "secret";
+
+    fn admin_config() -> Config {
+        Config {
+            capacity: None,
+            admin_token: Some(String::from(TEST_ADMIN_TOKEN)),
+            ..TEST_CONFIG_SINGLE_ITEM
+        }
+    }
+
+    fn api_delete_request(key: &str, created_before: Option<u128>) -> warp::test::RequestBuilder {
+        let request = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/del/{}", key));
+        match created_before {
+            Some(ms) => request.header("X-If-Created-Before", ms.to_string()),
+            None => request,
+        }
+    }
+
+    fn api_admin_request(method: &str, path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", format!("Bearer {}", TEST_ADMIN_TOKEN))
+    }
+
+    fn api_read_only_request(read_only: bool) -> warp::test::RequestBuilder {
+        api_admin_request("PUT", "/admin/read-only").body(format!("{}", read_only))
+    }
+
+    async fn get_stats(
+        api: &(impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'static),
+    ) -> serde_json::Value {
+        let res = warp::test::request()
+            .method("GET")
+            .path("/stats")
+            .reply(api)
+            .await;
+        assert_eq!(res.status(), 200);
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn non_existent_keys_return_not_found() {
+        let (_, api) = init();
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+
+        assert_eq!(get_res.status(), 404);
+        assert!(get_res.body().is_empty());
+        assert_eq!(get_res.headers()["content-length"], "0");
+        assert_eq!(get_res.headers()["x-cache-result"], "miss");
+
+        let get_res = api_get_request("abcda")
+            .header("accept", "application/json")
+            .reply(&api)
+            .await;
+
+        assert_eq!(get_res.status(), 404);
+        assert_eq!(get_res.body(), r#"{"error":"Not found"}"#);
+        assert_eq!(get_res.headers()["x-cache-result"], "miss");
+    }
+
+    #[tokio::test]
+    async fn values_are_returned_raw_as_json_envelope_or_base64() {
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let api = make_api(
+            tx.clone(),
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, &time).run().await });
+
+        let set_res = api_set_request("text", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        // api only takes utf-8 values, binary ones can still be set through the service
+        let binary = vec![0xff, 0x00, 0xfe];
+        let (cb, res) = tokio::sync::oneshot::channel();
+        tx.send(ServiceMessage::Write("binary".into(), binary.clone(), cb).into())
+            .unwrap();
+        res.await.unwrap().unwrap();
+
+        // default is unchanged
+        for accept in [None, Some("application/octet-stream"), Some("*/*")] {
+            let request = api_get_request("binary");
+            let request = match accept {
+                Some(accept) => request.header("accept", accept),
+                None => request,
+            };
+            let res = request.reply(&api).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body().to_vec(), binary);
+        }
+        assert_eq!(api_get_request("text").reply(&api).await.body(), "bcda");
+
+        let res = api_get_request("text")
+            .header("accept", "application/json")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        let envelope: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(envelope["key"], "text");
+        assert_eq!(envelope["value"], "bcda");
+        assert_eq!(envelope["encoding"], "utf8");
+        assert!(envelope["ttl_remaining_ms"].as_u64().unwrap() > 0);
+
+        let res = api_get_request("binary")
+            .header("accept", "application/json")
+            .reply(&api)
+            .await;
+        let envelope: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(envelope["value"], "/wD+");
+        assert_eq!(envelope["encoding"], "base64");
+
+        for (key, encoded) in [("text", "YmNkYQ=="), ("binary", "/wD+")] {
+            let res = api_get_request(&format!("{}?format=base64", key))
+                .header("accept", "application/json")
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), encoded);
+        }
+
+        let res = api_get_request("missing?format=json").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["x-cache-result"], "miss");
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_are_told_apart_from_key_misses() {
+        let (_, api) = init();
+
+        for accept in ["*/*", "application/json"] {
+            let res = warp::test::request()
+                .method("GET")
+                .path("/no-such-route")
+                .header("accept", accept)
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 404);
+            assert!(res.headers().get("x-cache-result").is_none());
+            assert_eq!(res.body(), "");
+
+            let res = api_get_request("missing")
+                .header("accept", accept)
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 404);
+            assert_eq!(res.headers()["x-cache-result"], "miss");
+        }
+    }
+
+    #[tokio::test]
+    async fn non_existent_keys_can_return_empty_ok() {
+        let (_, api) = init_with_config(Config {
+            miss_status: MissStatus::OkEmpty,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+
+        assert_eq!(get_res.status(), 200);
+        assert_eq!(get_res.body(), "");
+    }
+
+    #[tokio::test]
+    async fn able_to_set_value() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+
+        assert_eq!(set_res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn able_to_get_back_set_values() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+
+        assert_eq!(set_res.status(), 200);
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+
+        assert_eq!(get_res.status(), 200);
+        assert_eq!(get_res.body(), "bcda");
+    }
+
+    #[tokio::test]
+    async fn set_values_have_capacity() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        let set_res = api_set_request("abcda2", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn zero_capacity_rejects_all_sets() {
+        let (_, api) = init_with_config(Config {
+            capacity: Some(0),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 400);
+        assert_eq!(set_res.body(), "out of capacity: Some(0)");
+        assert_eq!(api_get_request("abcda").reply(&api).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn set_values_expire() {
+        let (time, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        // not pretty, but we have to make sure value is dropped so
+        // the lock is released and we can get time again
+        tokio::spawn(async move {
+            let lock = time.lock().await;
+            lock.add_secs(Duration::from_secs(11));
+        });
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+        assert_eq!(get_res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn keys_are_byte_exact_by_default() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let set_res = api_set_request("User:42", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let get_res = api_get_request("user:42").reply(&api).await;
+        assert_eq!(get_res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn lowercase_keys_hit_the_same_entry() {
+        let (_, api) = init_with_config(Config {
+            key_canonicalization: KeyCanonicalization {
+                lowercase: true,
+                trim_whitespace: false,
+                max_unicode_nfc: false,
+            },
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let set_res = api_set_request("User:42", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let get_res = api_get_request("user:42").reply(&api).await;
+        assert_eq!(get_res.status(), 200);
+        assert_eq!(get_res.body(), "bcda");
+
+        // same entry, so it does not count against capacity of a single item
+        let set_res = api_set_request("USER:42", "abcd").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_requires_admin_token() {
+        let (_, api) = init_with_config(admin_config());
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/admin/read-only")
+            .body("true")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/admin/read-only")
+            .header("authorization", "Bearer wrong")
+            .body("true")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        // admin api is disabled without a token
+        let (_, api) = init();
+        let res = api_read_only_request(true).reply(&api).await;
+        assert_eq!(res.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_freezes_writes() {
+        let (_, api) = init_with_config(admin_config());
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = api_read_only_request(true).reply(&api).await;
+        assert_eq!(res.status(), 200);
+
+        let set_res = api_set_request("abcda", "abcd").reply(&api).await;
+        assert_eq!(set_res.status(), 503);
+        assert_eq!(set_res.headers()["X-Read-Only"], "true");
+        let body: serde_json::Value = serde_json::from_slice(set_res.body()).unwrap();
+        assert!(body["error"].is_string());
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+        assert_eq!(get_res.status(), 200);
+        assert_eq!(get_res.body(), "bcda");
+
+        assert_eq!(get_stats(&api).await["read_only"], true);
+        let res = warp::test::request().path("/info").reply(&api).await;
+        let info: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info["read_only"], true);
+
+        let res = api_read_only_request(false).reply(&api).await;
+        assert_eq!(res.status(), 200);
+
+        let set_res = api_set_request("abcda", "abcd").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        assert_eq!(get_stats(&api).await["read_only"], false);
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_can_be_enabled_at_startup() {
+        let (_, api) = init_with_config(Config {
+            start_read_only: true,
+            ..admin_config()
+        });
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 503);
+    }
+
+    #[tokio::test]
+    async fn ttl_histogram_reports_live_entries() {
+        let (time, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/stats/ttl-histogram")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["less_than_1m"], 1);
+        assert_eq!(body["from_1m_to_5m"], 0);
+        assert_eq!(body["more_than_5m"], 0);
+
+        time.lock().await.add_secs(Duration::from_secs(11));
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/stats/ttl-histogram")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["less_than_1m"], 0);
+    }
+
+    #[tokio::test]
+    async fn access_count_is_reported_and_reset() {
+        let (_, api) = init();
+        let access = |method: &str, path: &str| warp::test::request().method(method).path(path);
+
+        assert_eq!(access("GET", "/access/key").reply(&api).await.status(), 404);
+        assert_eq!(
+            access("POST", "/access/key/reset")
+                .reply(&api)
+                .await
+                .status(),
+            404
+        );
+        assert_eq!(
+            api_set_request("key", "value").reply(&api).await.status(),
+            200
+        );
+        for _ in 0..3 {
+            assert_eq!(api_get_request("key").reply(&api).await.body(), "value");
+        }
+        let res = access("GET", "/access/key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"key":"key","count":3}"#);
+        // not a read of its own
+        let res = access("GET", "/access/key").reply(&api).await;
+        assert_eq!(res.body(), r#"{"key":"key","count":3}"#);
+
+        let res = access("POST", "/access/key/reset").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"key":"key","count":3}"#);
+        let res = access("GET", "/access/key").reply(&api).await;
+        assert_eq!(res.body(), r#"{"key":"key","count":0}"#);
+        assert_eq!(api_get_request("key").reply(&api).await.body(), "value");
+        let res = access("GET", "/access/key").reply(&api).await;
+        assert_eq!(res.body(), r#"{"key":"key","count":1}"#);
+    }
+
+    #[tokio::test]
+    async fn meta_reports_per_entry_hits() {
+        let (_, api) = init();
+
+        let meta_request = || warp::test::request().method("GET").path("/meta/abcda");
+
+        let res = meta_request().reply(&api).await;
+        assert_eq!(res.status(), 404);
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        for _ in 0..3 {
+            let get_res = api_get_request("abcda").reply(&api).await;
+            assert_eq!(get_res.status(), 200);
+        }
+
+        let res = meta_request().reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["hits"], 3);
+
+        let set_res = api_set_request("abcda", "abcd").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = meta_request().reply(&api).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["hits"], 0);
+    }
+
+    #[tokio::test]
+    async fn item_returns_value_with_its_metadata() {
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (time, api) = init();
+
+        let item_request = || warp::test::request().method("GET").path("/item/abcda");
+
+        let res = item_request().reply(&api).await;
+        assert_eq!(res.status(), 404);
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        time.lock().await.add_secs(Duration::from_secs(4));
+
+        let res = item_request().reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["value"], "bcda");
+        assert_eq!(body["encoding"], "utf8");
+        assert_eq!(body["ttl_secs"], 6);
+        let created_at = body["created_at"].as_u64().unwrap();
+        assert!(created_at >= started && created_at <= started + 1);
+
+        let meta: serde_json::Value = serde_json::from_slice(
+            warp::test::request()
+                .path("/meta/abcda")
+                .reply(&api)
+                .await
+                .body(),
+        )
+        .unwrap();
+        assert_eq!(body["version"], meta["version"]);
+        // counted as a read
+        assert_eq!(meta["hits"], 1);
+
+        time.lock().await.add_secs(Duration::from_secs(11));
+        let res = item_request().reply(&api).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn item_of_pinned_or_binary_value_is_not_misreported() {
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let api = make_api(
+            tx.clone(),
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, &time).run().await });
+        let item_request = |key: &str| warp::test::request().path(&format!("/item/{}", key));
+
+        assert_eq!(
+            api_set_request("pinned", "value")
+                .reply(&api)
+                .await
+                .status(),
+            200
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path("/pin/pinned")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = item_request("pinned").reply(&api).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["ttl_secs"], serde_json::Value::Null);
+
+        // api only takes utf-8 values, binary ones can still be set through the service
+        let (cb, res) = tokio::sync::oneshot::channel();
+        tx.send(ServiceMessage::Write("binary".into(), vec![0xff, 0x00, 0xfe], cb).into())
+            .unwrap();
+        res.await.unwrap().unwrap();
+        let res = item_request("binary").reply(&api).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["value"], "/wD+");
+        assert_eq!(body["encoding"], "base64");
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_from_the_same_version_let_only_one_through() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let versioned_set = |value: &str, version: u64| {
+            api_set_request("key", value).header("x-expect-version", version.to_string())
+        };
+        let version = |res: &warp::http::Response<warp::hyper::body::Bytes>| {
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            body["version"].as_u64().unwrap()
+        };
+
+        // 0 stands for a key that is not there
+        let res = versioned_set("first", 0).reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = versioned_set("again", 0).reply(&api).await;
+        assert_eq!(res.status(), 409);
+
+        for _ in 0..10 {
+            let base = version(&warp::test::request().path("/meta/key").reply(&api).await);
+            let (a, b) = tokio::join!(
+                versioned_set("a", base).reply(&api),
+                versioned_set("b", base).reply(&api)
+            );
+            let mut statuses = vec![a.status(), b.status()];
+            statuses.sort();
+            assert_eq!(statuses, vec![200, 409]);
+
+            let (won, lost) = if a.status() == 200 {
+                ("a", b)
+            } else {
+                ("b", a)
+            };
+            let current = version(&warp::test::request().path("/meta/key").reply(&api).await);
+            assert!(current > base);
+            assert_eq!(
+                lost.headers()["x-cache-version"],
+                current.to_string().as_str()
+            );
+            assert_eq!(api_get_request("key").reply(&api).await.body(), won);
+        }
+
+        // writes without a version still overwrite whatever is there
+        let res = api_set_request("key", "unversioned").reply(&api).await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn misses_are_answered_with_default_when_given() {
+        let (time, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = api_get_request("key?default=none%20yet").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "none yet");
+        assert_eq!(res.headers()["x-cache-result"], "miss");
+        // not stored
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 404);
+
+        assert_eq!(
+            api_set_request("key", "value").reply(&api).await.status(),
+            200
+        );
+        let res = api_get_request("key?default=none%20yet").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+
+        time.lock().await.add_secs(Duration::from_secs(11));
+        let res = api_get_request("key?default=").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "");
+    }
+
+    #[tokio::test]
+    async fn peek_does_not_count_reads_nor_remove_expired_entries() {
+        // expired entry is left to reads alone
+        let (time, api) = init_with_config(Config {
+            active_eviction: false,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let peek_request = || warp::test::request().method("GET").path("/peek/abcda");
+        let meta_request = || warp::test::request().method("GET").path("/meta/abcda");
+
+        let res = peek_request().reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["x-cache-result"], "miss");
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        for _ in 0..3 {
+            let res = peek_request().reply(&api).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), "bcda");
+        }
+        let body: serde_json::Value =
+            serde_json::from_slice(meta_request().reply(&api).await.body()).unwrap();
+        assert_eq!(body["hits"], 0);
+        assert_eq!(get_stats(&api).await["process"]["hits"], 0);
+
+        time.lock().await.add_secs(Duration::from_secs(11));
+        let res = peek_request().reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(get_stats(&api).await["keys_total"], 1);
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+        assert_eq!(get_res.status(), 404);
+        assert_eq!(get_stats(&api).await["keys_total"], 0);
+    }
+
+    #[tokio::test]
+    async fn ranges_of_values_are_answered_with_partial_content() {
+        let (_, api) = init();
+        let set_res = api_set_request("abcda", "0123456789").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let range_request = |range: &str| api_get_request("abcda").header("range", range);
+
+        for (range, body, content_range) in [
+            ("bytes=2-5", "2345", "bytes 2-5/10"),
+            ("bytes=7-", "789", "bytes 7-9/10"),
+            ("bytes=-3", "789", "bytes 7-9/10"),
+            // end past the value is cut to its length
+            ("bytes=8-100", "89", "bytes 8-9/10"),
+            ("bytes=0-", "0123456789", "bytes 0-9/10"),
+        ]
+        .iter()
+        {
+            let res = range_request(range).reply(&api).await;
+            assert_eq!(res.status(), 206, "{}", range);
+            assert_eq!(res.body(), body, "{}", range);
+            assert_eq!(res.headers()["content-range"], *content_range);
+            assert!(res.headers().get("content-encoding").is_none());
+        }
+
+        for range in [
+            "bytes=10-",
+            "bytes=5-2",
+            "bytes=-0",
+            "items=0-1",
+            "bytes=0-1,4-5",
+        ]
+        .iter()
+        {
+            let res = range_request(range).reply(&api).await;
+            assert_eq!(res.status(), 416, "{}", range);
+            assert_eq!(res.headers()["content-range"], "bytes */10");
+            assert!(res.body().is_empty());
+        }
+
+        // misses are answered as without a range
+        let res = api_get_request("missing")
+            .header("range", "bytes=0-1")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+
+    fn bulk_load_body() -> String {
+        (0..3000)
+            .map(|i| match i {
+                i if i % 100 == 7 => format!("malformed line {}", i),
+                i if i % 250 == 11 => format!("key{}\t{}", i, "x".repeat(100)),
+                i if i % 3 == 0 => format!("key{}\tvalue{}\t60", i, i),
+                i => format!("key{}\tvalue{}", i, i),
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn bulk_load_config() -> Config {
+        Config {
+            capacity: None,
+            max_value_bytes: Some(64),
+            bulk_load_batch_size: 100,
+            ..TEST_CONFIG_SINGLE_ITEM
+        }
+    }
+
+    #[tokio::test]
+    async fn tsv_lines_are_bulk_loaded_skipping_invalid_ones() {
+        let (_, api) = init_with_config(bulk_load_config());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/bulk-load")
+            .header("content-type", "text/tab-separated-values; charset=utf-8")
+            .body(bulk_load_body())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        // 30 malformed lines and 12 with values over `max_value_bytes`
+        assert_eq!(body["loaded"], 2958);
+        assert_eq!(body["skipped"], 42);
+        let errors = body["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 10);
+        assert_eq!(errors[0]["line"], 8);
+        assert_eq!(errors[1]["line"], 12);
+        assert_eq!(errors[1]["error"], "value is larger than 64 bytes");
+
+        assert_eq!(get_stats(&api).await["keys_total"], 2958);
+        let res = api_get_request("key2999").reply(&api).await;
+        assert_eq!(res.body(), "value2999");
+        let res = api_get_request("key11").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/mttl")
+            .json(&["key3", "key4"])
+            .reply(&api)
+            .await;
+        let ttls: Vec<u64> = serde_json::from_slice(res.body()).unwrap();
+        assert!(ttls[0] > 50_000 && ttls[0] <= 60_000);
+        assert!(ttls[1] <= 10_000);
+    }
+
+    #[tokio::test]
+    async fn bulk_load_dry_run_only_validates() {
+        let (_, api) = init_with_config(bulk_load_config());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/bulk-load?dry_run=true")
+            .header("content-type", "text/tab-separated-values")
+            .body(bulk_load_body())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["loaded"], 2958);
+        assert_eq!(body["skipped"], 42);
+        assert_eq!(get_stats(&api).await["keys_total"], 0);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/bulk-load")
+            .header("content-type", "application/json")
+            .body(bulk_load_body())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 415);
+    }
+
+    #[tokio::test]
+    async fn bulk_load_checks_key_length_and_skips_overlong_lines() {
+        let (_, api) = init_with_config(Config {
+            max_key_len: Some(8),
+            ..bulk_load_config()
+        });
+        let body = format!(
+            "key1\tvalue1\n{}\tvalue2\nkey3\t{}\nkey4\tvalue4\n",
+            "k".repeat(9),
+            "x".repeat(1000)
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/bulk-load")
+            .header("content-type", "text/tab-separated-values")
+            .body(body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["loaded"], 2);
+        assert_eq!(body["skipped"], 2);
+        assert_eq!(body["errors"][0]["error"], "key is longer than 8 bytes");
+        assert_eq!(body["errors"][1]["line"], 3);
+        assert_eq!(body["errors"][1]["error"], "line is longer than 136 bytes");
+        assert_eq!(api_get_request("key4").reply(&api).await.body(), "value4");
+    }
+
+    #[tokio::test]
+    async fn gzip_encoded_values_are_stored_decompressed() {
+        let (_, api) = init();
+
+        let set_res = warp::test::request()
+            .method("POST")
+            .path("/set/abcda")
+            .header("content-encoding", "gzip")
+            .body(encoding::gzip(b"bcda").unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 200);
+
+        let get_res = api_get_request("abcda").reply(&api).await;
+        assert_eq!(get_res.status(), 200);
+        assert!(get_res.headers().get("content-encoding").is_none());
+        assert_eq!(get_res.body(), "bcda");
+    }
+
+    #[tokio::test]
+    async fn gzip_body_expanding_past_max_value_bytes_is_rejected() {
+        let (_, api) = init_with_config(Config {
+            max_value_bytes: Some(1024),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let compressed = encoding::gzip(&b"x".repeat(256 * 1024)).unwrap();
+        assert!(compressed.len() < 1024);
+
+        let set_res = warp::test::request()
+            .method("POST")
+            .path("/set/abcda")
+            .header("content-encoding", "gzip")
+            .body(compressed)
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 413);
+        assert_eq!(api_get_request("abcda").reply(&api).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn malformed_gzip_body_is_rejected() {
+        let (_, api) = init();
+
+        let set_res = warp::test::request()
+            .method("POST")
+            .path("/set/abcda")
+            .header("content-encoding", "gzip")
+            .body("bcda")
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 400);
+
+        let set_res = warp::test::request()
+            .method("POST")
+            .path("/set/abcda")
+            .header("content-encoding", "br")
+            .body("bcda")
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 415);
+    }
+
+    #[tokio::test]
+    async fn values_are_compressed_when_client_accepts_gzip() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let get_res = api_get_request("abcda")
+            .header("accept-encoding", "gzip, deflate")
+            .reply(&api)
+            .await;
+        assert_eq!(get_res.status(), 200);
+        assert_eq!(get_res.headers()["content-encoding"], "gzip");
+        assert_eq!(encoding::gunzip(get_res.body(), None).unwrap(), b"bcda");
+    }
+
+    #[tokio::test]
+    async fn eviction_can_be_paused_and_resumed() {
+        let (_, api) = init_with_config(admin_config());
+
+        let res = api_admin_request("POST", "/admin/eviction/pause")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(get_stats(&api).await["eviction_paused"], true);
+
+        let res = api_admin_request("POST", "/admin/eviction/resume")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(get_stats(&api).await["eviction_paused"], false);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/eviction/pause")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn event_subscriptions_are_validated_and_capped() {
+        let (_, api) = init();
+
+        let events_request = |query: &str| {
+            warp::test::request()
+                .method("GET")
+                .path(format!("/events{}", query).as_str())
+        };
+
+        let res = events_request("?kinds=set,exploded").reply(&api).await;
+        assert_eq!(res.status(), 400);
+
+        // streams are kept open by the test client, so each one occupies a subscriber slot
+        let api_for_streams = api.clone();
+        tokio::spawn(async move {
+            let _ = events_request("?prefix=user:&kinds=set")
+                .reply(&api_for_streams)
+                .await;
+        });
+        let api_for_streams = api.clone();
+        tokio::spawn(async move {
+            let _ = events_request("").reply(&api_for_streams).await;
+        });
+
+        loop {
+            let stats = get_stats(&api).await;
+            if stats["event_subscribers"].as_array().unwrap().len() == 2 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+
+        let res = events_request("").reply(&api).await;
+        assert_eq!(res.status(), 503);
+    }
+
+    // reads the event stream until `needle` shows up, returns everything read
+    async fn read_events_until(body: &mut warp::hyper::Body, needle: &str) -> String {
+        use warp::hyper::body::HttpBody;
+
+        let mut text = String::new();
+        while !text.contains(needle) {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        text
+    }
+
+    #[tokio::test]
+    async fn event_streams_resume_from_last_event_id() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            event_history: 3,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let (addr, server) = warp::serve(api.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let client = warp::hyper::Client::new();
+        let subscribe = |last_event_id: Option<u64>| {
+            let mut request = warp::hyper::Request::get(format!("http://{}/events", addr));
+            if let Some(id) = last_event_id {
+                request = request.header("last-event-id", id);
+            }
+            client.request(request.body(warp::hyper::Body::empty()).unwrap())
+        };
+
+        let mut stream = subscribe(None).await.unwrap().into_body();
+        assert_eq!(api_set_request("a", "1").reply(&api).await.status(), 200);
+        let text = read_events_until(&mut stream, "id:1\n").await;
+        assert!(text.contains(r#""key":"a""#), "{}", text);
+        assert!(text.contains(r#""value_len":1"#), "{}", text);
+        drop(stream);
+
+        for key in ["b", "c"] {
+            assert_eq!(api_set_request(key, "1").reply(&api).await.status(), 200);
+        }
+        let mut stream = subscribe(Some(1)).await.unwrap().into_body();
+        let text = read_events_until(&mut stream, "id:3\n").await;
+        assert!(text.contains("id:2\n"), "{}", text);
+        assert!(!text.contains("event:gap"), "{}", text);
+        drop(stream);
+
+        // only the last 3 of 4 missed events are kept
+        for key in ["d", "e", "f", "g"] {
+            assert_eq!(api_set_request(key, "1").reply(&api).await.status(), 200);
+        }
+        let mut stream = subscribe(Some(3)).await.unwrap().into_body();
+        let text = read_events_until(&mut stream, "id:7\n").await;
+        let gap = text.find("event:gap").expect(&text);
+        assert!(text.contains(r#"{"missed":1}"#), "{}", text);
+        assert!(!text.contains("id:4\n"), "{}", text);
+        assert!(gap < text.find("id:5\n").unwrap(), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn stats_report_map_capacity() {
+        let (_, api) = init_with_config(admin_config());
+
+        for i in 0..100 {
+            let set_res = api_set_request(&format!("key{}", i), "bcda")
+                .reply(&api)
+                .await;
+            assert_eq!(set_res.status(), 200);
+        }
+
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["map_len"], 100);
+        assert!(stats["map_capacity"].as_u64().unwrap() >= 100);
+
+        let res = api_admin_request("POST", "/admin/compact")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(report["map_capacity_after"].as_u64().unwrap() >= 100);
+    }
+
+    #[tokio::test]
+    async fn map_can_be_reserved_ahead_of_writes() {
+        let (_, api) = init_with_config(admin_config());
+
+        let res = api_admin_request("POST", "/admin/reserve?additional=1000")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(report["map_capacity_after"].as_u64().unwrap() >= 1000);
+
+        for i in 0..1000 {
+            let set_res = api_set_request(&format!("key{}", i), "bcda")
+                .reply(&api)
+                .await;
+            assert_eq!(set_res.status(), 200);
+        }
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["map_growths"], 0);
+        assert!(stats["map_load_factor"].as_f64().unwrap() > 0.0);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/reserve?additional=1000")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        // a map that large is refused before the service tries to allocate it
+        let res = api_admin_request("POST", &format!("/admin/reserve?additional={}", usize::MAX))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "additional must be at most 16777216");
+    }
+
+    // writer thread appends records shortly after the operations are served
+    async fn read_audit_log(path: &std::path::Path, lines: usize) -> Vec<serde_json::Value> {
+        for _ in 0..100 {
+            let content = std::fs::read_to_string(path).unwrap_or_default();
+            if content.lines().count() >= lines {
+                return content
+                    .lines()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("audit log {} is missing records", path.display());
+    }
+
+    #[tokio::test]
+    async fn mutating_operations_are_audited_without_values_unless_enabled() {
+        let path =
+            std::env::temp_dir().join(format!("in-mem-cached-api-{}.audit", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config {
+            audit_path: Some(path.clone()),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let client: SocketAddr = ([10, 0, 0, 7], 41000).into();
+
+        let (_, api) = init_with_config(config.clone());
+        let set_res = api_set_request("a", "secret")
+            .remote_addr(client)
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 200);
+        // both keys have to exist
+        let swap_res = warp::test::request()
+            .method("POST")
+            .path("/swap?a=a&b=b")
+            .reply(&api)
+            .await;
+        assert_ne!(swap_res.status(), 200);
+        let del_res = api_delete_request("a", None)
+            .remote_addr(client)
+            .reply(&api)
+            .await;
+        assert_eq!(del_res.status(), 200);
+        // reads are not audited
+        api_get_request("a").reply(&api).await;
+
+        let records = read_audit_log(&path, 4).await;
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0]["operation"], "write");
+        assert_eq!(records[0]["key"], "a");
+        assert_eq!(records[0]["value_len"], 6);
+        assert_eq!(records[0]["outcome"], "ok");
+        assert_eq!(records[0]["client_ip"], "10.0.0.7");
+        assert!(records[0]["ts_ms"].as_u64().unwrap() > 0);
+        assert_eq!(records[1]["operation"], "swap");
+        assert_eq!(records[2]["key"], "b");
+        assert_ne!(records[2]["outcome"], "ok");
+        assert_eq!(records[3]["operation"], "delete");
+        assert_eq!(records[3]["client_ip"], "10.0.0.7");
+        assert!(records.iter().all(|r| r.get("value").is_none()));
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("secret"));
+        std::fs::remove_file(&path).unwrap();
+
+        let (_, api) = init_with_config(Config {
+            audit_include_values: true,
+            ..config
+        });
+        api_set_request("a", "secret").reply(&api).await;
+        let records = read_audit_log(&path, 1).await;
+        assert_eq!(records[0]["value"], "secret");
+        assert_eq!(get_stats(&api).await["audit_dropped"], 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_restored_skipping_corrupt_entries() {
+        let path =
+            std::env::temp_dir().join(format!("in-mem-cached-api-{}.snapshot", std::process::id()));
+        let config = Config {
+            snapshot_path: Some(path.clone()),
+            ..admin_config()
+        };
+
+        let (_, api) = init_with_config(config.clone());
+        for (key, value) in [("a", "first"), ("b", "second"), ("c", "third")] {
+            let set_res = api_set_request(key, value).reply(&api).await;
+            assert_eq!(set_res.status(), 200);
+        }
+        let res = api_admin_request("POST", "/admin/snapshot")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"entries":3,"skipped":0}"#);
+
+        let content = std::fs::read(&path).unwrap();
+        std::fs::write(&path, corrupt_value(content, "b")).unwrap();
+
+        let (_, api) = init_with_config(config);
+        assert_eq!(api_get_request("a").reply(&api).await.body(), "first");
+        assert_eq!(api_get_request("b").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("c").reply(&api).await.body(), "third");
+        assert_eq!(get_stats(&api).await["restore_skipped_corrupt"], 1);
+        std::fs::remove_file(&path).unwrap();
+
+        let (_, api) = init_with_config(admin_config());
+        let res = api_admin_request("POST", "/admin/snapshot")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 409);
+    }
+
+    #[tokio::test]
+    async fn requests_past_deadline_time_out() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("key", "value")
+            .header("x-request-deadline-ms", "0")
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 504);
+
+        let set_res = api_set_request("key", "value")
+            .header("x-request-deadline-ms", "1000")
+            .reply(&api)
+            .await;
+        assert_eq!(set_res.status(), 200);
+
+        let get_res = api_get_request("key")
+            .header("x-request-deadline-ms", "0")
+            .reply(&api)
+            .await;
+        assert_eq!(get_res.status(), 504);
+
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["deadline_exceeded"], 2);
+    }
+
+    #[tokio::test]
+    async fn values_are_returned_only_if_modified_since() {
+        let (time, api) = init();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        time.lock().await.add_secs(Duration::from_secs(5));
+        let set_res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = api_get_request(&format!("key?since={}", now - 10))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+
+        let res = api_get_request(&format!("key?since={}", now + 5))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+
+        let res = api_get_request(&format!("missing?since={}", now))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn values_carry_last_modified_and_honour_if_modified_since() {
+        let (_, api) = init_with_config(Config {
+            enable_last_modified: true,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let set_res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let last_modified = res.headers()["last-modified"].to_str().unwrap().to_string();
+        let modified_at = httpdate::parse_http_date(&last_modified).unwrap();
+        assert!(std::time::SystemTime::now() >= modified_at);
+
+        let conditional_get = |since: std::time::SystemTime| {
+            api_get_request("key").header("if-modified-since", httpdate::fmt_http_date(since))
+        };
+
+        // client copy is as recent as the value
+        let res = conditional_get(modified_at).reply(&api).await;
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+        assert_eq!(res.headers()["last-modified"], last_modified.as_str());
+
+        let res = conditional_get(modified_at - Duration::from_secs(60))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+
+        // unparseable dates are ignored
+        let res = api_get_request("key")
+            .header("if-modified-since", "yesterday")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        // off by default
+        let (_, api) = init();
+        assert_eq!(
+            api_set_request("key", "value").reply(&api).await.status(),
+            200
+        );
+        let res = conditional_get(modified_at).reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("last-modified").is_none());
+    }
+
+    #[tokio::test]
+    async fn delete_can_be_conditioned_on_creation_time() {
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let (_, api) = init();
+
+        let set_res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        // invalidation decided on information older than the write
+        let res = api_delete_request("key", Some(now_ms - 1000))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 412);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let created_at_ms = body["created_at_ms"].as_u64().unwrap() as u128;
+        assert!(created_at_ms >= now_ms);
+        assert_eq!(api_get_request("key").reply(&api).await.body(), "value");
+
+        // creation time itself is not strictly older
+        let res = api_delete_request("key", Some(created_at_ms))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 412);
+
+        let res = api_delete_request("key", Some(created_at_ms + 1))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 404);
+
+        let res = api_delete_request("key", None).reply(&api).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn flush_removes_everything_and_returns_fence() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        assert_eq!(api_set_request("x", "1").reply(&api).await.status(), 200);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/flush?fence=true")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(report["removed"], 1);
+        assert!(report["fence"].is_u64());
+        assert_eq!(api_get_request("x").reply(&api).await.status(), 404);
+
+        // written after the flush was answered
+        assert_eq!(api_set_request("x", "2").reply(&api).await.status(), 200);
+        assert_eq!(api_get_request("x").reply(&api).await.body(), "2");
+    }
+
+    #[tokio::test]
+    async fn generation_changes_on_flush_and_is_checked_against_expectation() {
+        let (_, api) = init();
+        let generation = |res: &warp::http::Response<warp::hyper::body::Bytes>| {
+            res.headers()["x-cache-generation"]
+                .to_str()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        };
+
+        let first = generation(&api_set_request("x", "1").reply(&api).await);
+        assert_eq!(generation(&api_get_request("x").reply(&api).await), first);
+        // misses and rejections carry it too
+        assert_eq!(generation(&api_get_request("y").reply(&api).await), first);
+        let res = warp::test::request().path("/info").reply(&api).await;
+        let info: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info["generation"], first);
+        assert_eq!(get_stats(&api).await["generation"], first);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/flush")
+            .reply(&api)
+            .await;
+        let flushed = generation(&res);
+        assert_ne!(flushed, first);
+        let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(report["generation"], flushed);
+
+        let res = api_get_request("x")
+            .header("X-Expect-Generation", first.to_string())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 412);
+        assert_eq!(generation(&res), flushed);
+        let res = api_set_request("x", "2")
+            .header("X-Expect-Generation", first.to_string())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 412);
+        let res = api_set_request("x", "2")
+            .header("X-Expect-Generation", flushed.to_string())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn maintenance_status_is_reported() {
+        let (_, api) = init();
+        let res = warp::test::request()
+            .path("/stats/maintenance")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(status["state"], "idle");
+        assert!(status["pending"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dashboard_is_served_only_when_enabled() {
+        let (_, api) = init_with_config(Config {
+            enable_dashboard: true,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let res = warp::test::request().path("/").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert!(String::from_utf8_lossy(res.body()).contains("fetch(\"stats\")"));
+
+        let (_, api) = init();
+        let res = warp::test::request().path("/").reply(&api).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn swap_exchanges_values_of_existing_keys() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        assert_eq!(api_set_request("x", "1").reply(&api).await.status(), 200);
+        assert_eq!(api_set_request("y", "2").reply(&api).await.status(), 200);
+
+        let swap = |query: &str| {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/swap?{}", query))
+        };
+        assert_eq!(swap("a=x&b=y").reply(&api).await.status(), 200);
+        assert_eq!(api_get_request("x").reply(&api).await.body(), "2");
+        assert_eq!(api_get_request("y").reply(&api).await.body(), "1");
+
+        let res = swap("a=x&b=missing").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(api_get_request("x").reply(&api).await.body(), "2");
+    }
+
+    #[tokio::test]
+    async fn rename_moves_entry_unless_destination_exists() {
+        let (time, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let rename = |path: &str| warp::test::request().method("POST").path(path);
+
+        assert_eq!(
+            api_set_request("tmp", "built").reply(&api).await.status(),
+            200
+        );
+        assert_eq!(
+            api_set_request("final", "old").reply(&api).await.status(),
+            200
+        );
+
+        let res = rename("/rename/tmp/final").reply(&api).await;
+        assert_eq!(res.status(), 409);
+        assert_eq!(api_get_request("final").reply(&api).await.body(), "old");
+        let res = rename("/rename/missing/final?overwrite=true")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404);
+
+        time.lock().await.add_secs(Duration::from_secs(5));
+        let res = rename("/rename/tmp/final?overwrite=true").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(api_get_request("tmp").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("final").reply(&api).await.body(), "built");
+
+        // keeps expiring when it was set under the old key
+        time.lock().await.add_secs(Duration::from_secs(11));
+        assert_eq!(api_get_request("final").reply(&api).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn pinned_keys_outlive_their_ttl_until_unpinned() {
+        let (time, api) = init();
+        let pin = |path: &str| warp::test::request().method("POST").path(path);
+
+        assert_eq!(pin("/pin/key").reply(&api).await.status(), 404);
+        assert_eq!(
+            api_set_request("key", "value").reply(&api).await.status(),
+            200
+        );
+        assert_eq!(pin("/pin/key").reply(&api).await.status(), 200);
+
+        time.lock().await.add_secs(Duration::from_secs(11));
+        assert_eq!(api_get_request("key").reply(&api).await.body(), "value");
+
+        assert_eq!(pin("/unpin/key").reply(&api).await.status(), 200);
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 404);
+        assert_eq!(pin("/unpin/key").reply(&api).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn ring_push_keeps_last_lines() {
+        let (_, api) = init();
+
+        let push = |line: &str, max: usize| {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/ringpush/log?max={}", max))
+                .body(line)
+        };
+        for line in ["one", "two", "three"].iter() {
+            assert_eq!(push(line, 2).reply(&api).await.status(), 200);
+        }
+        let res = push("four", 2).reply(&api).await;
+        assert_eq!(res.body(), r#"{"lines":2}"#);
+        assert_eq!(
+            api_get_request("log").reply(&api).await.body(),
+            "three\nfour"
+        );
+
+        assert_eq!(push("a\nb", 2).reply(&api).await.status(), 400);
+        assert_eq!(push("five", 0).reply(&api).await.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn txn_is_applied_all_or_nothing() {
+        let (_, api) = init_with_config(admin_config());
+
+        let set_res = api_set_request("b", "old").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/txn")
+            .body(
+                r#"[{"op": "set", "key": "a", "value": "1"},
+                    {"op": "del", "key": "b"},
+                    {"op": "set", "key": "b", "value": "new", "nx": true},
+                    {"op": "set", "key": "c", "value": "1", "xx": true}]"#,
+            )
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 409);
+        let result: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(result["committed"], false);
+        assert_eq!(
+            result["results"],
+            serde_json::json!(["ok", "ok", "ok", "condition_failed"])
+        );
+        assert_eq!(api_get_request("a").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("b").reply(&api).await.body(), "old");
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/txn")
+            .body(
+                r#"[{"op": "set", "key": "a", "value": "1"},
+                    {"op": "del", "key": "b"}]"#,
+            )
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"committed":true,"results":["ok","ok"]}"#);
+        assert_eq!(api_get_request("a").reply(&api).await.body(), "1");
+        assert_eq!(api_get_request("b").reply(&api).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn batch_reads_keep_positions_of_repeated_keys() {
+        let (_, api) = init_with_config(admin_config());
+
+        for (key, value) in [("a", "1"), ("b", "2")] {
+            let set_res = api_set_request(key, value).reply(&api).await;
+            assert_eq!(set_res.status(), 200);
+        }
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/mget")
+            .body(r#"["a", "c", "b", "a"]"#)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"["1",null,"2","1"]"#);
+    }
+
+    #[tokio::test]
+    async fn count_returns_number_of_matching_keys() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        for key in ["foo:1", "foo:2", "bar:1"].iter() {
+            let res = api_set_request(key, "v").reply(&api).await;
+            assert_eq!(res.status(), 200);
+        }
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/count?pattern=foo:*")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["pattern"], "foo:*");
+        assert_eq!(body["count"], 2);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/count?pattern=*")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["count"], get_stats(&api).await["keys_total"]);
+    }
+
+    #[tokio::test]
+    async fn eviction_can_be_run_on_demand() {
+        let (time, api) = init_with_config(Config {
+            active_eviction: false,
+            ..admin_config()
+        });
+
+        for i in 0..10 {
+            let set_res = api_set_request(&format!("key{}", i), "bcda")
+                .reply(&api)
+                .await;
+            assert_eq!(set_res.status(), 200);
+        }
+        time.lock().await.add_secs(Duration::from_secs(11));
+        assert_eq!(get_stats(&api).await["keys_total"], 10);
+
+        let res = api_admin_request("POST", "/admin/evict").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(report["removed"], 10);
+        assert_eq!(report["sampled"], 10);
+        assert!(report["rounds"].as_u64().unwrap() >= 1);
+        assert_eq!(report["keys_total"], 0);
+        assert_eq!(get_stats(&api).await["keys_total"], 0);
+    }
+
+    #[tokio::test]
+    async fn explain_reports_key_state_without_reading_it() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/explain/abcda?value_size=4")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["exists"], true);
+        assert_eq!(body["expired"], false);
+        assert_eq!(body["eviction_policy"], "reject_writes");
+        assert_eq!(body["set"]["allowed"], true);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/explain/other?value_size=4")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["exists"], false);
+        assert_eq!(body["set"]["allowed"], false);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/meta/abcda")
+            .reply(&api)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["hits"], 0);
+    }
+
+    #[tokio::test]
+    async fn tx_increments_counters() {
+        let (_, api) = init_with_config(admin_config());
+
+        let tx_request = || {
+            warp::test::request()
+                .method("POST")
+                .path("/tx")
+                .body(r#"[{"op": "incr", "key": "a"}, {"op": "incr", "key": "b", "by": 10}]"#)
+        };
+        assert_eq!(tx_request().reply(&api).await.status(), 200);
+        assert_eq!(tx_request().reply(&api).await.status(), 200);
+
+        assert_eq!(api_get_request("a").reply(&api).await.body(), "2");
+        assert_eq!(api_get_request("b").reply(&api).await.body(), "20");
+    }
+
+    #[tokio::test]
+    async fn strlen_returns_length_of_live_values() {
+        let (time, api) = init();
+        let strlen_request = |key: &str| {
+            warp::test::request()
+                .method("GET")
+                .path(format!("/strlen/{}", key).as_str())
+        };
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+
+        let res = strlen_request("abcda").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), r#"{"len":4}"#);
+
+        assert_eq!(strlen_request("other").reply(&api).await.status(), 404);
+
+        time.lock().await.add_secs(Duration::from_secs(11));
+        assert_eq!(strlen_request("abcda").reply(&api).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn metrics_report_process_and_lifetime_counters() {
+        let (_, api) = init();
+
+        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        assert_eq!(set_res.status(), 200);
+        assert_eq!(api_get_request("abcda").reply(&api).await.status(), 200);
+        assert_eq!(api_get_request("other").reply(&api).await.status(), 404);
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = std::str::from_utf8(res.body()).unwrap();
+        for line in [
+            "in_mem_cached_keys 1",
+            "in_mem_cached_process_hits_total 1",
+            "in_mem_cached_process_misses_total 1",
+            "in_mem_cached_process_sets_total 1",
+            "in_mem_cached_lifetime_hits_total 1",
+            "# TYPE in_mem_cached_lifetime_evictions_total counter",
+            "in_mem_cached_service_queue_length{lane=\"write\"} 0",
+            "in_mem_cached_deferred_drops_total 0",
+            "# TYPE in_mem_cached_deferred_drop_bytes_total counter",
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {}", line);
+        }
+
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["process"]["hits"], 1);
+        assert_eq!(stats["lifetime"]["misses"], 1);
+    }
+
+    #[tokio::test]
+    async fn keys_can_be_invalidated_by_tag() {
+        let (_, api) = init_with_config(admin_config());
+
+        for (key, tags) in [("a", "user:42,org:7"), ("b", "user:42"), ("c", "org:7")].iter() {
+            let res = api_set_request(&format!("{}?tags={}", key, tags), "value")
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200);
+        }
+        let res = api_set_request("d", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/tags/user:42")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["removed"], 2);
+        assert_eq!(body["more"], false);
+
+        assert_eq!(api_get_request("a").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("b").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("c").reply(&api).await.status(), 200);
+        assert_eq!(api_get_request("d").reply(&api).await.status(), 200);
+
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["tags"], 1);
+        assert_eq!(stats["tag_index_size"], 1);
+    }
+
+    #[tokio::test]
+    async fn auxiliary_memory_is_reported_within_caps() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            max_tag_index_refs: Some(2),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        for (key, tags) in [("a", "x,y"), ("b", "z")].iter() {
+            let res = api_set_request(&format!("{}?tags={}", key, tags), "value")
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200);
+        }
+        assert_eq!(api_get_request("a").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("b").reply(&api).await.status(), 200);
+
+        let stats = get_stats(&api).await;
+        let tag_index = &stats["auxiliary"]["tag_index"];
+        assert_eq!(tag_index["entries"], 1);
+        assert_eq!(tag_index["cap"], 2);
+        assert_eq!(tag_index["evicted"], 2);
+        assert!(tag_index["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(stats["tag_index_size"], 1);
+        assert_eq!(
+            stats["auxiliary"]["slowlog"]["cap"],
+            TEST_CONFIG_SINGLE_ITEM.slowlog_len
+        );
+        assert_eq!(
+            stats["auxiliary"]["event_history"]["cap"],
+            TEST_CONFIG_SINGLE_ITEM.event_history
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_with_too_many_tags_are_rejected() {
+        let (_, api) = init();
+
+        let res = api_set_request("a?tags=x,y,z", "value").reply(&api).await;
+
+        assert_eq!(res.status(), 400);
+        assert_eq!(api_get_request("a").reply(&api).await.status(), 404);
+    }
+
+    #[cfg(feature = "runtime-metrics")]
+    #[tokio::test]
+    async fn metrics_include_runtime_section() {
+        let (_, api) = init();
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let body = std::str::from_utf8(res.body()).unwrap();
+        let value = |name: &str| -> u64 {
+            body.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(' '))
+                .unwrap_or_else(|| panic!("missing {}", name))
+                .parse()
+                .unwrap()
+        };
+
+        assert!(value("in_mem_cached_tokio_workers") >= 1);
+        // at least the service task is alive
+        assert!(value("in_mem_cached_tokio_alive_tasks") >= 1);
+        value("in_mem_cached_tokio_global_queue_depth");
+    }
+
+    #[tokio::test]
+    async fn only_values_over_threshold_are_compressed() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            compress_response_min_bytes: 16,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let large = "value: String".repeat(10_000);
+
+        for (key, value) in [("large", large.as_str()), ("small", "bcda")].iter() {
+            let res = api_set_request(key, value).reply(&api).await;
+            assert_eq!(res.status(), 200);
+        }
+
+        let res = api_get_request("large")
+            .header("accept-encoding", "gzip")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(res.headers()["vary"], "Accept-Encoding");
+        assert!(res.body().len() < large.len());
+        assert_eq!(
+            encoding::gunzip(res.body(), None).unwrap(),
+            large.as_bytes()
+        );
+
+        let res = api_get_request("small")
+            .header("accept-encoding", "gzip")
+            .reply(&api)
+            .await;
+        assert!(res.headers().get("content-encoding").is_none());
+        assert_eq!(res.headers()["vary"], "Accept-Encoding");
+        assert_eq!(res.body(), "bcda");
+
+        let res = api_get_request("large").reply(&api).await;
+        assert!(res.headers().get("content-encoding").is_none());
+        assert_eq!(res.body(), large.as_str());
+    }
+
+    type RecordedWrites = Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+    // primary holding a single `remote` key, records writes it receives and counts reads
+    fn mock_primary() -> (String, RecordedWrites, Arc<AtomicUsize>) {
+        let writes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reads = Arc::new(AtomicUsize::new(0));
+
+        let recorded = writes.clone();
+        let set = warp::post()
+            .and(warp::path!("set" / String))
+            .and(warp::body::bytes())
+            .map(move |key: String, body: warp::hyper::body::Bytes| {
+                let value = String::from_utf8(body.to_vec()).unwrap();
+                recorded.lock().unwrap().push((key, value));
+                warp::reply::with_status(String::new(), StatusCode::OK)
+            });
+        let counted = reads.clone();
+        let get = warp::get()
+            .and(warp::path!("get" / String))
+            .map(move |key: String| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                if key == "remote" {
+                    warp::reply::with_status(String::from("from primary"), StatusCode::OK)
+                } else {
+                    warp::reply::with_status(String::new(), StatusCode::NOT_FOUND)
+                }
+            });
+
+        let (addr, server) = warp::serve(set.or(get)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), writes, reads)
+    }
+
+    #[tokio::test]
+    async fn replica_forwards_writes_and_fetches_misses_from_primary() {
+        let (primary, writes, reads) = mock_primary();
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            replica_of: Some(primary),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            *writes.lock().unwrap(),
+            vec![(String::from("key"), String::from("value"))]
+        );
+        assert_eq!(get_stats(&api).await["keys_total"], 0);
+
+        for _ in 0..2 {
+            let res = api_get_request("remote").reply(&api).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), "from primary");
+        }
+        // second read is served from the local copy
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        assert_eq!(get_stats(&api).await["keys_total"], 1);
+
+        assert_eq!(api_get_request("missing").reply(&api).await.status(), 404);
+
+        // local copy is dropped once the primary accepts a write of the key
+        let res = api_set_request("remote", "updated").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(get_stats(&api).await["keys_total"], 0);
+    }
+
+    #[tokio::test]
+    async fn replica_forwards_tags_and_keys_encoded() {
+        use std::collections::HashMap;
+
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = received.clone();
+        let set = warp::post()
+            .and(warp::path!("set" / String))
+            .and(warp::query::<HashMap<String, String>>())
+            .map(move |key: String, query: HashMap<String, String>| {
+                recorded.lock().unwrap().push((key, query));
+                warp::reply::with_status(String::new(), StatusCode::OK)
+            });
+        let (addr, server) = warp::serve(set).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            replica_of: Some(format!("http://{}", addr)),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/set/user%2042?tags=a%26b,c%23d&ttl=5s")
+            .body("value")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (key, query) = &received[0];
+        assert_eq!(key, "user%2042");
+        assert_eq!(query["tags"], "a&b,c#d");
+        assert_eq!(query["ttl"], "5000ms");
+    }
+
+    // origin that never answers `hang` and fails every other read until made healthy
+    fn failing_origin() -> (String, Arc<AtomicBool>, Arc<AtomicUsize>) {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let reads = Arc::new(AtomicUsize::new(0));
+
+        let (is_healthy, counted) = (healthy.clone(), reads.clone());
+        let get = warp::get()
+            .and(warp::path!("get" / String))
+            .and_then(move |key: String| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let healthy = is_healthy.load(Ordering::SeqCst);
+                async move {
+                    if key == "hang" {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                    Ok::<_, std::convert::Infallible>(if healthy {
+                        warp::reply::with_status(String::from("from origin"), StatusCode::OK)
+                    } else {
+                        warp::reply::with_status(String::new(), StatusCode::BAD_GATEWAY)
+                    })
+                }
+            });
+
+        let (addr, server) = warp::serve(get).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), healthy, reads)
+    }
+
+    #[tokio::test]
+    async fn replica_stops_asking_failing_origin_until_cooldown_is_over() {
+        let (origin, healthy, reads) = failing_origin();
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            replica_of: Some(origin),
+            origin_timeout: Some(Duration::from_millis(200)),
+            origin_failure_threshold: Some(2),
+            origin_cooldown: Duration::from_millis(300),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let started = Instant::now();
+        let res = api_get_request("hang").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert!(res.headers().get("x-cache").is_none());
+
+        // open, answered without asking the origin
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["x-cache"], "ORIGIN-BYPASSED");
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["origin_breaker"]["state"], "open");
+        assert_eq!(stats["origin_breaker"]["bypassed"], 1);
+
+        // half-open once the cooldown is over, a failed trial opens it again
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert_eq!(
+            get_stats(&api).await["origin_breaker"]["state"],
+            "half-open"
+        );
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 404);
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.headers()["x-cache"], "ORIGIN-BYPASSED");
+
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        healthy.store(true, Ordering::SeqCst);
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "from origin");
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["origin_breaker"]["state"], "closed");
+        assert_eq!(stats["origin_breaker"]["opened"], 2);
+    }
+
+    #[tokio::test]
+    async fn replica_reaches_origin_through_proxy_with_configured_headers() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let proxy = warp::get()
+            .and(warp::path::full())
+            .and(warp::header::<String>("host"))
+            .and(warp::header::<String>("authorization"))
+            .map(
+                move |path: warp::path::FullPath, host: String, authorization: String| {
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push((path.as_str().to_string(), host, authorization));
+                    String::from("via proxy")
+                },
+            );
+        let (proxy, server) = warp::serve(proxy).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            // never resolved, only the proxy is connected to
+            replica_of: Some(String::from("http://origin.invalid:8080")),
+            origin_proxy: Some(format!("http://{}", proxy)),
+            origin_headers: vec![(String::from("Authorization"), String::from("Bearer secret"))],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "via proxy");
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(
+                String::from("/get/key"),
+                String::from("origin.invalid:8080"),
+                String::from("Bearer secret")
+            )]
+        );
+        assert!(get_stats(&api).await.get("origin_breaker").is_none());
+    }
+
+    #[tokio::test]
+    async fn only_one_of_concurrent_misses_gets_refresh_lock() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let requests: Vec<_> = (0..20)
+            .map(|_| {
+                let api = api.clone();
+                tokio::spawn(async move { api_get_request("key?refresh_lock=5").reply(&api).await })
+            })
+            .collect();
+        let mut granted = 0;
+        for request in requests {
+            let res = request.await.unwrap();
+            assert_eq!(res.status(), 404);
+            match res.headers()["x-refresh"].to_str().unwrap() {
+                "granted" => granted += 1,
+                refresh => assert_eq!(refresh, "wait"),
+            }
+        }
+        assert_eq!(granted, 1);
+
+        let res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = api_get_request("key?refresh_lock=5").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+    }
+
+    #[tokio::test]
+    async fn duration_params_take_seconds_or_units() {
+        let (time, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        for (key, ttl) in [("secs", "30"), ("minute", "1m"), ("mixed", "1h30m")].iter() {
+            let res = api_set_request(&format!("{}?ttl={}", key, ttl), "value")
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200, "{}", ttl);
+        }
+        time.lock().await.add_secs(Duration::from_secs(40));
+        assert_eq!(api_get_request("secs").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("minute").reply(&api).await.status(), 200);
+        time.lock().await.add_secs(Duration::from_secs(70));
+        assert_eq!(api_get_request("minute").reply(&api).await.status(), 404);
+        assert_eq!(api_get_request("mixed").reply(&api).await.status(), 200);
+
+        for lock in ["5", "5s", "1m30s"].iter() {
+            let res = api_get_request(&format!("{}?refresh_lock={}", lock, lock))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 404);
+            assert_eq!(res.headers()["x-refresh"], "granted");
+        }
+
+        for (request, error) in [
+            (
+                api_set_request("key?ttl=soon", "value"),
+                "invalid ttl: expected number at 0",
+            ),
+            (
+                api_set_request("key?ttl=0s", "value"),
+                "invalid ttl: must not be zero",
+            ),
+            (
+                api_get_request("key?refresh_lock=0"),
+                "invalid refresh_lock: must not be zero",
+            ),
+            (
+                api_set_request("key?ttl=18446744073709551615", "value"),
+                "invalid ttl: must not be longer than 315360000s",
+            ),
+            (
+                api_get_request("key?refresh_lock=1.5m"),
+                "invalid refresh_lock: unit is missing",
+            ),
+        ] {
+            let res = request.reply(&api).await;
+            assert_eq!(res.status(), 400);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            let body = body["error"].as_str().unwrap();
+            assert!(body.starts_with(error), "{}", body);
+            assert!(body.ends_with("expected seconds or a duration like 90s, 5m or 1h30m"));
+        }
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 404);
+    }
+
+    // instance listening on an ephemeral port, returns its url and routes to inspect it directly
+    fn spawn_instance() -> (
+        String,
+        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+    ) {
+        spawn_instance_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        })
+    }
+
+    fn spawn_instance_with_config(
+        config: Config,
+    ) -> (
+        String,
+        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
+    ) {
+        let (_, api) = init_with_config(config);
+        let (addr, server) = warp::serve(api.clone()).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), api)
+    }
+
+    #[tokio::test]
+    async fn cluster_routes_keys_to_owning_peers() {
+        let (a, a_api) = spawn_instance();
+        let (b, b_api) = spawn_instance();
+        let (c, c_api) = spawn_instance();
+        let peers = vec![a, b, c];
+        let (_, router) = init_with_config(Config {
+            capacity: None,
+            cluster_peers: peers.clone(),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let ring = Ring::new(&peers, TEST_CONFIG_SINGLE_ITEM.cluster_virtual_nodes);
+
+        let keys: Vec<String> = (0..30).map(|i| format!("key{}", i)).collect();
+        let mut expected = [0; 3];
+        for key in keys.iter() {
+            let res = api_set_request(key, &format!("value of {}", key))
+                .reply(&router)
+                .await;
+            assert_eq!(res.status(), 200);
+            expected[ring.peer_for(key).unwrap()] += 1;
+        }
+
+        // router keeps nothing, every peer holds exactly the keys the ring assigns to it
+        assert_eq!(get_stats(&router).await["keys_total"], 0);
+        assert!(expected.iter().all(|e| *e > 0), "{:?}", expected);
+        assert_eq!(get_stats(&a_api).await["keys_total"], expected[0]);
+        assert_eq!(get_stats(&b_api).await["keys_total"], expected[1]);
+        assert_eq!(get_stats(&c_api).await["keys_total"], expected[2]);
+
+        for key in keys.iter() {
+            let res = api_get_request(key).reply(&router).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), format!("value of {}", key).as_str());
+        }
+        let res = api_get_request("missing").reply(&router).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["x-cache-result"], "miss");
+
+        let res = warp::test::request()
+            .path("/cluster/status")
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 200);
+        let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(status["peers"].as_array().unwrap().len(), 3);
+        assert!(status["peers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|p| p["up"] == true && p["ring_share"].as_f64().unwrap() > 0.0));
+
+        let res = warp::test::request()
+            .path("/cluster/status")
+            .reply(&a_api)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn downed_peer_fails_only_its_keys() {
+        let (a, _) = spawn_instance();
+        let (b, _) = spawn_instance();
+        // nothing listens there once the listener is dropped
+        let dead = {
+            let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let peers = vec![a, b, dead];
+        let (_, router) = init_with_config(Config {
+            capacity: None,
+            cluster_peers: peers.clone(),
+            peer_failure_threshold: 2,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let ring = Ring::new(&peers, TEST_CONFIG_SINGLE_ITEM.cluster_virtual_nodes);
+
+        let mut failed = 0;
+        for key in (0..30).map(|i| format!("key{}", i)) {
+            let res = api_set_request(&key, "value").reply(&router).await;
+            if ring.peer_for(&key) == Some(2) {
+                assert_eq!(res.status(), 502);
+                failed += 1;
+            } else {
+                assert_eq!(res.status(), 200);
+                assert_eq!(api_get_request(&key).reply(&router).await.status(), 200);
+            }
+        }
+        assert!(failed >= 2);
+
+        let res = warp::test::request()
+            .path("/cluster/status")
+            .reply(&router)
+            .await;
+        let status: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let up: Vec<_> = status["peers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["up"].as_bool().unwrap())
+            .collect();
+        assert_eq!(up, vec![true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn routed_writes_are_checked_against_allowlists() {
+        let (a, _) = spawn_instance();
+        let (_, router) = init_with_config(Config {
+            capacity: None,
+            cluster_peers: vec![a],
+            write_allowlist: Some(vec!["10.0.1.0/24".parse().unwrap()]),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = api_set_request("key", "value")
+            .remote_addr(from("10.0.2.20"))
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.body(), r#"{"error":"client is not allowed to write"}"#);
+        let res = api_delete_request("key", None)
+            .remote_addr(from("10.0.2.20"))
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 403);
+
+        let res = api_set_request("key", "value")
+            .remote_addr(from("10.0.1.20"))
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 200);
+        // reads stay open to everyone
+        let res = api_get_request("key")
+            .remote_addr(from("10.0.2.20"))
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+
+        // the peer trusts the router and checks the client the router saw, not the one
+        // the client claims to be
+        let (b, _) = spawn_instance_with_config(Config {
+            capacity: None,
+            write_allowlist: Some(vec![
+                "127.0.0.1/32".parse().unwrap(),
+                "10.0.1.0/24".parse().unwrap(),
+            ]),
+            trust_proxy: true,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let (_, router) = init_with_config(Config {
+            capacity: None,
+            cluster_peers: vec![b],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let res = api_set_request("key", "value")
+            .remote_addr(from("10.0.2.20"))
+            .header("x-forwarded-for", "10.0.1.5")
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 403);
+        let res = api_set_request("key", "value")
+            .remote_addr(from("10.0.1.20"))
+            .reply(&router)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn cluster_routes_pins_to_owning_peers() {
+        let (a, _) = spawn_instance();
+        let (b, _) = spawn_instance();
+        let (_, router) = init_with_config(Config {
+            capacity: None,
+            cluster_peers: vec![a, b],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let pin = |path: &str| warp::test::request().method("POST").path(path);
+
+        for key in (0..10).map(|i| format!("key{}", i)) {
+            let res = api_set_request(&key, "value").reply(&router).await;
+            assert_eq!(res.status(), 200);
+            let res = pin(&format!("/pin/{}", key)).reply(&router).await;
+            assert_eq!(res.status(), 200);
+            let res = pin(&format!("/unpin/{}", key)).reply(&router).await;
+            assert_eq!(res.status(), 200);
+        }
+        assert_eq!(pin("/pin/missing").reply(&router).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn cluster_routes_keys_by_their_canonical_form() {
+        let config = Config {
+            capacity: None,
+            key_canonicalization: CASE_INSENSITIVE_KEYS,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let (a, _) = spawn_instance_with_config(config.clone());
+        let (b, _) = spawn_instance_with_config(config.clone());
+        let (_, router) = init_with_config(Config {
+            cluster_peers: vec![a, b],
+            ..config
+        });
+
+        for i in 0..20 {
+            let res = api_set_request(&format!("KEY{}", i), "value")
+                .reply(&router)
+                .await;
+            assert_eq!(res.status(), 200);
+            let res = api_get_request(&format!("key{}", i)).reply(&router).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), "value");
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_routes_access_counts_to_owning_peers() {
+        let (a, _) = spawn_instance();
+        let (b, _) = spawn_instance();
+        let (_, router) = init_with_config(Config {
+            capacity: None,
+            cluster_peers: vec![a, b],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let access = |method: &str, path: &str| warp::test::request().method(method).path(path);
+
+        for key in (0..10).map(|i| format!("key{}", i)) {
+            let res = api_set_request(&key, "value").reply(&router).await;
+            assert_eq!(res.status(), 200);
+            for _ in 0..2 {
+                assert_eq!(api_get_request(&key).reply(&router).await.status(), 200);
+            }
+            let res = access("GET", &format!("/access/{}", key))
+                .reply(&router)
+                .await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(
+                res.body(),
+                format!(r#"{{"key":"{}","count":2}}"#, key).as_str()
+            );
+            let res = access("POST", &format!("/access/{}/reset", key))
+                .reply(&router)
+                .await;
+            assert_eq!(res.status(), 200);
+            let res = access("GET", &format!("/access/{}", key))
+                .reply(&router)
+                .await;
+            assert_eq!(
+                res.body(),
+                format!(r#"{{"key":"{}","count":0}}"#, key).as_str()
+            );
+        }
+    }
+
+    async fn ping_latency(
+        api: &(impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + 'static),
+    ) -> u64 {
+        let res = warp::test::request().path("/ping").reply(api).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        body["latency_us"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn ping_reports_time_spent_waiting_for_service() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let idle = ping_latency(&api).await;
+
+        // service busy with a backlog of writes queued ahead of the ping
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let busy_api = make_api(
+            tx.clone(),
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+        // replies are kept, writes nobody waits for are skipped
+        let mut replies = Vec::new();
+        for i in 0..20_000 {
+            let (cb, res) = tokio::sync::oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            replies.push(res);
+        }
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, &time).run().await });
+
+        assert!(ping_latency(&busy_api).await > idle);
+    }
+
+    #[tokio::test]
+    async fn slowlog_is_listed_and_reset() {
+        let (_, api) = init_with_config(Config {
+            slowlog_threshold: Some(Duration::ZERO),
+            ..admin_config()
+        });
+        assert_eq!(
+            api_set_request("key", "value").reply(&api).await.status(),
+            200
+        );
+
+        let res = warp::test::request().path("/slowlog").reply(&api).await;
+        assert_eq!(res.status(), 401);
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/slowlog")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let res = api_admin_request("GET", "/slowlog").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let logged: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let last = &logged.as_array().unwrap()[0];
+        assert_eq!(last["operation"], "write");
+        assert_eq!(last["key"], "key");
+        assert_eq!(last["batch_size"], 1);
+        assert!(last["duration_us"].is_u64() && last["queue_wait_us"].is_u64());
+
+        let res = api_admin_request("DELETE", "/slowlog").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        // the listing above is an operation of its own
+        assert_eq!(body["removed"], logged.as_array().unwrap().len() + 1);
+
+        let res = api_admin_request("GET", "/slowlog").reply(&api).await;
+        let logged: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let operations: Vec<&str> = logged
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|o| o["operation"].as_str().unwrap())
+            .collect();
+        assert_eq!(operations, vec!["reset-slowlog"]);
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_are_shed_while_overloaded() {
+        let (tx, rx) = service_queue();
+        let config = Config {
+            latency_budget: Some(Duration::from_millis(5)),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let api = make_api(
+            tx,
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        let service_flags = flags.clone();
+        tokio::spawn(async move {
+            TtlCacheService::new(config, rx, service_flags, &time)
+                .run()
+                .await
+        });
 
-use tokio::sync::mpsc;
-use tokio::sync::oneshot;
+        let res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
 
-async fn read(
-    queue: ServiceQueue,
-    key: String,
-) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let (tx, rx) = oneshot::channel::<Option<String>>();
+        // set by the service once operations take too long, see `service_tests`
+        flags.overloaded.store(true, Ordering::SeqCst);
+        let res = api_set_request("key", "other").reply(&api).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers()["retry-after"], "1");
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 503);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/get/key")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 503);
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/del/key")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 503);
+        for path in [
+            "/peek/key",
+            "/meta/key",
+            "/access/key",
+            "/strlen/key",
+            "/count?pattern=k*",
+            "/ns/tenant/get/key",
+        ]
+        .iter()
+        {
+            let res = warp::test::request().path(path).reply(&api).await;
+            assert_eq!(res.status(), 503, "{}", path);
+        }
+        let res = api_ns_set_request("tenant", "key", "value")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 503);
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["overloaded"], true);
 
-    // todo: warp does not allow any types apart from Infallible and Rejection
-    // thus it is a big ugly instead of using much more ergonomic '?' op
-    match queue.send(ServiceMessage::Read(key, tx)) {
-        Ok(_) => match rx.await {
-            Ok(v) => match v {
-                Some(vv) => Ok(warp::reply::with_status(vv, StatusCode::OK)),
-                None => Ok(warp::reply::with_status(
-                    String::from("Not found"),
-                    StatusCode::NOT_FOUND,
-                )),
-            },
-            Err(e) => Ok(warp::reply::with_status(
-                format!("{}", e),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            )),
-        },
-        Err(e) => Ok(warp::reply::with_status(
-            format!("{}", e),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        )),
+        flags.overloaded.store(false, Ordering::SeqCst);
+        assert_eq!(api_get_request("key").reply(&api).await.body(), "value");
     }
-}
 
-async fn write(
-    queue: ServiceQueue,
-    key: String,
-    value: warp::hyper::body::Bytes,
-) -> Result<impl warp::Reply, std::convert::Infallible> {
-    let (tx, rx) = oneshot::channel::<Result<(), String>>();
+    // response head of a write sent with `Expect: 100-continue`, without its body
+    async fn expect_continue(
+        api: impl Filter<Extract = impl warp::Reply, Error = warp::Rejection>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        key: &str,
+        content_length: usize,
+    ) -> (tokio::net::TcpStream, String) {
+        use tokio::io::AsyncReadExt;
+        use tokio::io::AsyncWriteExt;
 
-    match String::from_utf8(value.into_iter().collect::<Vec<_>>()) {
-        Ok(v) => match queue.send(ServiceMessage::Write(key, v, tx)) {
-            Ok(_) => match rx.await {
-                Ok(res) => match res {
-                    Ok(_) => Ok(warp::reply::with_status(String::new(), StatusCode::OK)),
-                    Err(e) => Ok(warp::reply::with_status(e, StatusCode::BAD_REQUEST)),
-                },
-                Err(e) => Ok(warp::reply::with_status(
-                    format!("{}", e),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )),
-            },
-            Err(e) => Ok(warp::reply::with_status(
-                format!("{}", e),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            )),
-        },
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "POST /set/{} HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\nexpect: 100-continue\r\n\r\n",
+            key, content_length
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
 
-        Err(e) => Ok(warp::reply::with_status(
-            format!("Could not decode utf-8: {}", e),
-            StatusCode::BAD_REQUEST,
-        )),
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await.unwrap();
+            response.push(byte[0]);
+        }
+        (stream, String::from_utf8(response).unwrap())
     }
-}
 
-fn with_cache_tx(
-    tx: ServiceQueue,
-) -> impl Filter<Extract = (ServiceQueue,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || tx.clone())
-}
+    #[tokio::test]
+    async fn oversized_write_is_rejected_before_body_is_uploaded() {
+        let (_, api) = init_with_config(Config {
+            max_value_bytes: Some(1024),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
 
-pub fn make_api(
-    tx: mpsc::UnboundedSender<ServiceMessage>,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    let hello = warp::get().and(warp::path("health-check")).map(|| "Ok");
+        let (_, response) = expect_continue(api.clone(), "key", 10 * 1024 * 1024).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
 
-    let set = warp::post()
-        .and(warp::path("set"))
-        .and(warp::path::param::<String>())
-        .and(warp::body::bytes())
-        .and(with_cache_tx(tx.clone()))
-        .and_then(
-            |key: String, value: warp::hyper::body::Bytes, tx: ServiceQueue| async move {
-                write(tx.clone(), key, value).await
-            },
-        );
+        // without a declared length the body is checked once read
+        let res = api_set_request("key", &"x".repeat(2048)).reply(&api).await;
+        assert_eq!(res.status(), 413);
+    }
 
-    let get = warp::get()
-        .and(warp::path("get"))
-        .and(warp::path::param::<String>())
-        .and(with_cache_tx(tx))
-        .and_then(|key: String, tx: ServiceQueue| async move { read(tx, key).await });
+    #[tokio::test]
+    async fn write_to_full_cache_is_rejected_before_body_is_uploaded() {
+        use tokio::io::AsyncReadExt;
+        use tokio::io::AsyncWriteExt;
 
-    hello.or(get).or(set)
-}
+        let (_, api) = init();
+        assert_eq!(api_set_request("a", "1").reply(&api).await.status(), 200);
 
-#[cfg(test)]
-mod api_tests {
-    use crate::api::make_api;
-    use crate::config::TEST_CONFIG_SINGLE_ITEM;
-    use crate::service::ServiceMessage;
-    use crate::service::TtlCacheService;
-    use crate::time::time_fixtures::TestTime;
-    use crate::time::Time;
+        let (_, response) = expect_continue(api.clone(), "b", 1024).await;
+        assert!(response.starts_with("HTTP/1.1 507"), "{}", response);
 
-    use std::sync::Arc;
-    use std::time::Duration;
-    use std::time::Instant;
-    use tokio::sync::Mutex;
+        // overwriting the only key needs no room, client is asked for the body
+        let (mut stream, response) = expect_continue(api.clone(), "a", 1).await;
+        assert!(
+            response.starts_with("HTTP/1.1 100 Continue"),
+            "{}",
+            response
+        );
+        stream.write_all(b"2").await.unwrap();
+        let mut status = [0u8; 12];
+        stream.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 200");
+        assert_eq!(api_get_request("a").reply(&api).await.body(), "2");
+    }
 
-    use tokio::sync::mpsc;
-    use warp::Filter;
+    #[tokio::test]
+    async fn requests_are_counted_in_flight_per_route() {
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let http_metrics = Arc::new(HttpMetrics::new());
+        let api = make_api(
+            tx,
+            &config,
+            flags.clone(),
+            http_metrics.clone(),
+            Upstreams::new(&config).unwrap(),
+        );
 
-    impl Time for Arc<Mutex<TestTime>> {
-        fn get_time(&self) -> std::time::Instant {
-            loop {
-                if let Ok(inner) = self.try_lock() {
-                    break inner.get_time();
-                }
-            }
+        // service is not running yet, so reads wait for it
+        let reads: Vec<_> = (0..3)
+            .map(|_| {
+                let api = api.clone();
+                tokio::spawn(async move { api_get_request("key").reply(&api).await })
+            })
+            .collect();
+        let versioned = {
+            let api = api.clone();
+            tokio::spawn(async move { warp::test::request().path("/v1/get/key").reply(&api).await })
+        };
+        while http_metrics.in_flight("get") < 4 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(http_metrics.in_flight("set"), 0);
+
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, &time).run().await });
+        for read in reads {
+            assert_eq!(read.await.unwrap().status(), 404);
         }
+        assert_eq!(versioned.await.unwrap().status(), 404);
+        assert_eq!(http_metrics.in_flight("get"), 0);
+
+        let res = warp::test::request().path("/metrics").reply(&api).await;
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(body.contains("in_mem_cached_http_requests_in_flight{route=\"get\"} 0"));
+        assert!(body.contains("in_mem_cached_http_request_duration_seconds_count{route=\"get\"} 4"));
+        // the metrics request itself is still being served
+        assert!(body.contains("in_mem_cached_http_requests_in_flight{route=\"other\"} 1"));
     }
 
-    fn init() -> (
-        Arc<Mutex<TestTime>>,
-        impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone,
-    ) {
-        let (tx, rx) = mpsc::unbounded_channel::<ServiceMessage>();
+    #[tokio::test]
+    async fn unsupported_methods_on_keys_are_not_allowed() {
+        let (_, api) = init();
 
-        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        let res = warp::test::request()
+            .method("POST")
+            .path("/get/key")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 405);
+        assert_eq!(res.headers()["allow"], "GET, HEAD");
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "method POST not allowed");
 
-        let time_for_svc = time.clone();
-        tokio::spawn(async move {
-            TtlCacheService::new(TEST_CONFIG_SINGLE_ITEM, rx, &time_for_svc)
-                .run()
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/set/key")
+            .body("value")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 405);
+        assert_eq!(res.headers()["allow"], "POST");
+
+        let res = api_set_request("key", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = warp::test::request()
+            .method("HEAD")
+            .path("/get/key")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn keys_in_path_longer_than_max_key_len_are_rejected() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            max_key_len: Some(8),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        assert_eq!(
+            api_set_request("key", "value").reply(&api).await.status(),
+            200
+        );
+        assert_eq!(
+            api_set_request("12345678", "v").reply(&api).await.status(),
+            200
+        );
+        assert_eq!(api_get_request("key").reply(&api).await.body(), "value");
+
+        let res = api_set_request("123456789", "value").reply(&api).await;
+        assert_eq!(res.status(), 414);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "key is longer than 8 bytes");
+        assert_eq!(
+            api_get_request(&"k".repeat(10_000))
+                .reply(&api)
                 .await
+                .status(),
+            414
+        );
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/del/123456789")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 414);
+        // rejected before reaching the cache
+        assert_eq!(get_stats(&api).await["process"]["misses"], 0);
+    }
+
+    #[tokio::test]
+    async fn keys_outside_allowed_prefixes_are_rejected() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            allowed_key_prefixes: vec![String::from("tenant1:"), String::from("shared:")],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = api_set_request("tenant1:key", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = api_get_request("tenant1:key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+
+        let res = api_set_request("tenant2:key", "value").reply(&api).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            body["error"],
+            "key tenant2:key is outside of allowed prefixes"
+        );
+        assert_eq!(
+            api_get_request("tenant2:key").reply(&api).await.status(),
+            400
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/mget")
+            .json(&vec!["tenant1:key", "other"])
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/txn")
+            .body(r#"[{"op": "set", "key": "shared:a", "value": "1"}, {"op": "del", "key": "b"}]"#)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400);
+        assert_eq!(get_stats(&api).await["keys_total"], 1);
+    }
+
+    #[tokio::test]
+    async fn empty_prefix_allowlist_allows_any_key() {
+        let (_, api) = init_with_config(Config {
+            capacity: None,
+            allowed_key_prefixes: Vec::new(),
+            ..TEST_CONFIG_SINGLE_ITEM
         });
 
-        (time, make_api(tx))
+        for key in ["tenant1:key", "anything"].iter() {
+            let res = api_set_request(key, "value").reply(&api).await;
+            assert_eq!(res.status(), 200);
+        }
     }
 
-    fn api_set_request(key: &str, value: &str) -> warp::test::RequestBuilder {
+    #[tokio::test]
+    async fn selftest_benchmarks_without_touching_live_keys() {
+        let (_, api) = init_with_config(admin_config());
+        let res = api_set_request("00000001", "live").reply(&api).await;
+        assert_eq!(res.status(), 200);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/selftest")
+            .body("{}")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let res = api_admin_request("POST", "/admin/selftest")
+            .body(r#"{"sets": 1000001}"#)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 400);
+
+        let res = api_admin_request("POST", "/admin/selftest")
+            .body(r#"{"sets": 50, "gets": 100, "key_size": 8, "value_size": 16}"#)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let report: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(report["set"]["ops"], 50);
+        assert_eq!(report["get"]["ops"], 100);
+        for op in ["set", "get"].iter() {
+            assert!(report[op]["ops_per_sec"].as_f64().unwrap() > 0.0);
+            assert!(
+                report[op]["p50_us"].as_f64().unwrap() <= report[op]["p99_us"].as_f64().unwrap()
+            );
+        }
+
+        // benchmark keys overlap with the live one, which is still there as it was
+        assert_eq!(get_stats(&api).await["keys_total"], 1);
+        let res = api_get_request("00000001").reply(&api).await;
+        assert_eq!(res.body(), "live");
+
+        let res = api_admin_request("POST", "/admin/selftest")
+            .body("{}")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 429);
+        assert!(res.headers().contains_key("retry-after"));
+    }
+
+    #[tokio::test]
+    async fn mttl_returns_remaining_ttls_in_order() {
+        let (time, api) = init_with_config(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/txn")
+            .body(
+                r#"[{"op": "set", "key": "fresh", "value": "1", "ttl_secs": 100},
+                    {"op": "set", "key": "expiring", "value": "1", "ttl_secs": 10}]"#,
+            )
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        time.lock().await.add_secs(Duration::from_secs(20));
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/mttl")
+            .json(&vec!["fresh", "missing", "expiring", "fresh"])
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let ttls: Vec<Option<u64>> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(ttls, vec![Some(80_000), None, None, Some(80_000)]);
+
+        // not counted as reads
+        let res = warp::test::request().path("/meta/fresh").reply(&api).await;
+        let meta: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(meta["hits"], 0);
+    }
+
+    fn api_ns_set_request(namespace: &str, key: &str, value: &str) -> warp::test::RequestBuilder {
         warp::test::request()
             .method("POST")
-            .path(format!("/set/{}", key).as_str())
+            .path(format!("/ns/{}/set/{}", namespace, key).as_str())
             .body(value)
     }
 
-    fn api_get_request(key: &str) -> warp::test::RequestBuilder {
-        warp::test::request()
-            .method("GET")
-            .path(format!("/get/{}", key).as_str())
+    #[tokio::test]
+    async fn namespaces_are_capped_and_idle_ones_reclaimed() {
+        let (time, api) = init_with_config(Config {
+            capacity: None,
+            max_namespaces: 2,
+            namespace_idle_ttl: Duration::from_secs(5),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        for namespace in ["a", "b"].iter() {
+            let res = api_ns_set_request(namespace, "key", namespace)
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200);
+        }
+        let res = api_ns_set_request("c", "key", "c").reply(&api).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error"], "too many namespaces, at most 2 allowed");
+
+        // namespaces are separate keyspaces, nothing lands in the main one
+        let res = warp::test::request()
+            .path("/ns/a/get/key")
+            .reply(&api)
+            .await;
+        assert_eq!(res.body(), "a");
+        let res = warp::test::request()
+            .path("/ns/c/get/key")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(api_get_request("key").reply(&api).await.status(), 404);
+
+        // `a` is emptied by expiry, `b` is kept busy
+        time.lock().await.add_secs(Duration::from_secs(11));
+        let res = api_ns_set_request("b", "key", "b").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["namespaces"][0]["name"], "a");
+        assert_eq!(stats["namespaces"][0]["keys_total"], 0);
+        assert_eq!(stats["namespaces"][1]["keys_total"], 1);
+
+        time.lock().await.add_secs(Duration::from_secs(17));
+        // sweep runs before the next message is processed
+        get_stats(&api).await;
+        let stats = get_stats(&api).await;
+        let namespaces = stats["namespaces"].as_array().unwrap();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0]["name"], "b");
+
+        let res = api_ns_set_request("c", "key", "c").reply(&api).await;
+        assert_eq!(res.status(), 200);
     }
 
     #[tokio::test]
-    async fn non_existent_keys_return_not_found() {
-        let (_, api) = init();
+    async fn detached_namespace_is_not_reclaimed_when_idle() {
+        let (time, api) = init_with_config(Config {
+            capacity: None,
+            namespace_idle_ttl: Duration::from_secs(5),
+            ..admin_config()
+        });
+        let res = api_ns_set_request("team", "key", "value").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = api_admin_request("POST", "/ns/team/detach")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
 
-        let get_res = api_get_request("abcda").reply(&api).await;
+        time.lock().await.add_secs(Duration::from_secs(11));
+        get_stats(&api).await;
+        time.lock().await.add_secs(Duration::from_secs(17));
+        get_stats(&api).await;
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["namespaces"][0]["name"], "team");
+        assert_eq!(stats["namespaces"][0]["keys_total"], 0);
+        assert_eq!(stats["namespaces"][0]["state"], "read-only");
 
-        assert_eq!(get_res.status(), 404);
+        // not recreated as a writable namespace
+        let res = api_ns_set_request("team", "key", "value").reply(&api).await;
+        assert_eq!(res.status(), 409);
     }
 
     #[tokio::test]
-    async fn able_to_set_value() {
-        let (_, api) = init();
+    async fn namespace_is_detached_dumped_and_dropped() {
+        let (_, api) = init_with_config(admin_config());
+        for (namespace, key) in [("team", "a"), ("team", "b"), ("other", "a")].iter() {
+            let res = api_ns_set_request(namespace, key, key).reply(&api).await;
+            assert_eq!(res.status(), 200);
+        }
 
-        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        let res = warp::test::request()
+            .method("POST")
+            .path("/ns/team/detach")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 401);
+        let res = api_admin_request("POST", "/ns/team/detach")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = api_admin_request("POST", "/ns/missing/detach")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404);
 
-        assert_eq!(set_res.status(), 200);
+        // reads are still served, writes are not
+        let res = api_ns_set_request("team", "c", "c").reply(&api).await;
+        assert_eq!(res.status(), 409);
+        let res = warp::test::request()
+            .path("/ns/team/get/a")
+            .reply(&api)
+            .await;
+        assert_eq!(res.body(), "a");
+        let res = api_ns_set_request("other", "b", "b").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let stats = get_stats(&api).await;
+        assert_eq!(stats["namespaces"][1]["name"], "team");
+        assert_eq!(stats["namespaces"][1]["state"], "read-only");
+
+        let res = api_admin_request("GET", "/ns/team/dump").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "application/x-ndjson");
+        let lines: Vec<serde_json::Value> = res
+            .body()
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        // header, entries and trailer, the same as a snapshot file
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3]["entries"], 2);
+        let mut dumped: Vec<(String, Vec<u8>)> = lines[1..3]
+            .iter()
+            .map(|entry| {
+                let entry: SnapshotEntry = serde_json::from_value(entry.clone()).unwrap();
+                (entry.key, entry.value)
+            })
+            .collect();
+        dumped.sort();
+        assert_eq!(
+            dumped,
+            vec![
+                (String::from("a"), b"a".to_vec()),
+                (String::from("b"), b"b".to_vec())
+            ]
+        );
+
+        let res = api_admin_request("DELETE", "/ns/team").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["removed"], 2);
+        let res = warp::test::request()
+            .path("/ns/team/get/a")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 404);
+        let res = api_admin_request("GET", "/ns/team/dump").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        let stats = get_stats(&api).await;
+        let namespaces = stats["namespaces"].as_array().unwrap();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0]["name"], "other");
+
+        // name can be taken again, by a new namespace
+        let res = api_ns_set_request("team", "a", "new").reply(&api).await;
+        assert_eq!(res.status(), 200);
     }
 
     #[tokio::test]
-    async fn able_to_get_back_set_values() {
+    async fn routes_are_served_with_and_without_version_prefix() {
         let (_, api) = init();
 
-        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/set/key")
+            .body("value")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
 
-        assert_eq!(set_res.status(), 200);
+        let res = warp::test::request().path("/v1/get/key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+        assert!(res.headers().get("deprecation").is_none());
 
-        let get_res = api_get_request("abcda").reply(&api).await;
+        let res = api_get_request("key").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "value");
+        assert_eq!(res.headers()["deprecation"], "true");
 
-        assert_eq!(get_res.status(), 200);
-        assert_eq!(get_res.body(), "bcda");
+        let res = warp::test::request().path("/v2/get/key").reply(&api).await;
+        assert_eq!(res.status(), 404);
+        assert!(res.body().is_empty());
+        assert!(res.headers().get("x-cache-result").is_none());
+    }
+
+    fn allowlist_config(trust_proxy: bool) -> Config {
+        Config {
+            write_allowlist: Some(vec![
+                "10.0.1.0/24".parse().unwrap(),
+                "192.168.0.7/32".parse().unwrap(),
+            ]),
+            trust_proxy,
+            ..admin_config()
+        }
+    }
+
+    fn from(addr: &str) -> SocketAddr {
+        SocketAddr::new(addr.parse().unwrap(), 40000)
     }
 
     #[tokio::test]
-    async fn set_values_have_capacity() {
-        let (_, api) = init();
+    async fn writes_are_limited_to_allowlisted_clients() {
+        let (_, api) = init_with_config(allowlist_config(false));
 
-        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
-        assert_eq!(set_res.status(), 200);
-        let set_res = api_set_request("abcda2", "bcda").reply(&api).await;
-        assert_eq!(set_res.status(), 400);
+        let res = api_set_request("key", "value")
+            .remote_addr(from("10.0.1.20"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        for addr in ["10.0.2.20", "192.168.0.8"].iter() {
+            let res = api_set_request("key", "other")
+                .remote_addr(from(addr))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 403);
+            assert_eq!(res.body(), r#"{"error":"client is not allowed to write"}"#);
+
+            let res = api_delete_request("key", None)
+                .remote_addr(from(addr))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 403);
+
+            let res = api_admin_request("POST", "/admin/evict")
+                .remote_addr(from(addr))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 403);
+
+            // reads stay open to everyone
+            let res = api_get_request("key")
+                .remote_addr(from(addr))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.body(), "value");
+        }
+
+        // ipv4 mapped address of an allowlisted peer
+        let res = api_delete_request("key", None)
+            .remote_addr(from("::ffff:192.168.0.7"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        // without the list anyone may write
+        let (_, api) = init_with_config(admin_config());
+        let res = api_set_request("key", "value")
+            .remote_addr(from("10.0.2.20"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
     }
 
     #[tokio::test]
-    async fn set_values_expire() {
-        let (time, api) = init();
+    async fn forwarded_client_is_checked_only_behind_trusted_proxy() {
+        let forwarded = |proxy: &str, chain: &str| {
+            api_set_request("key", "value")
+                .remote_addr(from(proxy))
+                .header("x-forwarded-for", chain)
+        };
 
-        let set_res = api_set_request("abcda", "bcda").reply(&api).await;
-        assert_eq!(set_res.status(), 200);
+        let (_, api) = init_with_config(allowlist_config(true));
+        // last entry is the client the allowlisted proxy saw, earlier ones can be forged
+        let res = forwarded("10.0.1.1", "10.0.2.20, 192.168.0.7")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let res = forwarded("10.0.1.1", "192.168.0.7, 10.0.2.20")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 403);
+        let res = forwarded("10.0.1.1", "unknown").reply(&api).await;
+        assert_eq!(res.status(), 403);
+        // header of a peer outside of the list is not honoured
+        let res = forwarded("10.0.2.1", "192.168.0.7").reply(&api).await;
+        assert_eq!(res.status(), 403);
 
-        // not pretty, but we have to make sure value is dropped so
-        // the lock is released and we can get time again
-        tokio::spawn(async move {
-            let lock = time.lock().await;
-            lock.add_secs(Duration::from_secs(11));
+        // without `trust_proxy` only the peer counts
+        let (_, api) = init_with_config(allowlist_config(false));
+        let res = forwarded("10.0.1.1", "10.0.2.20").reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = forwarded("10.0.2.1", "10.0.1.20").reply(&api).await;
+        assert_eq!(res.status(), 403);
+    }
+
+    // every route mounted by `make_api`, with a value for each path parameter, a route added
+    // there goes here along with its entry in `/openapi.json`
+    const MOUNTED_ROUTES: [(&str, &str); 50] = [
+        ("GET", "/"),
+        ("GET", "/health-check"),
+        ("GET", "/ping"),
+        ("GET", "/info"),
+        ("GET", "/openapi.json"),
+        ("GET", "/metrics"),
+        ("GET", "/stats"),
+        ("GET", "/stats/maintenance"),
+        ("GET", "/stats/ttl-histogram"),
+        ("GET", "/cluster/status"),
+        ("GET", "/events"),
+        ("GET", "/count"),
+        ("GET", "/get/k"),
+        ("HEAD", "/get/k"),
+        ("GET", "/peek/k"),
+        ("GET", "/item/k"),
+        ("GET", "/meta/k"),
+        ("GET", "/strlen/k"),
+        ("GET", "/explain/k"),
+        ("GET", "/access/k"),
+        ("POST", "/access/k/reset"),
+        ("POST", "/set/k"),
+        ("DELETE", "/del/k"),
+        ("POST", "/pin/k"),
+        ("POST", "/unpin/k"),
+        ("POST", "/rename/a/b"),
+        ("POST", "/swap"),
+        ("POST", "/ringpush/k"),
+        ("POST", "/mget"),
+        ("POST", "/mttl"),
+        ("POST", "/txn"),
+        ("POST", "/tx"),
+        ("POST", "/bulk-load"),
+        ("POST", "/flush"),
+        ("DELETE", "/tags/t"),
+        ("GET", "/ns/n/get/k"),
+        ("POST", "/ns/n/set/k"),
+        ("POST", "/ns/n/detach"),
+        ("GET", "/ns/n/dump"),
+        ("DELETE", "/ns/n"),
+        ("GET", "/slowlog"),
+        ("DELETE", "/slowlog"),
+        ("PUT", "/admin/read-only"),
+        ("POST", "/admin/compact"),
+        ("POST", "/admin/evict"),
+        ("POST", "/admin/eviction/pause"),
+        ("POST", "/admin/eviction/resume"),
+        ("POST", "/admin/reserve"),
+        ("POST", "/admin/selftest"),
+        ("POST", "/admin/snapshot"),
+    ];
+
+    #[tokio::test]
+    async fn every_mounted_route_is_documented() {
+        let (_, api) = init_with_config(Config {
+            enable_dashboard: true,
+            ..admin_config()
         });
+        let res = warp::test::request()
+            .method("GET")
+            .path("/openapi.json")
+            .reply(&api)
+            .await;
+        let document: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        let paths = document["paths"].as_object().unwrap();
 
-        let get_res = api_get_request("abcda").reply(&api).await;
-        assert_eq!(get_res.status(), 404);
+        let documented = |method: &str, path: &str| {
+            paths.iter().any(|(template, item)| {
+                let segments: Vec<&str> = path.split('/').collect();
+                let expected: Vec<&str> = template.split('/').collect();
+                segments.len() == expected.len()
+                    && segments
+                        .iter()
+                        .zip(&expected)
+                        .all(|(s, e)| s == e || e.starts_with('{'))
+                    && item.get(method.to_lowercase()).is_some()
+            })
+        };
+        for (method, path) in MOUNTED_ROUTES.iter() {
+            assert!(
+                documented(method, path),
+                "{} {} is not documented in /openapi.json",
+                method,
+                path
+            );
+        }
+        // and nothing else is documented
+        let operations: usize = paths
+            .values()
+            .map(|item| item.as_object().unwrap().len())
+            .sum();
+        assert_eq!(operations, MOUNTED_ROUTES.len());
+    }
+
+    #[tokio::test]
+    async fn openapi_document_lists_every_mounted_route() {
+        let (_, api) = init_with_config(Config {
+            enable_dashboard: true,
+            ..admin_config()
+        });
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/v1/openapi.json")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), 200);
+        let document: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+        assert_eq!(
+            document["components"]["securitySchemes"]["adminToken"]["scheme"],
+            "bearer"
+        );
+        let set = &document["paths"]["/set/{key}"]["post"];
+        let params: Vec<&str> = set["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(params, vec!["key", "ttl", "tags"]);
+        assert_eq!(
+            document["paths"]["/admin/snapshot"]["post"]["security"][0]["adminToken"],
+            serde_json::json!([])
+        );
+
+        // every documented operation is served, an unknown route is an empty 404, routes
+        // missing from the document are caught by `every_mounted_route_is_documented`
+        let paths = document["paths"].as_object().unwrap();
+        for (path, item) in paths {
+            for (method, operation) in item.as_object().unwrap() {
+                let concrete: Vec<String> = path
+                    .split('/')
+                    .map(|s| {
+                        if s.starts_with('{') {
+                            "k".to_string()
+                        } else {
+                            s.to_string()
+                        }
+                    })
+                    .collect();
+                let mut query: Vec<String> = operation["parameters"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .filter(|p| p["in"] == "query" && p["required"] == true)
+                    .map(|p| format!("{}=1", p["name"].as_str().unwrap()))
+                    .collect();
+                if path == "/events" {
+                    // rejected before the stream starts, it would never end otherwise
+                    query.push(String::from("kinds=nope"));
+                }
+                let concrete = format!("{}?{}", concrete.join("/"), query.join("&"));
+                // an empty one of the schema, json bodies failing to parse are not told
+                // apart from unknown routes
+                let body = match operation["requestBody"]["content"]["application/json"]["schema"]
+                    ["$ref"]
+                    .as_str()
+                {
+                    Some(schema) => {
+                        let name = schema.rsplit('/').next().unwrap();
+                        match document["components"]["schemas"][name]["type"].as_str() {
+                            Some("array") => "[]",
+                            _ => "{}",
+                        }
+                    }
+                    None => "1",
+                };
+                let res = api_admin_request(&method.to_uppercase(), &concrete)
+                    .body(body)
+                    .reply(&api)
+                    .await;
+                assert!(
+                    res.status() != 404
+                        || !res.body().is_empty()
+                        || res.headers().contains_key("x-cache-result"),
+                    "{} {} is not mounted",
+                    method,
+                    path
+                );
+            }
+        }
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/openapi.json")
+            .reply(&init().1)
+            .await;
+        let document: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        // neither the dashboard nor an auth scheme without them configured
+        assert!(document["paths"].get("/").is_none());
+        assert!(document["components"].get("securitySchemes").is_none());
+    }
+
+    #[tokio::test]
+    async fn writes_over_the_inflight_limit_are_turned_away_until_queued_ones_are_answered() {
+        let config = Config {
+            capacity: None,
+            max_inflight_write_bytes: Some(2500),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let api = make_api(
+            tx.clone(),
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+        let large = "a".repeat(1000);
+        let spawn_set = |key: &str, value: &str| {
+            let api = api.clone();
+            let request = api_set_request(key, value);
+            tokio::spawn(async move { request.reply(&api).await })
+        };
+        let wait_inflight = |bytes: usize| {
+            let tx = tx.clone();
+            async move {
+                let settled = async {
+                    while tx.inflight_write_bytes() != bytes {
+                        tokio::task::yield_now().await;
+                    }
+                };
+                tokio::time::timeout(Duration::from_secs(5), settled)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        // service is not running yet, so nothing queued is answered
+        let mut queued = vec![spawn_set("large1", &large), spawn_set("large2", &large)];
+        wait_inflight(2000).await;
+
+        let res = api_set_request("large3", &large).reply(&api).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers()["retry-after"], "1");
+
+        // still fits in what is left
+        queued.push(spawn_set("small", "value"));
+        wait_inflight(2005).await;
+
+        // turned away on the declared length, before the body is read
+        let declared = vec![
+            api_set_request("large3", "a"),
+            api_ns_set_request("ns", "large3", "a"),
+            warp::test::request()
+                .method("POST")
+                .path("/ringpush/log?max=10")
+                .body("a"),
+            warp::test::request()
+                .method("POST")
+                .path("/txn")
+                .body(r#"[{"op": "set", "key": "large3", "value": "a"}]"#),
+        ];
+        for request in declared {
+            let res = request.header("content-length", "1000").reply(&api).await;
+            assert_eq!(res.status(), 503);
+            assert_eq!(res.headers()["retry-after"], "1");
+        }
+        assert_eq!(tx.inflight_write_bytes(), 2005);
+
+        let time = Arc::new(Mutex::new(TestTime::new(Instant::now())));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, &time).run().await });
+        for write in queued {
+            assert_eq!(write.await.unwrap().status(), 200);
+        }
+        wait_inflight(0).await;
+
+        let res = api_set_request("large3", &large).reply(&api).await;
+        assert_eq!(res.status(), 200);
+        let res = warp::test::request().path("/metrics").reply(&api).await;
+        let metrics = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(metrics.contains("in_mem_cached_inflight_write_bytes 0\n"));
     }
 }
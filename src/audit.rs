@@ -0,0 +1,263 @@
+use std::fmt::Display;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+// records waiting for the writer, once it falls this far behind new ones are dropped
+const AUDIT_BUFFER: usize = 4096;
+
+// one line of the audit log, written once the outcome of the operation is known
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    // unix time in ms the operation was served at
+    pub ts_ms: u64,
+    pub operation: &'static str,
+    // tag for `invalidate-tag`, none for `flush`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_len: Option<usize>,
+    // only with `audit_include_values`, lossy for values that are not utf-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // `ok` or the error the operation failed with
+    pub outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    // namespace the operation was made in, none for the default one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+// appends a json line per mutating operation to `audit_path`, the service only hands records
+// over to a writer thread so it never waits for the disk, records are dropped while the
+// writer is behind
+pub struct AuditLog {
+    records: mpsc::SyncSender<AuditRecord>,
+    include_values: bool,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    // writer thread runs until the log is dropped, it is not started without `audit_path`
+    pub fn start(config: &Config) -> Option<AuditLog> {
+        let path = config.audit_path.clone()?;
+        let (records, received) = mpsc::sync_channel(AUDIT_BUFFER);
+        let writer = Writer {
+            path,
+            max_bytes: config.audit_max_bytes,
+            keep_files: config.audit_keep_files,
+            file: None,
+            written: 0,
+        };
+        let spawned = thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || writer.run(received));
+        if let Err(e) = spawned {
+            tracing::error!("failed to start audit writer: {}, audit log disabled", e);
+            return None;
+        }
+
+        Some(AuditLog {
+            records,
+            include_values: config.audit_include_values,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // record of an operation, completed with `finish` once its outcome is known
+    pub fn record(
+        &self,
+        operation: &'static str,
+        key: Option<&str>,
+        value: Option<&[u8]>,
+        client_ip: Option<IpAddr>,
+    ) -> AuditRecord {
+        AuditRecord {
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            operation,
+            key: key.map(str::to_string),
+            value_len: value.map(<[u8]>::len),
+            value: value
+                .filter(|_| self.include_values)
+                .map(|v| String::from_utf8_lossy(v).into_owned()),
+            outcome: String::new(),
+            client_ip,
+            tenant: None,
+        }
+    }
+
+    pub fn finish<V, E: Display>(&self, record: AuditRecord, result: &Result<V, E>) {
+        self.log(AuditRecord {
+            outcome: match result {
+                Ok(_) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            },
+            ..record
+        })
+    }
+
+    pub fn log(&self, record: AuditRecord) {
+        match self.records.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("audit writer is gone, record dropped");
+            }
+        }
+    }
+
+    // records lost because the writer was behind or failed
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    keep_files: usize,
+    file: Option<File>,
+    // size of the current file
+    written: u64,
+}
+
+impl Writer {
+    fn run(mut self, records: mpsc::Receiver<AuditRecord>) {
+        for record in records {
+            let mut line = match serde_json::to_vec(&record) {
+                Ok(line) => line,
+                Err(e) => {
+                    tracing::error!("failed to serialize audit record: {}", e);
+                    continue;
+                }
+            };
+            line.push(b'\n');
+            if let Err(e) = self.write(&line) {
+                tracing::error!("failed to write audit log {}: {}", self.path.display(), e);
+                // opened again for the next record
+                self.file = None;
+            }
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.file = None;
+            rotate(&self.path, self.keep_files)?;
+            self.written = 0;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                self.written = file.metadata()?.len();
+                self.file.get_or_insert(file)
+            }
+        };
+        // one write per line, so that the file never ends in the middle of a record
+        file.write_all(line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+// `audit.log` becomes `audit.log.1`, older files are shifted by one, at most `keep_files` of
+// them are kept
+fn rotate(path: &Path, keep_files: usize) -> io::Result<()> {
+    if keep_files == 0 {
+        return fs::remove_file(path);
+    }
+    for n in (1..keep_files).rev() {
+        let older = rotated(path, n);
+        if older.exists() {
+            fs::rename(older, rotated(path, n + 1))?;
+        }
+    }
+    fs::rename(path, rotated(path, 1))
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use crate::audit::rotated;
+    use crate::audit::AuditRecord;
+    use crate::audit::Writer;
+
+    use std::fs;
+
+    #[test]
+    fn log_is_rotated_by_size_keeping_given_number_of_files() {
+        let dir = std::env::temp_dir().join(format!("in-mem-cached-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let mut writer = Writer {
+            path: path.clone(),
+            max_bytes: 200,
+            keep_files: 2,
+            file: None,
+            written: 0,
+        };
+
+        for n in 0..20 {
+            let record = AuditRecord {
+                ts_ms: 0,
+                operation: "write",
+                key: Some(format!("key{}", n)),
+                value_len: Some(1),
+                value: None,
+                outcome: "ok".to_string(),
+                client_ip: None,
+                tenant: None,
+            };
+            let mut line = serde_json::to_vec(&record).unwrap();
+            line.push(b'\n');
+            writer.write(&line).unwrap();
+        }
+
+        for file in [path.clone(), rotated(&path, 1), rotated(&path, 2)].iter() {
+            let content = fs::read_to_string(file).unwrap();
+            assert!(content.len() <= 200);
+            for line in content.lines() {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(record["operation"], "write");
+            }
+        }
+        assert!(!rotated(&path, 3).exists());
+        // newest record is in the current file
+        assert!(fs::read_to_string(&path).unwrap().contains("\"key19\""));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,31 @@
+use serde::Serialize;
+
+// memory held next to the entries themselves, e.g. indexes and logs, reported under
+// `auxiliary` in `/stats`
+pub trait AuxiliaryMemory {
+    // records held, e.g. key references of the tag index
+    fn entries(&self) -> usize;
+    // approximate, what the records point to along with a fixed overhead per record
+    fn bytes(&self) -> usize;
+    // records held at most, ones over it are dropped oldest or least valuable first
+    fn cap(&self) -> Option<usize>;
+    // records dropped to stay within the cap
+    fn evicted(&self) -> u64;
+
+    fn usage(&self) -> AuxiliaryUsage {
+        AuxiliaryUsage {
+            entries: self.entries(),
+            bytes: self.bytes(),
+            cap: self.cap(),
+            evicted: self.evicted(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AuxiliaryUsage {
+    pub entries: usize,
+    pub bytes: usize,
+    pub cap: Option<usize>,
+    pub evicted: u64,
+}
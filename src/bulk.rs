@@ -0,0 +1,244 @@
+use crate::duration::parse_duration;
+use crate::duration::ACCEPTED_FORMATS;
+use crate::duration::MAX_DURATION;
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+// errors of at most this many lines are reported back
+pub const MAX_REPORTED_ERRORS: usize = 10;
+
+// entry of a bulk load, cache ttl applies when it has none
+#[derive(Debug, PartialEq)]
+pub struct BulkWrite {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LineError {
+    // 1-based
+    pub line: usize,
+    pub error: String,
+}
+
+// outcome of a bulk load, lines that could not be parsed or written are skipped
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct BulkLoadReport {
+    pub loaded: usize,
+    pub skipped: usize,
+    // first `MAX_REPORTED_ERRORS` skipped lines
+    pub errors: Vec<LineError>,
+}
+
+impl BulkLoadReport {
+    pub fn skip(&mut self, line: usize, error: String) {
+        self.skipped += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(LineError { line, error });
+        }
+    }
+}
+
+// room for the tabs, the ttl and the line ending of a line next to its key and value
+pub const LINE_OVERHEAD: usize = 64;
+
+// splits a body arriving in chunks into lines, only the unfinished last line is kept
+#[derive(Default)]
+pub struct Lines {
+    pending: Vec<u8>,
+    // lines returned so far
+    count: usize,
+    // longer lines are not buffered, they are returned as errors once they end
+    max_len: Option<usize>,
+    // the pending line went over `max_len`, the rest of it is dropped
+    overlong: bool,
+}
+
+impl Lines {
+    pub fn new(max_len: Option<usize>) -> Lines {
+        Lines {
+            max_len,
+            ..Lines::default()
+        }
+    }
+
+    // complete lines of the chunk along with their numbers, without the line ending
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<(usize, Result<Vec<u8>, String>)> {
+        let mut lines = Vec::new();
+        let mut rest = chunk;
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            self.append(&rest[..end]);
+            lines.push(self.take());
+            rest = &rest[end + 1..];
+        }
+        self.append(rest);
+        lines
+    }
+
+    // last line when the body does not end with a line break
+    pub fn finish(mut self) -> Option<(usize, Result<Vec<u8>, String>)> {
+        if self.pending.is_empty() && !self.overlong {
+            None
+        } else {
+            Some(self.take())
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        if self.overlong {
+            return;
+        }
+        match self.max_len {
+            Some(max) if self.pending.len() + bytes.len() > max => {
+                self.overlong = true;
+                self.pending = Vec::new();
+            }
+            _ => self.pending.extend_from_slice(bytes),
+        }
+    }
+
+    fn take(&mut self) -> (usize, Result<Vec<u8>, String>) {
+        self.count += 1;
+        if std::mem::take(&mut self.overlong) {
+            let max = self.max_len.unwrap_or_default();
+            return (
+                self.count,
+                Err(format!("line is longer than {} bytes", max)),
+            );
+        }
+        let mut line = std::mem::take(&mut self.pending);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        (self.count, Ok(line))
+    }
+}
+
+// `key<TAB>value[<TAB>ttl]`, ttl in seconds or like `5m`, values are stored as given, without unescaping
+pub fn parse_line(line: &[u8], max_value_bytes: Option<usize>) -> Result<BulkWrite, String> {
+    let line = std::str::from_utf8(line).map_err(|e| format!("not valid utf-8: {}", e))?;
+    let mut fields = line.split('\t');
+    let (key, value, ttl) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(key), Some(value), ttl, None) => (key, value, ttl),
+        _ => {
+            return Err(String::from(
+                "expected key, value and optional ttl separated by tabs",
+            ))
+        }
+    };
+    if key.is_empty() {
+        return Err(String::from("key is empty"));
+    }
+    if let Some(max) = max_value_bytes.filter(|max| value.len() > *max) {
+        return Err(format!("value is larger than {} bytes", max));
+    }
+    let ttl = match ttl.map(str::trim).filter(|t| !t.is_empty()) {
+        Some(ttl) => match parse_duration(ttl) {
+            Ok(ttl) if ttl > MAX_DURATION => {
+                return Err(format!(
+                    "ttl must not be longer than {}s",
+                    MAX_DURATION.as_secs()
+                ))
+            }
+            Ok(ttl) if !ttl.is_zero() => Some(ttl),
+            Ok(_) => return Err(String::from("ttl must not be zero")),
+            Err(e) => {
+                return Err(format!(
+                    "invalid ttl {}: {}, expected {}",
+                    ttl, e, ACCEPTED_FORMATS
+                ))
+            }
+        },
+        None => None,
+    };
+
+    Ok(BulkWrite {
+        key: key.to_string(),
+        value: value.as_bytes().to_vec(),
+        ttl,
+    })
+}
+
+#[cfg(test)]
+mod bulk_tests {
+    use crate::bulk::parse_line;
+    use crate::bulk::BulkWrite;
+    use crate::bulk::Lines;
+
+    use std::time::Duration;
+
+    #[test]
+    fn lines_are_split_across_chunks() {
+        let mut lines = Lines::default();
+        let mut all = Vec::new();
+        for chunk in ["a\t1\nb", "\t2\r\n\nc\t3", "\t4"].iter() {
+            all.extend(lines.push(chunk.as_bytes()));
+        }
+        all.extend(lines.finish());
+
+        assert_eq!(
+            all,
+            vec![
+                (1, Ok(b"a\t1".to_vec())),
+                (2, Ok(b"b\t2".to_vec())),
+                (3, Ok(Vec::new())),
+                (4, Ok(b"c\t3\t4".to_vec())),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_not_buffered() {
+        let mut lines = Lines::new(Some(4));
+        let mut all = Vec::new();
+        for chunk in ["a\t1\nbb\t2", "22222", "22\nc\t3\n", "ddddd"].iter() {
+            all.extend(lines.push(chunk.as_bytes()));
+            assert!(lines.pending.len() <= 4);
+        }
+        all.extend(lines.finish());
+
+        let too_long = || Err(String::from("line is longer than 4 bytes"));
+        assert_eq!(
+            all,
+            vec![
+                (1, Ok(b"a\t1".to_vec())),
+                (2, too_long()),
+                (3, Ok(b"c\t3".to_vec())),
+                (4, too_long()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_are_parsed_into_writes() {
+        assert_eq!(
+            parse_line(b"key\tvalue\t30", None),
+            Ok(BulkWrite {
+                key: String::from("key"),
+                value: b"value".to_vec(),
+                ttl: Some(Duration::from_secs(30)),
+            })
+        );
+        assert_eq!(parse_line(b"key\t", None).unwrap().value, b"");
+        assert_eq!(parse_line(b"key\tvalue\t", None).unwrap().ttl, None);
+
+        assert!(parse_line(b"key", None).is_err());
+        assert!(parse_line(b"\tvalue", None).is_err());
+        assert!(parse_line(b"key\tvalue\t30\textra", None).is_err());
+        assert_eq!(
+            parse_line(b"key\tvalue\t1h30m", None).unwrap().ttl,
+            Some(Duration::from_secs(90 * 60))
+        );
+        assert!(parse_line(b"key\tvalue\tsoon", None).is_err());
+        assert!(parse_line(b"key\tvalue\t0", None).is_err());
+        assert_eq!(
+            parse_line(b"key\tvalue\t18446744073709551615", None).unwrap_err(),
+            "ttl must not be longer than 315360000s"
+        );
+        assert!(parse_line(b"key\t\xff", None).is_err());
+        assert!(parse_line(b"key\tvalue", Some(4)).is_err());
+    }
+}
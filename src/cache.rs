@@ -1,229 +1,4035 @@
+use crate::config::CapacityUnit;
 use crate::config::Config;
+use crate::config::EvictionPolicy;
+use crate::config::ExpiryMode;
+use crate::deferred_drop::DeferredDrop;
+use crate::events::EventKind;
+use crate::events::EventSender;
+use crate::snapshot;
+use crate::stats::Counters;
+use crate::tag_index::TagIndex;
 use crate::time::Time;
+use crate::txn::TxnOp;
+use crate::txn::TxnOpStatus;
+use crate::txn::TxnResult;
 
-use std::collections::hash_map::Entry;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
-use std::ops::Add;
+use std::collections::HashSet;
+use std::collections::TryReserveError;
+use std::fmt;
 use std::result::Result;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use rand::prelude::*;
+use serde::Serialize;
 
+#[derive(Debug, PartialEq)]
+pub enum CacheError {
+    OutOfCapacity(Option<usize>),
+    // value does not fit into `max_bytes`
+    OutOfBytes(usize),
+    ReadOnly,
+    TooManySubscribers(usize),
+    TooManyTags(usize),
+    TooManyNamespaces(usize),
+    // operation needs a live entry under the key
+    NotFound(String),
+    // operation would replace a live entry it was told not to
+    Conflict(String),
+    // operation needs an existing namespace
+    UnknownNamespace(String),
+    // namespace only takes reads since it was detached, see `NamespaceState::ReadOnly`
+    NamespaceReadOnly(String),
+    // write was enqueued before a fenced flush, see `ServiceMessage::Flush`
+    Flushed,
+    // versioned write found the key at another version, carries the current one
+    VersionMismatch(String, u64),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::OutOfCapacity(capacity) => write!(f, "out of capacity: {:?}", capacity),
+            CacheError::OutOfBytes(max_bytes) => {
+                write!(f, "out of capacity: {} bytes", max_bytes)
+            }
+            CacheError::ReadOnly => write!(f, "cache is in read-only mode"),
+            CacheError::TooManySubscribers(max) => {
+                write!(f, "too many event subscribers, at most {} allowed", max)
+            }
+            CacheError::TooManyTags(max) => {
+                write!(f, "too many tags, at most {} allowed per key", max)
+            }
+            CacheError::TooManyNamespaces(max) => {
+                write!(f, "too many namespaces, at most {} allowed", max)
+            }
+            CacheError::NotFound(key) => write!(f, "key {} not found", key),
+            CacheError::Conflict(key) => write!(f, "key {} already exists", key),
+            CacheError::UnknownNamespace(name) => write!(f, "namespace {} not found", name),
+            CacheError::NamespaceReadOnly(name) => {
+                write!(f, "namespace {} is detached, read-only", name)
+            }
+            CacheError::Flushed => write!(f, "write was sent before a flush, dropped"),
+            CacheError::VersionMismatch(key, current) => {
+                write!(f, "key {} was changed, it is at version {}", key, current)
+            }
+        }
+    }
+}
+
+// small maps are not worth shrinking
+const SHRINK_MIN_REMOVED: usize = 1024;
+
+// pinned entries are ordered this far past their own expiry, so that every expiry check and
+// the eager expiry queue leave them alone without knowing about pins
+const PINNED_FOR: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+// applied to values on the way in or out of the cache, e.g. encryption at rest
+pub type Transform = Arc<dyn Fn(Vec<u8>) -> Vec<u8> + Send + Sync>;
+
+#[derive(Clone)]
 struct CacheEntry {
-    value: String,
+    // as stored, after write transform, shared between entries with `intern_values`
+    value: Arc<[u8]>,
     created: Instant,
+    // successful reads since the entry was set
+    hits: u64,
+    // overrides ttl from the config for this entry only, always counted from `created`
+    ttl: Option<Duration>,
+    // last write or read, entries without a ttl of their own expire from it with `idle_ttl`
+    last_access: Instant,
+    // only computed with `paranoid_checksums`
+    checksum: Option<u64>,
+    // supplied by the client on write, mirrored in `TtlCache::tags`
+    tags: Vec<String>,
+    // never expires nor gets evicted, see `TtlCache::set_pinned`
+    pinned: bool,
+    // changes with every write of the key, see `TtlCache::check_version`
+    version: u64,
+}
+
+// how entries without a ttl of their own expire, see `TtlCache::default_ttl`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DefaultTtl {
+    // `ttl` after they were set
+    Age(Duration),
+    // `idle_ttl` after they were last read or written
+    Idle(Duration),
+}
+
+// `at` moved by `by`, a point past what `Instant` can hold counts as never, i.e. `PINNED_FOR`
+fn later(at: Instant, by: Duration) -> Instant {
+    at.checked_add(by)
+        .or_else(|| at.checked_add(PINNED_FOR))
+        .unwrap_or(at)
 }
 
 impl CacheEntry {
-    fn is_expired(&self, now: Instant, ttl: Duration) -> bool {
-        self.created.add(ttl) < now
+    fn is_expired(&self, now: Instant, ttl: DefaultTtl) -> bool {
+        self.expires_at(ttl) < now
+    }
+
+    fn expires_at(&self, ttl: DefaultTtl) -> Instant {
+        let unpinned = self.unpinned_expires_at(ttl);
+        if self.pinned {
+            later(unpinned, PINNED_FOR)
+        } else {
+            unpinned
+        }
+    }
+
+    // when the entry expires once unpinned
+    fn unpinned_expires_at(&self, ttl: DefaultTtl) -> Instant {
+        match (self.ttl, ttl) {
+            (Some(own), _) => later(self.created, own),
+            (None, DefaultTtl::Age(ttl)) => later(self.created, ttl),
+            (None, DefaultTtl::Idle(idle)) => later(self.last_access, idle),
+        }
+    }
+
+    // pinned entries count down to zero and stay there
+    fn ttl_remaining(&self, now: Instant, ttl: DefaultTtl) -> Option<Duration> {
+        let remaining = self.unpinned_expires_at(ttl).checked_duration_since(now);
+        if self.pinned {
+            Some(remaining.unwrap_or_default())
+        } else {
+            remaining
+        }
+    }
+
+    fn is_corrupted(&self, key: &str) -> bool {
+        self.checksum
+            .map(|c| c != snapshot::checksum(key, &self.value))
+            .unwrap_or(false)
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ConditionalRead {
+    Value(Vec<u8>),
+    NotModified,
+    Missing,
+}
+
+// conditional read along with when the entry was created in unix seconds, none for misses
+#[derive(Debug, PartialEq)]
+pub struct DatedRead {
+    pub read: ConditionalRead,
+    pub created_at: Option<u64>,
+}
+
+// outcome of `delete`, optionally conditioned on when the entry was created
+#[derive(Debug, PartialEq)]
+pub enum ConditionalDelete {
+    Deleted,
+    Missing,
+    // entry is not older than the given time, carries when it was created in unix ms
+    CreatedAfter(u64),
+}
+
+// outcome of `get_or_lock`, tells which of the callers missing a key should refresh it
+#[derive(Debug, PartialEq)]
+pub enum LockedRead {
+    Value(Vec<u8>),
+    // caller got the lock and is expected to set the key
+    Granted,
+    // someone else holds the lock, caller should retry later
+    Wait,
+    // no lock was taken, e.g. in read-only mode where refreshed value could not be set anyway
+    Missing,
+}
+
+// marker entry held while a key is being refreshed by a client
+pub fn refresh_lock_key(key: &str) -> String {
+    format!("__lock:{}", key)
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct EntryMeta {
+    pub hits: u64,
+    pub ttl_remaining_ms: u128,
+    pub version: u64,
+}
+
+// value of a live entry together with its metadata, see `get_item`
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub value: Vec<u8>,
+    // none for pinned entries, they never expire
+    pub ttl_secs: Option<u64>,
+    // unix seconds the entry was written at
+    pub created_at: u64,
+    pub version: u64,
+}
+
+// what the cache would do with a key, computed without touching any entry
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Explanation {
+    // as stored, after key canonicalization
+    pub key: String,
+    // entry is in the map, expired or not
+    pub exists: bool,
+    pub expired: bool,
+    // unix time in seconds the entry expires at
+    pub expires_at: Option<u64>,
+    pub ttl_remaining_ms: Option<u128>,
+    pub eviction_policy: EvictionPolicy,
+    // only when asked about a write of a value of given size
+    pub set: Option<SetExplanation>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SetExplanation {
+    pub value_size: usize,
+    pub allowed: bool,
+    // expired entries would be dropped to make room
+    pub reclaims_expired: bool,
+    // a live entry would be evicted to make room
+    pub evicts: bool,
+}
+
+// outcome of a single `invalidate_tag` call
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TagInvalidation {
+    pub removed: usize,
+    // tag still has keys left, call again to continue
+    pub more: bool,
+}
+
+// what a complete eviction pass did, see `TtlCache::evict_expired`
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct EvictionPass {
+    // sampling rounds, keys looked at over all of them and expired ones removed
+    pub rounds: usize,
+    pub sampled: usize,
+    pub removed: usize,
+}
+
+// expired entries not removed yet and their summed value size, estimated, see
+// `TtlCache::evict_expired`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct ExpiredBacklog {
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+// counts of live entries bucketed by remaining ttl
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct TtlHistogram {
+    pub less_than_1m: usize,
+    pub from_1m_to_5m: usize,
+    pub more_than_5m: usize,
+}
+
 pub struct TtlCache<'a, T: Time> {
     pub keys_total: usize,
+    // reads that found value not matching its checksum
+    pub checksum_mismatches: u64,
+    // reads that found a live entry and ones that did not
+    pub hits: u64,
+    pub misses: u64,
+    // successful writes
+    pub sets: u64,
+    // entries removed because their ttl ran out
+    pub expirations: u64,
+    // entries evicted by `eviction_policy` to make room for new keys or bytes
+    pub capacity_evictions: u64,
+    // writes that grew the map, rehashing every entry
+    pub map_growths: u64,
+    // estimated from the last sampling round of eviction
+    pub expired_backlog: ExpiredBacklog,
+    // size of stored values, after write transform, interned values are counted once
+    pub bytes_total: usize,
+    // summed cost of entries that `capacity` is checked against, see `capacity_unit`,
+    // unlike `bytes_total` interned values are counted for every entry referencing them
+    pub total_cost: usize,
     cache_config: Config,
     cache: HashMap<String, CacheEntry>,
     time: &'a T,
+    // wall clock at the moment cache was created, `Time` is monotonic
+    // so wall clock time of an entry is derived from this point
+    epoch: (Instant, SystemTime),
+    removed_since_compact: usize,
+    // distinct values referenced by entries, only kept with `intern_values`
+    interned: Option<HashSet<Arc<[u8]>>>,
+    // entries ordered by expiry time, only kept with `ExpiryMode::Eager`
+    expiry_queue: Option<BTreeSet<(Instant, String)>>,
+    tags: TagIndex,
+    events: Option<EventSender>,
+    deferred_drop: Option<DeferredDrop>,
+    // last version given to an entry, kept across flushes so that a version is never reused
+    versions: u64,
+    on_write: Option<Transform>,
+    on_read: Option<Transform>,
 }
 
 impl<'a, T: Time> TtlCache<'a, T> {
+    #[allow(clippy::unwrap_or_default, clippy::needless_borrow)]
     pub fn new(cache_config: Config, t: &'a T) -> TtlCache<'a, T> {
-        let capacity = cache_config.capacity;
+        let initial_capacity = cache_config.initial_capacity;
+        let interned = if cache_config.intern_values {
+            Some(HashSet::new())
+        } else {
+            None
+        };
+        let expiry_queue = match cache_config.expiry_mode {
+            ExpiryMode::Eager => Some(BTreeSet::new()),
+            ExpiryMode::Lazy => None,
+        };
+        let tags = TagIndex::new(cache_config.max_tag_index_refs);
         TtlCache {
             keys_total: 0,
+            checksum_mismatches: 0,
+            hits: 0,
+            misses: 0,
+            sets: 0,
+            expirations: 0,
+            capacity_evictions: 0,
+            map_growths: 0,
+            expired_backlog: ExpiredBacklog::default(),
+            bytes_total: 0,
+            total_cost: 0,
             cache_config,
             // TODO:
             // we use hash-map here with default hasher since we do not have specific requirements for keys
             // but it is possible to tune performance by switching hashing algorithm
             // for short/long keys, see docs https://doc.rust-lang.org/std/collections/struct.HashMap.html
-            cache: capacity
+            cache: initial_capacity
                 .map(HashMap::with_capacity)
                 .unwrap_or_else(HashMap::new),
             time: &t,
+            epoch: (t.get_time(), SystemTime::now()),
+            removed_since_compact: 0,
+            interned,
+            expiry_queue,
+            tags,
+            events: None,
+            versions: 0,
+            deferred_drop: None,
+            on_write: None,
+            on_read: None,
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<(), String> {
-        if self
-            .cache_config
-            .capacity
-            .map(|c| self.keys_total < c)
-            .unwrap_or(true)
-            || self.cache.contains_key(&key)
-        {
-            let created = self.time.get_time();
-            let new_entry = CacheEntry { value, created };
-            match self.cache.entry(key) {
-                Entry::Occupied(mut e) => *e.get_mut() = new_entry,
-                Entry::Vacant(e) => {
-                    self.keys_total += 1;
-                    e.insert(new_entry);
+    // `on_write` is applied before value is stored and `on_read` before it is returned,
+    // so `on_read` has to reverse `on_write`
+    pub fn set_transforms(&mut self, on_write: Transform, on_read: Transform) {
+        self.on_write = Some(on_write);
+        self.on_read = Some(on_read);
+    }
+
+    // publishes key events to the channel, events are only built while there are subscribers
+    pub fn set_event_sender(&mut self, events: EventSender) {
+        self.events = Some(events);
+    }
+
+    // large values of removed entries are freed by the dropping thread from now on
+    pub fn set_deferred_drop(&mut self, deferred_drop: DeferredDrop) {
+        self.deferred_drop = Some(deferred_drop);
+    }
+
+    fn emit(&self, key: &str, kind: EventKind, entry: &CacheEntry) {
+        if let Some(events) = self.events.as_ref().filter(|e| e.is_wanted()) {
+            let ttl_remaining = entry.ttl_remaining(self.time.get_time(), self.default_ttl());
+            events.send(key, kind, entry.value.len(), ttl_remaining);
+        }
+    }
+
+    // identical values share a single allocation when interning is enabled
+    fn store_value(&mut self, value: Vec<u8>) -> Arc<[u8]> {
+        match self.interned.as_mut() {
+            Some(interned) => match interned.get(value.as_slice()) {
+                Some(shared) => shared.clone(),
+                None => {
+                    let shared: Arc<[u8]> = value.into();
+                    self.bytes_total += shared.len();
+                    interned.insert(shared.clone());
+                    shared
                 }
-            };
+            },
+            None => {
+                self.bytes_total += value.len();
+                value.into()
+            }
+        }
+    }
 
-            Ok(())
-        } else {
-            Err(format!("out of capacity: {:?}", self.cache_config.capacity))
+    // has to be called with value of an entry that is no longer in the map
+    fn release_value(&mut self, value: &Arc<[u8]>) {
+        match self.interned.as_mut() {
+            // the only other reference is the one held by the interned set
+            Some(interned) if Arc::strong_count(value) == 2 => {
+                interned.remove(&value[..]);
+                self.bytes_total -= value.len();
+            }
+            Some(_) => {}
+            None => self.bytes_total -= value.len(),
         }
     }
 
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        let now = self.time.get_time();
-        let ttl = self.cache_config.ttl;
+    pub fn counters(&self) -> Counters {
+        Counters {
+            hits: self.hits,
+            misses: self.misses,
+            sets: self.sets,
+            evictions: self.expirations + self.capacity_evictions,
+        }
+    }
 
-        match self.cache.get(key) {
-            Some(e) => {
-                if !e.is_expired(now, ttl) {
-                    Some(e.value.clone())
-                } else {
-                    self.cache.remove(key);
-                    self.keys_total -= 1;
-                    None
+    // distinct values stored, 0 without `intern_values`
+    pub fn interned_values(&self) -> usize {
+        self.interned.as_ref().map(|i| i.len()).unwrap_or(0)
+    }
+
+    // tags of an entry that is no longer in the map or is being replaced
+    fn untag(&mut self, key: &str, tags: &[String]) {
+        for tag in tags {
+            self.tags.remove(tag, key);
+        }
+    }
+
+    // entries go along with the tags having the fewest keys until the index is back within
+    // `max_tag_index_refs`, tags of the entry just written and of pinned ones are kept
+    fn evict_tags(&mut self, written: &str) {
+        let mut keep = match self.cache.get(written) {
+            Some(e) => e.tags.clone(),
+            None => return,
+        };
+        while self.tags.is_over_cap() {
+            let (tag, keys) = match self.tags.eviction_candidate(&keep) {
+                Some(candidate) => candidate,
+                None => return,
+            };
+            let refs = self.tags.refs();
+            for key in keys {
+                if !self.cache.get(&key).map(|e| e.pinned).unwrap_or(false) {
+                    self.remove_entry(&key, EventKind::Evicted);
                 }
             }
-            None => None,
+            self.tags.count_evicted(refs - self.tags.refs());
+            // only pinned entries were left under it
+            if self.tags.contains(&tag) {
+                keep.push(tag);
+            }
         }
     }
 
-    // an attempt to implement simplified version of what Redis has
-    // see for reference https://redis.io/commands/expire
-    pub fn evict_expired(&mut self) {
-        let now = self.time.get_time();
-        let ttl = self.cache_config.ttl;
-        let total_lookup = self.cache_config.eviction_number;
+    pub fn tag_index(&self) -> &TagIndex {
+        &self.tags
+    }
 
-        loop {
-            let mut removed: usize = 0;
-            let random_keys: Vec<String> = self
-                .cache
-                .keys()
-                .choose_multiple(&mut rand::thread_rng(), total_lookup)
-                .into_iter()
-                .cloned()
-                .collect();
+    // distinct tags carried by at least one entry
+    pub fn tag_count(&self) -> usize {
+        self.tags.len()
+    }
+
+    // key references held by the tag index
+    pub fn tag_index_size(&self) -> usize {
+        self.tags.refs()
+    }
+
+    // every removal goes through here so that accounting and events stay consistent,
+    // returns size of the removed value
+    fn remove_entry(&mut self, key: &str, kind: EventKind) -> Option<usize> {
+        let removed = self.cache.remove(key)?;
+        self.forget_entry(key, &removed, kind);
+        self.check_cost();
+        let len = removed.value.len();
+        self.drop_value(removed.value);
+        Some(len)
+    }
+
+    // accounting and events of an entry taken out of the map
+    fn forget_entry(&mut self, key: &str, e: &CacheEntry, kind: EventKind) {
+        self.keys_total -= 1;
+        self.total_cost -= self.cost(e.value.len());
+        self.release_value(&e.value);
+        self.untag(key, &e.tags);
+        let ttl = self.default_ttl();
+        if let Some(queue) = self.expiry_queue.as_mut() {
+            queue.remove(&(e.expires_at(ttl), key.to_string()));
+        }
+        self.removed_since_compact += 1;
+        match kind {
+            EventKind::Expired => self.expirations += 1,
+            EventKind::Evicted => self.capacity_evictions += 1,
+            _ => {}
+        }
+        self.emit(key, kind, e);
+    }
+
+    // value of an entry no longer in the map, large ones are freed off the service loop
+    // with `deferred_drop_min_bytes`
+    fn drop_value(&self, value: Arc<[u8]>) {
+        if let Some(deferred_drop) = &self.deferred_drop {
+            deferred_drop.value(value);
+        }
+    }
+
+    // takes over settings that can change while running, capacity is only lowered as far
+    // as entries already stored fit, anything else in `config` is left as it was
+    pub fn reload(&mut self, config: &Config) {
+        match config.capacity {
+            Some(capacity) if capacity < self.total_cost => tracing::warn!(
+                "[reload] capacity {} is below {} already used, kept {:?}",
+                capacity,
+                self.total_cost,
+                self.cache_config.capacity
+            ),
+            capacity => self.cache_config.capacity = capacity,
+        }
+        self.cache_config.eviction_number = config.eviction_number;
+        self.cache_config.eviction_ratio = config.eviction_ratio;
+        self.cache_config.eviction_policy = config.eviction_policy;
 
-            for k in random_keys {
-                if self
+        if self.cache_config.ttl != config.ttl {
+            self.cache_config.ttl = config.ttl;
+            // entries without own ttl now expire at a different time
+            let ttl = self.default_ttl();
+            if let Some(queue) = self.expiry_queue.as_mut() {
+                *queue = self
                     .cache
-                    .get(&k)
-                    .filter(|v| !v.is_expired(now, ttl))
-                    .is_none()
-                {
-                    self.cache.remove(&k);
-                    removed += 1;
-                }
-            }
-            self.keys_total -= removed;
-            if (removed as f32) / (total_lookup as f32) <= self.cache_config.eviction_ratio {
-                break;
+                    .iter()
+                    .map(|(k, e)| (e.expires_at(ttl), k.clone()))
+                    .collect();
             }
         }
     }
-}
 
-#[cfg(test)]
-mod cache_tests {
-    use std::time::Duration;
-    use std::time::Instant;
+    // expiry of entries without a ttl of their own, `idle_ttl` replaces `ttl` when set
+    fn default_ttl(&self) -> DefaultTtl {
+        match self.cache_config.idle_ttl {
+            Some(idle) => DefaultTtl::Idle(idle),
+            None => DefaultTtl::Age(self.cache_config.ttl),
+        }
+    }
 
-    use crate::cache::TtlCache;
-    use crate::config::TEST_CONFIG_SINGLE_ITEM;
-    use crate::time::time_fixtures::TestTime;
+    // what an entry with value of the given length costs against `capacity`
+    fn cost(&self, value_len: usize) -> usize {
+        match self.cache_config.capacity_unit {
+            CapacityUnit::Entries => 1,
+            CapacityUnit::Bytes => value_len,
+        }
+    }
 
-    pub fn init_cache<'a>(time: &'a TestTime) -> TtlCache<'a, TestTime> {
-        TtlCache::new(TEST_CONFIG_SINGLE_ITEM, time)
+    // O(n) in the number of keys, so only done in tests
+    #[cfg(test)]
+    fn check_cost(&self) {
+        let recomputed: usize = self.cache.values().map(|e| self.cost(e.value.len())).sum();
+        debug_assert_eq!(self.total_cost, recomputed, "total_cost drifted");
     }
 
-    #[test]
-    fn items_can_be_set() {
-        let time = TestTime::new(Instant::now());
-        let mut cache = init_cache(&time);
+    #[cfg(not(test))]
+    fn check_cost(&self) {}
 
-        let key = String::from("key: String");
-        let value = String::from("value: String");
+    // cost of an entry replaced under the key is assumed to be freed
+    fn has_room_for(&self, key: &str, value_len: usize) -> bool {
+        let capacity = match self.cache_config.capacity {
+            Some(capacity) => capacity,
+            None => return true,
+        };
+        let released = self
+            .cache
+            .get(key)
+            .map(|e| self.cost(e.value.len()))
+            .unwrap_or(0);
+        self.total_cost - released + self.cost(value_len) <= capacity
+    }
 
-        assert!(cache.set(key.clone(), value.clone()).is_ok());
-        assert_eq!(cache.keys_total, 1);
+    // drops every expired entry, O(n) in the number of keys
+    pub fn remove_all_expired(&mut self) {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
 
-        match cache.get(&key) {
-            Some(v) => assert_eq!(v, value),
-            None => assert!(false),
+        let expired: Vec<String> = self
+            .cache
+            .iter()
+            .filter(|(_, e)| e.is_expired(now, ttl))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in expired {
+            self.remove_entry(&k, EventKind::Expired);
         }
+        self.expired_backlog = ExpiredBacklog::default();
+        self.shrink_if_mostly_removed();
     }
 
-    #[test]
-    fn items_expire() {
-        let time = TestTime::new(Instant::now());
-        let mut cache = init_cache(&time);
+    // share of `capacity` estimated to be held by expired entries not removed yet,
+    // share of all entries when there is no capacity
+    pub fn expired_backlog_ratio(&self) -> f64 {
+        let (expired, limit) = match (self.cache_config.capacity, self.cache_config.capacity_unit) {
+            (Some(capacity), CapacityUnit::Bytes) => (self.expired_backlog.bytes, capacity),
+            (Some(capacity), CapacityUnit::Entries) => (self.expired_backlog.keys, capacity),
+            (None, _) => (self.expired_backlog.keys, self.keys_total),
+        };
+        if limit == 0 {
+            0.0
+        } else {
+            expired as f64 / limit as f64
+        }
+    }
 
-        let key = String::from("key: String");
-        let value = String::from("value: String");
+    // removes every entry that expired by now, no-op unless `ExpiryMode::Eager`
+    pub fn remove_due(&mut self) -> usize {
+        let now = self.time.get_time();
+        let mut removed = 0;
+        // head is popped before its entry is removed, so one without an entry cannot stall the loop
+        while let Some((_, key)) = self.expiry_queue.as_mut().and_then(|q| {
+            // same condition as `CacheEntry::is_expired`
+            q.first().filter(|(expires_at, _)| *expires_at < now)?;
+            q.pop_first()
+        }) {
+            if self.remove_entry(&key, EventKind::Expired).is_some() {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.shrink_if_mostly_removed();
+        }
+        removed
+    }
 
-        assert!(cache.set(key.clone(), value.clone()).is_ok());
-        assert_eq!(cache.keys_total, 1);
+    // when the next entry expires, only known with `ExpiryMode::Eager`
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiry_queue
+            .as_ref()
+            .and_then(|q| q.first())
+            .map(|(expires_at, _)| *expires_at)
+    }
 
-        time.add_secs(Duration::from_secs(11));
+    // out of `eviction_number` random entries evicts the one `eviction_policy` picks,
+    // pinned entries are never sampled, returns false when there was nothing to evict
+    fn evict_sampled(&mut self) -> bool {
+        let sample = self
+            .cache
+            .iter()
+            .filter(|(_, e)| !e.pinned)
+            .choose_multiple(&mut thread_rng(), self.cache_config.eviction_number)
+            .into_iter();
+        let victim = match self.cache_config.eviction_policy {
+            EvictionPolicy::LargestFirst => sample.max_by_key(|(_, e)| e.value.len()),
+            _ => sample.min_by_key(|(_, e)| e.created),
+        }
+        .map(|(k, _)| k.clone());
+        match victim {
+            Some(k) => self.remove_entry(&k, EventKind::Evicted).is_some(),
+            None => false,
+        }
+    }
 
-        assert!(cache.get(&key).is_none());
-        assert_eq!(cache.keys_total, 0);
+    // whether storing the value under the key would take `bytes_total` over `max_bytes`,
+    // value replaced under the key is assumed to be freed even if it is interned
+    fn exceeds_max_bytes(&self, key: &str, value: &[u8]) -> bool {
+        let max_bytes = match self.cache_config.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return false,
+        };
+        let added = match &self.interned {
+            Some(interned) if interned.contains(value) => 0,
+            _ => value.len(),
+        };
+        let released = self.cache.get(key).map(|e| e.value.len()).unwrap_or(0);
+        self.bytes_total - released + added > max_bytes
     }
 
-    #[test]
-    fn expired_keys_gets_evicted_with_eviction_call() {
-        let time = TestTime::new(Instant::now());
-        let mut cache = init_cache(&time);
+    // evicts entries until the value fits, reclaiming expired ones first
+    fn make_room_for_bytes(&mut self, key: &str, value: &[u8]) -> Result<(), CacheError> {
+        let max_bytes = match self.cache_config.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(()),
+        };
+        if value.len() > max_bytes {
+            return Err(CacheError::OutOfBytes(max_bytes));
+        }
+        if self.exceeds_max_bytes(key, value) {
+            self.remove_all_expired();
+        }
+        // cache gets smaller with every round until only pinned entries are left
+        while self.exceeds_max_bytes(key, value)
+            && self.cache_config.eviction_policy != EvictionPolicy::RejectWrites
+            && self.evict_sampled()
+        {}
+        if self.exceeds_max_bytes(key, value) {
+            Err(CacheError::OutOfBytes(max_bytes))
+        } else {
+            Ok(())
+        }
+    }
 
-        let key = String::from("key: String");
-        let value = String::from("value: String");
+    pub fn set(&mut self, key: String, value: Vec<u8>) -> Result<(), CacheError> {
+        self.set_with_ttl(key, value, None)
+    }
 
-        assert!(cache.set(key.clone(), value.clone()).is_ok());
-        assert_eq!(cache.keys_total, 1);
+    // `ttl` overrides the one from config for this entry, `None` means config ttl is used
+    pub fn set_with_ttl(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        self.set_with_tags(key, value, ttl, Vec::new())
+    }
 
-        time.add_secs(Duration::from_secs(11));
+    // tags replace the ones of an overwritten entry, writing without tags drops them
+    pub fn set_with_tags(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        tags: Vec<String>,
+    ) -> Result<(), CacheError> {
+        let value = match &self.on_write {
+            Some(transform) => transform(value),
+            None => value,
+        };
+        self.set_stored(key, value, ttl, tags)
+    }
 
-        cache.evict_expired();
-        assert_eq!(cache.keys_total, 0);
+    // same as `set_with_tags` for a value the write transform was already applied to
+    pub fn set_stored(
+        &mut self,
+        key: String,
+        stored: Vec<u8>,
+        ttl: Option<Duration>,
+        mut tags: Vec<String>,
+    ) -> Result<(), CacheError> {
+        tags.sort();
+        tags.dedup();
+        if tags.len() > self.cache_config.max_tags_per_key {
+            return Err(CacheError::TooManyTags(self.cache_config.max_tags_per_key));
+        }
+        self.insert(key, stored, ttl, tags)
     }
 
-    #[test]
-    fn capacity_is_checked_before_adding_new_items() {
-        let time = TestTime::new(Instant::now());
-        let mut cache = init_cache(&time);
+    // puts value that was already transformed, e.g. one read back from a snapshot
+    pub fn restore(
+        &mut self,
+        key: String,
+        stored: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), CacheError> {
+        self.insert(key, stored, ttl, Vec::new())
+    }
 
-        let key = String::from("key: String");
-        let key2 = String::from("key2: String");
-        let value = String::from("value: String");
+    fn insert(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        tags: Vec<String>,
+    ) -> Result<(), CacheError> {
+        // zero capacity cache stores nothing, there is nothing to reclaim either
+        if self.cache_config.capacity == Some(0) {
+            return Err(CacheError::OutOfCapacity(Some(0)));
+        }
+        // no amount of eviction makes room for an entry costing more than the whole capacity
+        if !self
+            .cache_config
+            .capacity
+            .map(|c| self.cost(value.len()) <= c)
+            .unwrap_or(true)
+        {
+            return Err(CacheError::OutOfCapacity(self.cache_config.capacity));
+        }
+        // expired entries count against capacity until an eviction pass or a read removes
+        // them, only those already due in the eager expiry queue are reclaimed here
+        if !self.has_room_for(&key, value.len()) {
+            self.remove_due();
+        }
+        // one eviction is enough with `CapacityUnit::Entries`, with bytes it may take more,
+        // cache gets smaller with every round until only pinned entries are left
+        while !self.has_room_for(&key, value.len())
+            && self.cache_config.eviction_policy != EvictionPolicy::RejectWrites
+            && self.evict_sampled()
+        {}
 
-        assert!(cache.set(key.clone(), value.clone()).is_ok());
-        assert_eq!(cache.keys_total, 1);
-        assert!(cache.set(key2.clone(), value.clone()).is_err());
-        assert_eq!(cache.keys_total, 1);
+        if self.has_room_for(&key, value.len()) {
+            self.make_room_for_bytes(&key, &value)?;
+            let created = self.time.get_time();
+            let checksum = if self.cache_config.paranoid_checksums {
+                Some(snapshot::checksum(&key, &value))
+            } else {
+                None
+            };
+            for tag in &tags {
+                self.tags.insert(tag, &key);
+            }
+            self.total_cost += self.cost(value.len());
+            // pin is on the key, it outlives rewrites
+            let pinned = self.cache.get(&key).map(|e| e.pinned).unwrap_or(false);
+            let new_entry = CacheEntry {
+                value: self.store_value(value),
+                created,
+                hits: 0,
+                ttl,
+                last_access: created,
+                checksum,
+                tags,
+                pinned,
+                version: self.next_version(),
+            };
+            self.emit(&key, EventKind::Set, &new_entry);
+            let default_ttl = self.default_ttl();
+            let expires_at = new_entry.expires_at(default_ttl);
+            let map_capacity = self.cache.capacity();
+            let replaced = self.cache.insert(key.clone(), new_entry);
+            if self.cache.capacity() > map_capacity {
+                self.on_map_growth();
+            }
+            match replaced {
+                Some(replaced) => {
+                    self.total_cost -= self.cost(replaced.value.len());
+                    self.release_value(&replaced.value);
+                    if let Some(queue) = self.expiry_queue.as_mut() {
+                        queue.remove(&(replaced.expires_at(default_ttl), key.clone()));
+                    }
+                    // tags kept by the new entry were re-added above
+                    let dropped: Vec<String> = replaced
+                        .tags
+                        .into_iter()
+                        .filter(|t| !self.cache[&key].tags.contains(t))
+                        .collect();
+                    self.untag(&key, &dropped);
+                    self.drop_value(replaced.value);
+                }
+                None => self.keys_total += 1,
+            };
+            self.evict_tags(&key);
+            // after the replaced entry is gone, both could expire at the same instant
+            if let Some(queue) = self.expiry_queue.as_mut() {
+                queue.insert((expires_at, key));
+            }
+            self.sets += 1;
+            self.check_cost();
 
-        match cache.get(&key) {
-            Some(v) => assert_eq!(v, value),
-            None => assert!(false),
+            Ok(())
+        } else {
+            Err(CacheError::OutOfCapacity(self.cache_config.capacity))
         }
     }
 
-    #[test]
-    fn capacity_check_allows_overwrite() {
-        let time = TestTime::new(Instant::now());
-        let mut cache = init_cache(&time);
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let stored = self.get_stored(key);
+        match &self.on_read {
+            Some(transform) => stored.map(|v| transform(v)),
+            None => stored,
+        }
+    }
 
-        let key = String::from("key: String");
-        let value = String::from("value: String");
-        let value2 = String::from("value2: String");
+    // value along with its metadata, meta already counts this read
+    pub fn get_with_meta(&mut self, key: &str) -> Option<(Vec<u8>, EntryMeta)> {
+        let value = self.get(key)?;
+        self.meta(key).map(|meta| (value, meta))
+    }
 
-        assert!(cache.set(key.clone(), value.clone()).is_ok());
-        assert_eq!(cache.keys_total, 1);
-        assert!(cache.set(key.clone(), value2.clone()).is_ok());
-        assert_eq!(cache.keys_total, 1);
+    // value with its remaining ttl, creation time and version, counted as a read like `get`
+    pub fn get_item(&mut self, key: &str) -> Option<Item> {
+        let value = self.get(key)?;
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
 
-        match cache.get(&key) {
-            Some(v) => assert_eq!(v, value2),
-            None => assert!(false),
+        self.peek_entry(key).map(|e| Item {
+            value,
+            ttl_secs: (!e.pinned).then(|| e.ttl_remaining(now, ttl).unwrap_or_default().as_secs()),
+            created_at: self.unix_secs(e.created),
+            version: e.version,
+        })
+    }
+
+    // value as stored, before the read transform, counted as a read like `get`
+    pub fn get_stored(&mut self, key: &str) -> Option<Vec<u8>> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        let stored = match self.cache.get_mut(key) {
+            Some(e) => {
+                if e.is_expired(now, ttl) {
+                    if self.cache_config.lazy_expiry {
+                        self.remove_entry(key, EventKind::Expired);
+                    }
+                    None
+                } else if e.is_corrupted(key) {
+                    tracing::error!("[read] checksum mismatch for key {}, entry dropped", key);
+                    self.checksum_mismatches += 1;
+                    self.remove_entry(key, EventKind::Deleted);
+                    None
+                } else {
+                    e.hits += 1;
+                    if let (DefaultTtl::Idle(_), None) = (ttl, e.ttl) {
+                        let expired_at = e.expires_at(ttl);
+                        e.last_access = now;
+                        if let Some(queue) = self.expiry_queue.as_mut() {
+                            queue.remove(&(expired_at, key.to_string()));
+                            queue.insert((e.expires_at(ttl), key.to_string()));
+                        }
+                    }
+                    Some(e.value.to_vec())
+                }
+            }
+            None => None,
+        };
+        if stored.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
         }
+        stored
+    }
+
+    // same as `get` without any side effect, for probes that should not perturb the cache:
+    // expired and corrupted entries are left in place and the read is not counted
+    pub fn peek(&self, key: &str) -> Option<Vec<u8>> {
+        let stored = self.peek_stored(key);
+        match &self.on_read {
+            Some(transform) => stored.map(|v| transform(v)),
+            None => stored,
+        }
+    }
+
+    // value as stored, before the read transform, see `peek`
+    pub fn peek_stored(&self, key: &str) -> Option<Vec<u8>> {
+        self.peek_entry(key).map(|e| e.value.to_vec())
+    }
+
+    fn peek_entry(&self, key: &str) -> Option<&CacheEntry> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache
+            .get(key)
+            .filter(|e| !e.is_expired(now, ttl) && !e.is_corrupted(key))
+    }
+
+    fn unix_secs(&self, at: Instant) -> u64 {
+        (self.unix_millis(at) / 1000) as u64
+    }
+
+    fn unix_millis(&self, at: Instant) -> u128 {
+        let (instant, system_time) = self.epoch;
+        (system_time + at.saturating_duration_since(instant))
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    }
+
+    // wall clock time a live entry was created at, in unix ms
+    pub fn created_at_ms(&self, key: &str) -> Option<u64> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache
+            .get(key)
+            .filter(|e| !e.is_expired(now, ttl))
+            .map(|e| self.unix_millis(e.created) as u64)
+    }
+
+    // on a miss sets a marker living for `lock_ttl` unless it is already there, so that
+    // only one of the clients missing the key at the same time goes to refresh it
+    pub fn get_or_lock(&mut self, key: &str, lock_ttl: Duration) -> LockedRead {
+        if let Some(value) = self.get(key) {
+            return LockedRead::Value(value);
+        }
+
+        let marker = refresh_lock_key(key);
+        if self.contains(&marker) {
+            return LockedRead::Wait;
+        }
+        // without room for the marker there is nothing to coordinate with, everyone refreshes
+        if let Err(e) = self.set_with_ttl(marker, b"1".to_vec(), Some(lock_ttl)) {
+            tracing::warn!("[refresh-lock] could not set marker for {}: {}", key, e);
+        }
+        LockedRead::Granted
+    }
+
+    // values in the order of keys, every distinct key is looked up once
+    // no matter how many times it is repeated
+    pub fn get_many(&mut self, keys: &[String]) -> Vec<Option<Vec<u8>>> {
+        let mut looked_up: HashMap<&str, Option<Vec<u8>>> = HashMap::with_capacity(keys.len());
+        for key in keys {
+            if !looked_up.contains_key(key.as_str()) {
+                let value = self.get(key);
+                looked_up.insert(key, value);
+            }
+        }
+
+        keys.iter()
+            .map(|k| looked_up.get(k.as_str()).cloned().flatten())
+            .collect()
+    }
+
+    // remaining ttl of every key, in order, `None` for missing or expired ones,
+    // not counted as reads
+    pub fn ttl_many(&self, keys: &[String]) -> Vec<Option<Duration>> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        keys.iter()
+            .map(|k| {
+                self.cache
+                    .get(k.as_str())
+                    .filter(|e| !e.is_expired(now, ttl))
+                    .and_then(|e| e.ttl_remaining(now, ttl))
+            })
+            .collect()
+    }
+
+    // value only if entry was set after `since` (unix seconds), not modified reads are not counted as hits
+    pub fn get_if_modified_since(&mut self, key: &str, since: u64) -> ConditionalRead {
+        self.get_dated(key, Some(since)).read
+    }
+
+    // value with its creation time, not modified if it was created at or before `since`
+    pub fn get_dated(&mut self, key: &str, since: Option<u64>) -> DatedRead {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        let created_at = self
+            .cache
+            .get(key)
+            .filter(|e| !e.is_expired(now, ttl))
+            .map(|e| self.unix_secs(e.created));
+
+        let read = match (created_at, since) {
+            (Some(created), Some(since)) if created <= since => ConditionalRead::NotModified,
+            _ => match self.get(key) {
+                Some(value) => ConditionalRead::Value(value),
+                None => ConditionalRead::Missing,
+            },
+        };
+        DatedRead {
+            // value failing its checksum is a miss
+            created_at: created_at.filter(|_| read != ConditionalRead::Missing),
+            read,
+        }
+    }
+
+    // removes the key, returns false if there was no live entry for it
+    pub fn delete(&mut self, key: &str) -> bool {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        match self.cache.get(key).map(|e| e.is_expired(now, ttl)) {
+            Some(false) => self.remove_entry(key, EventKind::Deleted).is_some(),
+            Some(true) => {
+                self.remove_entry(key, EventKind::Expired);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // removes every entry, returns how many there were
+    pub fn flush(&mut self) -> usize {
+        self.remove_all(EventKind::Deleted)
+    }
+
+    // same as `flush` with events of given kind
+    pub fn remove_all(&mut self, kind: EventKind) -> usize {
+        if let Some(deferred_drop) = self.deferred_drop.clone() {
+            return self.swap_out_all(kind, deferred_drop);
+        }
+        let keys: Vec<String> = self.cache.keys().cloned().collect();
+        for key in &keys {
+            self.remove_entry(key, kind);
+        }
+        self.expired_backlog = ExpiredBacklog::default();
+        self.shrink_if_mostly_removed();
+        keys.len()
+    }
+
+    // `remove_all` of a cache with deferred drop, the map is replaced with an empty one and
+    // freed by the dropping thread along with every value in it
+    fn swap_out_all(&mut self, kind: EventKind, deferred_drop: DeferredDrop) -> usize {
+        let empty = self
+            .cache_config
+            .initial_capacity
+            .map(HashMap::with_capacity)
+            .unwrap_or_default();
+        let removed = std::mem::replace(&mut self.cache, empty);
+        let mut bytes = 0;
+        for (key, entry) in &removed {
+            self.forget_entry(key, entry, kind);
+            bytes += entry.value.len();
+        }
+        // values shared by several entries are still referenced by the old map, so not all of
+        // them were released one by one
+        if let Some(interned) = self.interned.as_mut() {
+            interned.clear();
+            self.bytes_total = 0;
+        }
+        self.check_cost();
+        self.expired_backlog = ExpiredBacklog::default();
+        self.removed_since_compact = 0;
+        let count = removed.len();
+        deferred_drop.defer(Box::new(removed), bytes);
+        count
+    }
+
+    // exchanges entries of two live keys, ttl and tags travel together with the value,
+    // as if each value had been set under the other key in the first place
+    pub fn swap(&mut self, a: &str, b: &str) -> Result<(), CacheError> {
+        for key in [a, b].iter() {
+            if !self.contains(key) {
+                return Err(CacheError::NotFound(key.to_string()));
+            }
+        }
+        if a == b {
+            return Ok(());
+        }
+
+        let ttl = self.default_ttl();
+        let mut entry_a = self.cache.remove(a).unwrap();
+        let mut entry_b = self.cache.remove(b).unwrap();
+        self.untag(a, &entry_a.tags);
+        self.untag(b, &entry_b.tags);
+        for (key, entry) in [(b, &mut entry_a), (a, &mut entry_b)].iter_mut() {
+            if let Some(queue) = self.expiry_queue.as_mut() {
+                // entry is still under the other key in the queue
+                let other = if *key == a { b } else { a };
+                queue.remove(&(entry.expires_at(ttl), other.to_string()));
+                queue.insert((entry.expires_at(ttl), key.to_string()));
+            }
+            for tag in &entry.tags {
+                self.tags.insert(tag, key);
+            }
+            if entry.checksum.is_some() {
+                entry.checksum = Some(snapshot::checksum(key, &entry.value));
+            }
+            self.versions += 1;
+            entry.version = self.versions;
+        }
+        self.cache.insert(a.to_string(), entry_b);
+        self.cache.insert(b.to_string(), entry_a);
+        for key in [a, b] {
+            if let Some(entry) = self.cache.get(key) {
+                self.emit(key, EventKind::Set, entry);
+            }
+        }
+        Ok(())
+    }
+
+    // moves the entry to another key as is, ttl, tags and hits included, e.g. to publish an
+    // entry built under a temporary key, events are `Deleted` for the source and `Set` for
+    // the destination, an overwritten destination is `Deleted` first
+    pub fn rename(&mut self, from: &str, to: String, overwrite: bool) -> Result<(), CacheError> {
+        if !self.contains(from) {
+            return Err(CacheError::NotFound(from.to_string()));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if self.contains(&to) {
+            if !overwrite {
+                return Err(CacheError::Conflict(to));
+            }
+            self.remove_entry(&to, EventKind::Replaced);
+        } else {
+            self.remove_entry(&to, EventKind::Expired);
+        }
+
+        let ttl = self.default_ttl();
+        let mut entry = self.cache.remove(from).unwrap();
+        self.untag(from, &entry.tags);
+        if let Some(queue) = self.expiry_queue.as_mut() {
+            queue.remove(&(entry.expires_at(ttl), from.to_string()));
+            queue.insert((entry.expires_at(ttl), to.clone()));
+        }
+        for tag in &entry.tags {
+            self.tags.insert(tag, &to);
+        }
+        if entry.checksum.is_some() {
+            entry.checksum = Some(snapshot::checksum(&to, &entry.value));
+        }
+        entry.version = self.next_version();
+        self.emit(from, EventKind::Deleted, &entry);
+        self.emit(&to, EventKind::Set, &entry);
+        self.cache.insert(to, entry);
+        Ok(())
+    }
+
+    // pinned entry never expires and is never evicted to make room, it still counts against
+    // capacity, once unpinned it expires as if it was never pinned
+    pub fn set_pinned(&mut self, key: &str, pinned: bool) -> Result<(), CacheError> {
+        if !self.contains(key) {
+            return Err(CacheError::NotFound(key.to_string()));
+        }
+        let ttl = self.default_ttl();
+        let entry = self.cache.get_mut(key).unwrap();
+        let expired_at = entry.expires_at(ttl);
+        entry.pinned = pinned;
+        if let Some(queue) = self.expiry_queue.as_mut() {
+            queue.remove(&(expired_at, key.to_string()));
+            queue.insert((entry.expires_at(ttl), key.to_string()));
+        }
+        Ok(())
+    }
+
+    // zeroes the read count of a live entry, returns the count before, value and expiry are kept
+    pub fn reset_hits(&mut self, key: &str) -> Result<u64, CacheError> {
+        if !self.contains(key) {
+            return Err(CacheError::NotFound(key.to_string()));
+        }
+        let entry = self.cache.get_mut(key).unwrap();
+        Ok(std::mem::take(&mut entry.hits))
+    }
+
+    // appends the line to the value read as newline separated lines, keeping only the last
+    // `max` of them, e.g. a bounded per-key log, entry keeps its expiry time and tags,
+    // returns how many lines the value holds now
+    pub fn ring_push(&mut self, key: &str, line: &[u8], max: usize) -> Result<usize, CacheError> {
+        let now = self.time.get_time();
+        let default_ttl = self.default_ttl();
+
+        let (current, ttl, tags) = match self.cache.get(key) {
+            Some(e) if !e.is_expired(now, default_ttl) => (
+                e.value.to_vec(),
+                // rewritten entry is created now, remaining ttl keeps it expiring as before
+                Some(e.ttl_remaining(now, default_ttl).unwrap_or_default()),
+                e.tags.clone(),
+            ),
+            _ => (Vec::new(), None, Vec::new()),
+        };
+        let current = match &self.on_read {
+            Some(transform) if !current.is_empty() => transform(current),
+            _ => current,
+        };
+
+        let mut lines: Vec<&[u8]> = if current.is_empty() {
+            Vec::new()
+        } else {
+            current.split(|b| *b == b'\n').collect()
+        };
+        lines.push(line);
+        let kept = &lines[lines.len().saturating_sub(max)..];
+        let count = kept.len();
+        let value = kept.join(&b'\n');
+
+        self.set_with_tags(key.to_string(), value, ttl, tags)?;
+        Ok(count)
+    }
+
+    // removes the key only if its entry was created strictly before `before_ms` (unix ms),
+    // so that a delete decided on stale information does not drop a value written since
+    pub fn delete_if_created_before(&mut self, key: &str, before_ms: u64) -> ConditionalDelete {
+        match self.created_at_ms(key) {
+            Some(created) if created >= before_ms => ConditionalDelete::CreatedAfter(created),
+            _ if self.delete(key) => ConditionalDelete::Deleted,
+            _ => ConditionalDelete::Missing,
+        }
+    }
+
+    // deletes keys carrying the tag, at most `max_tag_invalidation_batch` of them per call,
+    // expired ones are dropped along the way but not counted as removed
+    pub fn invalidate_tag(&mut self, tag: &str) -> TagInvalidation {
+        self.invalidate_tag_matching(tag, |_| true)
+    }
+
+    // same as `invalidate_tag` for the keys `allowed` picks, the others keep the tag and
+    // do not count towards `more`
+    pub fn invalidate_tag_matching(
+        &mut self,
+        tag: &str,
+        allowed: impl Fn(&str) -> bool,
+    ) -> TagInvalidation {
+        let batch: Vec<String> = match self.tags.keys(tag) {
+            Some(keys) => keys
+                .iter()
+                .filter(|k| allowed(k))
+                .take(self.cache_config.max_tag_invalidation_batch)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let removed = batch.iter().filter(|k| self.delete(k)).count();
+        self.shrink_if_mostly_removed();
+
+        TagInvalidation {
+            removed,
+            more: self
+                .tags
+                .keys(tag)
+                .map(|keys| keys.iter().any(|k| allowed(k)))
+                .unwrap_or(false),
+        }
+    }
+
+    // entry expires `ttl` from now, returns false if there is no live entry for the key
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> bool {
+        let now = self.time.get_time();
+        let default_ttl = self.default_ttl();
+
+        match self.cache.get_mut(key) {
+            Some(e) if !e.is_expired(now, default_ttl) => {
+                let expired_at = e.expires_at(default_ttl);
+                e.ttl = Some(now.saturating_duration_since(e.created) + ttl);
+                if let Some(queue) = self.expiry_queue.as_mut() {
+                    queue.remove(&(expired_at, key.to_string()));
+                    queue.insert((e.expires_at(default_ttl), key.to_string()));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // length of the value as returned by `get`, not counted as a read
+    pub fn value_len(&self, key: &str) -> Option<usize> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache
+            .get(key)
+            .filter(|e| !e.is_expired(now, ttl))
+            .map(|e| match &self.on_read {
+                // stored length can differ from the one client gets back
+                Some(transform) => transform(e.value.to_vec()).len(),
+                None => e.value.len(),
+            })
+    }
+
+    // value of a live entry along with its remaining ttl, not counted as a read
+    fn peek_with_ttl(&self, key: &str) -> Option<(Vec<u8>, Duration)> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache
+            .get(key)
+            .filter(|e| !e.is_expired(now, ttl))
+            .and_then(|e| {
+                let value = match &self.on_read {
+                    Some(transform) => transform(e.value.to_vec()),
+                    None => e.value.to_vec(),
+                };
+                e.ttl_remaining(now, ttl)
+                    .map(|remaining| (value, remaining))
+            })
+    }
+
+    fn peek_integer(&self, key: &str) -> Option<i64> {
+        self.peek_with_ttl(key)
+            .and_then(|(value, _)| String::from_utf8(value).ok())
+            .and_then(|value| value.parse::<i64>().ok())
+    }
+
+    // all operations are validated against the state they would see if applied one
+    // after another, nothing is applied unless every one of them passes
+    pub fn apply_txn(&mut self, ops: Vec<TxnOp>) -> TxnResult {
+        if self.cache_config.capacity.is_some() || self.cache_config.max_bytes.is_some() {
+            // so that `total_cost` and `bytes_total` only count live entries
+            self.remove_all_expired();
+        }
+
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        // values written by earlier operations, for incr to build on
+        let mut written: HashMap<&str, String> = HashMap::new();
+        // cost of every key as it will be after operations so far, values are
+        // measured before write transform
+        let mut costs: HashMap<&str, usize> = HashMap::new();
+        let mut total_cost = self.total_cost;
+        let has_room =
+            |total_cost: usize, released: usize, len: usize| match self.cache_config.capacity {
+                Some(capacity) => {
+                    self.cost(len) <= capacity
+                        && (self.cache_config.eviction_policy != EvictionPolicy::RejectWrites
+                            || total_cost - released + self.cost(len) <= capacity)
+                }
+                None => true,
+            };
+        // replaced values are not subtracted, so a transaction may be rejected
+        // even though it would fit, but never the other way around
+        let mut bytes_total = self.bytes_total;
+        let fits_bytes = |bytes_total: usize, len: usize| match self.cache_config.max_bytes {
+            Some(max_bytes) => {
+                len <= max_bytes
+                    && (self.cache_config.eviction_policy != EvictionPolicy::RejectWrites
+                        || bytes_total + len <= max_bytes)
+            }
+            None => true,
+        };
+        let mut results = Vec::with_capacity(ops.len());
+        let mut aborted = false;
+
+        for op in &ops {
+            if aborted {
+                results.push(TxnOpStatus::Skipped);
+                continue;
+            }
+
+            let key = op.key();
+            let key_exists = exists
+                .get(key)
+                .copied()
+                .unwrap_or_else(|| self.contains(key));
+            let released = match costs.get(key) {
+                Some(cost) => *cost,
+                None if key_exists => self.cost(self.cache[key].value.len()),
+                None => 0,
+            };
+            let status = match op {
+                TxnOp::Set { nx, xx, value, .. } => {
+                    if (*nx && key_exists) || (*xx && !key_exists) {
+                        TxnOpStatus::ConditionFailed
+                    } else if !has_room(total_cost, released, value.len())
+                        || !fits_bytes(bytes_total, value.len())
+                    {
+                        TxnOpStatus::OutOfCapacity
+                    } else {
+                        exists.insert(key, true);
+                        total_cost = total_cost - released + self.cost(value.len());
+                        costs.insert(key, self.cost(value.len()));
+                        bytes_total += value.len();
+                        written.insert(key, value.clone());
+                        TxnOpStatus::Ok
+                    }
+                }
+                TxnOp::Incr { by, .. } => {
+                    let current = match written.get(key) {
+                        Some(value) if key_exists => value.parse::<i64>().ok(),
+                        _ if key_exists => self.peek_integer(key),
+                        _ => Some(0),
+                    };
+                    match current.and_then(|c| c.checked_add(*by)) {
+                        None => TxnOpStatus::NotAnInteger,
+                        Some(value) if !has_room(total_cost, released, value.to_string().len()) => {
+                            TxnOpStatus::OutOfCapacity
+                        }
+                        Some(value) if !fits_bytes(bytes_total, value.to_string().len()) => {
+                            TxnOpStatus::OutOfCapacity
+                        }
+                        Some(value) => {
+                            let len = value.to_string().len();
+                            exists.insert(key, true);
+                            total_cost = total_cost - released + self.cost(len);
+                            costs.insert(key, self.cost(len));
+                            bytes_total += value.to_string().len();
+                            written.insert(key, value.to_string());
+                            TxnOpStatus::Ok
+                        }
+                    }
+                }
+                TxnOp::Del { .. } if key_exists => {
+                    total_cost -= released;
+                    costs.insert(key, 0);
+                    exists.insert(key, false);
+                    written.remove(key);
+                    TxnOpStatus::Ok
+                }
+                TxnOp::Expire { .. } if key_exists => TxnOpStatus::Ok,
+                TxnOp::Del { .. } | TxnOp::Expire { .. } => TxnOpStatus::NotFound,
+            };
+
+            aborted = status.aborts();
+            results.push(status);
+        }
+
+        if aborted {
+            return TxnResult {
+                committed: false,
+                results,
+            };
+        }
+
+        // validation measures values before write transform and cannot tell whether eviction
+        // makes room past pinned entries, so a write can still fail, touched keys are then
+        // put back as they were, entries evicted on the way stay evicted
+        let mut touched: Vec<(String, Option<CacheEntry>)> = Vec::new();
+        for op in &ops {
+            if touched.iter().all(|(key, _)| key != op.key()) {
+                touched.push((op.key().to_string(), self.cache.get(op.key()).cloned()));
+            }
+        }
+        for (i, op) in ops.into_iter().enumerate() {
+            let result = match op {
+                TxnOp::Set {
+                    key,
+                    value,
+                    ttl_secs,
+                    ..
+                } => self.set_with_ttl(key, value.into_bytes(), ttl_secs.map(Duration::from_secs)),
+                TxnOp::Del { key } => {
+                    self.delete(&key);
+                    Ok(())
+                }
+                TxnOp::Expire { key, ttl_secs } => {
+                    self.expire(&key, Duration::from_secs(ttl_secs));
+                    Ok(())
+                }
+                TxnOp::Incr { key, by } => {
+                    let remaining = self.peek_with_ttl(&key).map(|(_, remaining)| remaining);
+                    let value = self.peek_integer(&key).unwrap_or(0) + by;
+                    self.set_with_ttl(key, value.to_string().into_bytes(), remaining)
+                }
+            };
+            if let Err(e) = result {
+                tracing::warn!("[txn] rolled back, validated write failed: {:?}", e);
+                for (key, original) in touched {
+                    self.remove_entry(&key, EventKind::Deleted);
+                    if let Some(original) = original {
+                        self.put_back(key, original);
+                    }
+                }
+                results[i] = TxnOpStatus::OutOfCapacity;
+                for status in &mut results[i + 1..] {
+                    *status = TxnOpStatus::Skipped;
+                }
+                return TxnResult {
+                    committed: false,
+                    results,
+                };
+            }
+        }
+
+        TxnResult {
+            committed: true,
+            results,
+        }
+    }
+
+    // entry as it was before a rolled back transaction, with its accounting and expiry
+    fn put_back(&mut self, key: String, entry: CacheEntry) {
+        self.keys_total += 1;
+        self.total_cost += self.cost(entry.value.len());
+        match self.interned.as_mut() {
+            Some(interned) => {
+                if interned.insert(entry.value.clone()) {
+                    self.bytes_total += entry.value.len();
+                }
+            }
+            None => self.bytes_total += entry.value.len(),
+        }
+        for tag in &entry.tags {
+            self.tags.insert(tag, &key);
+        }
+        let ttl = self.default_ttl();
+        if let Some(queue) = self.expiry_queue.as_mut() {
+            queue.insert((entry.expires_at(ttl), key.clone()));
+        }
+        self.emit(&key, EventKind::Set, &entry);
+        self.cache.insert(key, entry);
+    }
+
+    // whether key has a live entry, does not count as a hit
+    pub fn contains(&self, key: &str) -> bool {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache
+            .get(key)
+            .map(|e| !e.is_expired(now, ttl))
+            .unwrap_or(false)
+    }
+
+    // number of live keys matching glob pattern, see `glob_match`
+    pub fn count_matching(&self, pattern: &str) -> usize {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache
+            .iter()
+            .filter(|(k, e)| !e.is_expired(now, ttl) && glob_match(pattern, k))
+            .count()
+    }
+
+    // entries held by the map, including expired ones that were not evicted yet
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    // live entries as stored, with their remaining ttl, in no particular order
+    pub fn live_entries(&self) -> impl Iterator<Item = (&str, &[u8], Duration)> + '_ {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.cache.iter().filter_map(move |(k, e)| {
+            e.ttl_remaining(now, ttl)
+                .filter(|_| !e.is_expired(now, ttl))
+                .map(|remaining| (k.as_str(), &e.value[..], remaining))
+        })
+    }
+
+    // estimate of how many entries the map can hold without reallocating, as reported by
+    // `HashMap::capacity`, slots of removed entries are not counted until the map is rehashed
+    pub fn map_capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    // len over capacity, the map grows once this gets close to 7/8
+    pub fn map_load_factor(&self) -> f64 {
+        match self.cache.capacity() {
+            0 => 0.0,
+            capacity => self.cache.len() as f64 / capacity as f64,
+        }
+    }
+
+    fn on_map_growth(&mut self) {
+        self.map_growths += 1;
+        if let Some(threshold) = self.cache_config.growth_warning_threshold {
+            if self.cache.len() > threshold {
+                tracing::warn!(
+                    "map grew to capacity {} at {} keys, consider reserving ahead with /admin/reserve",
+                    self.cache.capacity(),
+                    self.cache.len()
+                );
+            }
+        }
+    }
+
+    // grows the map up front so that this many new keys can be written without a rehash,
+    // fails instead of aborting when the allocation is refused
+    pub fn reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.cache.try_reserve(additional)
+    }
+
+    // returns memory held after many insert/remove cycles back to the allocator
+    pub fn compact(&mut self) {
+        self.cache.shrink_to_fit();
+        if let Some(interned) = self.interned.as_mut() {
+            interned.shrink_to_fit();
+        }
+        self.removed_since_compact = 0;
+    }
+
+    // with `shrink_after_flush` compacts the map once more entries were removed than are left
+    fn shrink_if_mostly_removed(&mut self) {
+        if self.cache_config.shrink_after_flush
+            && self.removed_since_compact >= SHRINK_MIN_REMOVED
+            && self.removed_since_compact > self.cache.len()
+        {
+            self.compact();
+        }
+    }
+
+    // inspects an entry without counting it as a hit, see `peek`
+    pub fn meta(&self, key: &str) -> Option<EntryMeta> {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        self.peek_entry(key).map(|e| EntryMeta {
+            hits: e.hits,
+            ttl_remaining_ms: e.ttl_remaining(now, ttl).unwrap_or_default().as_millis(),
+            version: e.version,
+        })
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.versions += 1;
+        self.versions
+    }
+
+    // optimistic locking, a write read at `expected` goes through only while nobody else
+    // wrote the key since, 0 expects no live entry, e.g. to create the key only once
+    pub fn check_version(&self, key: &str, expected: Option<u64>) -> Result<(), CacheError> {
+        let current = self.peek_entry(key).map(|e| e.version).unwrap_or(0);
+        match expected {
+            Some(expected) if expected != current => {
+                Err(CacheError::VersionMismatch(key.to_string(), current))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // read-only, expired entries are not removed and reads are not counted
+    pub fn explain(&self, key: &str, value_size: Option<usize>) -> Explanation {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+        let entry = self.cache.get(key);
+
+        let set = value_size.map(|value_size| {
+            let has_room =
+                self.cache_config.capacity != Some(0) && self.has_room_for(key, value_size);
+            let fits_capacity = self
+                .cache_config
+                .capacity
+                .map(|c| self.cost(value_size) <= c)
+                .unwrap_or(true);
+            // same as `insert`, only entries due in the eager expiry queue are reclaimed
+            let reclaims_expired = !has_room
+                && self.cache_config.capacity != Some(0)
+                && self.next_expiry().map(|at| at < now).unwrap_or(false);
+            let evicts = !has_room
+                && !reclaims_expired
+                && self.cache_config.capacity != Some(0)
+                && self.cache_config.eviction_policy != EvictionPolicy::RejectWrites;
+            let released = entry.map(|e| e.value.len()).unwrap_or(0);
+            let fits_bytes = self
+                .cache_config
+                .max_bytes
+                .map(|max_bytes| {
+                    value_size <= max_bytes
+                        && (self.cache_config.eviction_policy != EvictionPolicy::RejectWrites
+                            || self.bytes_total - released + value_size <= max_bytes)
+                })
+                .unwrap_or(true);
+            SetExplanation {
+                value_size,
+                allowed: (has_room || reclaims_expired || evicts) && fits_capacity && fits_bytes,
+                reclaims_expired,
+                evicts,
+            }
+        });
+
+        Explanation {
+            key: key.to_string(),
+            exists: entry.is_some(),
+            expired: entry.map(|e| e.is_expired(now, ttl)).unwrap_or(false),
+            // pinned entries do not expire
+            expires_at: entry
+                .filter(|e| !e.pinned)
+                .map(|e| self.unix_secs(e.expires_at(ttl))),
+            ttl_remaining_ms: entry
+                .and_then(|e| e.ttl_remaining(now, ttl))
+                .map(|r| r.as_millis()),
+            eviction_policy: self.cache_config.eviction_policy,
+            set,
+        }
+    }
+
+    // single pass over all entries, O(n) in the number of keys
+    pub fn ttl_histogram(&self) -> TtlHistogram {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+
+        let mut histogram = TtlHistogram::default();
+        for remaining in self
+            .cache
+            .values()
+            .filter(|e| !e.is_expired(now, ttl))
+            .filter_map(|e| e.ttl_remaining(now, ttl))
+        {
+            if remaining < Duration::from_secs(60) {
+                histogram.less_than_1m += 1;
+            } else if remaining < Duration::from_secs(5 * 60) {
+                histogram.from_1m_to_5m += 1;
+            } else {
+                histogram.more_than_5m += 1;
+            }
+        }
+
+        histogram
+    }
+
+    // an attempt to implement simplified version of what Redis has
+    // see for reference https://redis.io/commands/expire
+    pub fn evict_expired(&mut self) -> EvictionPass {
+        self.evict_expired_with(&mut rand::thread_rng())
+    }
+
+    // same as `evict_expired` with keys sampled using given rng, for reproducible runs
+    pub fn evict_expired_with<R: Rng>(&mut self, rng: &mut R) -> EvictionPass {
+        let mut pass = EvictionPass::default();
+        while !self.eviction_round(rng, &mut pass) {}
+        pass
+    }
+
+    // runs at most `max_rounds` sampling rounds, so that a long eviction pass can be
+    // interleaved with other work, returns true once the pass is complete
+    pub fn evict_expired_rounds<R: Rng>(&mut self, rng: &mut R, max_rounds: usize) -> bool {
+        let mut pass = EvictionPass::default();
+        (0..max_rounds).any(|_| self.eviction_round(rng, &mut pass))
+    }
+
+    // samples keys once and removes expired ones among them,
+    // returns true when few enough were expired to stop
+    fn eviction_round<R: Rng>(&mut self, rng: &mut R, pass: &mut EvictionPass) -> bool {
+        let now = self.time.get_time();
+        let ttl = self.default_ttl();
+        let total_lookup = self.cache_config.eviction_number;
+
+        let mut removed: usize = 0;
+        let mut removed_bytes: usize = 0;
+        let random_keys: Vec<String> = self
+            .cache
+            .keys()
+            .choose_multiple(rng, total_lookup)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        let sampled = random_keys.len();
+        pass.rounds += 1;
+        pass.sampled += sampled;
+        for k in random_keys {
+            if self
+                .cache
+                .get(&k)
+                .filter(|v| !v.is_expired(now, ttl))
+                .is_none()
+            {
+                removed_bytes += self.remove_entry(&k, EventKind::Expired).unwrap_or(0);
+                removed += 1;
+            }
+        }
+        pass.removed += removed;
+        // rest of the keys is assumed to be expired as often as the sampled ones
+        if let (Some(keys), Some(bytes)) = (
+            (removed * self.keys_total).checked_div(sampled),
+            (removed_bytes * self.keys_total).checked_div(sampled),
+        ) {
+            self.expired_backlog = ExpiredBacklog { keys, bytes };
+        }
+
+        let done = (removed as f32) / (total_lookup as f32) <= self.cache_config.eviction_ratio;
+        if done {
+            self.shrink_if_mostly_removed();
+        }
+        done
+    }
+}
+
+// `*` matches any sequence of characters, including empty one, `?` matches exactly one
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut p, mut i) = (0, 0);
+    // position of the last `*` in the pattern and of the input it was matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // let the last `*` consume one more character
+            p = star + 1;
+            i = matched + 1;
+            backtrack = Some((star, i));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use std::collections::HashSet;
+    use std::mem::size_of;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use crate::auxiliary::AuxiliaryMemory;
+    use crate::auxiliary::AuxiliaryUsage;
+    use crate::cache::glob_match;
+    use crate::cache::CacheError;
+    use crate::cache::ConditionalDelete;
+    use crate::cache::LockedRead;
+    use crate::cache::TagInvalidation;
+    use crate::cache::TtlCache;
+    use crate::cache::TtlHistogram;
+    use crate::config::CapacityUnit;
+    use crate::config::Config;
+    use crate::config::EvictionPolicy;
+    use crate::config::ExpiryMode;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::deferred_drop::DeferredDrop;
+    use crate::events::EventKind;
+    use crate::events::EventSender;
+    use crate::stats::Counters;
+    use crate::time::time_fixtures::TestTime;
+    use crate::time::Time;
+    use crate::txn::TxnOp;
+    use crate::txn::TxnOpStatus;
+    use crate::txn::TxnResult;
+
+    pub fn init_cache<'a>(time: &'a TestTime) -> TtlCache<'a, TestTime> {
+        TtlCache::new(TEST_CONFIG_SINGLE_ITEM, time)
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn items_can_be_set() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.keys_total, 1);
+
+        match cache.get(&key) {
+            Some(v) => assert_eq!(v, value),
+            None => assert!(false),
+        }
+    }
+
+    #[test]
+    fn items_expire() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.keys_total, 1);
+
+        time.add_secs(Duration::from_secs(11));
+
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.keys_total, 0);
+    }
+
+    #[test]
+    fn expired_keys_gets_evicted_with_eviction_call() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.keys_total, 1);
+
+        time.add_secs(Duration::from_secs(11));
+
+        cache.evict_expired();
+        assert_eq!(cache.keys_total, 0);
+    }
+
+    #[test]
+    fn ttl_past_what_instant_holds_never_expires() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                expiry_mode: ExpiryMode::Eager,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        cache
+            .set_with_ttl(String::from("a"), vec![1], Some(Duration::MAX))
+            .unwrap();
+        cache.set_pinned("a", true).unwrap();
+        cache.set_pinned("a", false).unwrap();
+
+        time.add_secs(Duration::from_secs(365 * 24 * 60 * 60));
+        assert_eq!(cache.remove_due(), 0);
+        assert_eq!(cache.get("a"), Some(vec![1]));
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn capacity_is_checked_before_adding_new_items() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        let key = String::from("key: String");
+        let key2 = String::from("key2: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.keys_total, 1);
+        assert!(cache.set(key2.clone(), value.clone()).is_err());
+        assert_eq!(cache.keys_total, 1);
+
+        match cache.get(&key) {
+            Some(v) => assert_eq!(v, value),
+            None => assert!(false),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn capacity_check_allows_overwrite() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+        let value2 = b"value2: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.keys_total, 1);
+        assert!(cache.set(key.clone(), value2.clone()).is_ok());
+        assert_eq!(cache.keys_total, 1);
+
+        match cache.get(&key) {
+            Some(v) => assert_eq!(v, value2),
+            None => assert!(false),
+        }
+    }
+
+    #[test]
+    fn zero_capacity_cache_stores_nothing() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(0),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        for _ in 0..2 {
+            assert_eq!(
+                cache.set(key.clone(), value.clone()),
+                Err(CacheError::OutOfCapacity(Some(0)))
+            );
+            assert!(cache.get(&key).is_none());
+        }
+        assert!(!cache.contains(&key));
+        assert_eq!(cache.keys_total, 0);
+        assert_eq!(cache.bytes_total, 0);
+    }
+
+    fn txn_set(key: &str, nx: bool) -> TxnOp {
+        TxnOp::Set {
+            key: key.to_string(),
+            value: String::from("value"),
+            ttl_secs: None,
+            nx,
+            xx: false,
+        }
+    }
+
+    #[test]
+    fn txn_is_not_applied_when_last_operation_fails() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(2),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.set(String::from("b"), b"value".to_vec()).is_ok());
+
+        let result = cache.apply_txn(vec![
+            txn_set("a", false),
+            TxnOp::Del {
+                key: String::from("b"),
+            },
+            txn_set("c", false),
+            // `a` is already set by the first operation
+            txn_set("a", true),
+        ]);
+
+        assert_eq!(
+            result,
+            TxnResult {
+                committed: false,
+                results: vec![
+                    TxnOpStatus::Ok,
+                    TxnOpStatus::Ok,
+                    TxnOpStatus::Ok,
+                    TxnOpStatus::ConditionFailed
+                ],
+            }
+        );
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b"));
+        assert!(!cache.contains("c"));
+    }
+
+    #[test]
+    fn txn_capacity_accounts_for_earlier_operations() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(2),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.set(String::from("b"), b"value".to_vec()).is_ok());
+
+        let result = cache.apply_txn(vec![
+            txn_set("a", false),
+            txn_set("c", false),
+            TxnOp::Expire {
+                key: String::from("b"),
+                ttl_secs: 60,
+            },
+        ]);
+        assert!(!result.committed);
+        assert_eq!(result.results[1], TxnOpStatus::OutOfCapacity);
+        assert_eq!(result.results[2], TxnOpStatus::Skipped);
+
+        let result = cache.apply_txn(vec![
+            TxnOp::Del {
+                key: String::from("b"),
+            },
+            txn_set("a", false),
+            txn_set("c", false),
+            TxnOp::Expire {
+                key: String::from("c"),
+                ttl_secs: 60,
+            },
+            TxnOp::Del {
+                key: String::from("missing"),
+            },
+        ]);
+        assert!(result.committed);
+        assert_eq!(result.results[4], TxnOpStatus::NotFound);
+        assert!(cache.contains("a") && cache.contains("c") && !cache.contains("b"));
+
+        time.add_secs(Duration::from_secs(30));
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("c"));
+    }
+
+    #[test]
+    fn repeated_keys_are_looked_up_once_in_batch_reads() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.set(String::from("a"), b"1".to_vec()).is_ok());
+        assert!(cache.set(String::from("b"), b"2".to_vec()).is_ok());
+
+        let keys: Vec<String> = ["a", "missing", "b", "a", "missing", "a"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+
+        assert_eq!(
+            cache.get_many(&keys),
+            vec![
+                Some(b"1".to_vec()),
+                None,
+                Some(b"2".to_vec()),
+                Some(b"1".to_vec()),
+                None,
+                Some(b"1".to_vec())
+            ]
+        );
+        assert_eq!(cache.meta("a").map(|m| m.hits), Some(1));
+        assert_eq!(cache.meta("b").map(|m| m.hits), Some(1));
+    }
+
+    #[test]
+    fn ttl_histogram_buckets_live_entries_by_remaining_ttl() {
+        let time = TestTime::new(Instant::now());
+        let config = Config {
+            ttl: Duration::from_secs(10 * 60),
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let mut cache = TtlCache::new(config, &time);
+
+        let value = b"value: String".to_vec();
+        for (key, created_at) in [("expired", 0), ("soon", 60), ("later", 200), ("fresh", 400)] {
+            time.add_secs(Duration::from_secs(created_at));
+            assert!(cache.set(key.to_string(), value.clone()).is_ok());
+        }
+
+        time.add_secs(Duration::from_secs(650));
+
+        assert_eq!(
+            cache.ttl_histogram(),
+            TtlHistogram {
+                less_than_1m: 1,
+                from_1m_to_5m: 1,
+                more_than_5m: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_are_counted_per_entry() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.meta(&key).is_none());
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+
+        for _ in 0..3 {
+            assert!(cache.get(&key).is_some());
+        }
+        assert_eq!(cache.meta(&key).map(|m| m.hits), Some(3));
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.meta(&key).map(|m| m.hits), Some(0));
+
+        assert!(cache.get(&key).is_some());
+        assert_eq!(cache.reset_hits(&key), Ok(1));
+        assert_eq!(cache.meta(&key).map(|m| m.hits), Some(0));
+        assert_eq!(cache.get(&key), Some(value));
+        assert_eq!(
+            cache.reset_hits("missing"),
+            Err(CacheError::NotFound(String::from("missing")))
+        );
+    }
+
+    #[test]
+    fn every_write_of_a_key_changes_its_version() {
+        let time = TestTime::new(Instant::now());
+        let config = Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let mut cache = TtlCache::new(config, &time);
+        let version = |cache: &TtlCache<TestTime>, key: &str| cache.meta(key).unwrap().version;
+
+        assert_eq!(cache.check_version("a", Some(0)), Ok(()));
+        assert!(cache.set(String::from("a"), b"1".to_vec()).is_ok());
+        let first = version(&cache, "a");
+        assert_eq!(cache.check_version("a", Some(first)), Ok(()));
+        assert_eq!(cache.check_version("a", None), Ok(()));
+        assert_eq!(
+            cache.check_version("a", Some(0)),
+            Err(CacheError::VersionMismatch(String::from("a"), first))
+        );
+
+        assert!(cache.set(String::from("a"), b"1".to_vec()).is_ok());
+        let second = version(&cache, "a");
+        assert!(second > first);
+        assert_eq!(
+            cache.check_version("a", Some(first)),
+            Err(CacheError::VersionMismatch(String::from("a"), second))
+        );
+
+        // reads leave it as is, swaps and renames change the value under the key
+        assert!(cache.get("a").is_some());
+        assert_eq!(version(&cache, "a"), second);
+        assert!(cache.set(String::from("b"), b"2".to_vec()).is_ok());
+        assert_eq!(cache.swap("a", "b"), Ok(()));
+        assert!(version(&cache, "a") > second);
+        assert_eq!(cache.rename("a", String::from("c"), false), Ok(()));
+        assert_eq!(cache.check_version("a", Some(0)), Ok(()));
+        assert!(version(&cache, "c") > second);
+
+        // versions are not reused once the key is gone
+        assert!(cache.delete("c"));
+        assert!(cache.set(String::from("c"), b"3".to_vec()).is_ok());
+        assert!(version(&cache, "c") > second);
+    }
+
+    fn deferred_drop_cache<'a>(
+        time: &'a TestTime,
+        config: Config,
+    ) -> (TtlCache<'a, TestTime>, DeferredDrop) {
+        let deferred_drop = DeferredDrop::start(&config).unwrap();
+        let mut cache = TtlCache::new(config, time);
+        cache.set_deferred_drop(deferred_drop.clone());
+        (cache, deferred_drop)
+    }
+
+    #[test]
+    fn large_values_are_dropped_off_thread() {
+        let time = TestTime::new(Instant::now());
+        let (mut cache, deferred_drop) = deferred_drop_cache(
+            &time,
+            Config {
+                capacity: None,
+                deferred_drop_min_bytes: Some(1024),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+        );
+        let large = vec![7; 4096];
+
+        assert!(cache.set(String::from("key"), large.clone()).is_ok());
+        assert!(cache.set(String::from("key"), b"small".to_vec()).is_ok());
+        assert_eq!(cache.get("key"), Some(b"small".to_vec()));
+        assert_eq!((deferred_drop.drops(), deferred_drop.bytes()), (1, 4096));
+
+        assert!(cache.set(String::from("key"), large).is_ok());
+        assert!(cache.delete("key"));
+        assert_eq!(cache.get("key"), None);
+        assert_eq!((cache.keys_total, cache.bytes_total), (0, 0));
+        assert_eq!((deferred_drop.drops(), deferred_drop.bytes()), (2, 8192));
+
+        // small values are freed right away
+        assert!(cache.set(String::from("key"), b"small".to_vec()).is_ok());
+        assert!(cache.delete("key"));
+        assert_eq!((deferred_drop.drops(), deferred_drop.bytes()), (2, 8192));
+    }
+
+    #[test]
+    fn flush_with_deferred_drop_swaps_the_map_out() {
+        let time = TestTime::new(Instant::now());
+        let (mut cache, deferred_drop) = deferred_drop_cache(
+            &time,
+            Config {
+                capacity: None,
+                intern_values: true,
+                deferred_drop_min_bytes: Some(1024),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+        );
+        let events = EventSender::new(0);
+        let mut received = events.subscribe(None).receiver;
+        cache.set_event_sender(events);
+
+        for i in 0..100 {
+            // every value is shared by two keys
+            let value = format!("value{}", i / 2).into_bytes();
+            let tags = vec![format!("tag{}", i % 3)];
+            assert!(cache
+                .set_with_tags(format!("key{}", i), value, None, tags)
+                .is_ok());
+        }
+        while received.try_recv().is_ok() {}
+        assert_eq!(cache.flush(), 100);
+
+        assert_eq!(cache.get("key0"), None);
+        assert_eq!(
+            (cache.keys_total, cache.total_cost, cache.bytes_total),
+            (0, 0, 0)
+        );
+        assert_eq!((cache.interned_values(), cache.tag_count()), (0, 0));
+        assert_eq!(deferred_drop.drops(), 1);
+        // counted per entry, 20 of them hold `value0` to `value9` and 80 the longer ones
+        assert_eq!(deferred_drop.bytes(), 20 * 6 + 80 * 7);
+        let mut flushed = 0;
+        while let Ok(event) = received.try_recv() {
+            assert_eq!(event.kind, EventKind::Deleted);
+            flushed += 1;
+        }
+        assert_eq!(flushed, 100);
+
+        assert!(cache.set(String::from("key0"), b"value".to_vec()).is_ok());
+        assert_eq!(cache.get("key0"), Some(b"value".to_vec()));
+        assert_eq!((cache.keys_total, cache.interned_values()), (1, 1));
+    }
+
+    #[test]
+    fn key_events_are_published_to_subscribers() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+        let events = EventSender::new(0);
+        let mut rx = events.subscribe(None).receiver;
+        cache.set_event_sender(events);
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        time.add_secs(Duration::from_secs(11));
+        assert!(cache.get(&key).is_none());
+
+        let set = rx.try_recv().unwrap();
+        assert_eq!((set.kind, set.seq), (EventKind::Set, 1));
+        assert_eq!(set.value_len, Some(value.len()));
+        assert_eq!(set.ttl_remaining_ms, Some(10_000));
+        let expired = rx.try_recv().unwrap();
+        assert_eq!((expired.kind, expired.seq), (EventKind::Expired, 2));
+        assert_eq!(expired.ttl_remaining_ms, None);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn compact_releases_unused_map_capacity() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        // removed slots may be left as tombstones that hashbrown does not count
+        // in its capacity, so the map is grown well past what is checked below
+        for i in 0..1000 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+        time.add_secs(Duration::from_secs(11));
+        for i in 0..1000 {
+            assert!(cache.get(&format!("key{}", i)).is_none());
+        }
+        for i in 0..10 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+
+        let capacity_before = cache.map_capacity();
+        assert!(capacity_before > 10 * cache.keys_total);
+
+        cache.compact();
+
+        assert!(cache.map_capacity() < capacity_before);
+        assert!(cache.map_capacity() >= cache.keys_total);
+    }
+
+    fn xor(value: Vec<u8>) -> Vec<u8> {
+        value.into_iter().map(|b| b ^ 0x5a).collect()
+    }
+
+    #[test]
+    fn values_are_transformed_on_write_and_read() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+        // version byte in front makes stored value larger than the original
+        cache.set_transforms(
+            Arc::new(|v| [vec![1], xor(v)].concat()),
+            Arc::new(|v| xor(v[1..].to_vec())),
+        );
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+
+        let stored = &cache.cache.get(&key).unwrap().value;
+        assert_ne!(stored[1..], value[..]);
+        assert_eq!(cache.bytes_total, value.len() + 1);
+        assert_eq!(cache.get(&key), Some(value));
+
+        assert!(cache.set(key.clone(), b"v".to_vec()).is_ok());
+        assert_eq!(cache.bytes_total, 2);
+    }
+
+    #[test]
+    fn map_is_presized_with_initial_capacity_only() {
+        let time = TestTime::new(Instant::now());
+
+        let cache = TtlCache::new(
+            Config {
+                capacity: Some(10_000_000),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert_eq!(cache.map_capacity(), 0);
+
+        let cache = TtlCache::new(
+            Config {
+                capacity: None,
+                initial_capacity: Some(1000),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.map_capacity() >= 1000);
+    }
+
+    #[test]
+    fn map_growths_are_counted_unless_reserved_ahead() {
+        let time = TestTime::new(Instant::now());
+        let config = Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+
+        let mut cache = TtlCache::new(config.clone(), &time);
+        for i in 0..1000 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+        assert!(cache.map_growths > 0);
+        let growths = cache.map_growths;
+        // overwrites never grow the map
+        for i in 0..1000 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+        assert_eq!(cache.map_growths, growths);
+        assert!(cache.map_load_factor() > 0.0 && cache.map_load_factor() <= 1.0);
+
+        let mut cache = TtlCache::new(config, &time);
+        cache.reserve(1000).unwrap();
+        for i in 0..1000 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+        assert_eq!(cache.map_growths, 0);
+    }
+
+    #[test]
+    fn map_is_shrunk_after_mass_expiry() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                shrink_after_flush: true,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        for i in 0..5000 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+        let capacity_before = cache.map_capacity();
+
+        time.add_secs(Duration::from_secs(11));
+        cache.evict_expired();
+
+        assert!(cache.keys_total < 5000 / 4);
+        assert!(cache.map_capacity() < capacity_before / 2);
+    }
+
+    #[test]
+    fn corrupted_values_are_dropped_with_paranoid_checksums() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                paranoid_checksums: true,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        let key = String::from("key: String");
+        let value = b"value: String".to_vec();
+
+        assert!(cache.set(key.clone(), value.clone()).is_ok());
+        assert_eq!(cache.get(&key), Some(value));
+
+        let mut corrupted = b"value: String".to_vec();
+        corrupted[0] ^= 0x01;
+        cache.cache.get_mut(&key).unwrap().value = corrupted.into();
+
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.checksum_mismatches, 1);
+        assert_eq!(cache.keys_total, 0);
+    }
+
+    #[test]
+    fn oldest_sampled_entry_is_evicted_when_at_capacity() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(3),
+                // sample covers every entry, so the oldest one is always picked
+                eviction_number: 3,
+                eviction_policy: EvictionPolicy::OldestFirst,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            time.add_secs(Duration::from_secs(i as u64));
+            cache.set(key.to_string(), vec![1]).unwrap();
+        }
+        time.add_secs(Duration::from_secs(3));
+        cache.set(String::from("d"), vec![2]).unwrap();
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("d"), Some(vec![2]));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.capacity_evictions, 1);
+        assert_eq!(cache.expirations, 0);
+    }
+
+    #[test]
+    fn glob_patterns_match_keys() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("foo:*", "foo:1"));
+        assert!(glob_match("foo:*", "foo:"));
+        assert!(!glob_match("foo:*", "bar:1"));
+        assert!(glob_match("*:1?", "foo:12"));
+        assert!(!glob_match("*:1?", "foo:1"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn keys_matching_pattern_are_counted() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        for key in ["foo:1", "foo:2", "foo:bar", "bar:1", "foo"].iter() {
+            cache.set(key.to_string(), vec![1]).unwrap();
+        }
+
+        assert_eq!(cache.count_matching("foo:*"), 3);
+        assert_eq!(cache.count_matching("*:1"), 2);
+        assert_eq!(cache.count_matching("foo:?"), 2);
+        assert_eq!(cache.count_matching("baz*"), 0);
+        assert_eq!(cache.count_matching("*"), cache.keys_total);
+
+        // expired but not yet evicted keys are not counted
+        time.add_secs(Duration::from_secs(11));
+        assert_eq!(cache.count_matching("*"), 0);
+    }
+
+    fn init_interning_cache<'a>(time: &'a TestTime) -> TtlCache<'a, TestTime> {
+        TtlCache::new(
+            Config {
+                capacity: None,
+                intern_values: true,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            time,
+        )
+    }
+
+    #[test]
+    fn identical_values_are_stored_once_when_interned() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_interning_cache(&time);
+        let value = vec![7u8; 4096];
+
+        for i in 0..100 {
+            assert!(cache.set(format!("key{}", i), value.clone()).is_ok());
+        }
+        assert!(cache.set(String::from("other"), b"other".to_vec()).is_ok());
+
+        assert_eq!(cache.keys_total, 101);
+        assert_eq!(cache.interned_values(), 2);
+        assert_eq!(cache.bytes_total, value.len() + 5);
+        assert_eq!(cache.get("key42"), Some(value));
+    }
+
+    #[test]
+    fn interned_value_lives_until_last_entry_is_removed() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_interning_cache(&time);
+        let value = vec![7u8; 4096];
+
+        for i in 0..10 {
+            assert!(cache.set(format!("key{}", i), value.clone()).is_ok());
+        }
+        for i in 1..10 {
+            assert!(cache.delete(&format!("key{}", i)));
+        }
+        // overwriting with the same value keeps sharing it
+        assert!(cache.set(String::from("key0"), value.clone()).is_ok());
+
+        assert_eq!(cache.get("key0"), Some(value.clone()));
+        assert_eq!(cache.interned_values(), 1);
+        assert_eq!(cache.bytes_total, value.len());
+
+        assert!(cache.set(String::from("key0"), b"v".to_vec()).is_ok());
+        assert_eq!(cache.interned_values(), 1);
+        assert_eq!(cache.bytes_total, 1);
+
+        assert!(cache.delete("key0"));
+        assert_eq!(cache.interned_values(), 0);
+        assert_eq!(cache.bytes_total, 0);
+    }
+
+    #[test]
+    fn expired_entries_are_left_to_eviction_without_lazy_expiry() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                lazy_expiry: false,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        for i in 0..10 {
+            assert!(cache.set(format!("key{}", i), b"value".to_vec()).is_ok());
+        }
+        time.add_secs(Duration::from_secs(11));
+
+        for i in 0..10 {
+            assert!(cache.get(&format!("key{}", i)).is_none());
+        }
+        assert_eq!(cache.keys_total, 10);
+        assert_eq!(cache.bytes_total, 50);
+
+        cache.evict_expired();
+        assert_eq!(cache.keys_total, 0);
+        assert_eq!(cache.bytes_total, 0);
+    }
+
+    #[test]
+    fn entries_read_within_idle_ttl_never_expire() {
+        for expiry_mode in [ExpiryMode::Lazy, ExpiryMode::Eager].iter() {
+            let time = TestTime::new(Instant::now());
+            let mut cache = TtlCache::new(
+                Config {
+                    capacity: None,
+                    idle_ttl: Some(Duration::from_secs(5)),
+                    expiry_mode: *expiry_mode,
+                    ..TEST_CONFIG_SINGLE_ITEM
+                },
+                &time,
+            );
+            for key in ["read", "untouched"].iter() {
+                assert!(cache.set(key.to_string(), b"value".to_vec()).is_ok());
+            }
+            // ttl of its own expires by age no matter how often it is read
+            assert!(cache
+                .set_with_ttl(
+                    String::from("own ttl"),
+                    b"value".to_vec(),
+                    Some(Duration::from_secs(8))
+                )
+                .is_ok());
+
+            for secs in (3..=60).step_by(3) {
+                time.add_secs(Duration::from_secs(secs));
+                cache.remove_due();
+                assert_eq!(cache.get("read"), Some(b"value".to_vec()), "{}s", secs);
+                assert_eq!(cache.get("own ttl").is_some(), secs <= 8, "{}s", secs);
+                assert_eq!(cache.contains("untouched"), secs <= 5, "{}s", secs);
+                assert_eq!(
+                    cache.ttl_many(&[String::from("read")]),
+                    vec![Some(Duration::from_secs(5))]
+                );
+            }
+            cache.remove_all_expired();
+            assert_eq!(cache.keys_total, 1);
+        }
+    }
+
+    #[test]
+    fn peek_has_no_side_effects_unlike_get() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.set(String::from("live"), b"value".to_vec()).is_ok());
+        assert!(cache
+            .set_with_ttl(
+                String::from("expiring"),
+                b"value".to_vec(),
+                Some(Duration::from_secs(5))
+            )
+            .is_ok());
+
+        assert_eq!(cache.peek("live"), Some(b"value".to_vec()));
+        assert_eq!(cache.peek("missing"), None);
+        assert_eq!(cache.meta("live").unwrap().hits, 0);
+        assert_eq!((cache.hits, cache.misses), (0, 0));
+        assert_eq!(cache.get("live"), Some(b"value".to_vec()));
+        assert_eq!(cache.meta("live").unwrap().hits, 1);
+        assert_eq!((cache.hits, cache.misses), (1, 0));
+
+        time.add_secs(Duration::from_secs(6));
+        // expired entry stays in place for eviction
+        assert_eq!(cache.peek("expiring"), None);
+        assert_eq!(cache.keys_total, 2);
+        assert_eq!(cache.bytes_total, 10);
+        assert_eq!(cache.get("expiring"), None);
+        assert_eq!(cache.keys_total, 1);
+        assert_eq!(cache.bytes_total, 5);
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+    }
+
+    #[test]
+    fn explain_does_not_touch_entries() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(2),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        assert!(cache.set(String::from("old"), b"value".to_vec()).is_ok());
+        time.add_secs(Duration::from_secs(5));
+        assert!(cache.set(String::from("fresh"), b"value".to_vec()).is_ok());
+        assert!(cache.get("fresh").is_some());
+        time.add_secs(Duration::from_secs(11));
+
+        let fresh = cache.explain("fresh", None);
+        assert!(fresh.exists && !fresh.expired);
+        assert_eq!(fresh.ttl_remaining_ms, Some(4000));
+        assert_eq!(fresh.set, None);
+
+        let old = cache.explain("old", Some(10));
+        assert!(old.exists && old.expired);
+        assert_eq!(old.ttl_remaining_ms, None);
+        assert_eq!(old.expires_at.unwrap() + 5, fresh.expires_at.unwrap());
+
+        // at capacity, the expired entry holds its room until it is removed
+        let missing = cache.explain("missing", Some(10));
+        assert!(!missing.exists && !missing.expired);
+        assert_eq!(missing.expires_at, None);
+        let set = missing.set.unwrap();
+        assert!(!set.allowed && !set.reclaims_expired && !set.evicts);
+
+        assert_eq!(cache.keys_total, 2);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.meta("fresh").unwrap().hits, 1);
+    }
+
+    #[test]
+    fn explain_tells_when_set_would_fail() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        assert!(cache.set(String::from("a"), b"value".to_vec()).is_ok());
+
+        assert!(cache.explain("a", Some(10)).set.unwrap().allowed);
+        assert!(!cache.explain("b", Some(10)).set.unwrap().allowed);
+        assert_eq!(
+            cache.set(String::from("b"), vec![0; 10]),
+            Err(CacheError::OutOfCapacity(Some(1)))
+        );
+    }
+
+    fn txn_incr(key: &str, by: i64) -> TxnOp {
+        TxnOp::Incr {
+            key: key.to_string(),
+            by,
+        }
+    }
+
+    #[test]
+    fn txn_incr_builds_on_earlier_operations() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.set(String::from("hits"), b"40".to_vec()).is_ok());
+
+        let result = cache.apply_txn(vec![
+            txn_incr("hits", 1),
+            txn_incr("hits", 1),
+            TxnOp::Set {
+                key: String::from("a"),
+                value: String::from("10"),
+                ttl_secs: None,
+                nx: false,
+                xx: false,
+            },
+            txn_incr("a", -3),
+            txn_incr("new", 5),
+        ]);
+
+        assert!(result.committed);
+        assert_eq!(cache.get("hits"), Some(b"42".to_vec()));
+        assert_eq!(cache.get("a"), Some(b"7".to_vec()));
+        assert_eq!(cache.get("new"), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn txn_incr_keeps_remaining_ttl() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+        assert!(cache.set(String::from("a"), b"1".to_vec()).is_ok());
+        time.add_secs(Duration::from_secs(6));
+
+        assert!(cache.apply_txn(vec![txn_incr("a", 1)]).committed);
+        assert_eq!(cache.meta("a").unwrap().ttl_remaining_ms, 4000);
+    }
+
+    #[test]
+    fn txn_with_failing_incr_is_rolled_back() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(3),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        assert!(cache.set(String::from("name"), b"value".to_vec()).is_ok());
+        assert!(cache.set(String::from("a"), b"1".to_vec()).is_ok());
+
+        let result = cache.apply_txn(vec![txn_incr("a", 1), txn_incr("name", 1)]);
+        assert!(!result.committed);
+        assert_eq!(
+            result.results,
+            vec![TxnOpStatus::Ok, TxnOpStatus::NotAnInteger]
+        );
+
+        // third key fits, fourth one is over capacity mid-batch
+        let result = cache.apply_txn(vec![
+            txn_incr("a", 1),
+            txn_incr("b", 1),
+            txn_incr("c", 1),
+            txn_incr("a", 1),
+        ]);
+        assert!(!result.committed);
+        assert_eq!(
+            result.results,
+            vec![
+                TxnOpStatus::Ok,
+                TxnOpStatus::Ok,
+                TxnOpStatus::OutOfCapacity,
+                TxnOpStatus::Skipped
+            ]
+        );
+
+        assert_eq!(cache.get("a"), Some(b"1".to_vec()));
+        assert!(!cache.contains("b"));
+        assert_eq!(cache.keys_total, 2);
+    }
+
+    #[test]
+    fn value_len_is_reported_for_live_entries_only() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        assert!(cache.set(String::from("key"), b"value".to_vec()).is_ok());
+        assert_eq!(cache.value_len("key"), Some(5));
+        assert_eq!(cache.value_len("other"), None);
+        assert_eq!(cache.meta("key").unwrap().hits, 0);
+
+        time.add_secs(Duration::from_secs(11));
+        assert_eq!(cache.value_len("key"), None);
+    }
+
+    #[test]
+    fn value_len_is_of_value_after_read_transform() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+        cache.set_transforms(
+            Arc::new(|v| [vec![1], xor(v)].concat()),
+            Arc::new(|v| xor(v[1..].to_vec())),
+        );
+
+        assert!(cache.set(String::from("key"), b"value".to_vec()).is_ok());
+        assert_eq!(cache.value_len("key"), Some(5));
+    }
+
+    #[test]
+    fn reads_and_writes_are_counted() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        assert!(cache.set(String::from("key"), b"value".to_vec()).is_ok());
+        assert!(cache.set(String::from("key"), b"value".to_vec()).is_ok());
+        assert!(cache.set(String::from("other"), b"value".to_vec()).is_err());
+        assert!(cache.get("key").is_some());
+        assert!(cache.get("other").is_none());
+        time.add_secs(Duration::from_secs(11));
+        assert!(cache.get("key").is_none());
+
+        assert_eq!(
+            cache.counters(),
+            Counters {
+                hits: 1,
+                misses: 2,
+                sets: 2,
+                evictions: 1,
+            }
+        );
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn invalidating_tag_removes_only_tagged_keys() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        cache
+            .set_with_tags("a".into(), vec![1], None, tags(&["user:42", "org:7"]))
+            .unwrap();
+        cache
+            .set_with_tags("b".into(), vec![1], None, tags(&["user:42"]))
+            .unwrap();
+        cache
+            .set_with_tags("c".into(), vec![1], None, tags(&["org:7"]))
+            .unwrap();
+        cache.set("d".into(), vec![1]).unwrap();
+        assert_eq!(cache.tag_count(), 2);
+        assert_eq!(cache.tag_index_size(), 4);
+
+        assert_eq!(
+            cache.invalidate_tag("user:42"),
+            TagInvalidation {
+                removed: 2,
+                more: false
+            }
+        );
+        assert!(!cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("c"));
+        assert!(cache.contains("d"));
+        // `a` no longer holds on to `org:7`
+        assert_eq!(cache.tag_count(), 1);
+        assert_eq!(cache.tag_index_size(), 1);
+
+        assert_eq!(cache.invalidate_tag("user:42").removed, 0);
+    }
+
+    #[test]
+    fn tag_index_follows_overwrites_and_expiry() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        cache
+            .set_with_tags("a".into(), vec![1], None, tags(&["x", "y"]))
+            .unwrap();
+        cache
+            .set_with_tags("a".into(), vec![2], None, tags(&["y", "z"]))
+            .unwrap();
+        assert_eq!(cache.tag_count(), 2);
+        assert_eq!(cache.invalidate_tag("x").removed, 0);
+        assert!(cache.contains("a"));
+
+        cache.set("a".into(), vec![3]).unwrap();
+        assert_eq!(cache.tag_count(), 0);
+
+        cache
+            .set_with_tags("b".into(), vec![1], None, tags(&["x"]))
+            .unwrap();
+        time.add_secs(Duration::from_secs(11));
+        cache.evict_expired();
+        assert_eq!(cache.tag_count(), 0);
+        assert_eq!(cache.tag_index_size(), 0);
+    }
+
+    #[test]
+    fn tag_index_over_its_cap_drops_entries_with_the_smallest_tags() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                max_tag_index_refs: Some(5),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        let set = |cache: &mut TtlCache<TestTime>, key: &str, with: &[&str]| {
+            cache
+                .set_with_tags(key.into(), vec![1], None, tags(with))
+                .unwrap()
+        };
+
+        set(&mut cache, "a", &["x", "y"]);
+        set(&mut cache, "b", &["x"]);
+        set(&mut cache, "c", &["x", "p"]);
+        assert_eq!(cache.tag_index().usage().entries, 5);
+        assert_eq!(cache.tag_index().evicted(), 0);
+
+        // `p` has as few keys as `y`, but only a pinned entry carries it
+        cache.set_pinned("c", true).unwrap();
+        set(&mut cache, "d", &["w"]);
+        assert!(!cache.contains("a"));
+        for key in ["b", "c", "d"].iter() {
+            assert_eq!(cache.get(key), Some(vec![1]));
+        }
+        assert_eq!(cache.capacity_evictions, 1);
+        assert_eq!(
+            cache.tag_index().usage(),
+            AuxiliaryUsage {
+                entries: 4,
+                // x, p and w, twice, along with b and c under x, c under p and d under w
+                bytes: 2 * 3
+                    + 4
+                    + 4 * size_of::<String>()
+                    + 3 * (size_of::<String>()
+                        + size_of::<HashSet<String>>()
+                        + size_of::<(usize, String)>()),
+                cap: Some(5),
+                evicted: 2,
+            }
+        );
+        assert_eq!(cache.tag_index_size(), 4);
+
+        // index still reflects every entry left
+        assert_eq!(cache.invalidate_tag("w").removed, 1);
+        assert_eq!(cache.invalidate_tag("y").removed, 0);
+        assert_eq!(cache.invalidate_tag("x").removed, 2);
+        assert_eq!(cache.tag_index().usage().entries, 0);
+        assert_eq!(cache.tag_index().usage().bytes, 0);
+    }
+
+    #[test]
+    fn tag_index_over_its_cap_picks_tags_by_their_current_size() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                max_tag_index_refs: Some(5),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        let set = |cache: &mut TtlCache<TestTime>, key: &str, with: &[&str]| {
+            cache
+                .set_with_tags(key.into(), vec![1], None, tags(with))
+                .unwrap()
+        };
+
+        for key in ["a", "b", "c"].iter() {
+            set(&mut cache, key, &["x"]);
+        }
+        set(&mut cache, "d", &["y"]);
+        set(&mut cache, "e", &["y"]);
+        // `x` shrinks to as few keys as `z`, which it goes before by name
+        assert!(cache.delete("b"));
+        assert!(cache.delete("c"));
+        set(&mut cache, "f", &["y"]);
+        set(&mut cache, "g", &["z"]);
+
+        set(&mut cache, "h", &["w"]);
+        assert!(!cache.contains("a"));
+        for key in ["d", "e", "f", "g", "h"].iter() {
+            assert!(cache.contains(key));
+        }
+        assert_eq!(cache.tag_index().usage().entries, 5);
+    }
+
+    #[test]
+    fn tags_per_key_are_limited() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        assert_eq!(
+            cache.set_with_tags("a".into(), vec![1], None, tags(&["x", "y", "z"])),
+            Err(CacheError::TooManyTags(2))
+        );
+        // duplicates count once
+        assert!(cache
+            .set_with_tags("a".into(), vec![1], None, tags(&["x", "y", "x"]))
+            .is_ok());
+        assert_eq!(cache.tag_index_size(), 2);
+    }
+
+    #[test]
+    fn large_tag_invalidation_is_done_in_batches() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                max_tag_invalidation_batch: 2,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        for key in ["a", "b", "c"].iter() {
+            cache
+                .set_with_tags(key.to_string(), vec![1], None, tags(&["t"]))
+                .unwrap();
+        }
+
+        assert_eq!(
+            cache.invalidate_tag("t"),
+            TagInvalidation {
+                removed: 2,
+                more: true
+            }
+        );
+        assert_eq!(
+            cache.invalidate_tag("t"),
+            TagInvalidation {
+                removed: 1,
+                more: false
+            }
+        );
+        assert!(cache.is_empty());
+        assert_eq!(cache.tag_count(), 0);
+    }
+
+    #[test]
+    fn tag_invalidation_leaves_keys_it_is_not_allowed_to_touch() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                max_tag_invalidation_batch: 1,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        for key in ["a:1", "a:2", "b:1"].iter() {
+            cache
+                .set_with_tags(key.to_string(), vec![1], None, tags(&["t"]))
+                .unwrap();
+        }
+        let allowed = |key: &str| key.starts_with("a:");
+
+        assert_eq!(
+            cache.invalidate_tag_matching("t", allowed),
+            TagInvalidation {
+                removed: 1,
+                more: true
+            }
+        );
+        assert_eq!(
+            cache.invalidate_tag_matching("t", allowed),
+            TagInvalidation {
+                removed: 1,
+                more: false
+            }
+        );
+        assert!(cache.contains("b:1"));
+        assert_eq!(cache.tag_count(), 1);
+    }
+
+    fn init_byte_budget_cache<'a>(
+        time: &'a TestTime,
+        eviction_policy: EvictionPolicy,
+    ) -> TtlCache<'a, TestTime> {
+        TtlCache::new(
+            Config {
+                capacity: None,
+                max_bytes: Some(100),
+                // sample covers every entry, so the largest one is always picked
+                eviction_number: 10,
+                eviction_policy,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            time,
+        )
+    }
+
+    #[test]
+    fn largest_entries_are_evicted_until_value_fits_byte_budget() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_byte_budget_cache(&time, EvictionPolicy::LargestFirst);
+
+        for (key, size) in [("a", 40), ("b", 10), ("c", 30)].iter() {
+            cache.set(key.to_string(), vec![1; *size]).unwrap();
+        }
+        assert_eq!(cache.bytes_total, 80);
+
+        cache.set(String::from("d"), vec![2; 70]).unwrap();
+
+        assert!(!cache.contains("a"));
+        assert!(!cache.contains("c"));
+        assert!(cache.contains("b"));
+        assert!(cache.contains("d"));
+        assert_eq!(cache.bytes_total, 80);
+        assert_eq!(cache.capacity_evictions, 2);
+    }
+
+    #[test]
+    fn writes_over_byte_budget_are_rejected() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_byte_budget_cache(&time, EvictionPolicy::RejectWrites);
+
+        cache.set(String::from("a"), vec![1; 60]).unwrap();
+        assert_eq!(
+            cache.set(String::from("b"), vec![1; 50]),
+            Err(CacheError::OutOfBytes(100))
+        );
+        // replaced value is not counted against the budget
+        cache.set(String::from("a"), vec![2; 90]).unwrap();
+        assert_eq!(cache.bytes_total, 90);
+
+        // expired entries are reclaimed before rejecting
+        time.add_secs(Duration::from_secs(11));
+        cache.set(String::from("b"), vec![1; 50]).unwrap();
+        assert_eq!(cache.bytes_total, 50);
+
+        let mut cache = init_byte_budget_cache(&time, EvictionPolicy::LargestFirst);
+        assert_eq!(
+            cache.set(String::from("a"), vec![1; 101]),
+            Err(CacheError::OutOfBytes(100))
+        );
+    }
+
+    #[test]
+    fn txn_is_rejected_when_over_byte_budget() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_byte_budget_cache(&time, EvictionPolicy::RejectWrites);
+        let set = |key: &str, size: usize| TxnOp::Set {
+            key: key.to_string(),
+            value: "x".repeat(size),
+            ttl_secs: None,
+            nx: false,
+            xx: false,
+        };
+
+        let result = cache.apply_txn(vec![set("a", 60), set("b", 50)]);
+
+        assert!(!result.committed);
+        assert_eq!(result.results[1], TxnOpStatus::OutOfCapacity);
+        assert_eq!(cache.bytes_total, 0);
+    }
+
+    #[test]
+    fn txn_is_rolled_back_when_transformed_value_does_not_fit() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_byte_budget_cache(&time, EvictionPolicy::RejectWrites);
+        // stored values are twice as large as the ones the transaction is validated with
+        cache.set_transforms(
+            Arc::new(|v| [v.clone(), v].concat()),
+            Arc::new(|v| v[..v.len() / 2].to_vec()),
+        );
+        cache
+            .set_with_ttl(
+                String::from("a"),
+                b"a".repeat(20),
+                Some(Duration::from_secs(5)),
+            )
+            .unwrap();
+        let set = |key: &str, size: usize| TxnOp::Set {
+            key: key.to_string(),
+            value: "x".repeat(size),
+            ttl_secs: None,
+            nx: false,
+            xx: false,
+        };
+
+        let result = cache.apply_txn(vec![
+            TxnOp::Del {
+                key: String::from("a"),
+            },
+            set("b", 30),
+            set("c", 25),
+        ]);
+
+        assert_eq!(
+            result,
+            TxnResult {
+                committed: false,
+                results: vec![TxnOpStatus::Ok, TxnOpStatus::Ok, TxnOpStatus::OutOfCapacity],
+            }
+        );
+        assert_eq!(cache.get("a"), Some(b"a".repeat(20)));
+        assert!(!cache.contains("b"));
+        assert_eq!(cache.bytes_total, 40);
+        assert_eq!(cache.keys_total, 1);
+        time.add_secs(Duration::from_secs(6));
+        assert!(!cache.contains("a"));
+    }
+
+    #[test]
+    fn refresh_lock_is_granted_once_until_marker_expires() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        let lock_ttl = Duration::from_secs(5);
+
+        assert_eq!(cache.get_or_lock("key", lock_ttl), LockedRead::Granted);
+        assert_eq!(cache.get_or_lock("key", lock_ttl), LockedRead::Wait);
+
+        time.add_secs(Duration::from_secs(6));
+        assert_eq!(cache.get_or_lock("key", lock_ttl), LockedRead::Granted);
+
+        cache.set(String::from("key"), vec![1]).unwrap();
+        assert_eq!(
+            cache.get_or_lock("key", lock_ttl),
+            LockedRead::Value(vec![1])
+        );
+    }
+
+    #[test]
+    fn entries_are_removed_right_after_expiry_in_eager_mode() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                expiry_mode: ExpiryMode::Eager,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        cache.set(String::from("a"), vec![1]).unwrap();
+        cache
+            .set_with_ttl(String::from("b"), vec![1], Some(Duration::from_secs(5)))
+            .unwrap();
+        // overwrite and expire reschedule the entry instead of adding another one
+        cache.set(String::from("a"), vec![2]).unwrap();
+        assert!(cache.expire("b", Duration::from_secs(20)));
+        assert_eq!(
+            cache.next_expiry(),
+            Some(time.get_time() + Duration::from_secs(10))
+        );
+
+        time.add_secs(Duration::from_secs(10));
+        assert_eq!(cache.remove_due(), 0);
+
+        time.add_secs(Duration::from_secs(11));
+        assert_eq!(cache.remove_due(), 1);
+        assert_eq!(cache.keys_total, 1);
+        assert_eq!(cache.cache.len(), 1);
+        assert_eq!(cache.expirations, 1);
+
+        time.add_secs(Duration::from_secs(21));
+        assert_eq!(cache.remove_due(), 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.next_expiry(), None);
+    }
+
+    #[test]
+    fn writes_at_capacity_reclaim_entries_due_in_eager_mode() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(1),
+                expiry_mode: ExpiryMode::Eager,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        cache
+            .set_with_ttl(String::from("a"), vec![1], Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(cache.set(String::from("b"), vec![1]).is_err());
+
+        time.add_secs(Duration::from_secs(6));
+        let set = cache.explain("b", Some(1)).set.unwrap();
+        assert!(set.allowed && set.reclaims_expired);
+        cache.set(String::from("b"), vec![1]).unwrap();
+        assert!(!cache.contains("a"));
+        assert_eq!(cache.keys_total, 1);
+    }
+
+    #[test]
+    fn queued_expiry_without_entry_does_not_stall_removal() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                expiry_mode: ExpiryMode::Eager,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        cache.set(String::from("a"), vec![1]).unwrap();
+        let at = time.get_time();
+        cache
+            .expiry_queue
+            .as_mut()
+            .unwrap()
+            .insert((at, String::from("gone")));
+
+        time.add_secs(Duration::from_secs(11));
+        assert_eq!(cache.remove_due(), 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.next_expiry(), None);
+    }
+
+    fn bytes_capacity(capacity: usize, eviction_policy: EvictionPolicy) -> Config {
+        Config {
+            capacity: Some(capacity),
+            capacity_unit: CapacityUnit::Bytes,
+            eviction_policy,
+            ..TEST_CONFIG_SINGLE_ITEM
+        }
+    }
+
+    #[test]
+    fn overwrite_growing_entry_cost_is_checked_against_capacity() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(bytes_capacity(10, EvictionPolicy::RejectWrites), &time);
+
+        cache.set(String::from("a"), vec![0; 4]).unwrap();
+        cache.set(String::from("b"), vec![0; 4]).unwrap();
+        assert_eq!(cache.total_cost, 8);
+
+        // replaced value is freed, so growing `a` to 6 bytes still fits
+        cache.set(String::from("a"), vec![0; 6]).unwrap();
+        assert_eq!(cache.total_cost, 10);
+        assert_eq!(
+            cache.set(String::from("a"), vec![0; 7]),
+            Err(CacheError::OutOfCapacity(Some(10)))
+        );
+        assert_eq!(cache.total_cost, 10);
+        assert_eq!(cache.get("a"), Some(vec![0; 6]));
+    }
+
+    #[test]
+    fn overwrite_shrinking_entry_cost_frees_capacity() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(bytes_capacity(10, EvictionPolicy::RejectWrites), &time);
+
+        cache.set(String::from("a"), vec![0; 10]).unwrap();
+        assert_eq!(
+            cache.set(String::from("b"), vec![0; 1]),
+            Err(CacheError::OutOfCapacity(Some(10)))
+        );
+
+        cache.set(String::from("a"), vec![0; 3]).unwrap();
+        assert_eq!(cache.total_cost, 3);
+        cache.set(String::from("b"), vec![0; 7]).unwrap();
+        assert_eq!(cache.total_cost, 10);
+
+        cache.delete("a");
+        assert_eq!(cache.total_cost, 7);
+        time.add_secs(Duration::from_secs(11));
+        cache.remove_all_expired();
+        assert_eq!(cache.total_cost, 0);
+    }
+
+    #[test]
+    fn large_entry_evicts_as_many_entries_as_needed() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(bytes_capacity(10, EvictionPolicy::OldestFirst), &time);
+
+        for i in 0..5 {
+            cache.set(format!("key{}", i), vec![0; 2]).unwrap();
+        }
+        cache.set(String::from("large"), vec![0; 9]).unwrap();
+        assert_eq!(cache.keys_total, 1);
+        assert_eq!(cache.total_cost, 9);
+        assert_eq!(cache.capacity_evictions, 5);
+
+        // no amount of eviction makes room for it, nothing is evicted trying
+        assert_eq!(
+            cache.set(String::from("huge"), vec![0; 11]),
+            Err(CacheError::OutOfCapacity(Some(10)))
+        );
+        assert!(cache.contains("large"));
+    }
+
+    #[test]
+    fn entries_unit_keeps_counting_entries() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(2),
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        cache.set(String::from("a"), vec![0; 100]).unwrap();
+        cache.set(String::from("b"), vec![0; 100]).unwrap();
+        cache.set(String::from("a"), vec![0; 1]).unwrap();
+        assert_eq!(cache.total_cost, 2);
+        assert!(cache.set(String::from("c"), vec![0; 1]).is_err());
+    }
+
+    #[test]
+    fn delete_can_be_conditioned_on_creation_time() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        time.add_secs(Duration::from_secs(5));
+        cache.set(String::from("key"), b"value".to_vec()).unwrap();
+        let created = cache.created_at_ms("key").unwrap();
+
+        assert_eq!(
+            cache.delete_if_created_before("key", created - 1000),
+            ConditionalDelete::CreatedAfter(created)
+        );
+        assert!(cache.contains("key"));
+        assert_eq!(
+            cache.delete_if_created_before("key", created + 1),
+            ConditionalDelete::Deleted
+        );
+        assert_eq!(
+            cache.delete_if_created_before("key", created + 1),
+            ConditionalDelete::Missing
+        );
+    }
+
+    #[test]
+    fn swap_exchanges_values_together_with_ttl_and_tags() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                expiry_mode: ExpiryMode::Eager,
+                paranoid_checksums: true,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+
+        cache
+            .set_with_tags(
+                String::from("front"),
+                b"old".to_vec(),
+                Some(Duration::from_secs(5)),
+                vec![String::from("t")],
+            )
+            .unwrap();
+        cache
+            .set_with_ttl(
+                String::from("back"),
+                b"new".to_vec(),
+                Some(Duration::from_secs(60)),
+            )
+            .unwrap();
+
+        assert_eq!(cache.swap("front", "back"), Ok(()));
+        assert_eq!(cache.get("front"), Some(b"new".to_vec()));
+        assert_eq!(cache.get("back"), Some(b"old".to_vec()));
+        assert_eq!(
+            cache.ttl_many(&[String::from("front")])[0],
+            Some(Duration::from_secs(60))
+        );
+
+        // old value expires under its new key
+        time.add_secs(Duration::from_secs(6));
+        assert_eq!(cache.remove_due(), 1);
+        assert!(cache.contains("front"));
+        assert!(!cache.contains("back"));
+        assert_eq!(cache.tag_count(), 0);
+    }
+
+    #[test]
+    fn swap_fails_when_either_key_is_missing() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        cache.set(String::from("a"), b"1".to_vec()).unwrap();
+
+        assert_eq!(
+            cache.swap("a", "missing"),
+            Err(CacheError::NotFound(String::from("missing")))
+        );
+        assert_eq!(
+            cache.swap("missing", "a"),
+            Err(CacheError::NotFound(String::from("missing")))
+        );
+        assert_eq!(cache.get("a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn rename_moves_entry_with_its_ttl_and_tags() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: Some(2),
+                expiry_mode: ExpiryMode::Eager,
+                paranoid_checksums: true,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        let events = EventSender::new(0);
+        let mut rx = events.subscribe(None).receiver;
+        cache.set_event_sender(events);
+
+        cache
+            .set_with_tags(
+                String::from("tmp"),
+                b"built".to_vec(),
+                Some(Duration::from_secs(5)),
+                vec![String::from("t")],
+            )
+            .unwrap();
+        cache.set(String::from("final"), b"old".to_vec()).unwrap();
+
+        assert_eq!(
+            cache.rename("tmp", String::from("final"), false),
+            Err(CacheError::Conflict(String::from("final")))
+        );
+        assert_eq!(cache.get("final"), Some(b"old".to_vec()));
+        assert_eq!(
+            cache.rename("missing", String::from("final"), true),
+            Err(CacheError::NotFound(String::from("missing")))
+        );
+
+        while rx.try_recv().is_ok() {}
+        assert_eq!(cache.rename("tmp", String::from("final"), true), Ok(()));
+        let kinds: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|e| (e.key, e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (String::from("final"), EventKind::Replaced),
+                (String::from("tmp"), EventKind::Deleted),
+                (String::from("final"), EventKind::Set),
+            ]
+        );
+        assert!(!cache.contains("tmp"));
+        assert_eq!(cache.get("final"), Some(b"built".to_vec()));
+        assert_eq!(cache.keys_total, 1);
+        assert_eq!(cache.tag_index_size(), 1);
+        assert_eq!(
+            cache.ttl_many(&[String::from("final")])[0],
+            Some(Duration::from_secs(5))
+        );
+        // freed by the overwrite
+        cache.set(String::from("other"), b"1".to_vec()).unwrap();
+
+        // still expires when it would have under the old key
+        time.add_secs(Duration::from_secs(6));
+        assert_eq!(cache.remove_due(), 1);
+        assert!(!cache.contains("final"));
+        assert_eq!(
+            cache.rename("final", String::from("again"), false),
+            Err(CacheError::NotFound(String::from("final")))
+        );
+    }
+
+    #[test]
+    fn pinned_entries_are_neither_expired_nor_evicted_until_unpinned() {
+        for expiry_mode in [ExpiryMode::Lazy, ExpiryMode::Eager] {
+            let time = TestTime::new(Instant::now());
+            let mut cache = TtlCache::new(
+                Config {
+                    capacity: Some(3),
+                    eviction_policy: EvictionPolicy::OldestFirst,
+                    expiry_mode,
+                    ..TEST_CONFIG_SINGLE_ITEM
+                },
+                &time,
+            );
+            for key in ["pinned", "a", "b"] {
+                cache.set(String::from(key), b"v".to_vec()).unwrap();
+            }
+            assert_eq!(
+                cache.set_pinned("missing", true),
+                Err(CacheError::NotFound(String::from("missing")))
+            );
+            assert_eq!(cache.set_pinned("pinned", true), Ok(()));
+
+            time.add_secs(Duration::from_secs(11));
+            cache.remove_due();
+            cache.evict_expired();
+            assert!(!cache.contains("a") && !cache.contains("b"));
+            assert_eq!(cache.get("pinned"), Some(b"v".to_vec()));
+            assert_eq!(
+                cache.ttl_many(&[String::from("pinned")])[0],
+                Some(Duration::ZERO)
+            );
+
+            // oldest entry is pinned, so the other one is evicted, pin survives the rewrite
+            cache.set(String::from("c"), b"v".to_vec()).unwrap();
+            cache.set(String::from("d"), b"v".to_vec()).unwrap();
+            cache.set(String::from("pinned"), b"new".to_vec()).unwrap();
+            cache.set(String::from("e"), b"v".to_vec()).unwrap();
+            assert_eq!(cache.capacity_evictions, 1);
+            assert_eq!(cache.get("pinned"), Some(b"new".to_vec()));
+
+            // pinned entries still count against capacity and are not evicted for room
+            cache.set_pinned("d", true).ok();
+            cache.set_pinned("e", true).ok();
+            cache.set_pinned("c", true).ok();
+            assert!(cache.set(String::from("f"), b"v".to_vec()).is_err());
+
+            assert_eq!(cache.set_pinned("pinned", false), Ok(()));
+            time.add_secs(Duration::from_secs(22));
+            cache.remove_due();
+            assert_eq!(cache.get("pinned"), None);
+        }
+    }
+
+    #[test]
+    fn ring_push_drops_oldest_lines_beyond_limit() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = init_cache(&time);
+
+        assert_eq!(cache.ring_push("log", b"a", 3), Ok(1));
+        assert_eq!(cache.ring_push("log", b"b", 3), Ok(2));
+        assert_eq!(cache.ring_push("log", b"c", 3), Ok(3));
+        assert_eq!(cache.get("log"), Some(b"a\nb\nc".to_vec()));
+
+        assert_eq!(cache.ring_push("log", b"d", 3), Ok(3));
+        assert_eq!(cache.ring_push("log", b"e", 3), Ok(3));
+        assert_eq!(cache.get("log"), Some(b"c\nd\ne".to_vec()));
+
+        // smaller limit trims what is already there
+        assert_eq!(cache.ring_push("log", b"f", 1), Ok(1));
+        assert_eq!(cache.get("log"), Some(b"f".to_vec()));
+    }
+
+    #[test]
+    fn ring_push_keeps_expiry_of_the_entry() {
+        let time = TestTime::new(Instant::now());
+        let mut cache = TtlCache::new(
+            Config {
+                capacity: None,
+                ..TEST_CONFIG_SINGLE_ITEM
+            },
+            &time,
+        );
+        cache
+            .set_with_ttl(
+                String::from("log"),
+                b"a".to_vec(),
+                Some(Duration::from_secs(10)),
+            )
+            .unwrap();
+
+        time.add_secs(Duration::from_secs(4));
+        assert_eq!(cache.ring_push("log", b"b", 10), Ok(2));
+        assert_eq!(
+            cache.ttl_many(&[String::from("log")])[0],
+            Some(Duration::from_secs(6))
+        );
+
+        time.add_secs(Duration::from_secs(11));
+        assert_eq!(cache.get("log"), None);
+        // expired value is not carried over
+        assert_eq!(cache.ring_push("log", b"c", 10), Ok(1));
+    }
+}
+
+// random operation sequences are executed against both the cache and a naive model,
+// observable results have to agree after every step
+#[cfg(test)]
+mod cache_model_tests {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use proptest::prelude::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::cache::CacheError;
+    use crate::cache::TtlCache;
+    use crate::config::Config;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::time::time_fixtures::TestTime;
+    use crate::time::Time;
+
+    const KEYS: [&str; 5] = ["a", "b", "c", "d", "e"];
+    const CAPACITY: usize = 3;
+    const TTL_SECS: u64 = 10;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Set(usize, u8, Option<u64>),
+        Get(usize),
+        Delete(usize),
+        AdvanceSecs(u64),
+        Evict,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let key = 0..KEYS.len();
+        prop_oneof![
+            (
+                key.clone(),
+                any::<u8>(),
+                proptest::option::of(0..2 * TTL_SECS)
+            )
+                .prop_map(|(k, v, ttl)| Op::Set(k, v, ttl)),
+            key.clone().prop_map(Op::Get),
+            key.prop_map(Op::Delete),
+            (0..TTL_SECS + 5).prop_map(Op::AdvanceSecs),
+            Just(Op::Evict),
+        ]
+    }
+
+    // key, value and the instant entry expires after, expired entries are kept and take
+    // room until they are read, deleted or evicted
+    struct Model {
+        entries: Vec<(String, Vec<u8>, Instant)>,
+    }
+
+    impl Model {
+        fn set(&mut self, key: &str, value: Vec<u8>, ttl: Duration, now: Instant) -> bool {
+            let stored = self.entries.len();
+            match self.entries.iter_mut().find(|(k, _, _)| k == key) {
+                Some(entry) => *entry = (key.to_string(), value, now + ttl),
+                None if stored < CAPACITY => self.entries.push((key.to_string(), value, now + ttl)),
+                None => return false,
+            }
+            true
+        }
+
+        // expired entry is dropped when read, see `Config::lazy_expiry`
+        fn read(&mut self, key: &str, now: Instant) -> Option<Vec<u8>> {
+            let value = self.get(key, now);
+            self.entries
+                .retain(|(k, _, expires)| k != key || *expires >= now);
+            value
+        }
+
+        fn get(&self, key: &str, now: Instant) -> Option<Vec<u8>> {
+            self.entries
+                .iter()
+                .find(|(k, _, expires)| k == key && *expires >= now)
+                .map(|(_, v, _)| v.clone())
+        }
+
+        fn delete(&mut self, key: &str, now: Instant) -> bool {
+            let live = self.get(key, now).is_some();
+            self.entries.retain(|(k, _, _)| k != key);
+            live
+        }
+
+        fn live_keys(&self, now: Instant) -> usize {
+            self.entries
+                .iter()
+                .filter(|(_, _, expires)| *expires >= now)
+                .count()
+        }
+    }
+
+    fn run(ops: &[Op]) -> Result<(), TestCaseError> {
+        let time = TestTime::new(Instant::now());
+        let config = Config {
+            ttl: Duration::from_secs(TTL_SECS),
+            capacity: Some(CAPACITY),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let mut cache = TtlCache::new(config, &time);
+        let mut model = Model {
+            entries: Vec::new(),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut secs_passed = 0;
+
+        for op in ops {
+            let now = time.get_time();
+            match op {
+                Op::Set(k, v, ttl) => {
+                    let ttl = ttl.map(Duration::from_secs);
+                    let expected = model.set(
+                        KEYS[*k],
+                        vec![*v],
+                        ttl.unwrap_or(Duration::from_secs(TTL_SECS)),
+                        now,
+                    );
+                    let result = cache.set_with_ttl(KEYS[*k].to_string(), vec![*v], ttl);
+                    if expected {
+                        prop_assert_eq!(result, Ok(()));
+                    } else {
+                        prop_assert_eq!(result, Err(CacheError::OutOfCapacity(Some(CAPACITY))));
+                    }
+                }
+                Op::Get(k) => prop_assert_eq!(cache.get(KEYS[*k]), model.read(KEYS[*k], now)),
+                Op::Delete(k) => {
+                    prop_assert_eq!(cache.delete(KEYS[*k]), model.delete(KEYS[*k], now))
+                }
+                Op::AdvanceSecs(secs) => {
+                    secs_passed += secs;
+                    time.add_secs(Duration::from_secs(secs_passed));
+                }
+                Op::Evict => {
+                    cache.evict_expired_with(&mut rng);
+                    // pass samples at random, only expired entries may be gone after it
+                    for (k, _, expires) in model.entries.iter() {
+                        prop_assert!(*expires < now || cache.cache.contains_key(k));
+                    }
+                    model
+                        .entries
+                        .retain(|(k, _, _)| cache.cache.contains_key(k));
+                }
+            }
+
+            let now = time.get_time();
+            prop_assert_eq!(cache.keys_total, cache.len());
+            prop_assert_eq!(
+                cache.bytes_total,
+                cache.cache.values().map(|e| e.value.len()).sum::<usize>()
+            );
+            for k in KEYS.iter() {
+                prop_assert_eq!(cache.contains(k), model.get(k, now).is_some());
+            }
+            prop_assert_eq!(
+                KEYS.iter().filter(|k| cache.contains(k)).count(),
+                model.live_keys(now)
+            );
+        }
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn cache_agrees_with_model(ops in proptest::collection::vec(op(), 1..64)) {
+            run(&ops)?;
+        }
+    }
+
+    // expired entries not evicted yet keep new keys out, reading one of them makes room
+    #[test]
+    fn expired_entries_count_against_capacity_until_removed() {
+        run(&[
+            Op::Set(0, 0, None),
+            Op::Set(1, 0, None),
+            Op::Set(2, 0, Some(1)),
+            Op::AdvanceSecs(2),
+            Op::Set(3, 0, None),
+            Op::AdvanceSecs(TTL_SECS + 1),
+            Op::Set(4, 0, None),
+            Op::Get(0),
+            Op::Set(4, 0, None),
+            Op::Evict,
+            Op::Set(3, 0, None),
+        ])
+        .unwrap();
     }
 }
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::Response;
+use reqwest::StatusCode;
+use reqwest::Url;
+use serde::Deserialize;
+
+#[derive(Debug)]
+pub enum ClientError {
+    // server could not be reached or the response could not be read
+    Transport(reqwest::Error),
+    InvalidUrl(String),
+    // server was switched to read-only mode, see `/admin/read-only`
+    ReadOnly,
+    // value is over `max_value_bytes` of the server
+    ValueTooLarge(String),
+    // there is no room for the value, see `Expect: 100-continue` of `/set`
+    InsufficientStorage,
+    // request was not served within `request_timeout`
+    DeadlineExceeded,
+    // any other non-success status, with the error the server responded with
+    Status(StatusCode, String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "request failed: {}", e),
+            ClientError::InvalidUrl(url) => write!(f, "invalid url {}", url),
+            ClientError::ReadOnly => write!(f, "cache is in read-only mode"),
+            ClientError::ValueTooLarge(e) => write!(f, "value rejected: {}", e),
+            ClientError::InsufficientStorage => write!(f, "cache has no room for the value"),
+            ClientError::DeadlineExceeded => write!(f, "request deadline exceeded"),
+            ClientError::Status(status, e) => write!(f, "server responded with {}: {}", status, e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> ClientError {
+        ClientError::Transport(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+// async client of the HTTP API, statuses are mapped the way the server means them, e.g. a miss
+// is `Ok(None)` rather than an error
+#[derive(Clone)]
+pub struct CacheClient {
+    client: reqwest::Client,
+    base: Url,
+}
+
+impl CacheClient {
+    // `base` is scheme and authority, optionally with the `/v1` prefix, e.g. `http://127.0.0.1:8080`
+    pub fn new(base: &str) -> Result<CacheClient, ClientError> {
+        CacheClient::with_client(base, reqwest::Client::new())
+    }
+
+    // clients sharing a `reqwest::Client` share its connection pool
+    pub fn with_client(base: &str, client: reqwest::Client) -> Result<CacheClient, ClientError> {
+        let base =
+            Url::parse(base).map_err(|e| ClientError::InvalidUrl(format!("{}: {}", base, e)))?;
+        if base.cannot_be_a_base() {
+            return Err(ClientError::InvalidUrl(base.to_string()));
+        }
+        Ok(CacheClient { client, base })
+    }
+
+    // keys are sent as a single percent-encoded path segment
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(segments);
+        }
+        url
+    }
+
+    // `None` for missing or expired keys, whatever `miss_status` the server uses
+    pub async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ClientError> {
+        let response = self.client.get(self.url(&["get", key])).send().await?;
+        if response.status() == StatusCode::NOT_FOUND || is_miss(response.headers()) {
+            return Ok(None);
+        }
+        let response = check(response).await?;
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    // server only accepts UTF-8 values, others are rejected with `Status(400, ..)`
+    pub async fn set(&self, key: &str, value: impl Into<Vec<u8>>) -> Result<(), ClientError> {
+        let response = self
+            .client
+            .post(self.url(&["set", key]))
+            .body(value.into())
+            .send()
+            .await?;
+        check(response).await.map(|_| ())
+    }
+
+    // `false` when there was no entry under the key
+    pub async fn delete(&self, key: &str) -> Result<bool, ClientError> {
+        let response = self.client.delete(self.url(&["del", key])).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check(response).await.map(|_| true)
+    }
+
+    // remaining ttl, `None` for missing or expired keys, does not count as a read
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>, ClientError> {
+        let response = self
+            .client
+            .post(self.url(&["mttl"]))
+            .json(&[key])
+            .send()
+            .await?;
+        let ttls: Vec<Option<u64>> = check(response).await?.json().await?;
+        Ok(ttls.into_iter().next().flatten().map(Duration::from_millis))
+    }
+}
+
+// misses answered with an empty 200, see `MissStatus::OkEmpty`
+fn is_miss(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-cache-result")
+        .map(|v| v.as_bytes() == b"miss")
+        .unwrap_or(false)
+}
+
+async fn check(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let read_only = response.headers().contains_key("x-read-only");
+    // some errors are sent as `{"error": ..}`, others as plain text
+    let body = response.text().await?;
+    let error = serde_json::from_str::<ErrorBody>(&body)
+        .map(|b| b.error)
+        .unwrap_or(body);
+
+    Err(match status {
+        StatusCode::SERVICE_UNAVAILABLE if read_only => ClientError::ReadOnly,
+        StatusCode::PAYLOAD_TOO_LARGE => ClientError::ValueTooLarge(error),
+        StatusCode::INSUFFICIENT_STORAGE => ClientError::InsufficientStorage,
+        StatusCode::GATEWAY_TIMEOUT => ClientError::DeadlineExceeded,
+        status => ClientError::Status(status, error),
+    })
+}
+
+#[cfg(all(test, feature = "http-api"))]
+mod client_tests {
+    use crate::api::make_api;
+    use crate::api::Upstreams;
+    use crate::client::CacheClient;
+    use crate::client::ClientError;
+    use crate::config::Config;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::metrics::HttpMetrics;
+    use crate::service::service_queue;
+    use crate::service::ServiceFlags;
+    use crate::service::TtlCacheService;
+    use crate::time::REALTIME;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    // server on an ephemeral port, flags are returned to switch read-only mode
+    fn serve(config: Config) -> (CacheClient, Arc<ServiceFlags>) {
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let api = make_api(
+            tx,
+            &config,
+            flags.clone(),
+            Arc::new(HttpMetrics::new()),
+            Upstreams::new(&config).unwrap(),
+        );
+
+        let service_flags = flags.clone();
+        tokio::spawn(async move {
+            TtlCacheService::new(config, rx, service_flags, &REALTIME)
+                .run()
+                .await
+        });
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (
+            CacheClient::new(&format!("http://{}", addr)).unwrap(),
+            flags,
+        )
+    }
+
+    #[tokio::test]
+    async fn values_round_trip_through_the_client() {
+        let (client, _) = serve(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        assert_eq!(client.get("key").await.unwrap(), None);
+        assert_eq!(client.ttl("key").await.unwrap(), None);
+
+        client.set("key", "value").await.unwrap();
+        // sent percent-encoded as one path segment
+        client.set("user 42/name?", "Bob").await.unwrap();
+        assert_eq!(client.get("key").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(
+            client.get("user 42/name?").await.unwrap(),
+            Some(b"Bob".to_vec())
+        );
+        let ttl = client.ttl("key").await.unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(9) && ttl <= TEST_CONFIG_SINGLE_ITEM.ttl);
+
+        assert!(client.delete("key").await.unwrap());
+        assert!(!client.delete("key").await.unwrap());
+        assert_eq!(client.get("key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn error_statuses_are_mapped_to_client_errors() {
+        let (client, flags) = serve(Config {
+            capacity: None,
+            max_value_bytes: Some(4),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        match client.set("key", "too long").await {
+            Err(ClientError::ValueTooLarge(_)) => {}
+            other => panic!("expected value too large, got {:?}", other),
+        }
+        match client.set("key", vec![0xff, 0xfe]).await {
+            Err(ClientError::Status(StatusCode::BAD_REQUEST, e)) => {
+                assert!(e.contains("utf-8"), "{}", e)
+            }
+            other => panic!("expected bad request, got {:?}", other),
+        }
+
+        flags.set_read_only(true);
+        match client.set("key", "v").await {
+            Err(ClientError::ReadOnly) => {}
+            other => panic!("expected read-only, got {:?}", other),
+        }
+        match client.delete("key").await {
+            Err(ClientError::ReadOnly) => {}
+            other => panic!("expected read-only, got {:?}", other),
+        }
+
+        let unreachable = CacheClient::new("http://127.0.0.1:1").unwrap();
+        assert!(matches!(
+            unreachable.get("key").await,
+            Err(ClientError::Transport(_))
+        ));
+        assert!(matches!(
+            CacheClient::new("not a url"),
+            Err(ClientError::InvalidUrl(_))
+        ));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::config::Config;
+use crate::upstream::Forwarded;
+use crate::upstream::Upstream;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::Client;
+use hyper::Method;
+use serde::Serialize;
+use twox_hash::XxHash64;
+
+fn hash(bytes: &[u8]) -> u64 {
+    XxHash64::oneshot(0, bytes)
+}
+
+// consistent hash ring, every peer owns `virtual_nodes` points and a key belongs to the peer
+// owning the first point at or after its hash, so keys only move between peers when
+// the peer list changes and then only those of the added or removed peer
+pub struct Ring {
+    points: BTreeMap<u64, usize>,
+    peers: usize,
+}
+
+impl Ring {
+    pub fn new(peers: &[String], virtual_nodes: usize) -> Ring {
+        let mut points = BTreeMap::new();
+        for (i, peer) in peers.iter().enumerate() {
+            for node in 0..virtual_nodes.max(1) {
+                points.insert(hash(format!("{}#{}", peer, node).as_bytes()), i);
+            }
+        }
+
+        Ring {
+            points,
+            peers: peers.len(),
+        }
+    }
+
+    // index of the peer owning the key, `None` only for an empty ring
+    pub fn peer_for(&self, key: &str) -> Option<usize> {
+        self.points
+            .range(hash(key.as_bytes())..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, peer)| *peer)
+    }
+
+    // fraction of the hash space owned by every peer
+    pub fn shares(&self) -> Vec<f64> {
+        let mut owned = vec![0u64; self.peers];
+        let mut previous = self.points.keys().last().copied().unwrap_or(0);
+        for (point, peer) in &self.points {
+            // the first point also owns the wrapped around range after the last one
+            owned[*peer] = owned[*peer].wrapping_add(point.wrapping_sub(previous));
+            previous = *point;
+        }
+        owned
+            .into_iter()
+            .map(|o| o as f64 / u64::MAX as f64)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    // peer is skipped until then, one request is let through afterwards to probe it
+    down_until: Option<Instant>,
+}
+
+struct Peer {
+    upstream: Upstream,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerStatus {
+    pub url: String,
+    pub up: bool,
+    pub consecutive_failures: u32,
+    pub ring_share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClusterStatus {
+    pub virtual_nodes: usize,
+    pub peers: Vec<PeerStatus>,
+}
+
+// routes requests for a key to the peer owning it, see `cluster_peers`
+pub struct Cluster {
+    ring: Ring,
+    peers: Vec<Peer>,
+    virtual_nodes: usize,
+    failure_threshold: u32,
+    retry_interval: Duration,
+}
+
+impl Cluster {
+    pub fn new(config: &Config) -> Result<Cluster, String> {
+        // peers share a single connection pool
+        let client = Client::new();
+        let peers = config
+            .cluster_peers
+            .iter()
+            .map(|url| {
+                Ok(Peer {
+                    upstream: Upstream::with_client(url, client.clone())?,
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Cluster {
+            ring: Ring::new(&config.cluster_peers, config.cluster_virtual_nodes),
+            peers,
+            virtual_nodes: config.cluster_virtual_nodes,
+            failure_threshold: config.peer_failure_threshold.max(1),
+            retry_interval: config.peer_retry_interval,
+        })
+    }
+
+    fn is_up(&self, peer: &Peer, now: Instant) -> bool {
+        let health = peer.health.lock().unwrap();
+        health.down_until.map(|t| now >= t).unwrap_or(true)
+    }
+
+    fn record(&self, peer: &Peer, succeeded: bool) {
+        let mut health = peer.health.lock().unwrap();
+        if succeeded {
+            *health = Health::default();
+        } else {
+            health.consecutive_failures += 1;
+            if health.consecutive_failures >= self.failure_threshold {
+                health.down_until = Some(Instant::now() + self.retry_interval);
+            }
+        }
+    }
+
+    // passes the request to the peer owning the key, fails without trying while
+    // the peer is marked down, other peers keep serving their keys
+    pub async fn route(
+        &self,
+        key: &str,
+        method: Method,
+        path_and_query: &str,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Forwarded, String> {
+        let peer = self
+            .ring
+            .peer_for(key)
+            .map(|i| &self.peers[i])
+            .ok_or_else(|| String::from("no cluster peers configured"))?;
+        if !self.is_up(peer, Instant::now()) {
+            return Err(format!("peer {} is down", peer.upstream.base()));
+        }
+
+        let result = peer
+            .upstream
+            .forward(method, path_and_query, headers, body)
+            .await;
+        self.record(peer, result.is_ok());
+        result.map_err(|e| format!("peer {} failed: {}", peer.upstream.base(), e))
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let now = Instant::now();
+        let shares = self.ring.shares();
+        ClusterStatus {
+            virtual_nodes: self.virtual_nodes,
+            peers: self
+                .peers
+                .iter()
+                .zip(shares)
+                .map(|(peer, ring_share)| PeerStatus {
+                    url: peer.upstream.base().to_string(),
+                    up: self.is_up(peer, now),
+                    consecutive_failures: peer.health.lock().unwrap().consecutive_failures,
+                    ring_share,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod cluster_tests {
+    use crate::cluster::Ring;
+
+    fn peers() -> Vec<String> {
+        (0..3)
+            .map(|i| format!("http://127.0.0.1:{}", 8080 + i))
+            .collect()
+    }
+
+    #[test]
+    fn keys_map_stably_and_spread_over_peers() {
+        let ring = Ring::new(&peers(), 64);
+        let same = Ring::new(&peers(), 64);
+
+        let mut counts = [0; 3];
+        for i in 0..3000 {
+            let key = format!("key{}", i);
+            let peer = ring.peer_for(&key).unwrap();
+            assert_eq!(same.peer_for(&key), Some(peer));
+            counts[peer] += 1;
+        }
+        assert!(counts.iter().all(|c| *c > 500), "{:?}", counts);
+
+        let shares = ring.shares();
+        assert!((shares.iter().sum::<f64>() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn only_keys_of_removed_peer_move() {
+        let all = peers();
+        let ring = Ring::new(&all, 64);
+        let without_last = Ring::new(&all[..2], 64);
+
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            let peer = ring.peer_for(&key).unwrap();
+            if peer != 2 {
+                assert_eq!(without_last.peer_for(&key), Some(peer));
+            }
+        }
+    }
+}
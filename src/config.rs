@@ -1,19 +1,766 @@
+use crate::duration::deserialize_secs;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use ipnet::IpNet;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[cfg(feature = "unicode")]
+use unicode_normalization::UnicodeNormalization;
+
 #[derive(Clone)]
 pub struct Config {
     pub ttl: Duration,
+    // entries expire once not read nor written for this long instead of `ttl` after they
+    // were set, entries given a ttl of their own still expire by age
+    pub idle_ttl: Option<Duration>,
+    // hard limit on summed cost of entries, a number of entries unless `capacity_unit` says otherwise
     pub capacity: Option<usize>,
+    pub capacity_unit: CapacityUnit,
+    // limit on size of stored values, see `TtlCache::bytes_total`
+    pub max_bytes: Option<usize>,
+    // largest request body `/set` accepts, checked against `Content-Length` before the
+    // body is read, so `Expect: 100-continue` clients are turned away before uploading
+    pub max_value_bytes: Option<usize>,
+    // values of `/set` requests waiting in the service queue at most, writes that would
+    // go over it are answered with 503 instead of piling up in memory
+    pub max_inflight_write_bytes: Option<usize>,
+    // longest key accepted in a url path, longer ones are answered with 414 before the
+    // request reaches the service
+    pub max_key_len: Option<usize>,
+    // lines of a `/bulk-load` body written to the cache per service request
+    pub bulk_load_batch_size: usize,
+    // entries the map is pre-sized for on startup, does not limit anything
+    pub initial_capacity: Option<usize>,
     pub eviction_number: usize,
     pub eviction_ratio: f32,
     pub eviction_every: Duration,
+    // what happens to a write of a new key once `capacity` or `max_bytes` is reached
+    pub eviction_policy: EvictionPolicy,
+    // run eviction passes every `eviction_every`, without it expired entries are only
+    // removed on access, when room is needed or by `/admin/evict`
+    pub active_eviction: bool,
+    // sweep over all entries removing every expired one, for caches too quiet for sampling
+    // passes to keep up, runs even while idle, disabled when not set
+    pub full_gc_every: Option<Duration>,
+    // remove expired entries found on reads, without it they are left to eviction passes
+    pub lazy_expiry: bool,
+    pub expiry_mode: ExpiryMode,
+    // sampling rounds of an eviction pass run before the service gets back to requests,
+    // pass continues in between them until it is complete
+    pub max_eviction_rounds: usize,
+    // once expired entries left after an eviction pass are estimated to take more than
+    // this share of `capacity`, further passes are run right away, disabled when not set
+    pub expired_backlog_alert_ratio: Option<f32>,
+    // transforms set with `TtlCacheService::set_transforms` for reads and writes run on
+    // the blocking pool, at most this many at a time, 0 runs them on the service task
+    pub transform_workers: usize,
+    // give memory back once most of the entries were removed, e.g. after mass expiry
+    pub shrink_after_flush: bool,
+    // growing the map rehashes every entry in the middle of a write, a warning is logged
+    // when that happens with more keys than this, see `/admin/reserve`
+    pub growth_warning_threshold: Option<usize>,
+    pub key_canonicalization: KeyCanonicalization,
+    pub miss_status: MissStatus,
+    // `/get` values longer than this are compressed for clients sending `Accept-Encoding: gzip`,
+    // smaller ones are not worth the overhead
+    pub compress_response_min_bytes: usize,
+    // every route is also served under `/<api_version>/`, unprefixed routes are kept
+    // for clients not migrated yet and answered with `Deprecation` header
+    pub api_version: &'static str,
+    // `GET /` serves a page polling `/stats`, for a quick look from the browser
+    pub enable_dashboard: bool,
+    // `/get` answers with `Last-Modified` of when the value was set and honours
+    // `If-Modified-Since`, for browsers and CDNs in front of the cache
+    pub enable_last_modified: bool,
+    // bearer token required by `/admin` endpoints, those are disabled when not set
+    pub admin_token: Option<String>,
+    // clients allowed to write, e.g. `10.0.1.0/24`, mutating and admin routes answer 403 to
+    // everyone else, anyone may write when not set
+    pub write_allowlist: Option<Vec<IpNet>>,
+    // writes from an allowlisted peer, e.g. a load balancer, are checked against the last
+    // `X-Forwarded-For` address instead, when it has one
+    pub trust_proxy: bool,
+    pub start_read_only: bool,
+    // base url of the primary, e.g. `http://10.0.0.1:8080`, when set writes are forwarded
+    // there and keys missing locally are fetched from it
+    pub replica_of: Option<String>,
+    // how a replica talks to `replica_of`, timeout covers the whole exchange, headers are
+    // added to every request, e.g. `Authorization` the primary or a proxy in front of it wants
+    pub origin_timeout: Option<Duration>,
+    pub origin_connect_timeout: Option<Duration>,
+    // `http://host:port` of a forward proxy requests to the primary are sent through
+    pub origin_proxy: Option<String>,
+    pub origin_headers: Vec<(String, String)>,
+    // once this many reads in a row failed to fetch from the primary, misses are answered
+    // without asking it for `origin_cooldown`, after which a single read tries again
+    pub origin_failure_threshold: Option<u32>,
+    pub origin_cooldown: Duration,
+    // every key has to start with one of these, e.g. `tenant1:`, others are rejected,
+    // any key is allowed when empty
+    pub allowed_key_prefixes: Vec<String>,
+    // base urls of cluster peers, when set this instance stores nothing itself and passes
+    // requests for a key to the peer owning it on a consistent hash ring
+    pub cluster_peers: Vec<String>,
+    // points every peer owns on the ring, more spread keys more evenly
+    pub cluster_virtual_nodes: usize,
+    // consecutive failed requests after which a peer is marked down
+    pub peer_failure_threshold: u32,
+    // requests to a down peer fail right away until this passes, then it is tried again
+    pub peer_retry_interval: Duration,
+    pub max_event_subscribers: usize,
+    // interval of keep-alive comments on idle `/events` streams
+    pub sse_keepalive: Duration,
+    // last events kept for subscribers resuming with `Last-Event-ID`, 0 disables resuming
+    pub event_history: usize,
+    // server is started on every address, all of them share the same routes and cache
+    pub listen: Vec<SocketAddr>,
+    // connections served at once, others wait to be accepted, see `ConnectionLimits`
+    pub max_connections: Option<usize>,
+    // connection is closed when headers of a request take longer to arrive, or when
+    // nothing is read nor written for `idle_connection_timeout`
+    pub header_read_timeout: Option<Duration>,
+    pub idle_connection_timeout: Option<Duration>,
+    // entries are restored from here on startup and written here by `/admin/snapshot`
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_format: SnapshotFormat,
+    // only keys matching any of these globs are written to a snapshot, see `glob_match`, all
+    // of them when empty, restore loads whatever the file holds
+    pub snapshot_persist_patterns: Vec<String>,
+    // cumulative counters are persisted here every `stats_persist_every` and on shutdown,
+    // and continued from on startup
+    pub stats_path: Option<PathBuf>,
+    pub stats_persist_every: Duration,
+    // StatsD or DogStatsD agent, `host:port`, hits, misses, evictions and keys_total are
+    // pushed there over UDP every `statsd_every`, see `StatsdExporter`
+    pub statsd_addr: Option<String>,
+    pub statsd_every: Duration,
+    // DogStatsD tags sent with every metric, e.g. `env:prod`
+    pub statsd_tags: Vec<String>,
+    // every mutating operation is appended here as a json line, see `AuditLog`
+    pub audit_path: Option<PathBuf>,
+    // values are left out of the audit log unless enabled, only their length is logged
+    pub audit_include_values: bool,
+    // audit log is rotated once it would grow past this, keeping `audit_keep_files` old ones
+    pub audit_max_bytes: u64,
+    pub audit_keep_files: usize,
+    // every read and write of keys with any of these prefixes is logged under the `access`
+    // tracing target along with its outcome, see `TtlCacheService::access_log`
+    pub access_log_prefixes: Vec<String>,
+    // identical values are stored once and shared between entries, worth it when many
+    // keys hold the same value, e.g. flags, at the cost of hashing every written value
+    pub intern_values: bool,
+    // values at least this large are freed by a separate thread once deleted, overwritten or
+    // flushed, the whole map too on a flush, so the service never stalls freeing them
+    pub deferred_drop_min_bytes: Option<usize>,
+    // namespaces created on first write under `/ns/<name>`, writes creating more are rejected
+    pub max_namespaces: usize,
+    // namespace left without entries for this long is dropped along with its map
+    pub namespace_idle_ttl: Duration,
+    // tags a single entry may carry, writes with more are rejected
+    pub max_tags_per_key: usize,
+    // keys removed by a single tag invalidation, the rest is left for the next call
+    pub max_tag_invalidation_batch: usize,
+    // key references the tag index holds at most, once over it entries go along with the tags
+    // having the fewest keys, see `TagIndex::eviction_candidate`
+    pub max_tag_index_refs: Option<usize>,
+    // verify checksum of the value on every read, to catch corruption in memory
+    pub paranoid_checksums: bool,
+    // upper bound for time an operation may wait in the service queue,
+    // `X-Request-Deadline-Ms` header can only make it shorter
+    pub request_timeout: Option<Duration>,
+    // reads and writes are answered with 503 while the service spends longer than this on an
+    // operation on average, until the average is back under half of it
+    pub latency_budget: Option<Duration>,
+    // operations the service spent at least this long on are kept in the slow log, see
+    // `/slowlog`, along with how long they waited in the queue
+    pub slowlog_threshold: Option<Duration>,
+    // most recent slow operations kept
+    pub slowlog_len: usize,
+    // run set/get/delete/expiry cycle against the service before serving requests
+    pub self_test: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ttl: Duration::from_secs(30 * 60), // 30 minutes
+            idle_ttl: None,
+            capacity: None,
+            capacity_unit: CapacityUnit::Entries,
+            max_bytes: None,
+            max_value_bytes: None,
+            max_inflight_write_bytes: None,
+            max_key_len: None,
+            bulk_load_batch_size: 1000,
+            initial_capacity: None,
+            eviction_number: 20,
+            eviction_ratio: 0.25,
+            eviction_every: Duration::from_millis(250),
+            eviction_policy: EvictionPolicy::RejectWrites,
+            active_eviction: true,
+            full_gc_every: None,
+            lazy_expiry: true,
+            expiry_mode: ExpiryMode::Lazy,
+            max_eviction_rounds: 16,
+            expired_backlog_alert_ratio: None,
+            transform_workers: 0,
+            shrink_after_flush: false,
+            growth_warning_threshold: Some(1_000_000),
+            key_canonicalization: BYTE_EXACT_KEYS,
+            miss_status: MissStatus::NotFound,
+            compress_response_min_bytes: 1024,
+            api_version: "v1",
+            enable_dashboard: false,
+            enable_last_modified: false,
+            admin_token: None,
+            write_allowlist: None,
+            trust_proxy: false,
+            start_read_only: false,
+            replica_of: None,
+            origin_timeout: None,
+            origin_connect_timeout: None,
+            origin_proxy: None,
+            origin_headers: Vec::new(),
+            origin_failure_threshold: None,
+            origin_cooldown: Duration::from_secs(30),
+            allowed_key_prefixes: Vec::new(),
+            cluster_peers: Vec::new(),
+            cluster_virtual_nodes: 64,
+            peer_failure_threshold: 3,
+            peer_retry_interval: Duration::from_secs(10),
+            max_event_subscribers: 64,
+            sse_keepalive: Duration::from_secs(15),
+            event_history: 256,
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 8080))],
+            max_connections: None,
+            header_read_timeout: Some(Duration::from_secs(10)),
+            idle_connection_timeout: Some(Duration::from_secs(60)),
+            snapshot_path: None,
+            snapshot_format: SnapshotFormat::Json,
+            snapshot_persist_patterns: Vec::new(),
+            stats_path: None,
+            stats_persist_every: Duration::from_secs(60),
+            statsd_addr: None,
+            statsd_every: Duration::from_secs(10),
+            statsd_tags: Vec::new(),
+            audit_path: None,
+            audit_include_values: false,
+            audit_max_bytes: 64 * 1024 * 1024,
+            audit_keep_files: 5,
+            access_log_prefixes: Vec::new(),
+            intern_values: false,
+            deferred_drop_min_bytes: None,
+            max_namespaces: 1024,
+            namespace_idle_ttl: Duration::from_secs(10 * 60),
+            max_tags_per_key: 8,
+            max_tag_invalidation_batch: 1000,
+            max_tag_index_refs: None,
+            paranoid_checksums: false,
+            request_timeout: None,
+            latency_budget: None,
+            slowlog_threshold: None,
+            slowlog_len: 128,
+            self_test: false,
+        }
+    }
+}
+
+// shorter intervals make eviction compete with requests for the service loop
+pub const MIN_EVICTION_EVERY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    // eviction would run before every single request
+    ZeroEvictionInterval,
+    // entries would expire as soon as they are written
+    ZeroTtl,
+    // entries would expire right after every access
+    ZeroIdleTtl,
+    // primary would never be asked
+    ZeroOriginFailureThreshold,
+    // metrics would be pushed in a busy loop
+    ZeroStatsdInterval,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroEvictionInterval => write!(f, "eviction_every must not be zero"),
+            ConfigError::ZeroTtl => write!(f, "ttl must not be zero"),
+            ConfigError::ZeroIdleTtl => write!(f, "idle_ttl must not be zero"),
+            ConfigError::ZeroOriginFailureThreshold => {
+                write!(f, "origin_failure_threshold must not be zero")
+            }
+            ConfigError::ZeroStatsdInterval => write!(f, "statsd_every must not be zero"),
+        }
+    }
+}
+
+impl Config {
+    // fixes up values that work but are most likely a mistake, returns what was
+    // found suspicious so that it can be logged, fails on values that do not work at all
+    pub fn validate(&mut self) -> Result<Vec<String>, ConfigError> {
+        let mut warnings = Vec::new();
+
+        if self.eviction_every.is_zero() {
+            return Err(ConfigError::ZeroEvictionInterval);
+        }
+        if self.eviction_every < MIN_EVICTION_EVERY {
+            warnings.push(format!(
+                "eviction_every {:?} is too short, using {:?}",
+                self.eviction_every, MIN_EVICTION_EVERY
+            ));
+            self.eviction_every = MIN_EVICTION_EVERY;
+        }
+        if self.ttl.is_zero() {
+            return Err(ConfigError::ZeroTtl);
+        }
+        if self.idle_ttl.map(|idle| idle.is_zero()).unwrap_or(false) {
+            return Err(ConfigError::ZeroIdleTtl);
+        }
+        if self.origin_failure_threshold == Some(0) {
+            return Err(ConfigError::ZeroOriginFailureThreshold);
+        }
+        if self.statsd_addr.is_some() && self.statsd_every.is_zero() {
+            return Err(ConfigError::ZeroStatsdInterval);
+        }
+        let ttl = self.idle_ttl.unwrap_or(self.ttl);
+        if self.eviction_every > ttl {
+            warnings.push(format!(
+                "eviction_every {:?} is longer than ttl {:?}, expired entries will pile up in between passes",
+                self.eviction_every, ttl
+            ));
+        }
+        if let Some(max) = self.max_inflight_write_bytes {
+            if self
+                .max_value_bytes
+                .map(|value| value > max)
+                .unwrap_or(true)
+            {
+                warnings.push(format!(
+                    "max_value_bytes is over max_inflight_write_bytes {}, larger values are always rejected",
+                    max
+                ));
+            }
+        }
+        if self.replica_of.is_some() && !self.cluster_peers.is_empty() {
+            warnings.push(String::from(
+                "replica_of is ignored in cluster mode, requests for keys are passed to cluster_peers",
+            ));
+        }
+
+        Ok(warnings)
+    }
+}
+
+// JSON file with settings that can be changed while running, read on startup and
+// again on SIGHUP, see `CONFIG_PATH`, everything else comes from the environment
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    // `*_secs` settings are numbers of seconds or strings like `1h30m`, see `parse_duration`
+    #[serde(default, deserialize_with = "deserialize_secs")]
+    pub ttl_secs: Option<Duration>,
+    pub capacity: Option<usize>,
+    pub eviction_number: Option<usize>,
+    pub eviction_ratio: Option<f32>,
+    pub eviction_every_ms: Option<u64>,
+    pub eviction_policy: Option<EvictionPolicy>,
+    pub active_eviction: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_secs")]
+    pub full_gc_every_secs: Option<Duration>,
+    // settings that cannot be changed at runtime, only reported
+    #[serde(flatten)]
+    ignored: BTreeMap<String, serde_json::Value>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<ConfigFile, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    // settings from the file on top of the given ones, along with what was ignored
+    pub fn apply(&self, config: Config) -> (Config, Vec<String>) {
+        let ignored = self
+            .ignored
+            .keys()
+            .map(|k| format!("{} is not reloadable, ignored", k))
+            .collect();
+        let config = Config {
+            ttl: self.ttl_secs.unwrap_or(config.ttl),
+            capacity: self.capacity.or(config.capacity),
+            eviction_number: self.eviction_number.unwrap_or(config.eviction_number),
+            eviction_ratio: self.eviction_ratio.unwrap_or(config.eviction_ratio),
+            eviction_every: self
+                .eviction_every_ms
+                .map(Duration::from_millis)
+                .unwrap_or(config.eviction_every),
+            eviction_policy: self.eviction_policy.unwrap_or(config.eviction_policy),
+            active_eviction: self.active_eviction.unwrap_or(config.active_eviction),
+            full_gc_every: self.full_gc_every_secs.or(config.full_gc_every),
+            ..config
+        };
+
+        (config, ignored)
+    }
+}
+
+// comma separated list of addresses, e.g. `127.0.0.1:8080,[::1]:8080`
+pub fn parse_listen(addrs: &str) -> Result<Vec<SocketAddr>, String> {
+    let addrs = addrs
+        .split(',')
+        .map(|a| a.trim())
+        .filter(|a| !a.is_empty())
+        .map(|a| {
+            a.parse::<SocketAddr>()
+                .map_err(|e| format!("invalid listen address {}: {}", a, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if addrs.is_empty() {
+        Err(String::from("no listen addresses given"))
+    } else {
+        Ok(addrs)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+    // write fails with out of capacity error
+    RejectWrites,
+    // approximated, the oldest created entry out of `eviction_number` random ones
+    // is evicted to make room, same way Redis samples keys instead of keeping an order
+    OldestFirst,
+    // the largest value out of `eviction_number` random entries is evicted, frees
+    // the most memory per eviction when it is `max_bytes` that is reached
+    LargestFirst,
+}
+
+// what a single entry costs against `capacity`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapacityUnit {
+    // every entry costs 1, capacity is a number of entries
+    Entries,
+    // an entry costs the length of its stored value, so a few large values
+    // cannot crowd out many small ones unnoticed
+    Bytes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpiryMode {
+    // expired entries stay in memory until they are read or sampled by an eviction pass
+    Lazy,
+    // every entry is removed right when it expires, at the cost of keeping entries
+    // ordered by expiry time, for workloads that need memory bound tightly
+    Eager,
+}
+
+// format `/admin/snapshot` writes, either is recognized on restore
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotFormat {
+    // one JSON entry per line, readable with standard tools
+    Json,
+    // length-prefixed records, faster to write and read, compact for binary values
+    Binary,
+}
+
+// how `GET /get/<key>` responds when key is absent or expired
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissStatus {
+    // 404 with "Not found" body
+    NotFound,
+    // 200 with an empty body, for clients that treat 404 as an error
+    OkEmpty,
+}
+
+// rules applied to every key before it reaches the cache, regardless of the protocol
+// it came from, so that e.g. `User:42` and `user:42` can be treated as the same entry
+#[derive(Clone)]
+pub struct KeyCanonicalization {
+    pub lowercase: bool,
+    pub trim_whitespace: bool,
+    // only has effect when built with `unicode` feature
+    pub max_unicode_nfc: bool,
+}
+
+// keys are used byte-exact, as they were sent by the client
+pub const BYTE_EXACT_KEYS: KeyCanonicalization = KeyCanonicalization {
+    lowercase: false,
+    trim_whitespace: false,
+    max_unicode_nfc: false,
+};
+
+// `Foo` and `foo` are the same key, for deployments that match keys case-insensitively
+pub const CASE_INSENSITIVE_KEYS: KeyCanonicalization = KeyCanonicalization {
+    lowercase: true,
+    trim_whitespace: false,
+    max_unicode_nfc: false,
+};
+
+impl KeyCanonicalization {
+    pub fn is_byte_exact(&self) -> bool {
+        !self.lowercase && !self.trim_whitespace && !self.max_unicode_nfc
+    }
+
+    pub fn canonicalize(&self, key: String) -> String {
+        if self.is_byte_exact() {
+            return key;
+        }
+
+        let mut key = if self.trim_whitespace {
+            key.trim().to_string()
+        } else {
+            key
+        };
+
+        #[cfg(feature = "unicode")]
+        if self.max_unicode_nfc {
+            key = key.nfc().collect();
+        }
+
+        if self.lowercase {
+            key = key.to_lowercase();
+        }
+
+        key
+    }
 }
 
 #[cfg(test)]
 pub const TEST_CONFIG_SINGLE_ITEM: Config = Config {
     ttl: Duration::from_secs(10),
+    idle_ttl: None,
     capacity: Some(1),
+    capacity_unit: CapacityUnit::Entries,
+    max_bytes: None,
+    max_value_bytes: None,
+    max_inflight_write_bytes: None,
+    max_key_len: None,
+    bulk_load_batch_size: 1000,
+    initial_capacity: None,
     eviction_number: 20,
     eviction_ratio: 0.25,
     eviction_every: Duration::from_millis(250),
+    eviction_policy: EvictionPolicy::RejectWrites,
+    active_eviction: true,
+    full_gc_every: None,
+    lazy_expiry: true,
+    expiry_mode: ExpiryMode::Lazy,
+    max_eviction_rounds: 16,
+    expired_backlog_alert_ratio: None,
+    transform_workers: 0,
+    shrink_after_flush: false,
+    growth_warning_threshold: Some(1_000_000),
+    key_canonicalization: BYTE_EXACT_KEYS,
+    miss_status: MissStatus::NotFound,
+    compress_response_min_bytes: 0,
+    api_version: "v1",
+    enable_dashboard: false,
+    enable_last_modified: false,
+    admin_token: None,
+    write_allowlist: None,
+    trust_proxy: false,
+    start_read_only: false,
+    replica_of: None,
+    origin_timeout: None,
+    origin_connect_timeout: None,
+    origin_proxy: None,
+    origin_headers: Vec::new(),
+    origin_failure_threshold: None,
+    origin_cooldown: Duration::from_secs(30),
+    allowed_key_prefixes: Vec::new(),
+    cluster_peers: Vec::new(),
+    cluster_virtual_nodes: 64,
+    peer_failure_threshold: 3,
+    peer_retry_interval: Duration::from_secs(10),
+    max_event_subscribers: 2,
+    sse_keepalive: Duration::from_secs(15),
+    event_history: 256,
+    listen: Vec::new(),
+    max_connections: None,
+    header_read_timeout: Some(Duration::from_secs(10)),
+    idle_connection_timeout: Some(Duration::from_secs(60)),
+    snapshot_path: None,
+    snapshot_format: SnapshotFormat::Json,
+    snapshot_persist_patterns: Vec::new(),
+    stats_path: None,
+    stats_persist_every: Duration::from_secs(60),
+    statsd_addr: None,
+    statsd_every: Duration::from_secs(10),
+    statsd_tags: Vec::new(),
+    audit_path: None,
+    audit_include_values: false,
+    audit_max_bytes: 64 * 1024 * 1024,
+    audit_keep_files: 5,
+    access_log_prefixes: Vec::new(),
+    intern_values: false,
+    deferred_drop_min_bytes: None,
+    max_namespaces: 1024,
+    namespace_idle_ttl: Duration::from_secs(10 * 60),
+    max_tags_per_key: 2,
+    max_tag_invalidation_batch: 1000,
+    max_tag_index_refs: None,
+    paranoid_checksums: false,
+    request_timeout: None,
+    latency_budget: None,
+    slowlog_threshold: None,
+    slowlog_len: 128,
+    self_test: false,
 };
+
+#[cfg(test)]
+mod config_tests {
+    use crate::config::parse_listen;
+    use crate::config::Config;
+    use crate::config::ConfigError;
+    use crate::config::ConfigFile;
+    use crate::config::EvictionPolicy;
+    use crate::config::KeyCanonicalization;
+    use crate::config::BYTE_EXACT_KEYS;
+    use crate::config::MIN_EVICTION_EVERY;
+
+    use std::time::Duration;
+
+    #[test]
+    fn byte_exact_keys_are_left_untouched() {
+        let key = String::from(" User:42 ");
+
+        assert_eq!(BYTE_EXACT_KEYS.canonicalize(key.clone()), key);
+    }
+
+    #[test]
+    fn keys_can_be_trimmed_and_lowercased() {
+        let rules = KeyCanonicalization {
+            lowercase: true,
+            trim_whitespace: true,
+            max_unicode_nfc: false,
+        };
+
+        assert_eq!(rules.canonicalize(String::from(" User:42\t")), "user:42");
+    }
+
+    #[test]
+    fn multiple_listen_addresses_are_parsed() {
+        let addrs = parse_listen("127.0.0.1:8080, [::1]:9090").unwrap();
+
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].port(), 8080);
+        assert!(addrs[1].is_ipv6());
+        assert!(parse_listen("localhost").is_err());
+        assert!(parse_listen(" , ").is_err());
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn keys_can_be_nfc_normalized() {
+        let rules = KeyCanonicalization {
+            lowercase: false,
+            trim_whitespace: false,
+            max_unicode_nfc: true,
+        };
+
+        // 'e' followed by combining acute accent composes into a single 'é'
+        assert_eq!(
+            rules.canonicalize(String::from("caf\u{65}\u{301}")),
+            "caf\u{e9}"
+        );
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(Config::default().validate(), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn zero_eviction_interval_is_rejected() {
+        let mut config = Config {
+            eviction_every: Duration::ZERO,
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate(), Err(ConfigError::ZeroEvictionInterval));
+    }
+
+    #[test]
+    fn zero_idle_ttl_is_rejected() {
+        let mut config = Config {
+            idle_ttl: Some(Duration::ZERO),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate(), Err(ConfigError::ZeroIdleTtl));
+    }
+
+    #[test]
+    fn short_eviction_interval_is_clamped() {
+        let mut config = Config {
+            eviction_every: Duration::from_millis(1),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate().unwrap().len(), 1);
+        assert_eq!(config.eviction_every, MIN_EVICTION_EVERY);
+    }
+
+    #[test]
+    fn eviction_interval_longer_than_ttl_is_only_warned_about() {
+        let mut config = Config {
+            ttl: Duration::from_secs(5 * 60),
+            eviction_every: Duration::from_secs(60 * 60),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate().unwrap().len(), 1);
+        assert_eq!(config.eviction_every, Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn config_file_overrides_reloadable_settings_only() {
+        let file: ConfigFile = serde_json::from_str(
+            r#"{"ttl_secs": 60, "eviction_policy": "oldest_first", "listen": "0.0.0.0:80"}"#,
+        )
+        .unwrap();
+
+        let (config, ignored) = file.apply(Config::default());
+        assert_eq!(config.ttl, Duration::from_secs(60));
+        assert_eq!(config.eviction_policy, EvictionPolicy::OldestFirst);
+        assert_eq!(config.eviction_every, Config::default().eviction_every);
+        assert_eq!(config.listen, Config::default().listen);
+        assert_eq!(
+            ignored,
+            vec![String::from("listen is not reloadable, ignored")]
+        );
+    }
+
+    #[test]
+    fn config_file_durations_are_seconds_or_strings_with_units() {
+        let file: ConfigFile =
+            serde_json::from_str(r#"{"ttl_secs": "1h30m", "full_gc_every_secs": 600}"#).unwrap();
+        let (config, _) = file.apply(Config::default());
+        assert_eq!(config.ttl, Duration::from_secs(90 * 60));
+        assert_eq!(config.full_gc_every, Some(Duration::from_secs(600)));
+
+        let error = serde_json::from_str::<ConfigFile>(r#"{"ttl_secs": "soon"}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("expected seconds or a duration"),
+            "{}",
+            error
+        );
+
+        let file: ConfigFile = serde_json::from_str(r#"{"ttl_secs": "0s"}"#).unwrap();
+        let (mut config, _) = file.apply(Config::default());
+        assert_eq!(config.validate(), Err(ConfigError::ZeroTtl));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::config::Config;
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::thread;
+
+// values waiting to be freed, once full values are freed inline again rather than blocking
+const DROP_BUFFER: usize = 64;
+
+type Garbage = Box<dyn Send>;
+
+// frees large values off the service loop, so that a delete or overwrite of a huge value does
+// not stall the requests queued behind it, see `deferred_drop_min_bytes`
+#[derive(Clone)]
+pub struct DeferredDrop {
+    min_bytes: usize,
+    garbage: mpsc::SyncSender<Garbage>,
+    drops: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl DeferredDrop {
+    // dropping thread runs until every clone is dropped, it is not started without
+    // `deferred_drop_min_bytes`
+    pub fn start(config: &Config) -> Option<DeferredDrop> {
+        let min_bytes = config.deferred_drop_min_bytes?;
+        let (garbage, received) = mpsc::sync_channel::<Garbage>(DROP_BUFFER);
+        let spawned = thread::Builder::new()
+            .name("deferred-drop".to_string())
+            .spawn(move || received.into_iter().for_each(drop));
+        if let Err(e) = spawned {
+            tracing::error!(
+                "failed to start deferred drop: {}, values are freed inline",
+                e
+            );
+            return None;
+        }
+
+        Some(DeferredDrop {
+            min_bytes,
+            garbage,
+            drops: Arc::new(AtomicU64::new(0)),
+            bytes: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // value of an entry no longer in the cache, only the last reference of a large one is
+    // worth passing on, others are freed right away
+    pub fn value(&self, value: Arc<[u8]>) {
+        if value.len() >= self.min_bytes && Arc::strong_count(&value) == 1 {
+            let len = value.len();
+            self.defer(Box::new(value), len);
+        }
+    }
+
+    // anything else holding `bytes` of values, e.g. a whole map taken out of the cache
+    pub fn defer(&self, garbage: Garbage, bytes: usize) {
+        match self.garbage.try_send(garbage) {
+            Ok(()) => {
+                self.drops.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            // freed here once the dropping thread is behind or gone
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    // values and maps handed over to the dropping thread so far
+    pub fn drops(&self) -> u64 {
+        self.drops.load(Ordering::Relaxed)
+    }
+
+    // bytes of values handed over to the dropping thread so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Deserializer;
+
+// formats `parse_duration` accepts, for error messages
+pub const ACCEPTED_FORMATS: &str = "seconds or a duration like 90s, 5m or 1h30m";
+
+// longest duration a request may ask for, ten years, far below what an `Instant` can be moved by
+pub const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Empty,
+    // byte offset where a number was expected
+    ExpectedNumber(usize),
+    // number at the byte offset is not followed by a unit
+    MissingUnit(usize),
+    UnknownUnit(String),
+    Overflow,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "duration is empty"),
+            ParseError::ExpectedNumber(at) => write!(f, "expected number at {}", at),
+            ParseError::MissingUnit(at) => write!(f, "unit is missing after number at {}", at),
+            ParseError::UnknownUnit(unit) => write!(f, "unknown unit {}", unit),
+            ParseError::Overflow => write!(f, "duration is too long"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// bare integers are seconds, otherwise numbers with units in the format of `humantime`,
+// e.g. `90s`, `5m`, `1h 30m` or `250ms`, zero is left for callers to judge
+pub fn parse_duration(s: &str) -> Result<Duration, ParseError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        return s
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| ParseError::Overflow);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let at = s.len() - rest.len();
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(ParseError::ExpectedNumber(at));
+        }
+        let number: u64 = rest[..digits].parse().map_err(|_| ParseError::Overflow)?;
+        rest = &rest[digits..];
+
+        let letters = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        if letters == 0 {
+            return Err(ParseError::MissingUnit(at));
+        }
+        let part = unit(&rest[..letters], number)?;
+        total = total.checked_add(part).ok_or(ParseError::Overflow)?;
+        rest = rest[letters..].trim_start();
+    }
+
+    Ok(total)
+}
+
+fn unit(unit: &str, n: u64) -> Result<Duration, ParseError> {
+    let secs = |per: u64| {
+        n.checked_mul(per)
+            .map(Duration::from_secs)
+            .ok_or(ParseError::Overflow)
+    };
+    match unit {
+        "nsec" | "ns" => Ok(Duration::from_nanos(n)),
+        "usec" | "us" => Ok(Duration::from_micros(n)),
+        "msec" | "ms" => Ok(Duration::from_millis(n)),
+        "seconds" | "second" | "secs" | "sec" | "s" => secs(1),
+        "minutes" | "minute" | "mins" | "min" | "m" => secs(60),
+        "hours" | "hour" | "hrs" | "hr" | "h" => secs(60 * 60),
+        "days" | "day" | "d" => secs(24 * 60 * 60),
+        "weeks" | "week" | "w" => secs(7 * 24 * 60 * 60),
+        unit => Err(ParseError::UnknownUnit(unit.to_string())),
+    }
+}
+
+// `*_secs` settings of the config file, given either as a number of seconds or as a string
+// `parse_duration` accepts
+pub fn deserialize_secs<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Secs {
+        Number(u64),
+        Text(String),
+    }
+
+    match Option::<Secs>::deserialize(deserializer)? {
+        Some(Secs::Number(secs)) => Ok(Some(Duration::from_secs(secs))),
+        Some(Secs::Text(text)) => parse_duration(&text)
+            .map(Some)
+            .map_err(|e| serde::de::Error::custom(format!("{}, expected {}", e, ACCEPTED_FORMATS))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod duration_tests {
+    use crate::duration::parse_duration;
+    use crate::duration::ParseError;
+
+    use std::time::Duration;
+
+    #[test]
+    fn durations_are_parsed_with_or_without_units() {
+        let cases = [
+            ("90", Ok(Duration::from_secs(90))),
+            (" 90 ", Ok(Duration::from_secs(90))),
+            ("0", Ok(Duration::ZERO)),
+            ("90s", Ok(Duration::from_secs(90))),
+            ("5m", Ok(Duration::from_secs(5 * 60))),
+            ("1h30m", Ok(Duration::from_secs(90 * 60))),
+            ("1h 30m 15s", Ok(Duration::from_secs(90 * 60 + 15))),
+            ("2days", Ok(Duration::from_secs(2 * 24 * 60 * 60))),
+            ("1w", Ok(Duration::from_secs(7 * 24 * 60 * 60))),
+            ("250ms", Ok(Duration::from_millis(250))),
+            ("1s500ms", Ok(Duration::from_millis(1500))),
+            ("10us", Ok(Duration::from_micros(10))),
+            ("7ns", Ok(Duration::from_nanos(7))),
+            ("", Err(ParseError::Empty)),
+            ("  ", Err(ParseError::Empty)),
+            ("s", Err(ParseError::ExpectedNumber(0))),
+            ("1h m", Err(ParseError::ExpectedNumber(3))),
+            ("-5s", Err(ParseError::ExpectedNumber(0))),
+            ("1.5h", Err(ParseError::MissingUnit(0))),
+            ("1h30", Err(ParseError::MissingUnit(2))),
+            ("5 m", Err(ParseError::MissingUnit(0))),
+            ("5x", Err(ParseError::UnknownUnit(String::from("x")))),
+            ("5M", Err(ParseError::UnknownUnit(String::from("M")))),
+            ("99999999999999999999", Err(ParseError::Overflow)),
+            ("99999999999999999999s", Err(ParseError::Overflow)),
+            ("18446744073709551615w", Err(ParseError::Overflow)),
+        ];
+
+        for (input, expected) in cases.iter() {
+            assert_eq!(&parse_duration(input), expected, "{:?}", input);
+        }
+    }
+}
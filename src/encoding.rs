@@ -0,0 +1,70 @@
+use std::io::Read;
+use std::io::Write;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+pub const GZIP: &str = "gzip";
+
+pub fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// stops one byte past `limit`, a longer result tells the data is over it without inflating
+// all of it
+pub fn gunzip(data: &[u8], limit: Option<usize>) -> std::io::Result<Vec<u8>> {
+    let limit = limit.map(|max| max as u64 + 1).unwrap_or(u64::MAX);
+    let mut decoded = Vec::new();
+    GzDecoder::new(data).take(limit).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+// checks whether `Accept-Encoding` header value lists given coding,
+// codings explicitly disabled with `q=0` are not considered accepted
+pub fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    accept_encoding.split(',').any(|c| {
+        let mut parts = c.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let disabled = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .map(|q| q == 0.0)
+                .unwrap_or(false)
+        });
+        name.eq_ignore_ascii_case(coding) && !disabled
+    })
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use crate::encoding::accepts;
+    use crate::encoding::gunzip;
+    use crate::encoding::gzip;
+    use crate::encoding::GZIP;
+
+    #[test]
+    fn gzip_round_trips() {
+        let data = b"value: String".repeat(10);
+
+        assert_eq!(gunzip(&gzip(&data).unwrap(), None).unwrap(), data);
+    }
+
+    #[test]
+    fn gunzip_stops_past_the_limit() {
+        let data = vec![0; 1 << 20];
+
+        let decoded = gunzip(&gzip(&data).unwrap(), Some(100)).unwrap();
+        assert_eq!(decoded.len(), 101);
+    }
+
+    #[test]
+    fn accept_encoding_is_parsed() {
+        assert!(accepts("gzip", GZIP));
+        assert!(accepts("deflate, GZIP;q=0.5", GZIP));
+        assert!(!accepts("deflate, br", GZIP));
+        assert!(!accepts("gzip;q=0", GZIP));
+    }
+}
@@ -0,0 +1,441 @@
+use crate::auxiliary::AuxiliaryMemory;
+
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+use tokio_stream::StreamExt;
+
+// how many events can be buffered for a slow subscriber before it starts missing them
+pub const EVENT_BUFFER: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Set,
+    Expired,
+    Deleted,
+    // removed to make room for a new key while at capacity
+    Evicted,
+    // removed along with the whole namespace, see `DELETE /ns/<name>`
+    Flushed,
+    // overwritten by an entry renamed onto its key, see `TtlCache::rename`
+    Replaced,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Set => "set",
+            EventKind::Expired => "expired",
+            EventKind::Deleted => "deleted",
+            EventKind::Evicted => "evicted",
+            EventKind::Flushed => "flushed",
+            EventKind::Replaced => "replaced",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "set" => Ok(EventKind::Set),
+            "expired" => Ok(EventKind::Expired),
+            "deleted" => Ok(EventKind::Deleted),
+            "evicted" => Ok(EventKind::Evicted),
+            "flushed" => Ok(EventKind::Flushed),
+            "replaced" => Ok(EventKind::Replaced),
+            other => Err(format!("unknown event kind: {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct KeyEvent {
+    pub key: String,
+    pub kind: EventKind,
+    // stored value of the entry set or removed
+    pub value_len: Option<usize>,
+    // time the entry had left, none once it ran out
+    pub ttl_remaining_ms: Option<u64>,
+    // none for keys of the default keyspace
+    pub namespace: Option<String>,
+    // numbered from 1 in the order events happened, across namespaces
+    pub seq: u64,
+}
+
+// events were missed, either a subscriber fell behind or it resumed from an event
+// no longer kept, count is unknown for ids this process never sent, e.g. after a restart
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Gap {
+    pub missed: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventItem {
+    Event(KeyEvent),
+    Gap(Gap),
+}
+
+impl EventItem {
+    pub fn into_event(self) -> Option<KeyEvent> {
+        match self {
+            EventItem::Event(event) => Some(event),
+            EventItem::Gap(_) => None,
+        }
+    }
+}
+
+struct EventHistory {
+    last_seq: u64,
+    recent: VecDeque<KeyEvent>,
+    // events dropped from `recent` to keep it within `capacity`
+    dropped: u64,
+    // set by the first subscriber, from then on events are built with nobody listening
+    // so that subscribers can resume
+    recording: bool,
+}
+
+// numbers events and keeps the last `capacity` of them, cloned into every cache
+// publishing events, see `event_history`
+#[derive(Clone)]
+pub struct EventSender {
+    sender: broadcast::Sender<KeyEvent>,
+    history: Arc<Mutex<EventHistory>>,
+    capacity: usize,
+    namespace: Option<String>,
+}
+
+impl EventSender {
+    pub fn new(capacity: usize) -> EventSender {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventSender {
+            sender,
+            history: Arc::new(Mutex::new(EventHistory {
+                last_seq: 0,
+                recent: VecDeque::with_capacity(capacity),
+                dropped: 0,
+                recording: false,
+            })),
+            capacity,
+            namespace: None,
+        }
+    }
+
+    // same sequence and history, events marked with the namespace
+    pub fn for_namespace(&self, namespace: String) -> EventSender {
+        EventSender {
+            namespace: Some(namespace),
+            ..self.clone()
+        }
+    }
+
+    // events need not be built unless this holds
+    pub fn is_wanted(&self) -> bool {
+        self.sender.receiver_count() > 0 || self.history.lock().unwrap().recording
+    }
+
+    pub fn send(
+        &self,
+        key: &str,
+        kind: EventKind,
+        value_len: usize,
+        ttl_remaining: Option<Duration>,
+    ) {
+        let mut history = self.history.lock().unwrap();
+        history.last_seq += 1;
+        let event = KeyEvent {
+            key: key.to_string(),
+            kind,
+            value_len: Some(value_len),
+            ttl_remaining_ms: ttl_remaining.map(|ttl| ttl.as_millis() as u64),
+            namespace: self.namespace.clone(),
+            seq: history.last_seq,
+        };
+        if self.capacity > 0 && history.recording {
+            if history.recent.len() == self.capacity {
+                history.recent.pop_front();
+                history.dropped += 1;
+            }
+            history.recent.push_back(event.clone());
+        }
+        // can only fail if there are no receivers
+        let _ = self.sender.send(event);
+    }
+
+    // receiver of new events along with kept ones following `last_event_id`
+    pub fn subscribe(&self, last_event_id: Option<u64>) -> Resumed {
+        let mut history = self.history.lock().unwrap();
+        history.recording |= self.capacity > 0;
+        let receiver = self.sender.subscribe();
+
+        let last_seq = history.last_seq;
+        let (replay, gap) = match last_event_id {
+            None => (Vec::new(), None),
+            Some(id) if id > last_seq => (
+                history.recent.iter().cloned().collect(),
+                Some(Gap { missed: None }),
+            ),
+            Some(id) => {
+                let oldest = history
+                    .recent
+                    .front()
+                    .map(|e| e.seq)
+                    .unwrap_or(last_seq + 1);
+                let missed = oldest.saturating_sub(id + 1);
+                let replay = history
+                    .recent
+                    .iter()
+                    .filter(|e| e.seq > id)
+                    .cloned()
+                    .collect();
+                (
+                    replay,
+                    Some(Gap {
+                        missed: Some(missed),
+                    })
+                    .filter(|_| missed > 0),
+                )
+            }
+        };
+        Resumed {
+            receiver,
+            replay,
+            gap,
+        }
+    }
+}
+
+// events kept for resuming subscribers, see `event_history`
+impl AuxiliaryMemory for EventSender {
+    fn entries(&self) -> usize {
+        self.history.lock().unwrap().recent.len()
+    }
+
+    fn bytes(&self) -> usize {
+        let history = self.history.lock().unwrap();
+        history
+            .recent
+            .iter()
+            .map(|e| {
+                size_of::<KeyEvent>() + e.key.len() + e.namespace.as_ref().map_or(0, |n| n.len())
+            })
+            .sum()
+    }
+
+    fn cap(&self) -> Option<usize> {
+        Some(self.capacity)
+    }
+
+    fn evicted(&self) -> u64 {
+        self.history.lock().unwrap().dropped
+    }
+}
+
+pub struct Resumed {
+    pub receiver: broadcast::Receiver<KeyEvent>,
+    pub replay: Vec<KeyEvent>,
+    pub gap: Option<Gap>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EventFilter {
+    pub prefix: Option<String>,
+    pub kinds: Option<Vec<EventKind>>,
+}
+
+impl EventFilter {
+    // kinds are given as comma separated list, e.g. `set,expired`
+    pub fn parse(prefix: Option<String>, kinds: Option<&str>) -> Result<EventFilter, String> {
+        let kinds = match kinds {
+            Some(kinds) => Some(
+                kinds
+                    .split(',')
+                    .map(|k| k.trim().parse::<EventKind>())
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(EventFilter { prefix, kinds })
+    }
+
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        self.prefix
+            .as_ref()
+            .map(|p| event.key.starts_with(p.as_str()))
+            .unwrap_or(true)
+            && self
+                .kinds
+                .as_ref()
+                .map(|k| k.contains(&event.kind))
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SubscriberStats {
+    pub filter: EventFilter,
+    pub delivered: AtomicU64,
+    // events missed because subscriber was lagging behind
+    pub dropped: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriberSnapshot {
+    pub prefix: Option<String>,
+    pub kinds: Option<Vec<EventKind>>,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+impl SubscriberStats {
+    pub fn snapshot(&self) -> SubscriberSnapshot {
+        SubscriberSnapshot {
+            prefix: self.filter.prefix.clone(),
+            kinds: self.filter.kinds.clone(),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct Subscription {
+    pub events: Resumed,
+    pub stats: Arc<SubscriberStats>,
+}
+
+impl Subscription {
+    // events are filtered here, so the ones subscriber is not interested in never get serialized,
+    // a resumed subscription starts with the gap, if any, and kept events
+    pub fn matching_events(self) -> impl Stream<Item = EventItem> {
+        let stats = self.stats;
+        let lag_stats = stats.clone();
+        let resumed = self.events;
+        let kept = resumed
+            .gap
+            .map(EventItem::Gap)
+            .into_iter()
+            .chain(resumed.replay.into_iter().map(EventItem::Event));
+        tokio_stream::iter(kept)
+            .chain(
+                BroadcastStream::new(resumed.receiver).map(move |event| match event {
+                    Ok(event) => EventItem::Event(event),
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        lag_stats.dropped.fetch_add(missed, Ordering::Relaxed);
+                        EventItem::Gap(Gap {
+                            missed: Some(missed),
+                        })
+                    }
+                }),
+            )
+            .filter_map(move |item| match item {
+                EventItem::Event(event) if stats.filter.matches(&event) => {
+                    stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    Some(EventItem::Event(event))
+                }
+                EventItem::Event(_) => None,
+                EventItem::Gap(gap) => Some(EventItem::Gap(gap)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use crate::events::EventFilter;
+    use crate::events::EventKind;
+    use crate::events::EventSender;
+    use crate::events::Gap;
+    use crate::events::KeyEvent;
+
+    fn event(key: &str, kind: EventKind) -> KeyEvent {
+        KeyEvent {
+            key: key.to_string(),
+            kind,
+            value_len: None,
+            ttl_remaining_ms: None,
+            namespace: None,
+            seq: 1,
+        }
+    }
+
+    fn seqs(events: &[KeyEvent]) -> Vec<u64> {
+        events.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn filter_matches_prefix_and_kinds() {
+        let filter = EventFilter::parse(Some("user:".into()), Some("set")).unwrap();
+
+        assert!(filter.matches(&event("user:42", EventKind::Set)));
+        assert!(!filter.matches(&event("user:42", EventKind::Expired)));
+        assert!(!filter.matches(&event("org:7", EventKind::Set)));
+        assert!(EventFilter::default().matches(&event("org:7", EventKind::Expired)));
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected() {
+        assert!(EventFilter::parse(None, Some("set,exploded")).is_err());
+    }
+
+    #[test]
+    fn events_are_kept_for_resuming_once_subscribed() {
+        let events = EventSender::new(3);
+        // nobody subscribed yet, nothing is kept
+        events.send("a", EventKind::Set, 1, None);
+        drop(events.subscribe(None));
+
+        for key in ["b", "c"] {
+            events.send(key, EventKind::Set, 1, None);
+        }
+        let resumed = events.subscribe(Some(2));
+        assert_eq!(seqs(&resumed.replay), vec![3]);
+        assert_eq!(resumed.gap, None);
+
+        for key in ["d", "e", "f"] {
+            events.send(key, EventKind::Set, 1, None);
+        }
+        // 3 is no longer kept
+        let resumed = events.subscribe(Some(2));
+        assert_eq!(seqs(&resumed.replay), vec![4, 5, 6]);
+        assert_eq!(resumed.gap, Some(Gap { missed: Some(1) }));
+
+        // id from before a restart
+        let resumed = events.subscribe(Some(100));
+        assert_eq!(seqs(&resumed.replay), vec![4, 5, 6]);
+        assert_eq!(resumed.gap, Some(Gap { missed: None }));
+
+        assert!(events.subscribe(Some(6)).replay.is_empty());
+        assert!(events.subscribe(None).replay.is_empty());
+    }
+
+    #[test]
+    fn namespace_events_share_the_sequence() {
+        let events = EventSender::new(0);
+        let mut rx = events.subscribe(None).receiver;
+        events.send("a", EventKind::Set, 1, None);
+        events
+            .for_namespace(String::from("ns"))
+            .send("a", EventKind::Deleted, 1, None);
+
+        let first = rx.try_recv().unwrap();
+        assert_eq!((first.seq, first.namespace), (1, None));
+        let second = rx.try_recv().unwrap();
+        assert_eq!(
+            (second.seq, second.namespace),
+            (2, Some(String::from("ns")))
+        );
+    }
+}
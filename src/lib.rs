@@ -0,0 +1,33 @@
+#[cfg(feature = "http-api")]
+pub mod api;
+pub mod audit;
+pub mod auxiliary;
+pub mod bulk;
+pub mod cache;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "http-api")]
+pub mod cluster;
+pub mod config;
+pub mod deferred_drop;
+pub mod duration;
+#[cfg(feature = "http-api")]
+pub mod encoding;
+pub mod events;
+pub mod maintenance;
+pub mod metrics;
+#[cfg(feature = "http-api")]
+pub mod openapi;
+pub mod selftest;
+#[cfg(feature = "http-api")]
+pub mod server;
+pub mod service;
+pub mod slowlog;
+pub mod snapshot;
+pub mod stats;
+pub mod statsd;
+pub mod tag_index;
+pub mod time;
+pub mod txn;
+#[cfg(feature = "http-api")]
+pub mod upstream;
@@ -1,38 +1,315 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::sync::mpsc;
+use ipnet::IpNet;
+use tokio::sync::oneshot;
 
-mod api;
-mod cache;
-mod config;
-mod service;
-mod time;
-
-use api::make_api;
-use config::Config;
-use service::ServiceMessage;
-use service::TtlCacheService;
-use time::REALTIME;
+use in_mem_cached::api::make_api;
+use in_mem_cached::api::Upstreams;
+use in_mem_cached::config::parse_listen;
+use in_mem_cached::config::Config;
+use in_mem_cached::config::ConfigFile;
+use in_mem_cached::config::SnapshotFormat;
+use in_mem_cached::duration::parse_duration;
+use in_mem_cached::duration::ACCEPTED_FORMATS;
+use in_mem_cached::metrics::HttpMetrics;
+use in_mem_cached::selftest::self_test;
+use in_mem_cached::server::serve_all;
+use in_mem_cached::server::ConnectionLimits;
+use in_mem_cached::service::service_queue;
+use in_mem_cached::service::ServiceFlags;
+use in_mem_cached::service::ServiceMessage;
+use in_mem_cached::service::ServiceQueue;
+use in_mem_cached::service::TtlCacheService;
+use in_mem_cached::statsd::StatsdExporter;
+use in_mem_cached::time::REALTIME;
 
 #[tokio::main]
 async fn main() {
     let collector = tracing_subscriber::fmt().finish();
     tracing::subscriber::set_global_default(collector).expect("failed to subscribe tracer");
 
-    let cache_config = Config {
-        ttl: Duration::from_secs(30 * 60), // 30 minutes
-        capacity: None,
-        eviction_number: 20,
-        eviction_ratio: 0.25,
-        eviction_every: Duration::from_millis(250),
+    let default_config = Config::default();
+    let env_config = Config {
+        admin_token: std::env::var("ADMIN_TOKEN").ok(),
+        write_allowlist: std::env::var("WRITE_ALLOWLIST")
+            .ok()
+            .map(|nets| split_list(&nets).iter().map(|net| parse_net(net)).collect()),
+        trust_proxy: std::env::var("TRUST_PROXY")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(default_config.trust_proxy),
+        listen: match std::env::var("LISTEN") {
+            Ok(addrs) => parse_listen(&addrs).expect("invalid LISTEN"),
+            Err(_) => default_config.listen.clone(),
+        },
+        snapshot_path: std::env::var_os("SNAPSHOT_PATH").map(PathBuf::from),
+        snapshot_format: match std::env::var("SNAPSHOT_FORMAT").as_deref() {
+            Ok("binary") => SnapshotFormat::Binary,
+            Ok("json") | Err(_) => SnapshotFormat::Json,
+            Ok(other) => panic!("invalid SNAPSHOT_FORMAT {}, expected json or binary", other),
+        },
+        snapshot_persist_patterns: std::env::var("SNAPSHOT_PERSIST_PATTERNS")
+            .map(|patterns| split_list(&patterns))
+            .unwrap_or_default(),
+        stats_path: std::env::var_os("STATS_PATH").map(PathBuf::from),
+        statsd_addr: std::env::var("STATSD_ADDR").ok(),
+        statsd_every: std::env::var("STATSD_EVERY_SECS")
+            .map(|v| env_duration("STATSD_EVERY_SECS", &v))
+            .unwrap_or(default_config.statsd_every),
+        statsd_tags: std::env::var("STATSD_TAGS")
+            .map(|tags| split_list(&tags))
+            .unwrap_or_default(),
+        idle_ttl: std::env::var("IDLE_TTL_SECS")
+            .ok()
+            .map(|v| env_duration("IDLE_TTL_SECS", &v)),
+        latency_budget: std::env::var("LATENCY_BUDGET")
+            .ok()
+            .map(|v| env_duration("LATENCY_BUDGET", &v)),
+        slowlog_threshold: std::env::var("SLOWLOG_THRESHOLD")
+            .ok()
+            .map(|v| env_duration("SLOWLOG_THRESHOLD", &v)),
+        slowlog_len: std::env::var("SLOWLOG_LEN")
+            .map(|v| v.parse().expect("invalid SLOWLOG_LEN"))
+            .unwrap_or(default_config.slowlog_len),
+        max_connections: std::env::var("MAX_CONNECTIONS")
+            .ok()
+            .map(|v| v.parse().expect("invalid MAX_CONNECTIONS")),
+        audit_path: std::env::var_os("AUDIT_PATH").map(PathBuf::from),
+        audit_include_values: std::env::var("AUDIT_INCLUDE_VALUES")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(default_config.audit_include_values),
+        access_log_prefixes: std::env::var("ACCESS_LOG_PREFIXES")
+            .map(|prefixes| split_list(&prefixes))
+            .unwrap_or_default(),
+        max_value_bytes: std::env::var("MAX_VALUE_BYTES")
+            .ok()
+            .map(|v| v.parse().expect("invalid MAX_VALUE_BYTES")),
+        max_inflight_write_bytes: std::env::var("MAX_INFLIGHT_WRITE_BYTES")
+            .ok()
+            .map(|v| v.parse().expect("invalid MAX_INFLIGHT_WRITE_BYTES")),
+        max_tag_index_refs: std::env::var("MAX_TAG_INDEX_REFS")
+            .ok()
+            .map(|v| v.parse().expect("invalid MAX_TAG_INDEX_REFS")),
+        deferred_drop_min_bytes: std::env::var("DEFERRED_DROP_MIN_BYTES")
+            .ok()
+            .map(|v| v.parse().expect("invalid DEFERRED_DROP_MIN_BYTES")),
+        max_key_len: std::env::var("MAX_KEY_LEN")
+            .ok()
+            .map(|v| v.parse().expect("invalid MAX_KEY_LEN")),
+        replica_of: std::env::var("REPLICA_OF").ok(),
+        origin_timeout: std::env::var("ORIGIN_TIMEOUT_MS")
+            .ok()
+            .map(|v| Duration::from_millis(v.parse().expect("invalid ORIGIN_TIMEOUT_MS"))),
+        origin_connect_timeout: std::env::var("ORIGIN_CONNECT_TIMEOUT_MS")
+            .ok()
+            .map(|v| Duration::from_millis(v.parse().expect("invalid ORIGIN_CONNECT_TIMEOUT_MS"))),
+        origin_proxy: std::env::var("ORIGIN_PROXY").ok(),
+        origin_headers: std::env::var("ORIGIN_HEADERS")
+            .map(|headers| {
+                split_list(&headers)
+                    .iter()
+                    .map(|h| parse_header(h))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        origin_failure_threshold: std::env::var("ORIGIN_FAILURE_THRESHOLD")
+            .ok()
+            .map(|v| v.parse().expect("invalid ORIGIN_FAILURE_THRESHOLD")),
+        origin_cooldown: std::env::var("ORIGIN_COOLDOWN_SECS")
+            .map(|v| env_duration("ORIGIN_COOLDOWN_SECS", &v))
+            .unwrap_or(default_config.origin_cooldown),
+        allowed_key_prefixes: std::env::var("ALLOWED_KEY_PREFIXES")
+            .map(|prefixes| split_list(&prefixes))
+            .unwrap_or_default(),
+        cluster_peers: std::env::var("CLUSTER_PEERS")
+            .map(|peers| split_list(&peers))
+            .unwrap_or_default(),
+        enable_dashboard: std::env::var("ENABLE_DASHBOARD")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(default_config.enable_dashboard),
+        enable_last_modified: std::env::var("ENABLE_LAST_MODIFIED")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(default_config.enable_last_modified),
+        self_test: std::env::args().any(|a| a == "--self-test") || default_config.self_test,
+        ..default_config
+    };
+    let config_path = std::env::var_os("CONFIG_PATH").map(PathBuf::from);
+    let mut cache_config = match &config_path {
+        Some(path) => match load_config(path, &env_config) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("invalid config: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => env_config.clone(),
+    };
+    match cache_config.validate() {
+        Ok(warnings) => warnings.iter().for_each(|w| tracing::warn!("{}", w)),
+        Err(e) => {
+            tracing::error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let upstreams = match Upstreams::new(&cache_config) {
+        Ok(upstreams) => upstreams,
+        Err(e) => {
+            tracing::error!("invalid config: {}", e);
+            std::process::exit(1);
+        }
     };
+    let listen = cache_config.listen.clone();
+    let connection_limits = ConnectionLimits::from(&cache_config);
+    let run_self_test = cache_config.self_test;
 
-    let (tx, rx) = mpsc::unbounded_channel::<ServiceMessage>();
-    let mut service = TtlCacheService::new(cache_config, rx, &REALTIME);
+    let (tx, rx) = service_queue();
+    let flags = Arc::new(ServiceFlags::new(&cache_config));
+    let http_metrics = Arc::new(HttpMetrics::new());
+    let routes = make_api(
+        tx.clone(),
+        &cache_config,
+        flags.clone(),
+        http_metrics.clone(),
+        upstreams,
+    );
+
+    let statsd = StatsdExporter::new(&cache_config);
+    let mut service = TtlCacheService::new(cache_config, rx, flags, &REALTIME);
 
     tokio::spawn(async move { service.run().await });
+    if let Some(statsd) = statsd {
+        tokio::spawn(statsd.run(tx.clone()));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = config_path {
+        tokio::spawn(reload_on_hangup(path, env_config, tx.clone()));
+    }
+
+    if run_self_test {
+        match self_test(&tx).await {
+            Ok(()) => tracing::info!("self-test passed"),
+            Err(e) => {
+                tracing::error!("self-test failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let servers = serve_all(routes, &listen, http_metrics, connection_limits)
+        .expect("failed to bind listen addresses");
+    for (addr, _) in &servers {
+        tracing::info!("listening on http://{}", addr);
+    }
+    let serving = tokio::spawn(async move {
+        for (_, server) in servers {
+            server.await.expect("server task failed");
+        }
+    });
+
+    tokio::select! {
+        result = serving => result.expect("server task failed"),
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("shutting down");
+            let (cb, persisted) = oneshot::channel();
+            if tx.send(ServiceMessage::PersistStats(cb).into()).is_ok() {
+                if let Ok(Err(e)) = persisted.await {
+                    tracing::warn!("failed to persist stats: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// settings from the environment with the config file on top
+fn load_config(path: &Path, env_config: &Config) -> Result<Config, String> {
+    let file = ConfigFile::read(path)?;
+    // entries expire either by age or by idle time
+    if file.ttl_secs.is_some() && env_config.idle_ttl.is_some() {
+        return Err(String::from(
+            "ttl_secs cannot be combined with IDLE_TTL_SECS",
+        ));
+    }
+    let (config, ignored) = file.apply(env_config.clone());
+    ignored.iter().for_each(|w| tracing::warn!("{}", w));
+    Ok(config)
+}
+
+// re-reads the config file on every SIGHUP, a file that fails to load or validate
+// leaves the running config as it is
+#[cfg(unix)]
+async fn reload_on_hangup(path: PathBuf, env_config: Config, tx: ServiceQueue) {
+    use tokio::signal::unix::signal;
+    use tokio::signal::unix::SignalKind;
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::warn!(
+                "failed to listen for SIGHUP, config won't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        tracing::info!("reloading config from {}", path.display());
+        let mut config = match load_config(&path, &env_config) {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!("failed to reload config: {}", e);
+                continue;
+            }
+        };
+        match config.validate() {
+            Ok(warnings) => warnings.iter().for_each(|w| tracing::warn!("{}", w)),
+            Err(e) => {
+                tracing::error!("failed to reload config: {}", e);
+                continue;
+            }
+        }
+        if tx
+            .send(ServiceMessage::Reload(Box::new(config)).into())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
 
-    let routes = make_api(tx);
+// `*_SECS` variables, seconds or a duration like `1h30m`, see `parse_duration`
+fn env_duration(name: &str, value: &str) -> Duration {
+    parse_duration(value).unwrap_or_else(|e| {
+        panic!(
+            "invalid {} {}: {}, expected {}",
+            name, value, e, ACCEPTED_FORMATS
+        )
+    })
+}
+
+// `name: value`, see `ORIGIN_HEADERS`
+fn parse_header(header: &str) -> (String, String) {
+    match header.split_once(':') {
+        Some((name, value)) => (name.trim().to_string(), value.trim().to_string()),
+        None => panic!("invalid ORIGIN_HEADERS {}, expected name: value", header),
+    }
+}
+
+// `10.0.0.0/8`, a single address stands for itself, see `WRITE_ALLOWLIST`
+fn parse_net(net: &str) -> IpNet {
+    net.parse()
+        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+        .unwrap_or_else(|_| panic!("invalid WRITE_ALLOWLIST {}, expected a CIDR", net))
+}
 
-    warp::serve(routes).run(([127, 0, 0, 1], 8080)).await;
+// comma separated values, e.g. peer urls, blanks are skipped
+fn split_list(values: &str) -> Vec<String> {
+    values
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
 }
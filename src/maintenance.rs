@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+// runs kept for `/stats/maintenance`
+const RECENT_RUNS: usize = 16;
+
+// background work that must not interleave, e.g. a snapshot taken in the middle of an
+// eviction pass would persist entries that were just about to be removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceKind {
+    // in order of priority, eviction keeps memory bounded so it goes first
+    Eviction,
+    Snapshot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceState {
+    Idle,
+    Evicting,
+    Snapshotting,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MaintenanceRun {
+    pub kind: MaintenanceKind,
+    // unix seconds
+    pub started_at: u64,
+    pub took_us: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MaintenanceStatus {
+    pub state: MaintenanceState,
+    pub pending: Vec<MaintenanceKind>,
+    // oldest first
+    pub recent: Vec<MaintenanceRun>,
+}
+
+// lets one maintenance operation run at a time, others wait for it instead of being dropped,
+// only does the bookkeeping, running the operations is up to the caller
+#[derive(Default)]
+pub struct Coordinator {
+    running: Option<(MaintenanceKind, SystemTime)>,
+    // by priority, then in order of arrival
+    pending: Vec<MaintenanceKind>,
+    recent: VecDeque<MaintenanceRun>,
+}
+
+impl Coordinator {
+    pub fn new() -> Coordinator {
+        Coordinator::default()
+    }
+
+    pub fn state(&self) -> MaintenanceState {
+        match self.running {
+            None => MaintenanceState::Idle,
+            Some((MaintenanceKind::Eviction, _)) => MaintenanceState::Evicting,
+            Some((MaintenanceKind::Snapshot, _)) => MaintenanceState::Snapshotting,
+        }
+    }
+
+    pub fn is_running(&self, kind: MaintenanceKind) -> bool {
+        self.running.map(|(k, _)| k == kind).unwrap_or(false)
+    }
+
+    // an eviction pass already running or waiting covers any later one
+    pub fn request(&mut self, kind: MaintenanceKind) {
+        if kind == MaintenanceKind::Eviction
+            && (self.is_running(kind) || self.pending.contains(&kind))
+        {
+            return;
+        }
+        let at = self
+            .pending
+            .iter()
+            .position(|k| *k > kind)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(at, kind);
+    }
+
+    // starts the most important pending operation `can_start` allows, only when nothing runs
+    pub fn start_next(
+        &mut self,
+        can_start: impl Fn(MaintenanceKind) -> bool,
+    ) -> Option<MaintenanceKind> {
+        if self.running.is_some() {
+            return None;
+        }
+        let at = self.pending.iter().position(|k| can_start(*k))?;
+        let kind = self.pending.remove(at);
+        self.running = Some((kind, SystemTime::now()));
+        Some(kind)
+    }
+
+    pub fn finish(&mut self) {
+        if let Some((kind, started)) = self.running.take() {
+            if self.recent.len() == RECENT_RUNS {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(MaintenanceRun {
+                kind,
+                started_at: started
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                took_us: started.elapsed().unwrap_or(Duration::ZERO).as_micros(),
+            });
+        }
+    }
+
+    // puts the running operation back in front of others of its kind, to be started again
+    pub fn interrupt(&mut self) {
+        if let Some((kind, _)) = self.running.take() {
+            let at = self
+                .pending
+                .iter()
+                .position(|k| *k >= kind)
+                .unwrap_or(self.pending.len());
+            self.pending.insert(at, kind);
+        }
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        MaintenanceStatus {
+            state: self.state(),
+            pending: self.pending.clone(),
+            recent: self.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod maintenance_tests {
+    use crate::maintenance::Coordinator;
+    use crate::maintenance::MaintenanceKind;
+    use crate::maintenance::MaintenanceState;
+
+    #[test]
+    fn operations_never_overlap_and_eviction_goes_first() {
+        let mut coordinator = Coordinator::new();
+        coordinator.request(MaintenanceKind::Snapshot);
+        assert_eq!(
+            coordinator.start_next(|_| true),
+            Some(MaintenanceKind::Snapshot)
+        );
+
+        coordinator.request(MaintenanceKind::Snapshot);
+        coordinator.request(MaintenanceKind::Eviction);
+        coordinator.request(MaintenanceKind::Eviction);
+        assert_eq!(coordinator.start_next(|_| true), None);
+        let status = coordinator.status();
+        assert_eq!(status.state, MaintenanceState::Snapshotting);
+        assert_eq!(
+            status.pending,
+            vec![MaintenanceKind::Eviction, MaintenanceKind::Snapshot]
+        );
+
+        coordinator.finish();
+        assert_eq!(
+            coordinator.start_next(|_| true),
+            Some(MaintenanceKind::Eviction)
+        );
+        assert_eq!(coordinator.state(), MaintenanceState::Evicting);
+        assert_eq!(coordinator.status().recent.len(), 1);
+    }
+
+    #[test]
+    fn interrupted_operation_is_started_again() {
+        let mut coordinator = Coordinator::new();
+        coordinator.request(MaintenanceKind::Eviction);
+        coordinator.start_next(|_| true);
+        coordinator.request(MaintenanceKind::Snapshot);
+
+        // e.g. eviction paused, snapshot may run meanwhile
+        coordinator.interrupt();
+        assert_eq!(
+            coordinator.start_next(|k| k != MaintenanceKind::Eviction),
+            Some(MaintenanceKind::Snapshot)
+        );
+        coordinator.finish();
+        assert_eq!(
+            coordinator.start_next(|_| true),
+            Some(MaintenanceKind::Eviction)
+        );
+        assert!(coordinator.status().pending.is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::service::CacheStats;
+use crate::stats::Counters;
+
+// Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+const PREFIX: &str = "in_mem_cached";
+
+// label values requests are grouped by, anything not listed goes to `other`
+pub const ROUTES: [&str; 5] = ["get", "set", "del", "admin", "other"];
+
+// upper bounds of request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+struct RouteMetrics {
+    in_flight: AtomicUsize,
+    // requests that took at most the bound, not cumulative, last one for slower than all
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+// aggregate counters of the http layer, cheap enough to be updated on every request
+#[derive(Default)]
+pub struct HttpMetrics {
+    routes: [RouteMetrics; ROUTES.len()],
+    // open client connections over every listen address
+    connections: AtomicUsize,
+}
+
+impl HttpMetrics {
+    pub fn new() -> HttpMetrics {
+        HttpMetrics::default()
+    }
+
+    fn route_index(route: &str) -> usize {
+        ROUTES
+            .iter()
+            .position(|r| *r == route)
+            .unwrap_or(ROUTES.len() - 1)
+    }
+
+    // request counts as in flight until the returned guard is dropped
+    pub fn start(self: &Arc<Self>, route: &str) -> InFlight {
+        let route = HttpMetrics::route_index(route);
+        self.routes[route].in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            metrics: self.clone(),
+            route,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn in_flight(&self, route: &str) -> usize {
+        self.routes[HttpMetrics::route_index(route)]
+            .in_flight
+            .load(Ordering::Relaxed)
+    }
+
+    // connection counts as open until the returned guard is dropped
+    pub fn open_connection(self: &Arc<Self>) -> OpenConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection {
+            metrics: self.clone(),
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+pub struct InFlight {
+    metrics: Arc<HttpMetrics>,
+    route: usize,
+    started: Instant,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let route = &self.metrics.routes[self.route];
+        let took = self.started.elapsed();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| took.as_secs_f64() <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        route.count.fetch_add(1, Ordering::Relaxed);
+        route.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        route
+            .sum_us
+            .fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        route.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct OpenConnection {
+    metrics: Arc<HttpMetrics>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, value: u64) {
+    // writing to a String can not fail
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+    let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+}
+
+fn queue_length(out: &mut String, reads: usize, writes: usize) {
+    let name = format!("{}_service_queue_length", PREFIX);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{}{{lane=\"read\"}} {}", name, reads);
+    let _ = writeln!(out, "{}{{lane=\"write\"}} {}", name, writes);
+}
+
+fn http(out: &mut String, http: &HttpMetrics) {
+    let in_flight = format!("{}_http_requests_in_flight", PREFIX);
+    let _ = writeln!(out, "# TYPE {} gauge", in_flight);
+    for (route, metrics) in ROUTES.iter().zip(http.routes.iter()) {
+        let _ = writeln!(
+            out,
+            "{}{{route=\"{}\"}} {}",
+            in_flight,
+            route,
+            metrics.in_flight.load(Ordering::Relaxed)
+        );
+    }
+
+    let duration = format!("{}_http_request_duration_seconds", PREFIX);
+    let _ = writeln!(out, "# TYPE {} histogram", duration);
+    for (route, metrics) in ROUTES.iter().zip(http.routes.iter()) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(metrics.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                duration, route, bound, cumulative
+            );
+        }
+        let count = metrics.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+            duration, route, count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{route=\"{}\"}} {}",
+            duration,
+            route,
+            metrics.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count{{route=\"{}\"}} {}", duration, route, count);
+    }
+
+    metric(out, "http_connections", "gauge", http.connections() as u64);
+}
+
+// runtime the request is served on, which is the one the service runs on in the binary
+#[cfg(feature = "runtime-metrics")]
+fn runtime(out: &mut String) {
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        let metrics = handle.metrics();
+        metric(out, "tokio_workers", "gauge", metrics.num_workers() as u64);
+        metric(
+            out,
+            "tokio_alive_tasks",
+            "gauge",
+            metrics.num_alive_tasks() as u64,
+        );
+        // tasks spawned from outside the runtime waiting to be picked up by a worker
+        metric(
+            out,
+            "tokio_global_queue_depth",
+            "gauge",
+            metrics.global_queue_depth() as u64,
+        );
+    }
+}
+
+fn counters(out: &mut String, scope: &str, counters: &Counters) {
+    metric(
+        out,
+        &format!("{}_hits_total", scope),
+        "counter",
+        counters.hits,
+    );
+    metric(
+        out,
+        &format!("{}_misses_total", scope),
+        "counter",
+        counters.misses,
+    );
+    metric(
+        out,
+        &format!("{}_sets_total", scope),
+        "counter",
+        counters.sets,
+    );
+    metric(
+        out,
+        &format!("{}_evictions_total", scope),
+        "counter",
+        counters.evictions,
+    );
+}
+
+pub fn render(stats: &CacheStats, http_metrics: &HttpMetrics) -> String {
+    let mut out = String::new();
+
+    metric(&mut out, "keys", "gauge", stats.keys_total as u64);
+    metric(&mut out, "bytes", "gauge", stats.bytes_total as u64);
+    // estimated at the last eviction round
+    metric(
+        &mut out,
+        "expired_backlog_keys",
+        "gauge",
+        stats.expired_backlog.keys as u64,
+    );
+    metric(
+        &mut out,
+        "expired_backlog_bytes",
+        "gauge",
+        stats.expired_backlog.bytes as u64,
+    );
+    // since this process started
+    metric(
+        &mut out,
+        "process_started_at_seconds",
+        "gauge",
+        stats.started_at,
+    );
+    counters(&mut out, "process", &stats.process);
+    // including counters restored from `stats_path`
+    metric(
+        &mut out,
+        "lifetime_started_at_seconds",
+        "gauge",
+        stats.lifetime_started_at,
+    );
+    counters(&mut out, "lifetime", &stats.lifetime);
+    queue_length(&mut out, stats.queued.reads, stats.queued.writes);
+    metric(
+        &mut out,
+        "inflight_write_bytes",
+        "gauge",
+        stats.queued.inflight_write_bytes as u64,
+    );
+    // handed over to the dropping thread, see `deferred_drop_min_bytes`
+    metric(
+        &mut out,
+        "deferred_drops_total",
+        "counter",
+        stats.deferred_drops,
+    );
+    metric(
+        &mut out,
+        "deferred_drop_bytes_total",
+        "counter",
+        stats.deferred_drop_bytes,
+    );
+    http(&mut out, http_metrics);
+
+    #[cfg(feature = "runtime-metrics")]
+    runtime(&mut out);
+
+    out
+}
@@ -0,0 +1,241 @@
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+// request or response body of a route
+#[derive(Clone, Copy, Debug)]
+pub enum Body {
+    Empty,
+    // value bytes as they are stored, or lines of them
+    Raw,
+    Text,
+    Html,
+    EventStream,
+    // one of the schemas of `SCHEMAS`
+    Json(&'static str),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueryParam {
+    name: &'static str,
+    // json schema type
+    kind: &'static str,
+    required: bool,
+}
+
+pub const fn optional(name: &'static str, kind: &'static str) -> QueryParam {
+    QueryParam {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+pub const fn required(name: &'static str, kind: &'static str) -> QueryParam {
+    QueryParam {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+// what `/openapi.json` tells about a route, added next to where the route is defined in
+// `make_api`, path parameters are written as `{name}` and stand for a single segment
+#[derive(Clone, Debug)]
+pub struct RouteDoc {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    query: Vec<QueryParam>,
+    request: Body,
+    response: Body,
+    // behind `admin_token`
+    admin: bool,
+}
+
+impl RouteDoc {
+    pub fn new(method: &'static str, path: &'static str, summary: &'static str) -> RouteDoc {
+        RouteDoc {
+            method,
+            path,
+            summary,
+            query: Vec::new(),
+            request: Body::Empty,
+            response: Body::Empty,
+            admin: false,
+        }
+    }
+
+    pub fn query(self, query: &[QueryParam]) -> RouteDoc {
+        RouteDoc {
+            query: query.to_vec(),
+            ..self
+        }
+    }
+
+    pub fn request(self, request: Body) -> RouteDoc {
+        RouteDoc { request, ..self }
+    }
+
+    pub fn response(self, response: Body) -> RouteDoc {
+        RouteDoc { response, ..self }
+    }
+
+    pub fn admin(self) -> RouteDoc {
+        RouteDoc {
+            admin: true,
+            ..self
+        }
+    }
+
+    fn operation(&self, auth: bool) -> Value {
+        let mut parameters: Vec<Value> = self
+            .path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": {"type": "string"},
+                })
+            })
+            .collect();
+        parameters.extend(self.query.iter().map(|param| {
+            json!({
+                "name": param.name,
+                "in": "query",
+                "required": param.required,
+                "schema": {"type": param.kind},
+            })
+        }));
+
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": response(self.response),
+                "default": response(Body::Json("Error")),
+            },
+        });
+        if let Some(content) = content(self.request) {
+            operation["requestBody"] = json!({"required": true, "content": content});
+        }
+        if self.admin && auth {
+            operation["security"] = json!([{"adminToken": []}]);
+        }
+        operation
+    }
+}
+
+fn content(body: Body) -> Option<Value> {
+    let (media_type, schema) = match body {
+        Body::Empty => return None,
+        Body::Raw => (
+            "application/octet-stream",
+            json!({"type": "string", "format": "binary"}),
+        ),
+        Body::Text => ("text/plain", json!({"type": "string"})),
+        Body::Html => ("text/html", json!({"type": "string"})),
+        Body::EventStream => ("text/event-stream", json!({"type": "string"})),
+        Body::Json(name) => (
+            "application/json",
+            json!({"$ref": format!("#/components/schemas/{}", name)}),
+        ),
+    };
+    Some(json!({ media_type: {"schema": schema} }))
+}
+
+fn response(body: Body) -> Value {
+    let mut response = json!({"description": ""});
+    if let Some(content) = content(body) {
+        response["content"] = content;
+    }
+    response
+}
+
+// JSON envelopes referred to by `Body::Json`
+const SCHEMAS: &[(&str, &str)] = &[
+    (
+        "Error",
+        r#"{"type": "object", "required": ["error"], "properties": {"error": {"type": "string"}}}"#,
+    ),
+    ("Keys", r#"{"type": "array", "items": {"type": "string"}}"#),
+    // in the order of the keys asked for, null for missing ones
+    (
+        "Values",
+        r#"{"type": "array", "items": {"type": "string", "nullable": true}}"#,
+    ),
+    // remaining ttl in ms
+    (
+        "Ttls",
+        r#"{"type": "array", "items": {"type": "integer", "nullable": true}}"#,
+    ),
+    (
+        "TxnOps",
+        r#"{"type": "array", "items": {"type": "object", "required": ["op", "key"], "properties": {"op": {"type": "string", "enum": ["set", "del", "expire", "incr"]}, "key": {"type": "string"}, "value": {"type": "string"}, "ttl_secs": {"type": "integer"}, "by": {"type": "integer"}, "nx": {"type": "boolean"}, "xx": {"type": "boolean"}}}}"#,
+    ),
+    (
+        "Item",
+        r#"{"type": "object", "properties": {"value": {"type": "string"}, "encoding": {"type": "string", "enum": ["utf8", "base64"]}, "ttl_secs": {"type": "integer", "nullable": true}, "created_at": {"type": "integer"}, "version": {"type": "integer"}}}"#,
+    ),
+    (
+        "Info",
+        r#"{"type": "object", "properties": {"version": {"type": "string"}, "api_version": {"type": "string"}, "generation": {"type": "integer"}, "read_only": {"type": "boolean"}}}"#,
+    ),
+    (
+        "SnapshotReport",
+        r#"{"type": "object", "properties": {"entries": {"type": "integer"}, "skipped": {"type": "integer"}}}"#,
+    ),
+    ("Object", r#"{"type": "object"}"#),
+    ("OpenApi", r#"{"type": "object"}"#),
+];
+
+// routes mounted by `make_api`, in the order they are defined
+#[derive(Default)]
+pub struct RouteDocs {
+    routes: Vec<RouteDoc>,
+}
+
+impl RouteDocs {
+    pub fn add(&mut self, route: RouteDoc) {
+        self.routes.push(route);
+    }
+
+    // OpenAPI 3.0 document of the routes, served at `/openapi.json`
+    pub fn document(&self, api_version: &str, auth: bool) -> Value {
+        let mut paths = Map::new();
+        for route in &self.routes {
+            let item = paths
+                .entry(route.path)
+                .or_insert_with(|| Value::Object(Map::new()));
+            item[route.method] = route.operation(auth);
+        }
+
+        let schemas: Map<String, Value> = SCHEMAS
+            .iter()
+            .map(|(name, schema)| {
+                let schema = serde_json::from_str(schema).expect("invalid schema");
+                (name.to_string(), schema)
+            })
+            .collect();
+        let mut components = json!({ "schemas": schemas });
+        if auth {
+            components["securitySchemes"] =
+                json!({"adminToken": {"type": "http", "scheme": "bearer"}});
+        }
+
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            // routes are served without the prefix as well, marked as deprecated
+            "servers": [{"url": format!("/{}", api_version)}],
+            "paths": paths,
+            "components": components,
+        })
+    }
+}
@@ -0,0 +1,253 @@
+use crate::cache::ConditionalDelete;
+use crate::cache::TtlCache;
+use crate::config::Config;
+use crate::service::ServiceMessage;
+use crate::service::ServiceQueue;
+use crate::time::REALTIME;
+use crate::txn::TxnOp;
+
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+const SELF_TEST_KEY: &str = "__self_test__";
+
+async fn call<V>(
+    queue: &ServiceQueue,
+    message: impl FnOnce(oneshot::Sender<V>) -> ServiceMessage,
+) -> Result<V, String> {
+    let (tx, rx) = oneshot::channel();
+    queue
+        .send(message(tx).into())
+        .map_err(|e| format!("service is not running: {}", e))?;
+    rx.await
+        .map_err(|e| format!("service did not reply: {}", e))
+}
+
+// runs set/get/delete/expiry cycle through the service queue,
+// error describes the first step that did not behave as expected
+pub async fn self_test(queue: &ServiceQueue) -> Result<(), String> {
+    let key = String::from(SELF_TEST_KEY);
+    let value = b"self-test".to_vec();
+
+    call(queue, |cb| {
+        ServiceMessage::Write(key.clone(), value.clone(), cb)
+    })
+    .await?
+    .map_err(|e| format!("set failed: {}", e))?;
+
+    match call(queue, |cb| ServiceMessage::Read(key.clone(), cb)).await? {
+        Some(v) if v == value => (),
+        other => return Err(format!("get returned {:?} after set", other)),
+    }
+
+    if call(queue, |cb| ServiceMessage::Delete(key.clone(), None, cb))
+        .await?
+        .map_err(|e| format!("delete failed: {}", e))?
+        != ConditionalDelete::Deleted
+    {
+        return Err(String::from("delete did not find the key"));
+    }
+    if call(queue, |cb| ServiceMessage::Read(key.clone(), cb))
+        .await?
+        .is_some()
+    {
+        return Err(String::from("get returned value after delete"));
+    }
+
+    let set_expiring = vec![TxnOp::Set {
+        key: key.clone(),
+        value: String::from("self-test"),
+        ttl_secs: Some(0),
+        nx: false,
+        xx: false,
+    }];
+    let result = call(queue, |cb| ServiceMessage::Txn(set_expiring, cb))
+        .await?
+        .map_err(|e| format!("set with ttl failed: {}", e))?;
+    if !result.committed {
+        return Err(format!("set with ttl failed: {:?}", result.results));
+    }
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    if call(queue, |cb| ServiceMessage::Read(key.clone(), cb))
+        .await?
+        .is_some()
+    {
+        return Err(String::from("get returned value after it expired"));
+    }
+
+    Ok(())
+}
+
+// upper bounds for a benchmark, so that it can not take the instance down
+pub const MAX_BENCH_OPS: usize = 100_000;
+pub const MAX_BENCH_KEY_SIZE: usize = 1024;
+pub const MAX_BENCH_VALUE_SIZE: usize = 64 * 1024;
+// keys and values held by the throwaway cache at once
+pub const MAX_BENCH_BYTES: usize = 64 * 1024 * 1024;
+
+// synthetic workload, `sets` writes of distinct keys followed by `gets` reads of them
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BenchParams {
+    pub sets: usize,
+    pub gets: usize,
+    pub key_size: usize,
+    pub value_size: usize,
+}
+
+impl Default for BenchParams {
+    fn default() -> BenchParams {
+        BenchParams {
+            sets: 1000,
+            gets: 1000,
+            key_size: 16,
+            value_size: 128,
+        }
+    }
+}
+
+impl BenchParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sets > MAX_BENCH_OPS || self.gets > MAX_BENCH_OPS {
+            return Err(format!("at most {} sets and gets allowed", MAX_BENCH_OPS));
+        }
+        if self.key_size == 0 || self.key_size > MAX_BENCH_KEY_SIZE {
+            return Err(format!("key_size has to be 1..={}", MAX_BENCH_KEY_SIZE));
+        }
+        if self.value_size > MAX_BENCH_VALUE_SIZE {
+            return Err(format!(
+                "value_size has to be at most {}",
+                MAX_BENCH_VALUE_SIZE
+            ));
+        }
+        if self.sets * (self.key_size + self.value_size) > MAX_BENCH_BYTES {
+            return Err(format!(
+                "sets would store more than {} bytes",
+                MAX_BENCH_BYTES
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OpBench {
+    pub ops: usize,
+    pub ops_per_sec: f64,
+    pub p50_us: f64,
+    pub p99_us: f64,
+}
+
+impl OpBench {
+    fn new(mut latencies: Vec<Duration>, elapsed: Duration) -> OpBench {
+        latencies.sort();
+        let percentile = |p: f64| {
+            latencies
+                .get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1)))
+                .map(|l| l.as_secs_f64() * 1e6)
+                .unwrap_or(0.0)
+        };
+        OpBench {
+            ops: latencies.len(),
+            ops_per_sec: if elapsed.is_zero() {
+                0.0
+            } else {
+                latencies.len() as f64 / elapsed.as_secs_f64()
+            },
+            p50_us: percentile(0.5),
+            p99_us: percentile(0.99),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub set: OpBench,
+    pub get: OpBench,
+}
+
+fn timed(ops: usize, mut op: impl FnMut(usize)) -> OpBench {
+    let mut latencies = Vec::with_capacity(ops);
+    let started = Instant::now();
+    for i in 0..ops {
+        let op_started = Instant::now();
+        op(i);
+        latencies.push(op_started.elapsed());
+    }
+    OpBench::new(latencies, started.elapsed())
+}
+
+// runs the workload against a throwaway cache configured like the live one, minus its
+// limits, blocks for the whole run so it belongs on a blocking thread
+pub fn bench(config: &Config, params: &BenchParams) -> BenchReport {
+    let mut cache = TtlCache::new(
+        Config {
+            capacity: None,
+            max_bytes: None,
+            initial_capacity: Some(params.sets),
+            ..config.clone()
+        },
+        &REALTIME,
+    );
+    let key = |i: usize| format!("{:0>width$}", i, width = params.key_size);
+    let value = vec![b'x'; params.value_size];
+
+    let set = timed(params.sets, |i| {
+        // limits are lifted, nothing to fail on
+        let _ = cache.set_with_ttl(key(i), value.clone(), None);
+    });
+    let get = timed(params.gets, |i| {
+        cache.get(&key(i % params.sets.max(1)));
+    });
+
+    BenchReport { set, get }
+}
+
+#[cfg(test)]
+mod selftest_tests {
+    use crate::config::Config;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::selftest::self_test;
+    use crate::service::service_queue;
+    use crate::service::ServiceFlags;
+    use crate::service::ServiceQueue;
+    use crate::service::TtlCacheService;
+    use crate::time::REALTIME;
+
+    use std::sync::Arc;
+
+    fn spawn_service(config: Config) -> ServiceQueue {
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move {
+            TtlCacheService::new(config, rx, flags, &REALTIME)
+                .run()
+                .await
+        });
+        tx
+    }
+
+    #[tokio::test]
+    async fn self_test_passes_against_working_service() {
+        let tx = spawn_service(TEST_CONFIG_SINGLE_ITEM);
+
+        assert_eq!(self_test(&tx).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn self_test_fails_against_zero_capacity_service() {
+        let tx = spawn_service(Config {
+            capacity: Some(0),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        assert_eq!(
+            self_test(&tx).await,
+            Err(String::from("set failed: out of capacity: Some(0)"))
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use hyper::service::make_service_fn;
+use hyper::service::service_fn;
+use hyper::service::Service;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio::time::Sleep;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use warp::Filter;
+
+use crate::config::Config;
+use crate::metrics::HttpMetrics;
+use crate::metrics::OpenConnection;
+
+// accepted connections waiting to be picked up by the server
+const ACCEPT_BACKLOG: usize = 128;
+
+// limits on connections of every server, against clients holding connections open by
+// sending requests byte by byte or not at all, nothing is limited by default
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectionLimits {
+    // connections served at once over all addresses, others wait in the listen backlog
+    pub max_connections: Option<usize>,
+    // connection is closed when headers of a request do not arrive in time
+    pub header_read_timeout: Option<Duration>,
+    // connection is closed when nothing is read nor written for this long
+    pub idle_timeout: Option<Duration>,
+}
+
+impl From<&Config> for ConnectionLimits {
+    fn from(config: &Config) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: config.max_connections,
+            header_read_timeout: config.header_read_timeout,
+            idle_timeout: config.idle_connection_timeout,
+        }
+    }
+}
+
+// address of the client, put into request extensions since warp only knows it for
+// connections it accepted itself, see `warp::addr::remote`
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+// binds every address up front, so misconfigured one fails the startup instead of
+// leaving service half listening, then spawns a server per address sharing the routes
+pub fn serve_all<F>(
+    routes: F,
+    addrs: &[SocketAddr],
+    http_metrics: Arc<HttpMetrics>,
+    limits: ConnectionLimits,
+) -> io::Result<Vec<(SocketAddr, JoinHandle<()>)>>
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let listeners = addrs
+        .iter()
+        .map(|addr| {
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let connections = limits
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    listeners
+        .into_iter()
+        .map(|listener| {
+            let addr = listener.local_addr()?;
+            let (accepted, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+            tokio::spawn(accept(
+                listener,
+                accepted,
+                http_metrics.clone(),
+                connections.clone(),
+                limits.idle_timeout,
+            ));
+            let incoming = ReceiverStream::new(incoming).map(Ok::<_, io::Error>);
+            let mut server = hyper::Server::builder(hyper::server::accept::from_stream(incoming));
+            if let Some(timeout) = limits.header_read_timeout {
+                server = server.http1_header_read_timeout(timeout);
+            }
+            let routes = routes.clone();
+            let server = server.serve(make_service_fn(move |conn: &Counted| {
+                let client = conn.remote_addr.map(ClientAddr);
+                let mut service = warp::service(routes.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |mut request| {
+                        if let Some(client) = client {
+                            request.extensions_mut().insert(client);
+                        }
+                        service.call(request)
+                    }))
+                }
+            }));
+            let server = async move {
+                if let Err(e) = server.await {
+                    tracing::error!("server on {} failed: {}", addr, e);
+                }
+            };
+            Ok((addr, tokio::spawn(server)))
+        })
+        .collect()
+}
+
+// accepts connections for as long as the server takes them, while `max_connections` are
+// open new ones are left waiting in the listen backlog
+async fn accept(
+    listener: TcpListener,
+    accepted: mpsc::Sender<Counted>,
+    http_metrics: Arc<HttpMetrics>,
+    connections: Option<Arc<Semaphore>>,
+    idle_timeout: Option<Duration>,
+) {
+    loop {
+        let permit = match &connections {
+            Some(connections) => match connections.clone().acquire_owned().await {
+                Ok(permit) => Some(permit),
+                Err(_) => break,
+            },
+            None => None,
+        };
+        match listener.accept().await {
+            Ok((stream, remote_addr)) => {
+                let counted = Counted {
+                    stream,
+                    remote_addr: Some(remote_addr),
+                    idle: idle_timeout.map(|timeout| Idle {
+                        timeout,
+                        deadline: Box::pin(tokio::time::sleep(timeout)),
+                    }),
+                    _open: http_metrics.open_connection(),
+                    _permit: permit,
+                };
+                if accepted.send(counted).await.is_err() {
+                    break;
+                }
+            }
+            // e.g. out of file descriptors, retried once some are released
+            Err(e) => {
+                tracing::warn!("failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+struct Idle {
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+// connection counted as open in http metrics, and against `max_connections`, until
+// dropped by the server
+struct Counted {
+    stream: TcpStream,
+    remote_addr: Option<SocketAddr>,
+    idle: Option<Idle>,
+    _open: OpenConnection,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Counted {
+    // pending read or write fails once the connection was idle for too long
+    fn poll_idle<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let idle = match &mut self.idle {
+            Some(idle) => idle,
+            None => return poll,
+        };
+        match poll {
+            Poll::Ready(result) => {
+                let deadline = Instant::now() + idle.timeout;
+                idle.deadline.as_mut().reset(deadline);
+                Poll::Ready(result)
+            }
+            Poll::Pending => match idle.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection idle for too long",
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.poll_idle(cx, poll)
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.poll_idle(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use crate::config::Config;
+    use crate::metrics::HttpMetrics;
+    use crate::server::serve_all;
+    use crate::server::ConnectionLimits;
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use warp::Filter;
+
+    async fn health_check(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /health-check HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn server_is_started_on_every_address() {
+        let routes = warp::path("health-check").map(|| "Ok");
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ];
+
+        let servers = serve_all(
+            routes,
+            &addrs,
+            Arc::new(HttpMetrics::new()),
+            ConnectionLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!(servers.len(), 2);
+        assert_ne!(servers[0].0.port(), servers[1].0.port());
+        for (addr, _) in &servers {
+            assert!(health_check(*addr).await.ends_with("Ok"));
+        }
+    }
+
+    #[tokio::test]
+    async fn address_in_use_fails_the_whole_setup() {
+        let routes = warp::path("health-check").map(|| "Ok");
+        let http_metrics = Arc::new(HttpMetrics::new());
+        let servers = serve_all(
+            routes,
+            &["127.0.0.1:0".parse().unwrap()],
+            http_metrics.clone(),
+            ConnectionLimits::default(),
+        )
+        .unwrap();
+        let taken = servers[0].0;
+
+        assert!(serve_all(
+            routes,
+            &["127.0.0.1:0".parse().unwrap(), taken],
+            http_metrics,
+            ConnectionLimits::default()
+        )
+        .is_err());
+    }
+
+    async fn wait_for_connections(http_metrics: &HttpMetrics, expected: usize) {
+        for _ in 0..100 {
+            if http_metrics.connections() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(http_metrics.connections(), expected);
+    }
+
+    #[tokio::test]
+    async fn open_connections_are_counted() {
+        let routes = warp::path("health-check").map(|| "Ok");
+        let http_metrics = Arc::new(HttpMetrics::new());
+        let servers = serve_all(
+            routes,
+            &["127.0.0.1:0".parse().unwrap()],
+            http_metrics.clone(),
+            ConnectionLimits::default(),
+        )
+        .unwrap();
+        let addr = servers[0].0;
+
+        let idle = TcpStream::connect(addr).await.unwrap();
+        let other = TcpStream::connect(addr).await.unwrap();
+        wait_for_connections(&http_metrics, 2).await;
+
+        // closed by the server after the response
+        assert!(health_check(addr).await.ends_with("Ok"));
+        wait_for_connections(&http_metrics, 2).await;
+
+        drop(idle);
+        drop(other);
+        wait_for_connections(&http_metrics, 0).await;
+    }
+
+    #[test]
+    fn connection_limits_are_taken_from_config() {
+        let config = Config {
+            max_connections: Some(100),
+            header_read_timeout: Some(Duration::from_secs(5)),
+            idle_connection_timeout: None,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            ConnectionLimits::from(&config),
+            ConnectionLimits {
+                max_connections: Some(100),
+                header_read_timeout: Some(Duration::from_secs(5)),
+                idle_timeout: None,
+            }
+        );
+    }
+
+    fn serve_with_limits(limits: ConnectionLimits) -> SocketAddr {
+        let routes = warp::path("health-check").map(|| "Ok");
+        let servers = serve_all(
+            routes,
+            &["127.0.0.1:0".parse().unwrap()],
+            Arc::new(HttpMetrics::new()),
+            limits,
+        )
+        .unwrap();
+        servers[0].0
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_for_open_ones_to_close() {
+        let addr = serve_with_limits(ConnectionLimits {
+            max_connections: Some(1),
+            ..ConnectionLimits::default()
+        });
+
+        let open = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let waiting = tokio::spawn(health_check(addr));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiting.is_finished());
+
+        drop(open);
+        let response = tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.ends_with("Ok"));
+    }
+
+    // connection is expected to be closed by the server without an answer
+    async fn assert_dropped(mut stream: TcpStream) {
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("connection was not dropped");
+        assert!(read.is_err() || !String::from_utf8_lossy(&response).contains("Ok"));
+    }
+
+    #[tokio::test]
+    async fn slow_headers_and_idle_connections_are_dropped() {
+        let addr = serve_with_limits(ConnectionLimits {
+            header_read_timeout: Some(Duration::from_millis(100)),
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..ConnectionLimits::default()
+        });
+
+        // request line sent, headers never finished
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        slow.write_all(b"GET /health-check HTTP/1.1\r\nhost: loc")
+            .await
+            .unwrap();
+        assert_dropped(slow).await;
+
+        let addr = serve_with_limits(ConnectionLimits {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..ConnectionLimits::default()
+        });
+        assert_dropped(TcpStream::connect(addr).await.unwrap()).await;
+        // requests sent in time are served
+        assert!(health_check(addr).await.ends_with("Ok"));
+    }
+}
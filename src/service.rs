@@ -1,73 +1,3697 @@
+use crate::audit::AuditLog;
+use crate::audit::AuditRecord;
+use crate::auxiliary::AuxiliaryMemory;
+use crate::auxiliary::AuxiliaryUsage;
+use crate::bulk::BulkWrite;
+use crate::cache::glob_match;
+use crate::cache::CacheError;
+use crate::cache::ConditionalDelete;
+use crate::cache::ConditionalRead;
+use crate::cache::DatedRead;
+use crate::cache::EntryMeta;
+use crate::cache::ExpiredBacklog;
+use crate::cache::Explanation;
+use crate::cache::Item;
+use crate::cache::LockedRead;
+use crate::cache::TagInvalidation;
+use crate::cache::Transform;
 use crate::cache::TtlCache;
+use crate::cache::TtlHistogram;
+use crate::config::CapacityUnit;
 use crate::config::Config;
+use crate::config::KeyCanonicalization;
+use crate::deferred_drop::DeferredDrop;
+use crate::events::EventFilter;
+use crate::events::EventKind;
+use crate::events::EventSender;
+use crate::events::SubscriberSnapshot;
+use crate::events::SubscriberStats;
+use crate::events::Subscription;
+use crate::maintenance::Coordinator;
+use crate::maintenance::MaintenanceKind;
+use crate::maintenance::MaintenanceStatus;
+use crate::slowlog::KeyPrefix;
+use crate::slowlog::SlowLog;
+use crate::slowlog::SlowOperation;
+use crate::snapshot;
+use crate::snapshot::SnapshotError;
+use crate::stats;
+use crate::stats::Counters;
+use crate::stats::PersistedStats;
 use crate::time::Time;
+use crate::txn::TxnOp;
+use crate::txn::TxnResult;
 
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::TryReserveError;
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use serde::Serialize;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::oneshot;
+use tokio::sync::Semaphore;
 use tracing::instrument;
 
+// what a write sets along with the value, cache ttl applies when there is none
+#[derive(Debug, Default)]
+pub struct WriteOptions {
+    pub tags: Vec<String>,
+    pub ttl: Option<Duration>,
+    // write is rejected unless the key is still at this version, see `TtlCache::check_version`
+    pub expected_version: Option<u64>,
+}
+
 pub enum ServiceMessage {
-    Read(String, oneshot::Sender<Option<String>>),
-    Write(String, String, oneshot::Sender<Result<(), String>>),
+    Read(String, oneshot::Sender<Option<Vec<u8>>>),
+    // same as `Read` without side effects, see `TtlCache::peek`
+    Peek(String, oneshot::Sender<Option<Vec<u8>>>),
+    Write(String, Vec<u8>, oneshot::Sender<Result<(), CacheError>>),
+    // same as `Write`, with tags the entry can later be invalidated by and a ttl of its own
+    WriteTagged(
+        String,
+        Vec<u8>,
+        Box<WriteOptions>,
+        oneshot::Sender<Result<(), CacheError>>,
+    ),
+    // value the write transform was already applied to on the blocking pool, sent by the
+    // service to itself, see `transform_workers`
+    WriteStored(
+        String,
+        Vec<u8>,
+        Box<WriteOptions>,
+        oneshot::Sender<Result<(), CacheError>>,
+    ),
+    // write transform failed on the blocking pool, sent by the service to itself so that
+    // what was sent after the write of the key goes ahead
+    TransformFailed(String),
+    // writes applied one after another, with a result for each, see `/bulk-load`
+    WriteBatch(Vec<BulkWrite>, oneshot::Sender<Vec<Result<(), CacheError>>>),
+    ReadMany(Vec<String>, oneshot::Sender<Vec<Option<Vec<u8>>>>),
+    // remaining ttl of every key, `None` for missing ones, not counted as reads
+    MultiTtl(Vec<String>, oneshot::Sender<Vec<Option<Duration>>>),
+    // value only if it was set after given unix time in seconds
+    ReadIfModifiedSince(String, u64, oneshot::Sender<ConditionalRead>),
+    // value with its creation time in unix seconds, optionally only if set after given time
+    ReadDated(String, Option<u64>, oneshot::Sender<DatedRead>),
+    // value or refresh lock held for given time, see `TtlCache::get_or_lock`
+    ReadOrLock(String, Duration, oneshot::Sender<LockedRead>),
+    // deletes unless given, entry was created at or after that unix time in ms
+    Delete(
+        String,
+        Option<u64>,
+        oneshot::Sender<Result<ConditionalDelete, CacheError>>,
+    ),
+    // settings that can change while running taken from the config, see `ConfigFile`
+    Reload(Box<Config>),
+    // removes every entry of the default namespace, served ahead of queued writes, with
+    // fence writes sent before the flush are rejected instead of bringing entries back
+    Flush(bool, oneshot::Sender<Result<FlushReport, CacheError>>),
+    // exchanges entries of two keys, see `TtlCache::swap`
+    Swap(String, String, oneshot::Sender<Result<(), CacheError>>),
+    // moves entry to another key, replacing a live one only when told to, see `TtlCache::rename`
+    Rename(
+        String,
+        String,
+        bool,
+        oneshot::Sender<Result<(), CacheError>>,
+    ),
+    // pins or unpins the entry of the key, see `TtlCache::set_pinned`
+    Pin(String, bool, oneshot::Sender<Result<(), CacheError>>),
+    // zeroes read count of the entry, replies with the count before, see `TtlCache::reset_hits`
+    ResetHits(String, oneshot::Sender<Result<u64, CacheError>>),
+    // appends a line keeping at most given number of them, see `TtlCache::ring_push`
+    RingPush(
+        String,
+        Vec<u8>,
+        usize,
+        oneshot::Sender<Result<usize, CacheError>>,
+    ),
+    // deletes keys carrying the tag, in batches, see `TtlCache::invalidate_tag`
+    InvalidateTag(String, oneshot::Sender<Result<TagInvalidation, CacheError>>),
+    Meta(String, oneshot::Sender<Option<EntryMeta>>),
+    // same as `Read`, along with remaining ttl and hits, transformed on the service task
+    ReadWithMeta(String, oneshot::Sender<Option<(Vec<u8>, EntryMeta)>>),
+    // same as `Read`, along with when the entry was written and its version
+    ReadItem(String, oneshot::Sender<Option<Item>>),
+    // length of the value, without transferring it
+    ValueLen(String, oneshot::Sender<Option<usize>>),
+    // whether a value of given size could be written under the key right now, advisory,
+    // the write is checked again, see `TtlCache::explain`
+    CanWrite(String, usize, oneshot::Sender<bool>),
+    // what would happen to the key, optionally on a write of a value of given size
+    Explain(String, Option<usize>, oneshot::Sender<Explanation>),
+    // number of live keys matching glob pattern
+    Count(String, oneshot::Sender<usize>),
+    Txn(Vec<TxnOp>, oneshot::Sender<Result<TxnResult, CacheError>>),
+    Stats(oneshot::Sender<CacheStats>),
+    TtlHistogram(oneshot::Sender<TtlHistogram>),
+    Compact(oneshot::Sender<CompactReport>),
+    // grows the map ahead of this many new keys, see `growth_warning_threshold`
+    Reserve(
+        usize,
+        oneshot::Sender<Result<CompactReport, TryReserveError>>,
+    ),
+    // complete eviction pass, regardless of the schedule
+    Evict(oneshot::Sender<EvictionReport>),
+    // waits for maintenance already running, see `Coordinator`
+    Snapshot(oneshot::Sender<Result<SnapshotReport, SnapshotError>>),
+    MaintenanceStatus(oneshot::Sender<MaintenanceStatus>),
+    // writes counters to `stats_path` right away, e.g. before shutdown
+    PersistStats(oneshot::Sender<io::Result<()>>),
+    // optionally resumed after the event with given seq, see `event_history`, filter is boxed
+    // the same as the value of `NsWrite`
+    Subscribe(
+        Box<EventFilter>,
+        Option<u64>,
+        oneshot::Sender<Result<Subscription, CacheError>>,
+    ),
+    // replied right away, time to the reply is how long requests wait for the service
+    Ping(oneshot::Sender<()>),
+    // same as `Read` and `Write` within the namespace, first write creates it
+    NsRead(String, String, oneshot::Sender<Option<Vec<u8>>>),
+    // value is boxed so that requests moved through the queues stay small
+    NsWrite(
+        String,
+        String,
+        Box<[u8]>,
+        oneshot::Sender<Result<(), CacheError>>,
+    ),
+    // namespace keeps serving reads, writes to it are rejected from now on
+    NsDetach(String, oneshot::Sender<Result<(), CacheError>>),
+    // copies of the live entries of the namespace, encoded by the caller with `snapshot::dump`
+    NsDump(String, oneshot::Sender<Result<Vec<DumpEntry>, CacheError>>),
+    // drops the namespace along with its entries, returns how many there were
+    NsDrop(String, oneshot::Sender<Result<usize, CacheError>>),
+    // operations over `slowlog_threshold`, most recent first
+    SlowLog(oneshot::Sender<Vec<SlowOperation>>),
+    // empties the slow log, replies with how many operations were in it
+    ResetSlowLog(oneshot::Sender<usize>),
+}
+
+impl ServiceMessage {
+    // true when whoever sent the message is no longer waiting for the reply
+    fn is_cancelled(&self) -> bool {
+        match self {
+            ServiceMessage::Read(_, cb) => cb.is_closed(),
+            ServiceMessage::Peek(_, cb) => cb.is_closed(),
+            ServiceMessage::WriteBatch(_, cb) => cb.is_closed(),
+            ServiceMessage::Write(_, _, cb) => cb.is_closed(),
+            ServiceMessage::WriteTagged(_, _, _, cb) => cb.is_closed(),
+            ServiceMessage::WriteStored(_, _, _, cb) => cb.is_closed(),
+            ServiceMessage::TransformFailed(_) => false,
+            ServiceMessage::ReadMany(_, cb) => cb.is_closed(),
+            ServiceMessage::MultiTtl(_, cb) => cb.is_closed(),
+            ServiceMessage::ReadIfModifiedSince(_, _, cb) => cb.is_closed(),
+            ServiceMessage::ReadDated(_, _, cb) => cb.is_closed(),
+            ServiceMessage::ReadOrLock(_, _, cb) => cb.is_closed(),
+            ServiceMessage::Delete(_, _, cb) => cb.is_closed(),
+            ServiceMessage::Swap(_, _, cb) => cb.is_closed(),
+            ServiceMessage::Rename(_, _, _, cb) => cb.is_closed(),
+            ServiceMessage::Pin(_, _, cb) => cb.is_closed(),
+            ServiceMessage::ResetHits(_, cb) => cb.is_closed(),
+            ServiceMessage::Flush(_, cb) => cb.is_closed(),
+            ServiceMessage::RingPush(_, _, _, cb) => cb.is_closed(),
+            ServiceMessage::Reload(_) => false,
+            ServiceMessage::InvalidateTag(_, cb) => cb.is_closed(),
+            ServiceMessage::Meta(_, cb) => cb.is_closed(),
+            ServiceMessage::ReadWithMeta(_, cb) => cb.is_closed(),
+            ServiceMessage::ReadItem(_, cb) => cb.is_closed(),
+            ServiceMessage::ValueLen(_, cb) => cb.is_closed(),
+            ServiceMessage::CanWrite(_, _, cb) => cb.is_closed(),
+            ServiceMessage::Explain(_, _, cb) => cb.is_closed(),
+            ServiceMessage::Count(_, cb) => cb.is_closed(),
+            ServiceMessage::Txn(_, cb) => cb.is_closed(),
+            ServiceMessage::Stats(cb) => cb.is_closed(),
+            ServiceMessage::TtlHistogram(cb) => cb.is_closed(),
+            ServiceMessage::Compact(cb) => cb.is_closed(),
+            ServiceMessage::Reserve(_, cb) => cb.is_closed(),
+            ServiceMessage::Evict(cb) => cb.is_closed(),
+            ServiceMessage::Snapshot(cb) => cb.is_closed(),
+            ServiceMessage::MaintenanceStatus(cb) => cb.is_closed(),
+            ServiceMessage::PersistStats(cb) => cb.is_closed(),
+            ServiceMessage::Subscribe(_, _, cb) => cb.is_closed(),
+            ServiceMessage::Ping(cb) => cb.is_closed(),
+            ServiceMessage::NsRead(_, _, cb) => cb.is_closed(),
+            ServiceMessage::NsWrite(_, _, _, cb) => cb.is_closed(),
+            ServiceMessage::NsDetach(_, cb) => cb.is_closed(),
+            ServiceMessage::NsDump(_, cb) => cb.is_closed(),
+            ServiceMessage::NsDrop(_, cb) => cb.is_closed(),
+            ServiceMessage::SlowLog(cb) => cb.is_closed(),
+            ServiceMessage::ResetSlowLog(cb) => cb.is_closed(),
+        }
+    }
+
+    // operation name, first key and number of keys or entries covered, for the slow log
+    fn describe(&self) -> (&'static str, Option<&str>, usize) {
+        match self {
+            ServiceMessage::Read(key, _) => ("read", Some(key), 1),
+            ServiceMessage::Peek(key, _) => ("peek", Some(key), 1),
+            ServiceMessage::Write(key, _, _)
+            | ServiceMessage::WriteTagged(key, _, _, _)
+            | ServiceMessage::WriteStored(key, _, _, _)
+            | ServiceMessage::TransformFailed(key) => ("write", Some(key), 1),
+            ServiceMessage::WriteBatch(writes, _) => (
+                "write-batch",
+                writes.first().map(|w| w.key.as_str()),
+                writes.len(),
+            ),
+            ServiceMessage::ReadMany(keys, _) => {
+                ("read-many", keys.first().map(String::as_str), keys.len())
+            }
+            ServiceMessage::MultiTtl(keys, _) => {
+                ("ttl", keys.first().map(String::as_str), keys.len())
+            }
+            ServiceMessage::ReadIfModifiedSince(key, _, _)
+            | ServiceMessage::ReadDated(key, _, _) => ("read", Some(key), 1),
+            ServiceMessage::ReadOrLock(key, _, _) => ("read-or-lock", Some(key), 1),
+            ServiceMessage::Delete(key, _, _) => ("delete", Some(key), 1),
+            ServiceMessage::Reload(_) => ("reload", None, 0),
+            ServiceMessage::Flush(_, _) => ("flush", None, 0),
+            ServiceMessage::Swap(a, _, _) => ("swap", Some(a), 2),
+            ServiceMessage::Rename(from, _, _, _) => ("rename", Some(from), 2),
+            ServiceMessage::Pin(key, true, _) => ("pin", Some(key), 1),
+            ServiceMessage::Pin(key, false, _) => ("unpin", Some(key), 1),
+            ServiceMessage::ResetHits(key, _) => ("reset-hits", Some(key), 1),
+            ServiceMessage::RingPush(key, _, _, _) => ("ring-push", Some(key), 1),
+            ServiceMessage::InvalidateTag(_, _) => ("invalidate-tag", None, 0),
+            ServiceMessage::Meta(key, _) => ("meta", Some(key), 1),
+            ServiceMessage::ReadWithMeta(key, _) => ("read", Some(key), 1),
+            ServiceMessage::ReadItem(key, _) => ("read", Some(key), 1),
+            ServiceMessage::ValueLen(key, _) => ("strlen", Some(key), 1),
+            ServiceMessage::CanWrite(key, _, _) => ("can-write", Some(key), 1),
+            ServiceMessage::Explain(key, _, _) => ("explain", Some(key), 1),
+            ServiceMessage::Count(_, _) => ("count", None, 0),
+            ServiceMessage::Txn(ops, _) => ("txn", ops.first().map(TxnOp::key), ops.len()),
+            ServiceMessage::Stats(_) => ("stats", None, 0),
+            ServiceMessage::TtlHistogram(_) => ("ttl-histogram", None, 0),
+            ServiceMessage::Compact(_) => ("compact", None, 0),
+            ServiceMessage::Reserve(_, _) => ("reserve", None, 0),
+            ServiceMessage::Evict(_) => ("evict", None, 0),
+            ServiceMessage::Snapshot(_) => ("snapshot", None, 0),
+            ServiceMessage::MaintenanceStatus(_) => ("maintenance-status", None, 0),
+            ServiceMessage::PersistStats(_) => ("persist-stats", None, 0),
+            ServiceMessage::Subscribe(_, _, _) => ("subscribe", None, 0),
+            ServiceMessage::Ping(_) => ("ping", None, 0),
+            ServiceMessage::NsRead(_, key, _) => ("ns-read", Some(key), 1),
+            ServiceMessage::NsWrite(_, key, _, _) => ("ns-write", Some(key), 1),
+            ServiceMessage::NsDetach(_, _) => ("ns-detach", None, 0),
+            ServiceMessage::NsDump(_, _) => ("ns-dump", None, 0),
+            ServiceMessage::NsDrop(_, _) => ("ns-drop", None, 0),
+            ServiceMessage::SlowLog(_) => ("slowlog", None, 0),
+            ServiceMessage::ResetSlowLog(_) => ("reset-slowlog", None, 0),
+        }
+    }
+
+    // keys the message changes, see `Held`
+    fn written_keys(&self) -> Vec<&str> {
+        match self {
+            ServiceMessage::Write(key, _, _)
+            | ServiceMessage::WriteTagged(key, _, _, _)
+            | ServiceMessage::Delete(key, _, _)
+            | ServiceMessage::Pin(key, _, _)
+            | ServiceMessage::ResetHits(key, _)
+            | ServiceMessage::RingPush(key, _, _, _) => vec![key],
+            ServiceMessage::Swap(a, b, _) | ServiceMessage::Rename(a, b, _, _) => vec![a, b],
+            ServiceMessage::WriteBatch(writes, _) => {
+                writes.iter().map(|w| w.key.as_str()).collect()
+            }
+            ServiceMessage::Txn(ops, _) => ops.iter().map(TxnOp::key).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // may change any key, waits for every offloaded write in flight, see `Held`
+    fn writes_all_keys(&self) -> bool {
+        matches!(
+            self,
+            ServiceMessage::Flush(..) | ServiceMessage::InvalidateTag(..)
+        )
+    }
+}
+
+// message along with the data about the request it came from
+pub struct ServiceRequest {
+    pub message: ServiceMessage,
+    // operation is skipped if it is dequeued after this point, the caller has given up by then
+    pub deadline: Option<Instant>,
+    // order in which requests were sent, across both lanes, set by `ServiceQueue`
+    pub seq: u64,
+    // address the request came from, for the audit log, see `ServiceQueue::for_client`,
+    // boxed so that requests moved through the queues stay small
+    pub client: Option<Box<IpAddr>>,
+    // when the request was made, time until the service takes it is its queue wait
+    pub sent_at: Instant,
+}
+
+impl ServiceMessage {
+    pub fn with_deadline(self, deadline: Option<Instant>) -> ServiceRequest {
+        ServiceRequest {
+            message: self,
+            deadline,
+            seq: 0,
+            client: None,
+            sent_at: Instant::now(),
+        }
+    }
+}
+
+impl From<ServiceMessage> for ServiceRequest {
+    fn from(message: ServiceMessage) -> Self {
+        message.with_deadline(None)
+    }
+}
+
+// reads of keys go to their own lane and are served first, so that interactive reads
+// do not wait behind a flood of writes, everything else keeps its order in the other lane
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lane {
+    Read,
+    Write,
+}
+
+impl ServiceMessage {
+    pub fn lane(&self) -> Lane {
+        match self {
+            ServiceMessage::Read(..)
+            | ServiceMessage::Peek(..)
+            | ServiceMessage::ReadMany(..)
+            | ServiceMessage::MultiTtl(..)
+            | ServiceMessage::NsRead(..)
+            | ServiceMessage::ReadIfModifiedSince(..)
+            | ServiceMessage::ReadDated(..)
+            | ServiceMessage::Meta(..)
+            | ServiceMessage::ReadWithMeta(..)
+            | ServiceMessage::ReadItem(..)
+            | ServiceMessage::ValueLen(..)
+            | ServiceMessage::CanWrite(..)
+            | ServiceMessage::Flush(..) => Lane::Read,
+            _ => Lane::Write,
+        }
+    }
+}
+
+// shortest wait for eager expiry, entry is only expired once time moved past its expiry,
+// so without it service would spin while time is exactly at it
+const MIN_EXPIRY_WAIT: Duration = Duration::from_millis(1);
+
+// extra eviction passes run in a row while the expired backlog stays above
+// `expired_backlog_alert_ratio`, the next scheduled pass may start another series
+const MAX_EXTRA_EVICTION_PASSES: usize = 8;
+
+// weight of the latest operation in the moving average checked against `latency_budget`
+const TURN_TIME_WEIGHT: f64 = 0.2;
+
+// write lane is served at least once in this many requests while there are writes,
+// so that a steady stream of reads does not starve writes either
+pub const WRITE_EVERY: usize = 16;
+
+// requests are only ordered within a lane, a read sent before the reply to a write of the
+// same key was received may be served before that write
+#[derive(Clone)]
+pub struct ServiceQueue {
+    reads: mpsc::UnboundedSender<ServiceRequest>,
+    writes: mpsc::UnboundedSender<ServiceRequest>,
+    // shared by clones, so that requests from every frontend are ordered
+    last_seq: Arc<AtomicU64>,
+    client: Option<IpAddr>,
+    // value bytes of writes reserved with `reserve_write` and not answered yet
+    inflight_write_bytes: Arc<AtomicUsize>,
+    // see `max_inflight_write_bytes`
+    max_inflight_write_bytes: Option<usize>,
+}
+
+// room taken by a write in the in-flight bytes of the queue, given back on drop
+pub struct InflightWrite {
+    inflight_write_bytes: Arc<AtomicUsize>,
+    len: usize,
+}
+
+impl Drop for InflightWrite {
+    fn drop(&mut self) {
+        self.inflight_write_bytes
+            .fetch_sub(self.len, Ordering::SeqCst);
+    }
+}
+
+pub fn service_queue() -> (ServiceQueue, ServiceReceiver) {
+    let (reads_tx, reads_rx) = mpsc::unbounded_channel();
+    let (writes_tx, writes_rx) = mpsc::unbounded_channel();
+    let (completed_tx, completed_rx) = mpsc::unbounded_channel();
+    let inflight_write_bytes = Arc::new(AtomicUsize::new(0));
+
+    (
+        ServiceQueue {
+            reads: reads_tx,
+            writes: writes_tx,
+            last_seq: Arc::new(AtomicU64::new(0)),
+            client: None,
+            inflight_write_bytes: inflight_write_bytes.clone(),
+            max_inflight_write_bytes: None,
+        },
+        ServiceReceiver {
+            reads: reads_rx,
+            writes: writes_rx,
+            completed: completed_rx,
+            completed_tx,
+            reads_in_row: 0,
+            inflight_write_bytes,
+        },
+    )
+}
+
+impl ServiceQueue {
+    // clone stamping requests sent through it with the client address
+    pub fn for_client(&self, client: Option<IpAddr>) -> ServiceQueue {
+        ServiceQueue {
+            client,
+            ..self.clone()
+        }
+    }
+
+    // clone whose `reserve_write` turns away writes over `max` in-flight bytes
+    pub fn limit_inflight_writes(&self, max: Option<usize>) -> ServiceQueue {
+        ServiceQueue {
+            max_inflight_write_bytes: max,
+            ..self.clone()
+        }
+    }
+
+    // takes room for a value about to be queued, to be held until the write is answered,
+    // none when it would take the in-flight bytes over the limit
+    pub fn reserve_write(&self, len: usize) -> Option<InflightWrite> {
+        self.take_inflight(len).then(|| InflightWrite {
+            inflight_write_bytes: self.inflight_write_bytes.clone(),
+            len,
+        })
+    }
+
+    // takes more room once the value turned out larger than reserved, e.g. sent without
+    // `Content-Length` or decompressed, false when the rest does not fit
+    pub fn grow_write(&self, write: &mut InflightWrite, len: usize) -> bool {
+        let more = len.saturating_sub(write.len);
+        if more > 0 && !self.take_inflight(more) {
+            return false;
+        }
+        write.len += more;
+        true
+    }
+
+    fn take_inflight(&self, len: usize) -> bool {
+        let max = self.max_inflight_write_bytes;
+        self.inflight_write_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |inflight| {
+                match (inflight.checked_add(len), max) {
+                    (Some(total), Some(max)) if total > max => None,
+                    (total, _) => total,
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn inflight_write_bytes(&self) -> usize {
+        self.inflight_write_bytes.load(Ordering::SeqCst)
+    }
+
+    // lane is picked by the kind of message
+    pub fn send(&self, request: ServiceRequest) -> Result<(), SendError<ServiceRequest>> {
+        match request.message.lane() {
+            Lane::Read => self.send_read(request),
+            Lane::Write => self.send_write(request),
+        }
+    }
+
+    pub fn send_read(&self, request: ServiceRequest) -> Result<(), SendError<ServiceRequest>> {
+        self.reads.send(self.stamped(request))
+    }
+
+    pub fn send_write(&self, request: ServiceRequest) -> Result<(), SendError<ServiceRequest>> {
+        self.writes.send(self.stamped(request))
+    }
+
+    fn stamped(&self, mut request: ServiceRequest) -> ServiceRequest {
+        request.seq = self.last_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        request.client = request.client.or_else(|| self.client.map(Box::new));
+        request
+    }
+}
+
+pub struct ServiceReceiver {
+    reads: mpsc::UnboundedReceiver<ServiceRequest>,
+    writes: mpsc::UnboundedReceiver<ServiceRequest>,
+    // writes coming back from the blocking pool, served ahead of both lanes, the service
+    // holds the sender so this never closes
+    completed: mpsc::UnboundedReceiver<ServiceRequest>,
+    completed_tx: mpsc::UnboundedSender<ServiceRequest>,
+    reads_in_row: usize,
+    // shared with the queue, see `ServiceQueue::reserve_write`
+    inflight_write_bytes: Arc<AtomicUsize>,
+}
+
+impl ServiceReceiver {
+    fn try_recv(&mut self) -> Result<ServiceRequest, TryRecvError> {
+        if let Ok(request) = self.completed.try_recv() {
+            return Ok(request);
+        }
+        if self.reads_in_row >= WRITE_EVERY {
+            if let Ok(request) = self.writes.try_recv() {
+                self.reads_in_row = 0;
+                return Ok(request);
+            }
+        }
+
+        match self.reads.try_recv() {
+            Ok(request) => {
+                self.reads_in_row += 1;
+                Ok(request)
+            }
+            Err(_) => {
+                self.reads_in_row = 0;
+                self.writes.try_recv()
+            }
+        }
+    }
+
+    // requests waiting in the read and write lanes
+    fn queued(&self) -> QueueLength {
+        QueueLength {
+            reads: self.reads.len(),
+            writes: self.writes.len(),
+            inflight_write_bytes: self.inflight_write_bytes.load(Ordering::SeqCst),
+        }
+    }
+
+    async fn recv(&mut self) -> Option<ServiceRequest> {
+        // `try_recv` does not count against the task budget, without this the service
+        // would not give other tasks on its worker a chance until both lanes are drained
+        tokio::task::consume_budget().await;
+        match self.try_recv() {
+            Ok(request) => Some(request),
+            Err(TryRecvError::Disconnected) => None,
+            Err(TryRecvError::Empty) => tokio::select! {
+                biased;
+                request = self.reads.recv() => {
+                    self.reads_in_row += 1;
+                    request
+                }
+                request = self.writes.recv() => {
+                    self.reads_in_row = 0;
+                    request
+                }
+                Some(request) = self.completed.recv() => Some(request),
+            },
+        }
+    }
+}
+
+// runtime switches shared between the service and the protocol frontends,
+// so they can be flipped without going through the queue
+#[derive(Default)]
+pub struct ServiceFlags {
+    pub read_only: AtomicBool,
+    pub eviction_paused: AtomicBool,
+    // reads and writes are shed while operations take longer than `latency_budget`
+    pub overloaded: AtomicBool,
+    // changes on every restart and flush, so clients can tell cached data was dropped
+    pub generation: AtomicU64,
+}
+
+impl ServiceFlags {
+    pub fn new(config: &Config) -> ServiceFlags {
+        ServiceFlags {
+            read_only: AtomicBool::new(config.start_read_only),
+            eviction_paused: AtomicBool::new(false),
+            overloaded: AtomicBool::new(false),
+            generation: AtomicU64::new(rand::random()),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst)
+    }
+
+    pub fn is_eviction_paused(&self) -> bool {
+        self.eviction_paused.load(Ordering::SeqCst)
+    }
+
+    // while paused expired entries still miss on reads, but are not reclaimed proactively
+    pub fn set_eviction_paused(&self, paused: bool) {
+        self.eviction_paused.store(paused, Ordering::SeqCst)
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::SeqCst)
+    }
+
+    fn set_overloaded(&self, overloaded: bool) {
+        self.overloaded.store(overloaded, Ordering::SeqCst)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    fn next_generation(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub keys_total: usize,
+    pub map_len: usize,
+    // estimate, see `TtlCache::map_capacity`
+    pub map_capacity: usize,
+    pub map_load_factor: f64,
+    // writes that grew the map, each rehashing every entry
+    pub map_growths: u64,
+    pub bytes_total: usize,
+    // summed cost of entries and what it is measured in, `capacity` applies to it
+    pub total_cost: usize,
+    pub capacity_unit: CapacityUnit,
+    // distinct values when `intern_values` is enabled
+    pub interned_values: usize,
+    // distinct tags in use and key references held by the tag index
+    pub tags: usize,
+    pub tag_index_size: usize,
+    pub read_only: bool,
+    pub eviction_paused: bool,
+    // reads and writes are being shed, see `latency_budget`
+    pub overloaded: bool,
+    // moving average of time spent on an operation, in microseconds
+    pub turn_time_us: u128,
+    // times shedding started
+    pub overload_trips: u64,
+    // see `ServiceFlags::generation`
+    pub generation: u64,
+    pub cancelled_operations: u64,
+    // replies of finished operations whose caller was gone by then, per operation
+    pub dropped_replies: BTreeMap<&'static str, u64>,
+    // operations dequeued after their deadline, skipped without a reply
+    pub deadline_exceeded: u64,
+    // audit records dropped while the writer was behind, see `audit_path`
+    pub audit_dropped: u64,
+    // values and flushed maps freed by the dropping thread and bytes of values in them,
+    // see `deferred_drop_min_bytes`
+    pub deferred_drops: u64,
+    pub deferred_drop_bytes: u64,
+    // snapshot entries skipped on startup because they failed verification
+    pub restore_skipped_corrupt: usize,
+    pub checksum_mismatches: u64,
+    pub expirations: u64,
+    // evictions of live entries to make room, not counted in `expirations`
+    pub capacity_evictions: u64,
+    // unix seconds this process started at, and the first run counters were restored from
+    pub started_at: u64,
+    pub lifetime_started_at: u64,
+    // counters of this run and of all runs, see `stats_path`
+    pub process: Counters,
+    pub lifetime: Counters,
+    pub event_subscribers: Vec<SubscriberSnapshot>,
+    // requests still queued when stats were taken, stats request itself not included
+    pub queued: QueueLength,
+    pub full_gc_runs: u64,
+    pub last_full_gc: Option<GcReport>,
+    // estimate of expired entries eviction has not got to yet
+    pub expired_backlog: ExpiredBacklog,
+    // eviction passes started right after another one, see `expired_backlog_alert_ratio`
+    pub extra_eviction_passes: u64,
+    pub namespaces: Vec<NamespaceStats>,
+    // memory of indexes and logs kept next to the entries, see `AuxiliaryMemory`
+    pub auxiliary: BTreeMap<&'static str, AuxiliaryUsage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NamespaceStats {
+    pub name: String,
+    pub state: NamespaceState,
+    pub keys_total: usize,
+    // unix seconds of the last read or write
+    pub last_active_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueLength {
+    pub reads: usize,
+    pub writes: usize,
+    // values of writes not answered yet, see `max_inflight_write_bytes`
+    pub inflight_write_bytes: usize,
+}
+
+// outcome of the last full sweep, see `full_gc_every`
+#[derive(Clone, Debug, Serialize)]
+pub struct GcReport {
+    // unix seconds
+    pub ran_at: u64,
+    pub removed: usize,
+    pub bytes_reclaimed: usize,
+    pub took_us: u128,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotReport {
+    pub entries: usize,
+    // live entries left out by `snapshot_persist_patterns`
+    pub skipped: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompactReport {
+    pub map_capacity_before: usize,
+    pub map_capacity_after: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FlushReport {
+    pub removed: usize,
+    // sequence number of the flush, writes sent before it are rejected, only with fence
+    pub fence: Option<u64>,
+    // generation the cache was moved to
+    pub generation: u64,
 }
 
-pub type ServiceQueue = mpsc::UnboundedSender<ServiceMessage>;
+#[derive(Debug, Serialize)]
+pub struct EvictionReport {
+    pub removed: usize,
+    // keys looked at over all sampling rounds of the pass
+    pub sampled: usize,
+    pub rounds: usize,
+    // left in the cache after the pass
+    pub keys_total: usize,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// loads entries from snapshot, returns how many of them were skipped as corrupt
+// keys are canonicalized again, snapshot could be taken with different rules
+fn restore<T: Time>(
+    ttl_cache: &mut TtlCache<'_, T>,
+    path: &Path,
+    canonicalization: &KeyCanonicalization,
+) -> usize {
+    match snapshot::read(path) {
+        Ok(restored) => {
+            let total = restored.entries.len();
+            let mut failed = 0;
+            for entry in restored.entries {
+                let ttl = Duration::from_millis(entry.ttl_remaining_ms);
+                if ttl_cache
+                    .restore(
+                        canonicalization.canonicalize(entry.key),
+                        entry.value,
+                        Some(ttl),
+                    )
+                    .is_err()
+                {
+                    failed += 1;
+                }
+            }
+            tracing::info!(
+                "restored {} entries from {}, restore_skipped_corrupt {}, out of capacity {}",
+                total - failed,
+                path.display(),
+                restored.skipped_corrupt,
+                failed
+            );
+            restored.skipped_corrupt
+        }
+        Err(SnapshotError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            tracing::info!("no snapshot at {}, starting empty", path.display());
+            0
+        }
+        Err(e) => {
+            tracing::error!(
+                "failed to restore {}: {}, starting empty",
+                path.display(),
+                e
+            );
+            0
+        }
+    }
+}
+
+// runs transforms of reads and writes on the blocking pool, see `transform_workers`
+struct Offload {
+    on_write: Transform,
+    on_read: Transform,
+    workers: Arc<Semaphore>,
+    completed: mpsc::UnboundedSender<ServiceRequest>,
+}
+
+impl Offload {
+    // transformed value comes back to the service as `WriteStored` with seq of the original
+    // request, so that a flush fence sent in the meantime still rejects it
+    fn write(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        options: Box<WriteOptions>,
+        seq: u64,
+        client: Option<IpAddr>,
+        cb: oneshot::Sender<Result<(), CacheError>>,
+    ) {
+        let transform = self.on_write.clone();
+        let workers = self.workers.clone();
+        let completed = self.completed.clone();
+        tokio::spawn(async move {
+            let _worker = workers.acquire_owned().await;
+            match tokio::task::spawn_blocking(move || transform(value)).await {
+                Ok(stored) => {
+                    let _ = completed.send(ServiceRequest {
+                        message: ServiceMessage::WriteStored(key, stored, options, cb),
+                        deadline: None,
+                        seq,
+                        client: client.map(Box::new),
+                        sent_at: Instant::now(),
+                    });
+                }
+                // dropping the reply lets the caller know
+                Err(e) => {
+                    tracing::error!("[write] transform of key {} failed: {}", key, e);
+                    let _ = completed.send(ServiceRequest {
+                        message: ServiceMessage::TransformFailed(key),
+                        deadline: None,
+                        seq,
+                        client: None,
+                        sent_at: Instant::now(),
+                    });
+                }
+            }
+        });
+    }
+
+    // replied from the blocking pool, misses right away
+    fn read(&self, stored: Option<Vec<u8>>, cb: oneshot::Sender<Option<Vec<u8>>>) {
+        let stored = match stored {
+            Some(stored) => stored,
+            None => {
+                let _ = cb.send(None);
+                return;
+            }
+        };
+        let transform = self.on_read.clone();
+        let workers = self.workers.clone();
+        tokio::spawn(async move {
+            let _worker = workers.acquire_owned().await;
+            match tokio::task::spawn_blocking(move || transform(stored)).await {
+                Ok(value) => {
+                    if cb.send(Some(value)).is_err() {
+                        tracing::debug!("[read] receiver is gone, reply dropped");
+                    }
+                }
+                Err(e) => tracing::error!("[read] transform failed: {}", e),
+            }
+        });
+    }
+}
+
+// writes of a key come back from the blocking pool in any order, they are applied in the
+// order they were sent, and whatever changes the key after them waits until they are
+enum Held {
+    // being transformed, seq of the write
+    Transforming(u64),
+    // back from the blocking pool before a write sent ahead of it
+    Transformed(ServiceRequest),
+    Waiting(ServiceRequest),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamespaceState {
+    Active,
+    // detached, reads are still served, see `ServiceMessage::NsDetach`
+    ReadOnly,
+}
+
+// key, value and remaining ttl of an entry, as `snapshot::dump` takes them
+pub type DumpEntry = (String, Vec<u8>, Duration);
+
+// separate keyspace with its own cache, configured like the main one
+struct Namespace<'a, T: Time> {
+    cache: TtlCache<'a, T>,
+    state: NamespaceState,
+    last_active: Instant,
+    // since when the cache holds no entries, see `namespace_idle_ttl`
+    empty_since: Option<Instant>,
+}
 
 pub struct TtlCacheService<'a, T: Time> {
     config: Config,
-    queue: mpsc::UnboundedReceiver<ServiceMessage>,
+    queue: ServiceReceiver,
+    flags: Arc<ServiceFlags>,
     ttl_cache: TtlCache<'a, T>,
+    offload: Option<Offload>,
+    // keys with offloaded writes in flight, see `Held`
+    held: HashMap<String, VecDeque<Held>>,
+    // changes of every key waiting for `held` to empty, and writes sent after them
+    held_all: VecDeque<ServiceRequest>,
+    audit: Option<AuditLog>,
+    deferred_drop: Option<DeferredDrop>,
     last_eviction_ran: Instant,
+    eviction_pending: bool,
+    // extra passes run since the last scheduled one
+    extra_passes_in_row: usize,
+    extra_eviction_passes: u64,
+    // eviction passes and snapshots take turns, see `run_maintenance`
+    maintenance: Coordinator,
+    pending_snapshots: VecDeque<oneshot::Sender<Result<SnapshotReport, SnapshotError>>>,
+    // called once a snapshot is written, stands in for a snapshot taking a while
+    #[cfg(test)]
+    snapshot_delay: Option<Box<dyn Fn() + Send + 'a>>,
+    // called before every operation, stands in for an overloaded host
+    #[cfg(test)]
+    operation_delay: Option<Box<dyn Fn() + Send + 'a>>,
+    // moving average of time spent on an operation, see `record_turn`
+    turn_time: Duration,
+    overload_trips: u64,
+    slowlog: SlowLog,
+    last_full_gc_ran: Instant,
+    full_gc_runs: u64,
+    last_full_gc: Option<GcReport>,
+    namespaces: BTreeMap<String, Namespace<'a, T>>,
+    // sequence number of the last fenced flush, writes sent before it are rejected
+    flush_fence: u64,
     time: &'a T,
+    cancelled_operations: u64,
+    dropped_replies: BTreeMap<&'static str, u64>,
+    deadline_exceeded: u64,
+    restore_skipped_corrupt: usize,
+    events: EventSender,
+    subscribers: Vec<Arc<SubscriberStats>>,
+    // unix seconds
+    started_at: u64,
+    // counters of previous runs restored from `stats_path`
+    restored_stats: PersistedStats,
+    last_stats_persisted: Instant,
 }
 
 impl<'a, T: Time> TtlCacheService<'a, T> {
     pub fn new(
         cache_config: Config,
-        queue: mpsc::UnboundedReceiver<ServiceMessage>,
+        queue: ServiceReceiver,
+        flags: Arc<ServiceFlags>,
         time: &'a T,
     ) -> TtlCacheService<'a, T> {
+        #[cfg(not(feature = "unicode"))]
+        if cache_config.key_canonicalization.max_unicode_nfc {
+            tracing::warn!(
+                "NFC key normalization requires 'unicode' feature, keys won't be normalized"
+            );
+        }
+
+        let events = EventSender::new(cache_config.event_history);
+        let deferred_drop = DeferredDrop::start(&cache_config);
+        let slowlog = SlowLog::new(cache_config.slowlog_len);
+        let mut ttl_cache = TtlCache::new(cache_config.clone(), time);
+        ttl_cache.set_event_sender(events.clone());
+        if let Some(deferred_drop) = &deferred_drop {
+            ttl_cache.set_deferred_drop(deferred_drop.clone());
+        }
+        let restore_skipped_corrupt = cache_config
+            .snapshot_path
+            .as_ref()
+            .map(|path| restore(&mut ttl_cache, path, &cache_config.key_canonicalization))
+            .unwrap_or(0);
+        // restored entries were not set by clients
+        ttl_cache.sets = 0;
+
+        let audit = AuditLog::start(&cache_config);
+        let started_at = unix_now();
+        let restored_stats = cache_config
+            .stats_path
+            .as_ref()
+            .and_then(|path| stats::load(path))
+            .unwrap_or(PersistedStats {
+                started_at,
+                counters: Counters::default(),
+            });
+
         TtlCacheService {
-            config: cache_config.clone(),
+            config: cache_config,
             queue,
-            ttl_cache: TtlCache::new(cache_config, time),
+            flags,
+            ttl_cache,
+            offload: None,
+            held: HashMap::new(),
+            held_all: VecDeque::new(),
+            audit,
+            deferred_drop,
             last_eviction_ran: time.get_time(),
+            eviction_pending: false,
+            extra_passes_in_row: 0,
+            extra_eviction_passes: 0,
+            maintenance: Coordinator::new(),
+            pending_snapshots: VecDeque::new(),
+            #[cfg(test)]
+            snapshot_delay: None,
+            #[cfg(test)]
+            operation_delay: None,
+            turn_time: Duration::ZERO,
+            overload_trips: 0,
+            slowlog,
+            last_full_gc_ran: time.get_time(),
+            full_gc_runs: 0,
+            last_full_gc: None,
+            namespaces: BTreeMap::new(),
+            flush_fence: 0,
             time,
+            cancelled_operations: 0,
+            dropped_replies: BTreeMap::new(),
+            deadline_exceeded: 0,
+            restore_skipped_corrupt,
+            events,
+            subscribers: Vec::new(),
+            started_at,
+            restored_stats,
+            last_stats_persisted: time.get_time(),
+        }
+    }
+
+    fn lifetime_stats(&self) -> PersistedStats {
+        PersistedStats {
+            started_at: self.restored_stats.started_at,
+            counters: self.restored_stats.counters + self.ttl_cache.counters(),
+        }
+    }
+
+    // with `latency_budget` reads and writes are shed once the moving average of time spent
+    // on an operation goes over the budget, until it is back under half of it
+    fn record_turn(&mut self, took: Duration) {
+        let budget = match self.config.latency_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        self.turn_time =
+            self.turn_time.mul_f64(1.0 - TURN_TIME_WEIGHT) + took.mul_f64(TURN_TIME_WEIGHT);
+        self.check_overload(budget);
+    }
+
+    fn check_overload(&mut self, budget: Duration) {
+        let overloaded = self.flags.is_overloaded();
+        if !overloaded && self.turn_time > budget {
+            tracing::warn!(
+                "[overload] operations take {:?} on average, over {:?} budget, shedding reads and writes",
+                self.turn_time,
+                budget
+            );
+            self.overload_trips += 1;
+            self.flags.set_overloaded(true);
+        } else if overloaded && self.turn_time <= budget / 2 {
+            tracing::info!(
+                "[overload] operations take {:?} on average, serving reads and writes again",
+                self.turn_time
+            );
+            self.flags.set_overloaded(false);
+        }
+    }
+
+    // every `latency_budget` spent idle counts as an operation taking no time
+    fn record_idle(&mut self, idle: Duration) {
+        if let Some(budget) = self.config.latency_budget.filter(|b| !b.is_zero()) {
+            let turns = idle.as_secs_f64() / budget.as_secs_f64();
+            self.turn_time = self.turn_time.mul_f64((1.0 - TURN_TIME_WEIGHT).powf(turns));
+            self.check_overload(budget);
+        }
+    }
+
+    // how long to wait for a request before checking whether shedding can stop
+    fn overload_check_wait(&self) -> Option<Duration> {
+        self.config
+            .latency_budget
+            .filter(|_| self.flags.is_overloaded())
+            .map(|budget| budget.max(MIN_EXPIRY_WAIT))
+    }
+
+    fn persist_stats(&mut self) -> io::Result<()> {
+        self.last_stats_persisted = self.time.get_time();
+        match &self.config.stats_path {
+            Some(path) => stats::save(path, &self.lifetime_stats()),
+            None => Ok(()),
+        }
+    }
+
+    // values are transformed on the way in and out, see `TtlCache::set_transforms`, with
+    // `transform_workers` those of reads and writes run on the blocking pool instead
+    pub fn set_transforms(&mut self, on_write: Transform, on_read: Transform) {
+        self.ttl_cache
+            .set_transforms(on_write.clone(), on_read.clone());
+        if self.config.transform_workers > 0 {
+            self.offload = Some(Offload {
+                on_write,
+                on_read,
+                workers: Arc::new(Semaphore::new(self.config.transform_workers)),
+                completed: self.queue.completed_tx.clone(),
+            });
+        }
+    }
+
+    fn write(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        options: Box<WriteOptions>,
+        seq: u64,
+        client: Option<IpAddr>,
+        cb: oneshot::Sender<Result<(), CacheError>>,
+    ) {
+        if self.flags.is_read_only() {
+            let result = Err(CacheError::ReadOnly);
+            self.audit("write", &key, Some(&value), client, &result);
+            self.access_log("write", &key, &outcome(&result));
+            self.reply("write", cb, result);
+        } else if let Some(offload) = &self.offload {
+            // audited once it comes back as `WriteStored`
+            self.held
+                .entry(key.clone())
+                .or_default()
+                .push_back(Held::Transforming(seq));
+            offload.write(key, value, options, seq, client, cb);
+        } else {
+            let logged = self.access_logged(&key);
+            let record = self.audit_record("write", &key, Some(&value), client);
+            let result = self
+                .ttl_cache
+                .check_version(&key, options.expected_version)
+                .and_then(|_| {
+                    self.ttl_cache
+                        .set_with_tags(key, value, options.ttl, options.tags)
+                });
+            self.audit_finish(record, &result);
+            if let Some(key) = logged {
+                self.access_log("write", &key, &outcome(&result));
+            }
+            self.reply("write", cb, result);
+        }
+    }
+
+    // taken before the operation when it consumes the key or the value
+    fn audit_record(
+        &self,
+        operation: &'static str,
+        key: &str,
+        value: Option<&[u8]>,
+        client: Option<IpAddr>,
+    ) -> Option<AuditRecord> {
+        self.audit
+            .as_ref()
+            .map(|audit| audit.record(operation, Some(key), value, client))
+    }
+
+    fn audit_finish<V>(&self, record: Option<AuditRecord>, result: &Result<V, CacheError>) {
+        if let (Some(audit), Some(record)) = (&self.audit, record) {
+            audit.finish(record, result);
+        }
+    }
+
+    fn audit<V>(
+        &self,
+        operation: &'static str,
+        key: &str,
+        value: Option<&[u8]>,
+        client: Option<IpAddr>,
+        result: &Result<V, CacheError>,
+    ) {
+        let record = self.audit_record(operation, key, value, client);
+        self.audit_finish(record, result);
+    }
+
+    // copy of the key when it is one of `access_log_prefixes`, for operations consuming it
+    fn access_logged(&self, key: &str) -> Option<String> {
+        self.config
+            .access_log_prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+            .then(|| key.to_string())
+    }
+
+    // structured line under the `access` target for keys of `access_log_prefixes`, any other
+    // key is only logged the usual way
+    fn access_log(&self, operation: &'static str, key: &str, outcome: &str) {
+        if self.access_logged(key).is_some() {
+            let ts_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            tracing::info!(target: "access", ts_ms, operation, key, outcome);
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        filter: EventFilter,
+        last_event_id: Option<u64>,
+    ) -> Result<Subscription, CacheError> {
+        // subscription stats are only referenced by us once the stream is gone
+        self.subscribers.retain(|s| Arc::strong_count(s) > 1);
+
+        if self.subscribers.len() >= self.config.max_event_subscribers {
+            Err(CacheError::TooManySubscribers(
+                self.config.max_event_subscribers,
+            ))
+        } else {
+            let stats = Arc::new(SubscriberStats {
+                filter,
+                ..SubscriberStats::default()
+            });
+            self.subscribers.push(stats.clone());
+
+            Ok(Subscription {
+                events: self.events.subscribe(last_event_id),
+                stats,
+            })
+        }
+    }
+
+    // clients disconnecting mid-request is normal behavior, so failing to reply is not an error
+    fn reply<V>(&mut self, operation: &'static str, cb: oneshot::Sender<V>, value: V) {
+        if cb.send(value).is_err() {
+            self.cancelled_operations += 1;
+            *self.dropped_replies.entry(operation).or_default() += 1;
+            tracing::debug!("[{}] receiver is gone, reply dropped", operation);
+        }
+    }
+
+    // starts maintenance that is due once the previous one is finished, snapshots run to
+    // completion right away, an eviction pass goes in rounds in between requests
+    fn run_maintenance(&mut self) {
+        let paused = self.flags.is_eviction_paused();
+        while let Some(kind) = self
+            .maintenance
+            .start_next(|kind| kind != MaintenanceKind::Eviction || !paused)
+        {
+            match kind {
+                MaintenanceKind::Eviction => {
+                    self.eviction_pending = true;
+                    return;
+                }
+                MaintenanceKind::Snapshot => {
+                    self.snapshot();
+                    self.maintenance.finish();
+                }
+            }
+        }
+    }
+
+    fn snapshot(&mut self) {
+        let cb = match self.pending_snapshots.pop_front() {
+            Some(cb) => cb,
+            None => return,
+        };
+        let patterns = &self.config.snapshot_persist_patterns;
+        let result = match &self.config.snapshot_path {
+            Some(path) => {
+                let mut skipped = 0;
+                let entries = self.ttl_cache.live_entries().filter(|(key, _, _)| {
+                    let persisted =
+                        patterns.is_empty() || patterns.iter().any(|p| glob_match(p, key));
+                    if !persisted {
+                        skipped += 1;
+                    }
+                    persisted
+                });
+                snapshot::write(path, self.config.snapshot_format, entries)
+                    .map(|entries| SnapshotReport { entries, skipped })
+            }
+            None => Err(SnapshotError::NotConfigured),
+        };
+        #[cfg(test)]
+        if let Some(snapshot_delay) = &self.snapshot_delay {
+            snapshot_delay();
+        }
+        tracing::info!("[snapshot] {:?}", result);
+        self.reply("snapshot", cb, result);
+    }
+
+    // a pass stops once few of the sampled keys were expired, when the rest of them is still
+    // estimated to take too much of the capacity another pass is started right away
+    fn follow_up_eviction(&mut self) {
+        let alert_ratio = match self.config.expired_backlog_alert_ratio {
+            Some(alert_ratio) => f64::from(alert_ratio),
+            None => return,
+        };
+        let ratio = self.ttl_cache.expired_backlog_ratio();
+        if ratio <= alert_ratio {
+            self.extra_passes_in_row = 0;
+            return;
+        }
+
+        let backlog = self.ttl_cache.expired_backlog;
+        if self.extra_passes_in_row >= MAX_EXTRA_EVICTION_PASSES {
+            tracing::warn!(
+                "[evict] {} extra passes did not bring expired backlog of {} keys ({} bytes) under {}, waiting for the next scheduled pass",
+                self.extra_passes_in_row,
+                backlog.keys,
+                backlog.bytes,
+                alert_ratio
+            );
+            self.extra_passes_in_row = 0;
+            return;
         }
+        if self.extra_passes_in_row == 0 {
+            tracing::warn!(
+                "[evict] about {} expired keys ({} bytes) left, {:.2} of capacity, running extra passes",
+                backlog.keys,
+                backlog.bytes,
+                ratio
+            );
+        }
+        self.extra_passes_in_row += 1;
+        self.extra_eviction_passes += 1;
+        self.maintenance.request(MaintenanceKind::Eviction);
+    }
+
+    fn auxiliary_usage(&self) -> BTreeMap<&'static str, AuxiliaryUsage> {
+        let structures: [(&'static str, &dyn AuxiliaryMemory); 3] = [
+            ("tag_index", self.ttl_cache.tag_index()),
+            ("event_history", &self.events),
+            ("slowlog", &self.slowlog),
+        ];
+        structures
+            .iter()
+            .map(|(name, structure)| (*name, structure.usage()))
+            .collect()
+    }
+
+    // none while eviction is paused, so that the service does not wake up for nothing
+    fn next_full_gc(&self) -> Option<Instant> {
+        self.config
+            .full_gc_every
+            .filter(|_| !self.flags.is_eviction_paused())
+            .map(|every| self.last_full_gc_ran + every)
+    }
+
+    // ttl, capacity and eviction settings of a reloaded config, applied to every cache
+    fn reload(&mut self, config: Config) {
+        self.ttl_cache.reload(&config);
+        for namespace in self.namespaces.values_mut() {
+            namespace.cache.reload(&config);
+        }
+        // namespaces created from now on start empty, any capacity fits them
+        self.config.capacity = config.capacity;
+        self.config.ttl = config.ttl;
+        self.config.eviction_number = config.eviction_number;
+        self.config.eviction_ratio = config.eviction_ratio;
+        self.config.eviction_every = config.eviction_every;
+        self.config.eviction_policy = config.eviction_policy;
+        self.config.active_eviction = config.active_eviction;
+        self.config.full_gc_every = config.full_gc_every;
+        tracing::info!(
+            "[reload] ttl {:?}, capacity {:?}, eviction every {:?}",
+            config.ttl,
+            config.capacity,
+            config.eviction_every
+        );
+    }
+
+    // existing namespace, or a new one while there is room for it
+    fn namespace_for_write(&mut self, name: String) -> Result<&mut Namespace<'a, T>, CacheError> {
+        let max = self.config.max_namespaces;
+        if !self.namespaces.contains_key(&name) && self.namespaces.len() >= max {
+            return Err(CacheError::TooManyNamespaces(max));
+        }
+
+        if let Some(state) = self.namespaces.get(&name).map(|ns| ns.state) {
+            if state != NamespaceState::Active {
+                return Err(CacheError::NamespaceReadOnly(name));
+            }
+        }
+
+        let (config, time, events) = (&self.config, self.time, &self.events);
+        let deferred_drop = &self.deferred_drop;
+        Ok(self.namespaces.entry(name.clone()).or_insert_with(|| {
+            // limits are those of the main cache, the map grows with the namespace instead
+            // of being sized up front for the main one
+            let config = Config {
+                initial_capacity: None,
+                ..config.clone()
+            };
+            let mut cache = TtlCache::new(config, time);
+            cache.set_event_sender(events.for_namespace(name));
+            if let Some(deferred_drop) = deferred_drop {
+                cache.set_deferred_drop(deferred_drop.clone());
+            }
+            Namespace {
+                cache,
+                state: NamespaceState::Active,
+                last_active: time.get_time(),
+                empty_since: None,
+            }
+        }))
+    }
+
+    // runs an eviction pass over every namespace, bounded like the one of the main cache,
+    // and drops active ones left empty for `namespace_idle_ttl`, detached ones are kept
+    // until they are dropped explicitly
+    fn sweep_namespaces(&mut self) {
+        let now = self.time.get_time();
+        let idle_ttl = self.config.namespace_idle_ttl;
+        let max_rounds = self.config.max_eviction_rounds.max(1);
+        let mut rng = rand::thread_rng();
+        self.namespaces.retain(|name, namespace| {
+            namespace.cache.remove_due();
+            namespace.cache.evict_expired_rounds(&mut rng, max_rounds);
+            if namespace.state != NamespaceState::Active || !namespace.cache.is_empty() {
+                namespace.empty_since = None;
+                return true;
+            }
+            let empty_since = *namespace.empty_since.get_or_insert(now);
+            let keep = now.saturating_duration_since(empty_since) < idle_ttl;
+            if !keep {
+                tracing::info!("[namespace] {} is idle, dropped", name);
+            }
+            keep
+        });
+    }
+
+    fn namespace_stats(&self) -> Vec<NamespaceStats> {
+        let now = self.time.get_time();
+        let unix_now = unix_now();
+        self.namespaces
+            .iter()
+            .map(|(name, namespace)| NamespaceStats {
+                name: name.clone(),
+                state: namespace.state,
+                keys_total: namespace.cache.keys_total,
+                last_active_at: unix_now.saturating_sub(
+                    now.saturating_duration_since(namespace.last_active)
+                        .as_secs(),
+                ),
+            })
+            .collect()
+    }
+
+    fn full_gc(&mut self) {
+        let keys_before = self.ttl_cache.keys_total;
+        let bytes_before = self.ttl_cache.bytes_total;
+        let started = Instant::now();
+        self.ttl_cache.remove_all_expired();
+
+        let report = GcReport {
+            ran_at: unix_now(),
+            removed: keys_before - self.ttl_cache.keys_total,
+            bytes_reclaimed: bytes_before.saturating_sub(self.ttl_cache.bytes_total),
+            took_us: started.elapsed().as_micros(),
+        };
+        tracing::info!("[full-gc] {:?}", report);
+        self.full_gc_runs += 1;
+        self.last_full_gc = Some(report);
+        self.last_full_gc_ran = self.time.get_time();
     }
 
     #[instrument(skip(self))]
     pub async fn run(&mut self) {
         loop {
-            if self.last_eviction_ran.elapsed() > self.config.eviction_every {
-                self.ttl_cache.evict_expired();
+            let since_last_eviction = self
+                .time
+                .get_time()
+                .saturating_duration_since(self.last_eviction_ran);
+            if self.config.active_eviction && since_last_eviction > self.config.eviction_every {
+                self.maintenance.request(MaintenanceKind::Eviction);
                 self.last_eviction_ran = self.time.get_time();
+                if !self.flags.is_eviction_paused() {
+                    self.sweep_namespaces();
+                }
             }
-            // todo: future is blocked on the queue here
-            // so we won't be expiring stuff in case service is idling
-            // this can be worked around by adding a timeout on future await
-            if let Some(msg) = self.queue.recv().await {
-                match msg {
-                    ServiceMessage::Read(key, cb) => {
-                        let value = self.ttl_cache.get(&key);
-                        tracing::info!("[read] key {} -> {:?}", &key, &value);
-                        cb.send(value).unwrap_or_else(|e| {
-                            tracing::error!("[read] failed sending callback: {:?}", e)
-                        });
-                    }
-                    ServiceMessage::Write(key, value, cb) => {
-                        tracing::info!("[write] key {} value {:?}", &key, &value);
-                        let result = self.ttl_cache.set(key, value);
-                        cb.send(result).unwrap_or_else(|e| {
-                            tracing::error!("[write] failed sending callback: {:?}", e)
-                        });
-                    }
+            if self.config.stats_path.is_some()
+                && self
+                    .time
+                    .get_time()
+                    .saturating_duration_since(self.last_stats_persisted)
+                    >= self.config.stats_persist_every
+            {
+                if let Err(e) = self.persist_stats() {
+                    tracing::warn!("failed to persist stats: {}", e);
                 }
-            } else {
-                break;
             }
+            if self
+                .next_full_gc()
+                .map(|t| self.time.get_time() >= t)
+                .unwrap_or(false)
+            {
+                self.full_gc();
+            }
+            // paused eviction gives way to other maintenance until it is resumed
+            if self.flags.is_eviction_paused()
+                && self.maintenance.is_running(MaintenanceKind::Eviction)
+            {
+                self.maintenance.interrupt();
+            }
+            self.run_maintenance();
+            if self.eviction_pending && !self.flags.is_eviction_paused() {
+                self.eviction_pending = !self.ttl_cache.evict_expired_rounds(
+                    &mut rand::thread_rng(),
+                    self.config.max_eviction_rounds.max(1),
+                );
+                if !self.eviction_pending {
+                    self.maintenance.finish();
+                    self.follow_up_eviction();
+                    self.run_maintenance();
+                }
+            }
+
+            let next = if self.eviction_pending && !self.flags.is_eviction_paused() {
+                // eviction is not finished, take a request if there is one and get back to it
+                match self.queue.try_recv() {
+                    Ok(msg) => Some(msg),
+                    Err(TryRecvError::Empty) => {
+                        tokio::task::yield_now().await;
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => None,
+                }
+            } else if let Some(wait) = self.overload_check_wait() {
+                // requests are shed, nothing may come to bring the average down, so time
+                // spent idle is counted as operations taking no time
+                let idle_since = Instant::now();
+                match tokio::time::timeout(wait, self.queue.recv()).await {
+                    Ok(next) => {
+                        self.record_idle(idle_since.elapsed());
+                        next
+                    }
+                    Err(_) => {
+                        self.record_idle(wait);
+                        self.ttl_cache.remove_due();
+                        continue;
+                    }
+                }
+            } else if let Some(wake_at) = [self.ttl_cache.next_expiry(), self.next_full_gc()]
+                .iter()
+                .flatten()
+                .min()
+            {
+                // eager expiry or full gc, wake up once either is due even if idle
+                let wait = wake_at
+                    .saturating_duration_since(self.time.get_time())
+                    .max(MIN_EXPIRY_WAIT);
+                match tokio::time::timeout(wait, self.queue.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        self.ttl_cache.remove_due();
+                        continue;
+                    }
+                }
+            } else {
+                // todo: future is blocked on the queue here
+                // so we won't be expiring stuff in case service is idling
+                // this can be worked around by adding a timeout on future await
+                self.queue.recv().await
+            };
+
+            match next {
+                Some(request) => self.process(request),
+                None => break,
+            }
+        }
+
+        // every sender is gone, the process is shutting down
+        if let Err(e) = self.persist_stats() {
+            tracing::warn!("failed to persist stats: {}", e);
+        }
+    }
+
+    // changes of keys with offloaded writes in flight are held back, see `Held`
+    fn process(&mut self, request: ServiceRequest) {
+        if let ServiceMessage::WriteStored(key, ..) | ServiceMessage::TransformFailed(key) =
+            &request.message
+        {
+            let key = key.clone();
+            let seq = request.seq;
+            let slot = self.held.get_mut(&key).and_then(|held| {
+                held.iter_mut()
+                    .find(|h| matches!(h, Held::Transforming(s) if *s == seq))
+            });
+            match slot {
+                Some(slot) => {
+                    *slot = Held::Transformed(request);
+                    self.release(&key);
+                }
+                None => self.handle(request),
+            }
+            if self.held.is_empty() && !self.held_all.is_empty() {
+                for request in std::mem::take(&mut self.held_all) {
+                    self.process(request);
+                }
+            }
+            return;
+        }
+
+        let writes_all = request.message.writes_all_keys();
+        if (writes_all && !self.held.is_empty())
+            || (!self.held_all.is_empty()
+                && (writes_all || !request.message.written_keys().is_empty()))
+        {
+            self.held_all.push_back(request);
+            return;
+        }
+
+        let key = if self.held.is_empty() {
+            None
+        } else {
+            let canonicalization = &self.config.key_canonicalization;
+            request
+                .message
+                .written_keys()
+                .into_iter()
+                .map(|key| canonicalization.canonicalize(key.to_string()))
+                .find(|key| self.held.contains_key(key))
+        };
+        // another write is offloaded right away unless something waits already
+        let offloaded = matches!(
+            request.message,
+            ServiceMessage::Write(..) | ServiceMessage::WriteTagged(..)
+        );
+        if let Some(held) = key.and_then(|key| self.held.get_mut(&key)) {
+            if !offloaded || held.iter().any(|h| matches!(h, Held::Waiting(_))) {
+                held.push_back(Held::Waiting(request));
+                return;
+            }
+        }
+        self.handle(request);
+    }
+
+    // applies writes of the key back from the blocking pool up to the first one that is
+    // not, once none is left what waited for them goes ahead in the order it was sent
+    fn release(&mut self, key: &str) {
+        while let Some(held) = self.held.get_mut(key) {
+            match held.front() {
+                Some(Held::Transforming(_)) => return,
+                Some(Held::Transformed(_)) => {
+                    if let Some(Held::Transformed(request)) = held.pop_front() {
+                        self.handle(request);
+                    }
+                }
+                _ => {
+                    let mut waiting = self.held.remove(key).unwrap_or_default().into_iter();
+                    while let Some(Held::Waiting(request)) = waiting.next() {
+                        self.process(request);
+                        // write offloaded again, the rest waits for it
+                        if let Some(held) = self.held.get_mut(key) {
+                            held.extend(waiting);
+                            return;
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    // one request as it comes out of the queue
+    fn handle(&mut self, request: ServiceRequest) {
+        let ServiceRequest {
+            message: msg,
+            deadline,
+            seq,
+            client,
+            sent_at,
+        } = request;
+        let client = client.map(|ip| *ip);
+        // skip the work entirely if nobody is going to read the result
+        if msg.is_cancelled() {
+            self.cancelled_operations += 1;
+            tracing::debug!("receiver is gone, operation skipped");
+            return;
+        }
+        // dropping the reply lets the caller know the deadline was missed
+        if deadline.map(|d| d <= Instant::now()).unwrap_or(false) {
+            self.deadline_exceeded += 1;
+            tracing::debug!("deadline exceeded, operation skipped");
+            return;
+        }
+
+        let started = Instant::now();
+        // the message is taken apart below, what the slow log needs of it is kept without
+        // allocating and only turned into a record once the operation was slow
+        let described = self.config.slowlog_threshold.map(|_| {
+            let (operation, key, batch_size) = msg.describe();
+            (operation, key.map(KeyPrefix::new), batch_size)
+        });
+        #[cfg(test)]
+        if let Some(operation_delay) = &self.operation_delay {
+            operation_delay();
+        }
+        // entries that expired while waiting are never served, nor counted in stats
+        self.ttl_cache.remove_due();
+
+        match msg {
+            // would bring back entries that were there before the fenced flush
+            ServiceMessage::Write(key, value, cb)
+            | ServiceMessage::WriteTagged(key, value, _, cb)
+            | ServiceMessage::WriteStored(key, value, _, cb)
+                if seq < self.flush_fence =>
+            {
+                tracing::info!("[write] key {} was sent before flush, rejected", &key);
+                let result = Err(CacheError::Flushed);
+                self.audit("write", &key, Some(&value), client, &result);
+                self.access_log("write", &key, &outcome(&result));
+                self.reply("write", cb, result);
+            }
+            ServiceMessage::WriteBatch(writes, cb) if seq < self.flush_fence => {
+                tracing::info!(
+                    "[write] batch of {} was sent before flush, rejected",
+                    writes.len()
+                );
+                let results = writes
+                    .iter()
+                    .map(|w| {
+                        let result = Err(CacheError::Flushed);
+                        self.audit("write", &w.key, Some(&w.value), client, &result);
+                        result
+                    })
+                    .collect();
+                self.reply("write", cb, results);
+            }
+            ServiceMessage::RingPush(key, line, _, cb) if seq < self.flush_fence => {
+                tracing::info!("[ring-push] key {} was sent before flush, rejected", &key);
+                let result = Err(CacheError::Flushed);
+                self.audit("ring-push", &key, Some(&line), client, &result);
+                self.reply("ring-push", cb, result);
+            }
+            ServiceMessage::Txn(ops, cb) if seq < self.flush_fence => {
+                let result = Err(CacheError::Flushed);
+                for op in &ops {
+                    self.audit("txn", op.key(), None, client, &result);
+                }
+                self.reply("txn", cb, result);
+            }
+            ServiceMessage::Read(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                match &self.offload {
+                    Some(offload) => {
+                        let stored = self.ttl_cache.get_stored(&key);
+                        tracing::info!("[read] key {} -> stored {:?}", &key, &stored);
+                        self.access_log("read", &key, hit_or_miss(stored.is_some()));
+                        offload.read(stored, cb);
+                    }
+                    None => {
+                        let value = self.ttl_cache.get(&key);
+                        tracing::info!("[read] key {} -> {:?}", &key, &value);
+                        self.access_log("read", &key, hit_or_miss(value.is_some()));
+                        self.reply("read", cb, value);
+                    }
+                }
+            }
+            ServiceMessage::Peek(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                match &self.offload {
+                    Some(offload) => offload.read(self.ttl_cache.peek_stored(&key), cb),
+                    None => {
+                        let value = self.ttl_cache.peek(&key);
+                        self.reply("peek", cb, value);
+                    }
+                }
+            }
+            ServiceMessage::Write(key, value, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                tracing::info!("[write] key {} value {:?}", &key, &value);
+                self.write(key, value, Box::default(), seq, client, cb);
+            }
+            ServiceMessage::WriteTagged(key, value, options, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                tracing::info!("[write] key {} value {:?} {:?}", &key, &value, &options);
+                self.write(key, value, options, seq, client, cb);
+            }
+            ServiceMessage::WriteStored(key, stored, options, cb) => {
+                // value as stored, after the write transform
+                let logged = self.access_logged(&key);
+                let record = self.audit_record("write", &key, Some(&stored), client);
+                // version is checked once the value is back, writes queued in the
+                // meantime count as concurrent ones
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.ttl_cache
+                        .check_version(&key, options.expected_version)
+                        .and_then(|_| {
+                            self.ttl_cache
+                                .set_stored(key, stored, options.ttl, options.tags)
+                        })
+                };
+                self.audit_finish(record, &result);
+                if let Some(key) = logged {
+                    self.access_log("write", &key, &outcome(&result));
+                }
+                self.reply("write", cb, result);
+            }
+            // its turn in line is given up by `process`, the caller learns from the
+            // dropped reply
+            ServiceMessage::TransformFailed(_) => {}
+            ServiceMessage::WriteBatch(writes, cb) => {
+                tracing::info!("[write] batch of {}", writes.len());
+                let read_only = self.flags.is_read_only();
+                let results = writes
+                    .into_iter()
+                    .map(|w| {
+                        let key = self.config.key_canonicalization.canonicalize(w.key);
+                        let logged = self.access_logged(&key);
+                        let record = self.audit_record("write", &key, Some(&w.value), client);
+                        let result = if read_only {
+                            Err(CacheError::ReadOnly)
+                        } else {
+                            self.ttl_cache.set_with_ttl(key, w.value, w.ttl)
+                        };
+                        self.audit_finish(record, &result);
+                        if let Some(key) = logged {
+                            self.access_log("write", &key, &outcome(&result));
+                        }
+                        result
+                    })
+                    .collect();
+                self.reply("write", cb, results);
+            }
+            ServiceMessage::ReadMany(keys, cb) => {
+                let keys: Vec<String> = keys
+                    .into_iter()
+                    .map(|k| self.config.key_canonicalization.canonicalize(k))
+                    .collect();
+                let values = self.ttl_cache.get_many(&keys);
+                tracing::info!("[read] {} keys", keys.len());
+                for (key, value) in keys.iter().zip(&values) {
+                    self.access_log("read", key, hit_or_miss(value.is_some()));
+                }
+                self.reply("read", cb, values);
+            }
+            ServiceMessage::MultiTtl(keys, cb) => {
+                let keys: Vec<String> = keys
+                    .into_iter()
+                    .map(|k| self.config.key_canonicalization.canonicalize(k))
+                    .collect();
+                let ttls = self.ttl_cache.ttl_many(&keys);
+                tracing::info!("[ttl] {} keys", keys.len());
+                self.reply("ttl", cb, ttls);
+            }
+            ServiceMessage::ReadIfModifiedSince(key, since, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let result = self.ttl_cache.get_if_modified_since(&key, since);
+                tracing::info!("[read] key {} since {} -> {:?}", &key, since, &result);
+                self.access_log("read", &key, conditional_outcome(&result));
+                self.reply("read", cb, result);
+            }
+            ServiceMessage::ReadDated(key, since, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let result = self.ttl_cache.get_dated(&key, since);
+                tracing::info!("[read] key {} since {:?} -> {:?}", &key, since, &result);
+                self.access_log("read", &key, conditional_outcome(&result.read));
+                self.reply("read", cb, result);
+            }
+            ServiceMessage::ReadOrLock(key, lock_ttl, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let result = if self.flags.is_read_only() {
+                    self.ttl_cache
+                        .get(&key)
+                        .map(LockedRead::Value)
+                        .unwrap_or(LockedRead::Missing)
+                } else {
+                    self.ttl_cache.get_or_lock(&key, lock_ttl)
+                };
+                tracing::info!("[read] key {} with refresh lock -> {:?}", &key, &result);
+                let access = match &result {
+                    LockedRead::Value(_) => "hit",
+                    LockedRead::Granted => "lock-granted",
+                    LockedRead::Wait => "lock-wait",
+                    LockedRead::Missing => "miss",
+                };
+                self.access_log("read", &key, access);
+                self.reply("read", cb, result);
+            }
+            ServiceMessage::Delete(key, created_before, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let result = match created_before {
+                    _ if self.flags.is_read_only() => Err(CacheError::ReadOnly),
+                    Some(before_ms) => Ok(self.ttl_cache.delete_if_created_before(&key, before_ms)),
+                    None if self.ttl_cache.delete(&key) => Ok(ConditionalDelete::Deleted),
+                    None => Ok(ConditionalDelete::Missing),
+                };
+                tracing::info!("[delete] key {} -> {:?}", &key, &result);
+                self.audit("delete", &key, None, client, &result);
+                self.reply("delete", cb, result);
+            }
+            ServiceMessage::Reload(config) => self.reload(*config),
+            ServiceMessage::Flush(fence, cb) => {
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    if fence {
+                        self.flush_fence = seq;
+                    }
+                    let removed = self.ttl_cache.flush();
+                    self.flags.next_generation();
+                    Ok(FlushReport {
+                        removed,
+                        fence: if fence { Some(seq) } else { None },
+                        generation: self.flags.generation(),
+                    })
+                };
+                tracing::info!("[flush] -> {:?}", &result);
+                if let Some(audit) = &self.audit {
+                    audit.finish(audit.record("flush", None, None, client), &result);
+                }
+                self.reply("flush", cb, result);
+            }
+            ServiceMessage::Swap(a, b, cb) => {
+                let a = self.config.key_canonicalization.canonicalize(a);
+                let b = self.config.key_canonicalization.canonicalize(b);
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.ttl_cache.swap(&a, &b)
+                };
+                tracing::info!("[swap] keys {} and {} -> {:?}", &a, &b, &result);
+                self.audit("swap", &a, None, client, &result);
+                self.audit("swap", &b, None, client, &result);
+                self.reply("swap", cb, result);
+            }
+            ServiceMessage::Rename(from, to, overwrite, cb) => {
+                let from = self.config.key_canonicalization.canonicalize(from);
+                let to = self.config.key_canonicalization.canonicalize(to);
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.ttl_cache.rename(&from, to.clone(), overwrite)
+                };
+                tracing::info!("[rename] key {} to {} -> {:?}", &from, &to, &result);
+                self.audit("rename", &from, None, client, &result);
+                self.audit("rename", &to, None, client, &result);
+                self.reply("rename", cb, result);
+            }
+            ServiceMessage::Pin(key, pinned, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let operation = if pinned { "pin" } else { "unpin" };
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.ttl_cache.set_pinned(&key, pinned)
+                };
+                tracing::info!("[{}] key {} -> {:?}", operation, &key, &result);
+                self.audit(operation, &key, None, client, &result);
+                self.reply(operation, cb, result);
+            }
+            ServiceMessage::ResetHits(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.ttl_cache.reset_hits(&key)
+                };
+                tracing::info!("[reset-hits] key {} -> {:?}", &key, &result);
+                self.audit("reset-hits", &key, None, client, &result);
+                self.reply("reset-hits", cb, result);
+            }
+            ServiceMessage::RingPush(key, line, max, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.ttl_cache.ring_push(&key, &line, max)
+                };
+                tracing::info!("[ring-push] key {} line {:?} -> {:?}", &key, &line, &result);
+                self.audit("ring-push", &key, Some(&line), client, &result);
+                self.reply("ring-push", cb, result);
+            }
+            ServiceMessage::InvalidateTag(tag, cb) => {
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    // keys are stored canonicalized, so prefixes apply as they are
+                    let prefixes = &self.config.allowed_key_prefixes;
+                    Ok(self.ttl_cache.invalidate_tag_matching(&tag, |key| {
+                        prefixes.is_empty() || prefixes.iter().any(|p| key.starts_with(p.as_str()))
+                    }))
+                };
+                tracing::info!("[invalidate] tag {} -> {:?}", &tag, &result);
+                self.audit("invalidate-tag", &tag, None, client, &result);
+                self.reply("invalidate-tag", cb, result);
+            }
+            ServiceMessage::Meta(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let meta = self.ttl_cache.meta(&key);
+                self.reply("meta", cb, meta);
+            }
+            ServiceMessage::ReadWithMeta(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let read = self.ttl_cache.get_with_meta(&key);
+                tracing::info!("[read] key {} with meta -> {:?}", &key, &read);
+                self.access_log("read", &key, hit_or_miss(read.is_some()));
+                self.reply("read", cb, read);
+            }
+            ServiceMessage::ReadItem(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let item = self.ttl_cache.get_item(&key);
+                tracing::info!("[read] key {} as item -> {:?}", &key, &item);
+                self.access_log("read", &key, hit_or_miss(item.is_some()));
+                self.reply("read", cb, item);
+            }
+            ServiceMessage::ValueLen(key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let len = self.ttl_cache.value_len(&key);
+                self.reply("strlen", cb, len);
+            }
+            ServiceMessage::CanWrite(key, value_size, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let allowed = !self.flags.is_read_only()
+                    && self
+                        .ttl_cache
+                        .explain(&key, Some(value_size))
+                        .set
+                        .map(|set| set.allowed)
+                        .unwrap_or(true);
+                self.reply("can-write", cb, allowed);
+            }
+            ServiceMessage::Explain(key, value_size, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let mut explanation = self.ttl_cache.explain(&key, value_size);
+                if let Some(set) = explanation.set.as_mut() {
+                    set.allowed &= !self.flags.is_read_only();
+                }
+                self.reply("explain", cb, explanation);
+            }
+            ServiceMessage::Count(pattern, cb) => {
+                // same rules as for keys, so e.g. `User:*` matches lowercased keys
+                let pattern = self.config.key_canonicalization.canonicalize(pattern);
+                let count = self.ttl_cache.count_matching(&pattern);
+                self.reply("count", cb, count);
+            }
+            ServiceMessage::Txn(ops, cb) => {
+                let canonicalization = &self.config.key_canonicalization;
+                let ops: Vec<TxnOp> = ops
+                    .into_iter()
+                    .map(|op| op.map_key(|k| canonicalization.canonicalize(k)))
+                    .collect();
+                // a line per key, all with the outcome of the whole transaction
+                let records: Vec<_> = ops
+                    .iter()
+                    .filter_map(|op| self.audit_record("txn", op.key(), None, client))
+                    .collect();
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    Ok(self.ttl_cache.apply_txn(ops))
+                };
+                for record in records {
+                    self.audit_finish(Some(record), &result);
+                }
+                tracing::info!("[txn] {:?}", result);
+                self.reply("txn", cb, result);
+            }
+            ServiceMessage::Stats(cb) => {
+                let stats = CacheStats {
+                    keys_total: self.ttl_cache.keys_total,
+                    map_len: self.ttl_cache.keys_total,
+                    map_capacity: self.ttl_cache.map_capacity(),
+                    map_load_factor: self.ttl_cache.map_load_factor(),
+                    map_growths: self.ttl_cache.map_growths,
+                    read_only: self.flags.is_read_only(),
+                    overloaded: self.flags.is_overloaded(),
+                    turn_time_us: self.turn_time.as_micros(),
+                    overload_trips: self.overload_trips,
+                    eviction_paused: self.flags.is_eviction_paused(),
+                    generation: self.flags.generation(),
+                    bytes_total: self.ttl_cache.bytes_total,
+                    total_cost: self.ttl_cache.total_cost,
+                    capacity_unit: self.config.capacity_unit,
+                    interned_values: self.ttl_cache.interned_values(),
+                    tags: self.ttl_cache.tag_count(),
+                    tag_index_size: self.ttl_cache.tag_index_size(),
+                    cancelled_operations: self.cancelled_operations,
+                    dropped_replies: self.dropped_replies.clone(),
+                    deadline_exceeded: self.deadline_exceeded,
+                    audit_dropped: self.audit.as_ref().map(AuditLog::dropped).unwrap_or(0),
+                    deferred_drops: self
+                        .deferred_drop
+                        .as_ref()
+                        .map(DeferredDrop::drops)
+                        .unwrap_or(0),
+                    deferred_drop_bytes: self
+                        .deferred_drop
+                        .as_ref()
+                        .map(DeferredDrop::bytes)
+                        .unwrap_or(0),
+                    restore_skipped_corrupt: self.restore_skipped_corrupt,
+                    checksum_mismatches: self.ttl_cache.checksum_mismatches,
+                    expirations: self.ttl_cache.expirations,
+                    capacity_evictions: self.ttl_cache.capacity_evictions,
+                    started_at: self.started_at,
+                    lifetime_started_at: self.restored_stats.started_at,
+                    process: self.ttl_cache.counters(),
+                    lifetime: self.lifetime_stats().counters,
+                    event_subscribers: self
+                        .subscribers
+                        .iter()
+                        .filter(|s| Arc::strong_count(s) > 1)
+                        .map(|s| s.snapshot())
+                        .collect(),
+                    queued: self.queue.queued(),
+                    full_gc_runs: self.full_gc_runs,
+                    last_full_gc: self.last_full_gc.clone(),
+                    expired_backlog: self.ttl_cache.expired_backlog,
+                    extra_eviction_passes: self.extra_eviction_passes,
+                    namespaces: self.namespace_stats(),
+                    auxiliary: self.auxiliary_usage(),
+                };
+                self.reply("stats", cb, stats);
+            }
+            ServiceMessage::TtlHistogram(cb) => {
+                let histogram = self.ttl_cache.ttl_histogram();
+                self.reply("ttl-histogram", cb, histogram);
+            }
+            ServiceMessage::Compact(cb) => {
+                let map_capacity_before = self.ttl_cache.map_capacity();
+                self.ttl_cache.compact();
+                let report = CompactReport {
+                    map_capacity_before,
+                    map_capacity_after: self.ttl_cache.map_capacity(),
+                };
+                tracing::info!("[compact] {:?}", report);
+                self.reply("compact", cb, report);
+            }
+            ServiceMessage::Reserve(additional, cb) => {
+                let map_capacity_before = self.ttl_cache.map_capacity();
+                let result = self.ttl_cache.reserve(additional).map(|_| CompactReport {
+                    map_capacity_before,
+                    map_capacity_after: self.ttl_cache.map_capacity(),
+                });
+                tracing::info!("[reserve] {} keys {:?}", additional, result);
+                self.reply("reserve", cb, result);
+            }
+            ServiceMessage::Evict(cb) => {
+                let pass = self.ttl_cache.evict_expired();
+                let report = EvictionReport {
+                    removed: pass.removed,
+                    sampled: pass.sampled,
+                    rounds: pass.rounds,
+                    keys_total: self.ttl_cache.keys_total,
+                };
+                tracing::info!("[evict] {:?}", report);
+                self.reply("evict", cb, report);
+            }
+            ServiceMessage::Snapshot(cb) if self.config.snapshot_path.is_none() => {
+                self.reply("snapshot", cb, Err(SnapshotError::NotConfigured));
+            }
+            ServiceMessage::Snapshot(cb) => {
+                self.pending_snapshots.push_back(cb);
+                self.maintenance.request(MaintenanceKind::Snapshot);
+                self.run_maintenance();
+            }
+            ServiceMessage::MaintenanceStatus(cb) => {
+                let status = self.maintenance.status();
+                self.reply("maintenance-status", cb, status);
+            }
+            ServiceMessage::PersistStats(cb) => {
+                let result = self.persist_stats();
+                tracing::info!("[persist-stats] {:?}", result);
+                self.reply("persist-stats", cb, result);
+            }
+            ServiceMessage::Subscribe(mut filter, last_event_id, cb) => {
+                // events carry canonical keys
+                filter.prefix = filter
+                    .prefix
+                    .map(|p| self.config.key_canonicalization.canonicalize(p));
+                let subscription = self.subscribe(*filter, last_event_id);
+                self.reply("subscribe", cb, subscription);
+            }
+            ServiceMessage::Ping(cb) => self.reply("ping", cb, ()),
+            ServiceMessage::NsRead(namespace, key, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                let now = self.time.get_time();
+                // reads never create a namespace
+                let value = self.namespaces.get_mut(&namespace).and_then(|ns| {
+                    ns.last_active = now;
+                    ns.cache.get(&key)
+                });
+                tracing::info!(
+                    "[read] namespace {} key {} -> {:?}",
+                    &namespace,
+                    &key,
+                    &value
+                );
+                self.reply("read", cb, value);
+            }
+            ServiceMessage::NsWrite(namespace, key, value, cb) => {
+                let key = self.config.key_canonicalization.canonicalize(key);
+                tracing::info!(
+                    "[write] namespace {} key {} value {:?}",
+                    &namespace,
+                    &key,
+                    &value
+                );
+                let record = self
+                    .audit_record("write", &key, Some(&value), client)
+                    .map(|record| AuditRecord {
+                        tenant: Some(namespace.clone()),
+                        ..record
+                    });
+                let now = self.time.get_time();
+                let result = if self.flags.is_read_only() {
+                    Err(CacheError::ReadOnly)
+                } else {
+                    self.namespace_for_write(namespace).and_then(|ns| {
+                        ns.last_active = now;
+                        ns.empty_since = None;
+                        ns.cache.set(key, value.into_vec())
+                    })
+                };
+                self.audit_finish(record, &result);
+                self.reply("write", cb, result);
+            }
+            ServiceMessage::NsDetach(namespace, cb) => {
+                let result = match self.namespaces.get_mut(&namespace) {
+                    Some(ns) => {
+                        ns.state = NamespaceState::ReadOnly;
+                        Ok(())
+                    }
+                    None => Err(CacheError::UnknownNamespace(namespace.clone())),
+                };
+                tracing::info!("[namespace] {} detached -> {:?}", &namespace, &result);
+                self.reply("ns-detach", cb, result);
+            }
+            ServiceMessage::NsDump(namespace, cb) => {
+                let result = match self.namespaces.get(&namespace) {
+                    Some(ns) => Ok(ns
+                        .cache
+                        .live_entries()
+                        .map(|(key, value, ttl)| (key.to_string(), value.to_vec(), ttl))
+                        .collect()),
+                    None => Err(CacheError::UnknownNamespace(namespace.clone())),
+                };
+                tracing::info!("[namespace] {} dumped", &namespace);
+                self.reply("ns-dump", cb, result);
+            }
+            ServiceMessage::NsDrop(namespace, cb) => {
+                let result = match self.namespaces.get_mut(&namespace) {
+                    _ if self.flags.is_read_only() => Err(CacheError::ReadOnly),
+                    Some(ns) => Ok(ns.cache.remove_all(EventKind::Flushed)),
+                    None => Err(CacheError::UnknownNamespace(namespace.clone())),
+                };
+                if result.is_ok() {
+                    self.namespaces.remove(&namespace);
+                }
+                tracing::info!("[namespace] {} dropped -> {:?}", &namespace, &result);
+                if let Some(audit) = &self.audit {
+                    let record = AuditRecord {
+                        tenant: Some(namespace),
+                        ..audit.record("drop-namespace", None, None, client)
+                    };
+                    audit.finish(record, &result);
+                }
+                self.reply("ns-drop", cb, result);
+            }
+            ServiceMessage::SlowLog(cb) => {
+                let operations = self.slowlog.operations();
+                self.reply("slowlog", cb, operations);
+            }
+            ServiceMessage::ResetSlowLog(cb) => {
+                let logged = self.slowlog.reset();
+                tracing::info!("[slowlog] reset, {} operations dropped", logged);
+                self.reply("reset-slowlog", cb, logged);
+            }
+        }
+        let took = started.elapsed();
+        self.record_turn(took);
+        if let (Some(threshold), Some((operation, key, batch_size))) =
+            (self.config.slowlog_threshold, described)
+        {
+            if took >= threshold {
+                self.slowlog.record(SlowOperation::new(
+                    operation,
+                    key.as_ref().map(KeyPrefix::as_str),
+                    batch_size,
+                    took,
+                    started.saturating_duration_since(sent_at),
+                ));
+            }
+        }
+    }
+}
+
+// outcomes of reads and writes in the access log, see `access_log_prefixes`
+fn hit_or_miss(hit: bool) -> &'static str {
+    if hit {
+        "hit"
+    } else {
+        "miss"
+    }
+}
+
+fn conditional_outcome(read: &ConditionalRead) -> &'static str {
+    match read {
+        ConditionalRead::Value(_) => "hit",
+        ConditionalRead::NotModified => "not-modified",
+        ConditionalRead::Missing => "miss",
+    }
+}
+
+fn outcome<V>(result: &Result<V, CacheError>) -> String {
+    match result {
+        Ok(_) => String::from("ok"),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod service_tests {
+    use crate::cache::CacheError;
+    use crate::cache::ConditionalDelete;
+    use crate::config::Config;
+    use crate::config::ExpiryMode;
+    use crate::config::CASE_INSENSITIVE_KEYS;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::events::EventFilter;
+    use crate::events::EventItem;
+    use crate::events::EventKind;
+    use crate::events::Subscription;
+    use crate::maintenance::MaintenanceKind;
+    use crate::maintenance::MaintenanceState;
+    use crate::maintenance::MaintenanceStatus;
+    use crate::service::service_queue;
+    use crate::service::CacheStats;
+    use crate::service::FlushReport;
+    use crate::service::ServiceFlags;
+    use crate::service::ServiceMessage;
+    use crate::service::ServiceQueue;
+    use crate::service::SnapshotReport;
+    use crate::service::TtlCacheService;
+    use crate::service::WriteOptions;
+    use crate::service::WRITE_EVERY;
+    use crate::snapshot::SnapshotError;
+    use crate::stats::Counters;
+    use crate::time::time_fixtures::TestTime;
+    use crate::time::REALTIME;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use tokio::sync::oneshot;
+    use tokio_stream::StreamExt;
+
+    fn spawn_service(config: Config) -> (ServiceQueue, Arc<ServiceFlags>) {
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+
+        let flags_for_svc = flags.clone();
+        tokio::spawn(async move {
+            TtlCacheService::new(config, rx, flags_for_svc, &REALTIME)
+                .run()
+                .await
+        });
+
+        (tx, flags)
+    }
+
+    async fn stats(tx: &ServiceQueue) -> CacheStats {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Stats(cb).into()).unwrap();
+        res.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_are_rejected_in_read_only_mode() {
+        let (tx, flags) = spawn_service(TEST_CONFIG_SINGLE_ITEM);
+
+        flags.set_read_only(true);
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Write("key".into(), "value".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), Err(CacheError::ReadOnly));
+
+        flags.set_read_only(false);
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Write("key".into(), "value".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn operations_are_skipped_when_receiver_is_gone() {
+        let (tx, _) = spawn_service(TEST_CONFIG_SINGLE_ITEM);
+
+        let (cb, res) = oneshot::channel();
+        drop(res);
+        tx.send(ServiceMessage::TtlHistogram(cb).into()).unwrap();
+
+        let (cb, res) = oneshot::channel();
+        drop(res);
+        tx.send(ServiceMessage::Write("key".into(), "value".into(), cb).into())
+            .unwrap();
+
+        assert_eq!(stats(&tx).await.cancelled_operations, 2);
+        assert_eq!(stats(&tx).await.keys_total, 0);
+    }
+
+    #[tokio::test]
+    async fn replies_dropped_after_processing_are_counted_per_operation() {
+        let (_tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&TEST_CONFIG_SINGLE_ITEM));
+        let mut service = TtlCacheService::new(TEST_CONFIG_SINGLE_ITEM, rx, flags, &REALTIME);
+
+        // receivers going away while the operation is processed, past the cancellation check
+        let (cb, res) = oneshot::channel::<Option<Vec<u8>>>();
+        drop(res);
+        service.reply("read", cb, None);
+        let (cb, res) = oneshot::channel::<Result<(), CacheError>>();
+        drop(res);
+        service.reply("write", cb, Ok(()));
+        let (cb, res) = oneshot::channel::<Result<(), CacheError>>();
+        drop(res);
+        service.reply("write", cb, Ok(()));
+        let (cb, res) = oneshot::channel::<Result<(), CacheError>>();
+        service.reply("write", cb, Ok(()));
+        assert_eq!(res.await.unwrap(), Ok(()));
+
+        assert_eq!(service.dropped_replies.get("read"), Some(&1));
+        assert_eq!(service.dropped_replies.get("write"), Some(&2));
+        assert_eq!(service.cancelled_operations, 3);
+    }
+
+    #[tokio::test]
+    async fn operations_past_deadline_are_skipped() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Write("key".into(), "value".into(), cb).into())
+            .unwrap();
+        let mut late = Vec::new();
+        for i in 0..3 {
+            let (cb, res) = oneshot::channel();
+            let deadline = Instant::now() + Duration::from_millis(1);
+            tx.send(
+                ServiceMessage::Write(format!("late{}", i), "value".into(), cb)
+                    .with_deadline(Some(deadline)),
+            )
+            .unwrap();
+            late.push(res);
+        }
+        // service runs on the same thread, so it is stalled while we sleep
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(res.await.unwrap(), Ok(()));
+        for res in late {
+            assert!(res.await.is_err());
+        }
+        let stats = stats(&tx).await;
+        assert_eq!(stats.deadline_exceeded, 3);
+        assert_eq!(stats.keys_total, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_not_reclaimed_while_eviction_is_paused() {
+        let (tx, flags) = spawn_service(Config {
+            ttl: Duration::from_millis(1),
+            capacity: None,
+            eviction_every: Duration::ZERO,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        flags.set_eviction_paused(true);
+        for i in 0..50 {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let stats_while_paused = stats(&tx).await;
+        assert!(stats_while_paused.eviction_paused);
+        assert_eq!(stats_while_paused.keys_total, 50);
+
+        flags.set_eviction_paused(false);
+        // eviction pass runs before the next message is processed
+        stats(&tx).await;
+        assert_eq!(stats(&tx).await.keys_total, 0);
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_in_between_eviction_chunks() {
+        let (tx, flags) = spawn_service(Config {
+            ttl: Duration::from_millis(1),
+            capacity: None,
+            eviction_every: Duration::ZERO,
+            max_eviction_rounds: 1,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        flags.set_eviction_paused(true);
+        for i in 0..5000 {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        flags.set_eviction_paused(false);
+        stats(&tx).await;
+        let started = std::time::Instant::now();
+        let stats_during_eviction = stats(&tx).await;
+
+        // a single round removes at most `eviction_number` keys before the request is served
+        assert!(stats_during_eviction.keys_total >= 5000 - 2 * 20);
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // pass keeps going while the service is idle
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(stats(&tx).await.keys_total < 5000 - 2 * 20);
+    }
+
+    async fn subscribe(tx: &ServiceQueue, filter: EventFilter) -> Result<Subscription, CacheError> {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Subscribe(Box::new(filter), None, cb).into())
+            .unwrap();
+        res.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscribers_only_receive_matching_events() {
+        let (tx, _) = spawn_service(Config {
+            ttl: Duration::from_millis(1),
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let users = subscribe(&tx, EventFilter::parse(Some("user:".into()), None).unwrap())
+            .await
+            .unwrap();
+        let expirations = subscribe(&tx, EventFilter::parse(None, Some("expired")).unwrap())
+            .await
+            .unwrap();
+
+        for key in ["user:1", "org:1"] {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(key.into(), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Read("org:1".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), None);
+
+        let mut users = Box::pin(users.matching_events().filter_map(EventItem::into_event));
+        let event = users.next().await.unwrap();
+        assert_eq!((event.key.as_str(), event.kind), ("user:1", EventKind::Set));
+
+        let mut expirations = Box::pin(
+            expirations
+                .matching_events()
+                .filter_map(EventItem::into_event),
+        );
+        let event = expirations.next().await.unwrap();
+        assert_eq!(
+            (event.key.as_str(), event.kind),
+            ("org:1", EventKind::Expired)
+        );
+
+        let stats = stats(&tx).await;
+        assert_eq!(stats.event_subscribers.len(), 2);
+        assert_eq!(stats.event_subscribers[0].delivered, 1);
+    }
+
+    #[test]
+    fn namespaces_are_not_presized_like_the_main_cache() {
+        let (_tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            initial_capacity: Some(10_000),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let mut service = TtlCacheService::new(config, rx, flags, &REALTIME);
+        assert!(service.ttl_cache.map_capacity() >= 10_000);
+
+        let namespace = service.namespace_for_write(String::from("team")).unwrap();
+        assert_eq!(namespace.cache.map_capacity(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_namespace_removes_its_entries_with_flushed_events() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let flushed = subscribe(&tx, EventFilter::parse(None, Some("flushed")).unwrap())
+            .await
+            .unwrap();
+
+        for (namespace, key) in [("team", "a"), ("team", "b"), ("other", "a")] {
+            let (cb, res) = oneshot::channel();
+            tx.send(
+                ServiceMessage::NsWrite(namespace.into(), key.into(), b"value"[..].into(), cb)
+                    .into(),
+            )
+            .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::NsDrop("team".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), Ok(2));
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::NsDrop("team".into(), cb).into())
+            .unwrap();
+        assert_eq!(
+            res.await.unwrap(),
+            Err(CacheError::UnknownNamespace("team".into()))
+        );
+
+        let mut flushed = Box::pin(flushed.matching_events().filter_map(EventItem::into_event));
+        let mut keys = Vec::new();
+        for _ in 0..2 {
+            let event = flushed.next().await.unwrap();
+            assert_eq!(event.namespace.as_deref(), Some("team"));
+            keys.push(event.key);
+        }
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+
+        let namespaces = stats(&tx).await.namespaces;
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0].name, "other");
+    }
+
+    #[tokio::test]
+    async fn subscriber_count_is_capped() {
+        let (tx, _) = spawn_service(TEST_CONFIG_SINGLE_ITEM);
+
+        let first = subscribe(&tx, EventFilter::default()).await.unwrap();
+        let _second = subscribe(&tx, EventFilter::default()).await.unwrap();
+        assert_eq!(
+            subscribe(&tx, EventFilter::default()).await.err(),
+            Some(CacheError::TooManySubscribers(2))
+        );
+
+        // slot is freed once subscriber goes away
+        drop(first);
+        assert!(subscribe(&tx, EventFilter::default()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn eviction_cadence_follows_injected_clock() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            eviction_every: Duration::from_secs(60),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+
+        for i in 0..10 {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+
+        // entries expired, but it is not time for eviction pass yet
+        time.add_secs(Duration::from_secs(11));
+        stats(&tx).await;
+        assert_eq!(stats(&tx).await.keys_total, 10);
+
+        time.add_secs(Duration::from_secs(61));
+        // eviction pass runs before the next message is processed
+        stats(&tx).await;
+        assert_eq!(stats(&tx).await.keys_total, 0);
+    }
+
+    #[tokio::test]
+    async fn large_expired_backlog_triggers_extra_eviction_passes() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            eviction_every: Duration::from_secs(5),
+            // passes stop early, leaving plenty of expired entries behind
+            eviction_ratio: 0.5,
+            // requests are taken in between rounds, so that stats show the estimate going down
+            max_eviction_rounds: 1,
+            expired_backlog_alert_ratio: Some(0.1),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+
+        let write_keys = |keys: std::ops::Range<usize>| {
+            let tx = tx.clone();
+            async move {
+                for i in keys {
+                    let (cb, res) = oneshot::channel();
+                    tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                        .unwrap();
+                    assert_eq!(res.await.unwrap(), Ok(()));
+                }
+            }
+        };
+        write_keys(0..80).await;
+        time.add_secs(Duration::from_secs(8));
+        write_keys(80..100).await;
+        assert_eq!(stats(&tx).await.extra_eviction_passes, 0);
+
+        // 80% of the keys expired, scheduled pass starts after this request
+        time.add_secs(Duration::from_secs(14));
+        stats(&tx).await;
+        let mut backlog = Vec::new();
+        for _ in 0..200 {
+            backlog.push(stats(&tx).await.expired_backlog.keys);
+        }
+        let stats = stats(&tx).await;
+
+        assert!(stats.extra_eviction_passes > 0);
+        assert!(stats.keys_total < 100);
+        assert!(stats.expired_backlog.keys < *backlog.iter().max().unwrap());
+        assert!(stats.expired_backlog.keys as f64 <= 0.1 * stats.keys_total as f64);
+    }
+
+    #[tokio::test]
+    async fn reload_changes_ttl_and_eviction_cadence() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            eviction_every: Duration::from_secs(60),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let reloaded = Config {
+            ttl: Duration::from_secs(5),
+            eviction_every: Duration::from_secs(2),
+            ..config.clone()
+        };
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+
+        for i in 0..10 {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        tx.send(ServiceMessage::Reload(Box::new(reloaded)).into())
+            .unwrap();
+
+        // expired under the new ttl only, and swept by a pass that would not be due
+        // for another minute with the old cadence
+        time.add_secs(Duration::from_secs(6));
+        stats(&tx).await;
+        assert_eq!(stats(&tx).await.keys_total, 0);
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Write(String::from("key"), "value".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), Ok(()));
+        time.add_secs(Duration::from_secs(12));
+        assert!(!key_exists(&tx, "key").await);
+    }
+
+    #[tokio::test]
+    async fn reload_does_not_shrink_capacity_below_stored_entries() {
+        let (tx, _) = spawn_service(Config {
+            capacity: Some(3),
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        for i in 0..2 {
+            assert_eq!(
+                write(&tx, &format!("key{}", i), "value").await.unwrap(),
+                Ok(())
+            );
+        }
+
+        tx.send(
+            ServiceMessage::Reload(Box::new(Config {
+                capacity: Some(1),
+                ..TEST_CONFIG_SINGLE_ITEM
+            }))
+            .into(),
+        )
+        .unwrap();
+        assert_eq!(write(&tx, "key2", "value").await.unwrap(), Ok(()));
+        assert!(write(&tx, "key3", "value").await.unwrap().is_err());
+    }
+
+    fn flush(tx: &ServiceQueue, fence: bool) -> oneshot::Receiver<Result<FlushReport, CacheError>> {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Flush(fence, cb).into()).unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn fenced_flush_rejects_writes_sent_before_it() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        assert_eq!(write(&tx, "old", "value").await.unwrap(), Ok(()));
+
+        // nothing is served until the first await, flush jumps ahead of queued writes
+        let before: Vec<_> = (0..100)
+            .map(|i| write(&tx, &format!("before{}", i), "value"))
+            .collect();
+        let flushed = flush(&tx, true);
+        let after: Vec<_> = (0..10)
+            .map(|i| write(&tx, &format!("after{}", i), "value"))
+            .collect();
+
+        let report = flushed.await.unwrap().unwrap();
+        assert_eq!(report.removed, 1);
+        assert!(report.fence.is_some());
+        for res in before {
+            assert_eq!(res.await.unwrap(), Err(CacheError::Flushed));
+        }
+        for res in after {
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        assert_eq!(stats(&tx).await.keys_total, 10);
+        assert!(!key_exists(&tx, "old").await);
+    }
+
+    #[tokio::test]
+    async fn unfenced_flush_lets_queued_writes_through() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        let before: Vec<_> = (0..10)
+            .map(|i| write(&tx, &format!("before{}", i), "value"))
+            .collect();
+        let report = flush(&tx, false).await.unwrap().unwrap();
+        assert_eq!(report.fence, None);
+        for res in before {
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        assert_eq!(stats(&tx).await.keys_total, 10);
+    }
+
+    fn snapshot(tx: &ServiceQueue) -> oneshot::Receiver<Result<SnapshotReport, SnapshotError>> {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Snapshot(cb).into()).unwrap();
+        res
+    }
+
+    async fn maintenance_status(tx: &ServiceQueue) -> MaintenanceStatus {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::MaintenanceStatus(cb).into())
+            .unwrap();
+        res.await.unwrap()
+    }
+
+    fn maintenance_config(name: &str) -> Config {
+        Config {
+            capacity: None,
+            eviction_every: Duration::from_secs(60),
+            snapshot_path: Some(std::env::temp_dir().join(format!(
+                "in-mem-cached-{}-{}.snapshot",
+                name,
+                std::process::id()
+            ))),
+            ..TEST_CONFIG_SINGLE_ITEM
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_operations_trip_load_shedding_until_latency_recovers() {
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            latency_budget: Some(Duration::from_millis(2)),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let mut service = TtlCacheService::new(config, rx, flags.clone(), &REALTIME);
+        let slow = Arc::new(AtomicBool::new(false));
+        let slow_for_svc = slow.clone();
+        service.operation_delay = Some(Box::new(move || {
+            if slow_for_svc.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }));
+        tokio::spawn(async move { service.run().await });
+
+        for i in 0..20 {
+            assert_eq!(
+                write(&tx, &format!("key{}", i), "value").await.unwrap(),
+                Ok(())
+            );
+        }
+        assert!(!flags.is_overloaded());
+
+        // a single slow operation is not enough
+        slow.store(true, Ordering::SeqCst);
+        assert_eq!(write(&tx, "key0", "value").await.unwrap(), Ok(()));
+        slow.store(false, Ordering::SeqCst);
+        assert_eq!(write(&tx, "key0", "value").await.unwrap(), Ok(()));
+        assert!(!flags.is_overloaded());
+
+        slow.store(true, Ordering::SeqCst);
+        for i in 0..5 {
+            assert_eq!(
+                write(&tx, &format!("key{}", i), "value").await.unwrap(),
+                Ok(())
+            );
+        }
+        assert!(flags.is_overloaded());
+        let tripped = stats(&tx).await;
+        assert!(tripped.overloaded);
+        assert_eq!(tripped.overload_trips, 1);
+        assert!(tripped.turn_time_us > 2_000);
+
+        // nothing comes in while requests are shed, idle time brings the average down
+        slow.store(false, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while flags.is_overloaded() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(!flags.is_overloaded());
+        let recovered = stats(&tx).await;
+        assert!(!recovered.overloaded);
+        assert!(recovered.turn_time_us <= 1_000);
+        assert_eq!(recovered.overload_trips, 1);
+    }
+
+    #[tokio::test]
+    async fn slow_operations_are_kept_in_slow_log() {
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            slowlog_threshold: Some(Duration::from_millis(3)),
+            slowlog_len: 2,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let mut service = TtlCacheService::new(config, rx, flags, &REALTIME);
+        let slow = Arc::new(AtomicBool::new(false));
+        let slow_for_svc = slow.clone();
+        service.operation_delay = Some(Box::new(move || {
+            if slow_for_svc.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }));
+        tokio::spawn(async move { service.run().await });
+        let slowlog = || async {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::SlowLog(cb).into()).unwrap();
+            res.await.unwrap()
+        };
+
+        assert_eq!(write(&tx, "fast", "value").await.unwrap(), Ok(()));
+        assert!(slowlog().await.is_empty());
+
+        slow.store(true, Ordering::SeqCst);
+        assert_eq!(write(&tx, "key", "value").await.unwrap(), Ok(()));
+        assert_eq!(read(&tx, "key").await.unwrap(), Some(b"value".to_vec()));
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::ReadMany(vec!["a".into(), "b".into(), "c".into()], cb).into())
+            .unwrap();
+        res.await.unwrap();
+        slow.store(false, Ordering::SeqCst);
+
+        // oldest one, the write, is rotated out
+        let logged = slowlog().await;
+        let described: Vec<(&str, Option<&str>, usize)> = logged
+            .iter()
+            .map(|o| (o.operation, o.key.as_deref(), o.batch_size))
+            .collect();
+        assert_eq!(
+            described,
+            vec![("read-many", Some("a"), 3), ("read", Some("key"), 1)]
+        );
+        assert!(logged.iter().all(|o| o.duration_us >= 3_000));
+        assert!(logged[0].ts_ms >= logged[1].ts_ms);
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::ResetSlowLog(cb).into()).unwrap();
+        assert_eq!(res.await.unwrap(), 2);
+        assert!(slowlog().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn eviction_due_during_long_snapshot_runs_right_after() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = maintenance_config("long-snapshot");
+        let path = config.snapshot_path.clone().unwrap();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        let mut service = TtlCacheService::new(config, rx, flags, time);
+        // entries expire and eviction becomes due while the snapshot is written
+        service.snapshot_delay = Some(Box::new(move || time.add_secs(Duration::from_secs(61))));
+        tokio::spawn(async move { service.run().await });
+
+        for i in 0..10 {
+            assert_eq!(
+                write(&tx, &format!("key{}", i), "value").await.unwrap(),
+                Ok(())
+            );
+        }
+        assert_eq!(snapshot(&tx).await.unwrap().unwrap().entries, 10);
+
+        assert_eq!(stats(&tx).await.keys_total, 0);
+        let status = maintenance_status(&tx).await;
+        assert_eq!(status.state, MaintenanceState::Idle);
+        let kinds: Vec<_> = status.recent.iter().map(|run| run.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![MaintenanceKind::Snapshot, MaintenanceKind::Eviction]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_requested_during_eviction_pass_waits_for_it() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = Config {
+            // a single round per request, so that the pass spans many of them
+            max_eviction_rounds: 1,
+            ..maintenance_config("eviction-pass")
+        };
+        let path = config.snapshot_path.clone().unwrap();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+
+        for i in 0..1000 {
+            assert_eq!(
+                write(&tx, &format!("key{}", i), "value").await.unwrap(),
+                Ok(())
+            );
+        }
+        time.add_secs(Duration::from_secs(61));
+        // eviction pass starts before the next message is processed
+        stats(&tx).await;
+
+        let snapshotted = snapshot(&tx);
+        let status = maintenance_status(&tx).await;
+        assert_eq!(status.state, MaintenanceState::Evicting);
+        assert_eq!(status.pending, vec![MaintenanceKind::Snapshot]);
+
+        // taken once the pass is over, nothing it was about to remove is persisted
+        assert_eq!(snapshotted.await.unwrap().unwrap().entries, 0);
+        let status = maintenance_status(&tx).await;
+        assert_eq!(status.state, MaintenanceState::Idle);
+        let kinds: Vec<_> = status.recent.iter().map(|run| run.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![MaintenanceKind::Eviction, MaintenanceKind::Snapshot]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_keeps_only_keys_matching_persist_patterns() {
+        let config = Config {
+            snapshot_persist_patterns: vec![String::from("user:*"), String::from("?eep")],
+            ..maintenance_config("persist-patterns")
+        };
+        let path = config.snapshot_path.clone().unwrap();
+        let (tx, _) = spawn_service(config);
+
+        for key in ["user:1", "user:2", "keep", "session:1", "keeper"].iter() {
+            assert_eq!(write(&tx, key, "value").await.unwrap(), Ok(()));
+        }
+
+        let report = snapshot(&tx).await.unwrap().unwrap();
+        assert_eq!((report.entries, report.skipped), (3, 2));
+
+        let restored = crate::snapshot::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let mut keys: Vec<&str> = restored.entries.iter().map(|e| e.key.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["keep", "user:1", "user:2"]);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_stay_without_active_eviction() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            eviction_every: Duration::from_secs(1),
+            active_eviction: false,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+
+        for i in 0..10 {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+
+        // many eviction intervals later nothing was reclaimed on its own
+        time.add_secs(Duration::from_secs(60));
+        stats(&tx).await;
+        assert_eq!(stats(&tx).await.keys_total, 10);
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Evict(cb).into()).unwrap();
+        assert_eq!(res.await.unwrap().removed, 10);
+        assert_eq!(stats(&tx).await.keys_total, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_gc_sweeps_expired_entries_while_idle() {
+        let time: &'static TestTime = Box::leak(Box::new(TestTime::new(Instant::now())));
+        let (tx, rx) = service_queue();
+        let config = Config {
+            capacity: None,
+            // sampling passes never run, only the full sweep can reclaim entries
+            active_eviction: false,
+            full_gc_every: Some(Duration::from_secs(60)),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move { TtlCacheService::new(config, rx, flags, time).run().await });
+
+        for i in 0..10 {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(format!("key{}", i), "value".into(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+
+        time.add_secs(Duration::from_secs(11));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let before_gc = stats(&tx).await;
+        assert_eq!(before_gc.keys_total, 10);
+        assert_eq!(before_gc.full_gc_runs, 0);
+
+        time.add_secs(Duration::from_secs(61));
+        // no request needed, service wakes up for the sweep on its own,
+        // runtime clock is paused and only catches up with the injected one here
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let after_gc = stats(&tx).await;
+        assert_eq!(after_gc.keys_total, 0);
+        assert_eq!(after_gc.full_gc_runs, 1);
+        let report = after_gc.last_full_gc.unwrap();
+        assert_eq!(report.removed, 10);
+        assert_eq!(report.bytes_reclaimed, 50);
+    }
+
+    fn write(
+        tx: &ServiceQueue,
+        key: &str,
+        value: &str,
+    ) -> oneshot::Receiver<Result<(), CacheError>> {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Write(key.into(), value.into(), cb).into())
+            .unwrap();
+        res
+    }
+
+    fn read(tx: &ServiceQueue, key: &str) -> oneshot::Receiver<Option<Vec<u8>>> {
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Read(key.into(), cb).into())
+            .unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn slow_transforms_do_not_block_other_operations() {
+        let config = Config {
+            capacity: None,
+            transform_workers: 2,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move {
+            let mut service = TtlCacheService::new(config, rx, flags, &REALTIME);
+            // values starting with `slow` take a while on the way in and out
+            let slow = |value: &[u8]| {
+                if value.starts_with(b"slow") {
+                    std::thread::sleep(Duration::from_millis(300));
+                }
+            };
+            service.set_transforms(
+                Arc::new(move |v| {
+                    slow(&v);
+                    [b"!".to_vec(), v].concat()
+                }),
+                Arc::new(move |v| {
+                    slow(&v[1..]);
+                    v[1..].to_vec()
+                }),
+            );
+            service.run().await
+        });
+        let fast = Duration::from_millis(100);
+
+        let slow_write = write(&tx, "slow", "slow value");
+        assert_eq!(
+            tokio::time::timeout(fast, write(&tx, "fast", "value"))
+                .await
+                .unwrap(),
+            Ok(Ok(()))
+        );
+        assert_eq!(slow_write.await.unwrap(), Ok(()));
+
+        let slow_read = read(&tx, "slow");
+        let value = tokio::time::timeout(fast, read(&tx, "fast")).await.unwrap();
+        assert_eq!(value, Ok(Some(b"value".to_vec())));
+        assert_eq!(slow_read.await.unwrap(), Some(b"slow value".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn versioned_writes_transformed_at_once_let_only_one_through() {
+        let config = Config {
+            capacity: None,
+            transform_workers: 2,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move {
+            let mut service = TtlCacheService::new(config, rx, flags, &REALTIME);
+            // both writes are on the blocking pool at the same time
+            service.set_transforms(
+                Arc::new(|v| {
+                    std::thread::sleep(Duration::from_millis(50));
+                    v
+                }),
+                Arc::new(|v| v),
+            );
+            service.run().await
+        });
+        let versioned = |value: &str, expected_version| {
+            let (cb, res) = oneshot::channel();
+            let options = WriteOptions {
+                expected_version: Some(expected_version),
+                ..WriteOptions::default()
+            };
+            tx.send(
+                ServiceMessage::WriteTagged("key".into(), value.into(), Box::new(options), cb)
+                    .into(),
+            )
+            .unwrap();
+            res
+        };
+
+        let (a, b) = tokio::join!(versioned("a", 0), versioned("b", 0));
+        let mut results = [a.unwrap(), b.unwrap()];
+        results.sort_by_key(|r| r.is_err());
+        assert_eq!(results[0], Ok(()));
+        assert!(matches!(
+            &results[1],
+            Err(CacheError::VersionMismatch(key, current)) if key == "key" && *current > 0
+        ));
+    }
+
+    // values starting with `slow` take a while to transform on the way in
+    fn spawn_with_slow_writes() -> ServiceQueue {
+        let config = Config {
+            capacity: None,
+            transform_workers: 2,
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move {
+            let mut service = TtlCacheService::new(config, rx, flags, &REALTIME);
+            service.set_transforms(
+                Arc::new(|v| {
+                    if v.starts_with(b"slow") {
+                        std::thread::sleep(Duration::from_millis(200));
+                    }
+                    v
+                }),
+                Arc::new(|v| v),
+            );
+            service.run().await
+        });
+        tx
+    }
+
+    #[tokio::test]
+    async fn offloaded_writes_of_a_key_are_applied_in_the_order_they_were_sent() {
+        let tx = spawn_with_slow_writes();
+
+        let first = write(&tx, "key", "slow value");
+        let second = write(&tx, "key", "value");
+        assert_eq!(second.await.unwrap(), Ok(()));
+        assert_eq!(first.await.unwrap(), Ok(()));
+        assert_eq!(read(&tx, "key").await.unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn changes_sent_after_an_offloaded_write_wait_for_it() {
+        let tx = spawn_with_slow_writes();
+
+        let written = write(&tx, "key", "slow value");
+        let (cb, deleted) = oneshot::channel();
+        tx.send(ServiceMessage::Delete("key".into(), None, cb).into())
+            .unwrap();
+        assert_eq!(deleted.await.unwrap(), Ok(ConditionalDelete::Deleted));
+        assert_eq!(written.await.unwrap(), Ok(()));
+        assert_eq!(read(&tx, "key").await.unwrap(), None);
+
+        // writes of other keys are not held back meanwhile
+        let written = write(&tx, "key", "slow value");
+        let fast = Duration::from_millis(100);
+        assert_eq!(
+            tokio::time::timeout(fast, write(&tx, "other", "value"))
+                .await
+                .unwrap(),
+            Ok(Ok(()))
+        );
+        assert_eq!(written.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn flush_and_tag_invalidation_wait_for_offloaded_writes() {
+        let tx = spawn_with_slow_writes();
+
+        let written = write(&tx, "key", "slow value");
+        // once this is stored the slow write was taken, flush would jump ahead of it otherwise
+        assert_eq!(write(&tx, "warm", "value").await.unwrap(), Ok(()));
+        let flushed = flush(&tx, false);
+        // sent after the flush, so it is kept
+        let after = write(&tx, "other", "value");
+        assert_eq!(flushed.await.unwrap().unwrap().removed, 2);
+        assert_eq!(written.await.unwrap(), Ok(()));
+        assert_eq!(after.await.unwrap(), Ok(()));
+        assert_eq!(read(&tx, "key").await.unwrap(), None);
+        assert_eq!(read(&tx, "other").await.unwrap(), Some(b"value".to_vec()));
+
+        let (cb, written) = oneshot::channel();
+        let options = WriteOptions {
+            tags: vec![String::from("t")],
+            ..WriteOptions::default()
+        };
+        tx.send(
+            ServiceMessage::WriteTagged(
+                "tagged".into(),
+                b"slow value".to_vec(),
+                Box::new(options),
+                cb,
+            )
+            .into(),
+        )
+        .unwrap();
+        let (cb, invalidated) = oneshot::channel();
+        tx.send(ServiceMessage::InvalidateTag("t".into(), cb).into())
+            .unwrap();
+        assert_eq!(invalidated.await.unwrap().unwrap().removed, 1);
+        assert_eq!(written.await.unwrap(), Ok(()));
+        assert_eq!(read(&tx, "tagged").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn tag_invalidation_only_removes_keys_within_allowed_prefixes() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            allowed_key_prefixes: vec![String::from("tenant1:")],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        // written straight to the service, e.g. restored from a snapshot taken before
+        // the prefixes were set
+        for key in ["tenant1:a", "tenant2:a"].iter() {
+            let (cb, written) = oneshot::channel();
+            let options = WriteOptions {
+                tags: vec![String::from("t")],
+                ..WriteOptions::default()
+            };
+            let message =
+                ServiceMessage::WriteTagged(key.to_string(), vec![1], Box::new(options), cb);
+            tx.send(message.into()).unwrap();
+            assert_eq!(written.await.unwrap(), Ok(()));
+        }
+
+        let (cb, invalidated) = oneshot::channel();
+        tx.send(ServiceMessage::InvalidateTag("t".into(), cb).into())
+            .unwrap();
+        let result = invalidated.await.unwrap().unwrap();
+        assert_eq!((result.removed, result.more), (1, false));
+        assert!(!key_exists(&tx, "tenant1:a").await);
+        assert!(key_exists(&tx, "tenant2:a").await);
+    }
+
+    #[tokio::test]
+    async fn reads_are_served_before_queued_writes() {
+        let (tx, _) = spawn_service(TEST_CONFIG_SINGLE_ITEM);
+        assert_eq!(write(&tx, "key", "old").await.unwrap(), Ok(()));
+
+        let writes: Vec<_> = (0..100)
+            .map(|i| write(&tx, "key", &format!("new{}", i)))
+            .collect();
+        assert_eq!(read(&tx, "key").await.unwrap(), Some(b"old".to_vec()));
+
+        for res in writes {
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        // writes kept their order, and a read sent after their replies sees the last one
+        assert_eq!(read(&tx, "key").await.unwrap(), Some(b"new99".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn writes_make_progress_during_read_flood() {
+        let (tx, _) = spawn_service(TEST_CONFIG_SINGLE_ITEM);
+        assert_eq!(write(&tx, "key", "old").await.unwrap(), Ok(()));
+
+        let write_res = write(&tx, "key", "new");
+        let reads: Vec<_> = (0..100).map(|_| read(&tx, "key")).collect();
+
+        let mut values = Vec::new();
+        for res in reads {
+            values.push(res.await.unwrap().unwrap());
+        }
+        assert_eq!(write_res.await.unwrap(), Ok(()));
+        assert!(values[..WRITE_EVERY].iter().all(|v| v == b"old"));
+        assert!(values[WRITE_EVERY..].iter().all(|v| v == b"new"));
+    }
+
+    #[tokio::test]
+    async fn lifetime_stats_continue_after_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "in-mem-cached-service-{}.stats",
+            std::process::id()
+        ));
+        let config = Config {
+            stats_path: Some(path.clone()),
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+
+        let (tx, _) = spawn_service(config.clone());
+        assert_eq!(write(&tx, "key", "value").await.unwrap(), Ok(()));
+        assert!(read(&tx, "key").await.unwrap().is_some());
+        assert!(read(&tx, "other").await.unwrap().is_none());
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::PersistStats(cb).into()).unwrap();
+        assert!(res.await.unwrap().is_ok());
+        let first_run = stats(&tx).await;
+        drop(tx);
+
+        let (tx, _) = spawn_service(config.clone());
+        assert!(read(&tx, "key").await.unwrap().is_none());
+        let second_run = stats(&tx).await;
+
+        let expected_process = Counters {
+            misses: 1,
+            ..Counters::default()
+        };
+        assert_eq!(second_run.process, expected_process);
+        assert_eq!(second_run.lifetime, first_run.lifetime + expected_process);
+        assert_eq!(second_run.lifetime.sets, 1);
+        assert_eq!(second_run.lifetime_started_at, first_run.started_at);
+
+        // services persist stats once their queue is gone, let them finish first
+        drop(tx);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // corrupt file is ignored and counting starts over
+        std::fs::write(&path, b"not json").unwrap();
+        let (tx, _) = spawn_service(config);
+        let third_run = stats(&tx).await;
+        assert_eq!(third_run.lifetime, Counters::default());
+        assert_eq!(third_run.lifetime_started_at, third_run.started_at);
+
+        drop(tx);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    async fn key_exists(tx: &ServiceQueue, key: &str) -> bool {
+        read(tx, key).await.unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn case_insensitive_keys_are_the_same_entry_everywhere() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            key_canonicalization: CASE_INSENSITIVE_KEYS,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let mut events = Box::pin(
+            subscribe(&tx, EventFilter::parse(Some("USER:".into()), None).unwrap())
+                .await
+                .unwrap()
+                .matching_events()
+                .filter_map(EventItem::into_event),
+        );
+
+        assert_eq!(write(&tx, "User:42", "value").await.unwrap(), Ok(()));
+        assert!(key_exists(&tx, "user:42").await);
+        assert!(key_exists(&tx, "USER:42").await);
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Count("USER:*".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), 1);
+        assert_eq!(events.next().await.unwrap().key, "user:42");
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Delete("uSeR:42".into(), None, cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), Ok(ConditionalDelete::Deleted));
+        assert!(!key_exists(&tx, "User:42").await);
+    }
+
+    #[tokio::test]
+    async fn keys_differing_in_case_are_distinct_by_default() {
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        assert_eq!(write(&tx, "Foo", "value").await.unwrap(), Ok(()));
+        assert!(key_exists(&tx, "Foo").await);
+        assert!(!key_exists(&tx, "foo").await);
+    }
+
+    #[tokio::test]
+    async fn idle_service_removes_entries_when_they_expire_in_eager_mode() {
+        let ttl = Duration::from_millis(50);
+        let (tx, _) = spawn_service(Config {
+            ttl,
+            capacity: None,
+            // nothing else would remove the entry
+            active_eviction: false,
+            expiry_mode: ExpiryMode::Eager,
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+        let expirations = subscribe(&tx, EventFilter::parse(None, Some("expired")).unwrap())
+            .await
+            .unwrap();
+
+        let written_at = Instant::now();
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Write("key".into(), "value".into(), cb).into())
+            .unwrap();
+        assert_eq!(res.await.unwrap(), Ok(()));
+
+        let mut expirations = Box::pin(
+            expirations
+                .matching_events()
+                .filter_map(EventItem::into_event),
+        );
+        let event = tokio::time::timeout(Duration::from_secs(5), expirations.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.key, "key");
+        assert!(written_at.elapsed() >= ttl);
+        assert_eq!(stats(&tx).await.keys_total, 0);
+    }
+
+    // formatted fields of every `access` event
+    struct AccessEvents(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl tracing::Subscriber for AccessEvents {
+        fn register_callsite(
+            &self,
+            _: &'static tracing::Metadata<'static>,
+        ) -> tracing::subscriber::Interest {
+            // other tests run without a subscriber
+            tracing::subscriber::Interest::sometimes()
+        }
+
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            metadata.target() == "access"
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Vec::new();
+            event.record(
+                &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                    fields.push(format!("{}={:?}", field.name(), value))
+                },
+            );
+            self.0.lock().unwrap().push(fields.join(" "));
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_of_access_logged_prefixes_are_logged() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let _subscriber = tracing::subscriber::set_default(AccessEvents(events.clone()));
+        let (tx, _) = spawn_service(Config {
+            capacity: None,
+            access_log_prefixes: vec![String::from("secret:")],
+            ..TEST_CONFIG_SINGLE_ITEM
+        });
+
+        assert_eq!(read(&tx, "secret:1").await.unwrap(), None);
+        assert_eq!(write(&tx, "secret:1", "value").await.unwrap(), Ok(()));
+        assert!(read(&tx, "secret:1").await.unwrap().is_some());
+        assert_eq!(write(&tx, "public:1", "value").await.unwrap(), Ok(()));
+        assert!(read(&tx, "public:1").await.unwrap().is_some());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3, "{:?}", *events);
+        for (event, (operation, outcome)) in events
+            .iter()
+            .zip([("read", "miss"), ("write", "ok"), ("read", "hit")].iter())
+        {
+            assert!(event.starts_with("ts_ms="), "{}", event);
+            assert!(
+                event.ends_with(&format!(
+                    "operation=\"{}\" key=\"secret:1\" outcome=\"{}\"",
+                    operation, outcome
+                )),
+                "{}",
+                event
+            );
         }
     }
 }
@@ -0,0 +1,193 @@
+use crate::auxiliary::AuxiliaryMemory;
+
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+// keys are cut to this many bytes in the log, on a char boundary
+pub const MAX_KEY_BYTES: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SlowOperation {
+    pub operation: &'static str,
+    // first key of the operation, truncated to `MAX_KEY_BYTES`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    // time the service spent on the operation
+    pub duration_us: u64,
+    // time the operation waited in the queue before the service took it
+    pub queue_wait_us: u64,
+    // keys or entries the operation covered, 1 for single key ones
+    pub batch_size: usize,
+    // unix time in milliseconds the operation finished at
+    pub ts_ms: u64,
+}
+
+impl SlowOperation {
+    pub fn new(
+        operation: &'static str,
+        key: Option<&str>,
+        batch_size: usize,
+        duration: Duration,
+        queue_wait: Duration,
+    ) -> SlowOperation {
+        SlowOperation {
+            operation,
+            key: key.map(truncate),
+            duration_us: duration.as_micros() as u64,
+            queue_wait_us: queue_wait.as_micros() as u64,
+            batch_size,
+            ts_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        }
+    }
+}
+
+fn truncate(key: &str) -> String {
+    KeyPrefix::new(key).as_str().to_string()
+}
+
+// key of an operation as much of it as the log keeps, copied to the stack before the
+// operation takes the key over, a `String` is only made of it once the operation was slow
+pub struct KeyPrefix {
+    bytes: [u8; MAX_KEY_BYTES],
+    len: usize,
+}
+
+impl KeyPrefix {
+    pub fn new(key: &str) -> KeyPrefix {
+        let mut len = key.len().min(MAX_KEY_BYTES);
+        while !key.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; MAX_KEY_BYTES];
+        bytes[..len].copy_from_slice(&key.as_bytes()[..len]);
+        KeyPrefix { bytes, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // cut on a char boundary of a str
+        std::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+// last `slowlog_len` operations that took longer than `slowlog_threshold`, oldest are dropped
+// once it is full
+pub struct SlowLog {
+    operations: VecDeque<SlowOperation>,
+    len: usize,
+    // operations dropped to make room for newer ones
+    dropped: u64,
+}
+
+impl SlowLog {
+    pub fn new(len: usize) -> SlowLog {
+        SlowLog {
+            operations: VecDeque::with_capacity(len),
+            len,
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, operation: SlowOperation) {
+        if self.len == 0 {
+            return;
+        }
+        if self.operations.len() == self.len {
+            self.operations.pop_front();
+            self.dropped += 1;
+        }
+        self.operations.push_back(operation);
+    }
+
+    // most recent first
+    pub fn operations(&self) -> Vec<SlowOperation> {
+        self.operations.iter().rev().cloned().collect()
+    }
+
+    // returns how many operations were logged
+    pub fn reset(&mut self) -> usize {
+        let logged = self.operations.len();
+        self.operations.clear();
+        logged
+    }
+}
+
+impl AuxiliaryMemory for SlowLog {
+    fn entries(&self) -> usize {
+        self.operations.len()
+    }
+
+    fn bytes(&self) -> usize {
+        self.operations
+            .iter()
+            .map(|o| size_of::<SlowOperation>() + o.key.as_ref().map_or(0, |k| k.len()))
+            .sum()
+    }
+
+    fn cap(&self) -> Option<usize> {
+        Some(self.len)
+    }
+
+    fn evicted(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod slowlog_tests {
+    use crate::auxiliary::AuxiliaryMemory;
+    use crate::slowlog::KeyPrefix;
+    use crate::slowlog::SlowLog;
+    use crate::slowlog::SlowOperation;
+    use crate::slowlog::MAX_KEY_BYTES;
+
+    use std::time::Duration;
+
+    fn slow(key: &str) -> SlowOperation {
+        SlowOperation::new(
+            "read",
+            Some(key),
+            1,
+            Duration::from_millis(500),
+            Duration::ZERO,
+        )
+    }
+
+    #[test]
+    fn oldest_operations_are_dropped_once_full() {
+        let mut log = SlowLog::new(2);
+        for key in ["a", "b", "c"].iter() {
+            log.record(slow(key));
+        }
+        let keys: Vec<Option<String>> = log.operations().into_iter().map(|o| o.key).collect();
+        assert_eq!(keys, vec![Some(String::from("c")), Some(String::from("b"))]);
+        assert_eq!((log.entries(), log.evicted()), (2, 1));
+
+        assert_eq!(log.reset(), 2);
+        assert!(log.operations().is_empty());
+
+        let mut disabled = SlowLog::new(0);
+        disabled.record(slow("a"));
+        assert!(disabled.operations().is_empty());
+    }
+
+    #[test]
+    fn long_keys_are_truncated_on_char_boundary() {
+        let key = "é".repeat(MAX_KEY_BYTES);
+        let logged = slow(&key).key.unwrap();
+        assert_eq!(logged.len(), MAX_KEY_BYTES);
+        assert!(key.starts_with(&logged));
+
+        let key = format!("a{}", "é".repeat(MAX_KEY_BYTES));
+        assert_eq!(slow(&key).key.unwrap().len(), MAX_KEY_BYTES - 1);
+        assert_eq!(KeyPrefix::new(&key).as_str().len(), MAX_KEY_BYTES - 1);
+        assert_eq!(KeyPrefix::new("short").as_str(), "short");
+    }
+}
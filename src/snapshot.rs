@@ -0,0 +1,624 @@
+use crate::config::SnapshotFormat;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use twox_hash::XxHash64;
+
+// Snapshot is a file with a header line, one JSON entry per line and a trailer line:
+//
+//   {"format":"in-mem-cached","version":1}
+//   {"key":"user:1","value":[104,105],"ttl_remaining_ms":5000,"checksum":123}
+//   {"entries":1,"bytes":75,"checksum":456}
+//
+// each entry carries a checksum of its key and value, trailer carries length and
+// checksum of the entries, so that truncated file is rejected as a whole, files written
+// before the header was introduced start right with the entries and are read as version 1
+//
+// Binary snapshot keeps the same guarantees with length-prefixed records, all integers
+// are little endian:
+//
+//   magic `IMCSNAP\0`, version u8
+//   per entry: record length u32, then key length u32, key, value length u32, value,
+//              ttl_remaining_ms u64, flags u8, checksum u64
+//   end marker u32::MAX, then entries u64, bytes u64, checksum u64 of all records
+//
+// fields added later go after the known ones, readers skip what is left of a record
+// past the fields they know, so such additions keep the version, it is only bumped by
+// changes older readers can not cope with, those reject versions they do not know
+
+const JSON_FORMAT: &str = "in-mem-cached";
+const JSON_VERSION: u8 = 1;
+const BINARY_MAGIC: &[u8; 8] = b"IMCSNAP\0";
+const BINARY_VERSION: u8 = 1;
+const BINARY_END: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    NotConfigured,
+    Io(io::Error),
+    Truncated,
+    // written by a version of the format this build can not read, e.g. after a downgrade
+    UnsupportedVersion(&'static str, u8),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotConfigured => write!(f, "snapshot path is not configured"),
+            SnapshotError::Io(e) => write!(f, "snapshot io error: {}", e),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::UnsupportedVersion(format, version) => write!(
+                f,
+                "snapshot is {} format version {}, only version {} is supported",
+                format,
+                version,
+                if *format == "json" {
+                    JSON_VERSION
+                } else {
+                    BINARY_VERSION
+                }
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub key: String,
+    // as stored in the cache, after write transform
+    pub value: Vec<u8>,
+    pub ttl_remaining_ms: u64,
+    pub checksum: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    format: String,
+    version: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotTrailer {
+    entries: usize,
+    bytes: usize,
+    checksum: u64,
+}
+
+#[derive(Debug)]
+pub struct Restored {
+    pub entries: Vec<SnapshotEntry>,
+    // entries that failed checksum verification or could not be parsed
+    pub skipped_corrupt: usize,
+}
+
+// xxhash64 of key and value, key length is mixed in so that moving bytes
+// between key and value changes the checksum
+pub fn checksum(key: &str, value: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write_u64(key.len() as u64);
+    hasher.write(key.as_bytes());
+    hasher.write(value);
+    hasher.finish()
+}
+
+// written to a temporary file first and moved in place, so an interrupted
+// write never replaces previous snapshot, entries are streamed to the file
+// without collecting them first
+pub fn write<'e>(
+    path: &Path,
+    format: SnapshotFormat,
+    entries: impl Iterator<Item = (&'e str, &'e [u8], Duration)>,
+) -> Result<usize, SnapshotError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    let written = dump(&mut out, format, entries)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    fs::rename(tmp_path, path)?;
+    Ok(written)
+}
+
+// entries the way they are written to a snapshot file, e.g. to export a namespace
+pub fn dump<'e>(
+    out: &mut impl Write,
+    format: SnapshotFormat,
+    entries: impl Iterator<Item = (&'e str, &'e [u8], Duration)>,
+) -> io::Result<usize> {
+    match format {
+        SnapshotFormat::Json => write_json(out, entries),
+        SnapshotFormat::Binary => write_binary(out, entries),
+    }
+}
+
+fn write_json<'e>(
+    out: &mut impl Write,
+    entries: impl Iterator<Item = (&'e str, &'e [u8], Duration)>,
+) -> io::Result<usize> {
+    let mut header = serde_json::to_vec(&SnapshotHeader {
+        format: String::from(JSON_FORMAT),
+        version: JSON_VERSION,
+    })
+    .map_err(io::Error::from)?;
+    header.push(b'\n');
+    out.write_all(&header)?;
+
+    let mut file_hasher = XxHash64::with_seed(0);
+    let mut written = 0;
+    let mut bytes = 0;
+
+    for (key, value, ttl_remaining) in entries {
+        let mut line = serde_json::to_vec(&SnapshotEntry {
+            key: key.to_string(),
+            value: value.to_vec(),
+            ttl_remaining_ms: ttl_remaining.as_millis() as u64,
+            checksum: checksum(key, value),
+        })
+        .map_err(io::Error::from)?;
+        line.push(b'\n');
+
+        out.write_all(&line)?;
+        file_hasher.write(&line);
+        written += 1;
+        bytes += line.len();
+    }
+
+    let mut trailer = serde_json::to_vec(&SnapshotTrailer {
+        entries: written,
+        bytes,
+        checksum: file_hasher.finish(),
+    })
+    .map_err(io::Error::from)?;
+    trailer.push(b'\n');
+    out.write_all(&trailer)?;
+    Ok(written)
+}
+
+fn write_binary<'e>(
+    out: &mut impl Write,
+    entries: impl Iterator<Item = (&'e str, &'e [u8], Duration)>,
+) -> io::Result<usize> {
+    out.write_all(BINARY_MAGIC)?;
+    out.write_all(&[BINARY_VERSION])?;
+
+    let mut file_hasher = XxHash64::with_seed(0);
+    let mut written = 0;
+    let mut bytes = 0;
+    let mut record = Vec::new();
+    for (key, value, ttl_remaining) in entries {
+        let too_large = || io::Error::other(format!("entry {} is too large for a snapshot", key));
+        let key_len: u32 = key.len().try_into().map_err(|_| too_large())?;
+        let value_len: u32 = value.len().try_into().map_err(|_| too_large())?;
+
+        record.clear();
+        record.extend_from_slice(&key_len.to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(&value_len.to_le_bytes());
+        record.extend_from_slice(value);
+        record.extend_from_slice(&(ttl_remaining.as_millis() as u64).to_le_bytes());
+        // no flags defined yet
+        record.push(0);
+        record.extend_from_slice(&checksum(key, value).to_le_bytes());
+        let record_len: u32 = record.len().try_into().map_err(|_| too_large())?;
+        if record_len == BINARY_END {
+            return Err(too_large());
+        }
+
+        let len = record_len.to_le_bytes();
+        out.write_all(&len)?;
+        out.write_all(&record)?;
+        file_hasher.write(&len);
+        file_hasher.write(&record);
+        written += 1;
+        bytes += len.len() + record.len();
+    }
+
+    out.write_all(&BINARY_END.to_le_bytes())?;
+    out.write_all(&(written as u64).to_le_bytes())?;
+    out.write_all(&(bytes as u64).to_le_bytes())?;
+    out.write_all(&file_hasher.finish().to_le_bytes())?;
+    Ok(written)
+}
+
+// format is told by the first bytes, so snapshots of either format can be restored
+// whatever `snapshot_format` is set to
+pub fn read(path: &Path) -> Result<Restored, SnapshotError> {
+    let content = fs::read(path)?;
+
+    match content.strip_prefix(BINARY_MAGIC.as_ref()) {
+        Some(rest) => read_binary(path, rest),
+        None => read_json(path, &content),
+    }
+}
+
+// takes `n` bytes off the front of `bytes`
+fn take<'b>(bytes: &mut &'b [u8], n: usize) -> Option<&'b [u8]> {
+    if bytes.len() < n {
+        return None;
+    }
+    let (taken, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Some(taken)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Option<u32> {
+    take(bytes, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    take(bytes, 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+// fields of a version 1 record, whatever follows them is left for later versions
+fn parse_record(mut record: &[u8]) -> Option<SnapshotEntry> {
+    let key_len = take_u32(&mut record)? as usize;
+    let key = String::from_utf8(take(&mut record, key_len)?.to_vec()).ok()?;
+    let value_len = take_u32(&mut record)? as usize;
+    let value = take(&mut record, value_len)?.to_vec();
+    let ttl_remaining_ms = take_u64(&mut record)?;
+    let _flags = take(&mut record, 1)?;
+    let checksum = take_u64(&mut record)?;
+
+    Some(SnapshotEntry {
+        key,
+        value,
+        ttl_remaining_ms,
+        checksum,
+    })
+}
+
+fn read_binary(path: &Path, mut content: &[u8]) -> Result<Restored, SnapshotError> {
+    let version = take(&mut content, 1).ok_or(SnapshotError::Truncated)?[0];
+    tracing::debug!("snapshot {} binary version {}", path.display(), version);
+    if version != BINARY_VERSION {
+        return Err(SnapshotError::UnsupportedVersion("binary", version));
+    }
+
+    let mut restored = Restored {
+        entries: Vec::new(),
+        skipped_corrupt: 0,
+    };
+    let mut file_hasher = XxHash64::with_seed(0);
+    let mut bytes = 0;
+    loop {
+        let len_bytes = take(&mut content, 4).ok_or(SnapshotError::Truncated)?;
+        let record_len = u32::from_le_bytes(len_bytes.try_into().unwrap());
+        if record_len == BINARY_END {
+            break;
+        }
+        let record = take(&mut content, record_len as usize).ok_or(SnapshotError::Truncated)?;
+        file_hasher.write(len_bytes);
+        file_hasher.write(record);
+        bytes += len_bytes.len() + record.len();
+
+        match parse_record(record) {
+            Some(entry) if checksum(&entry.key, &entry.value) == entry.checksum => {
+                restored.entries.push(entry)
+            }
+            _ => restored.skipped_corrupt += 1,
+        }
+    }
+
+    let entries = take_u64(&mut content).ok_or(SnapshotError::Truncated)?;
+    let expected_bytes = take_u64(&mut content).ok_or(SnapshotError::Truncated)?;
+    let expected_checksum = take_u64(&mut content).ok_or(SnapshotError::Truncated)?;
+    if expected_bytes != bytes as u64
+        || entries != (restored.entries.len() + restored.skipped_corrupt) as u64
+    {
+        return Err(SnapshotError::Truncated);
+    }
+    if file_hasher.finish() != expected_checksum {
+        tracing::warn!(
+            "snapshot {} checksum mismatch, corrupt entries will be skipped",
+            path.display()
+        );
+    }
+
+    Ok(restored)
+}
+
+fn read_json(path: &Path, content: &[u8]) -> Result<Restored, SnapshotError> {
+    let first_line = content.split(|b| *b == b'\n').next().unwrap_or_default();
+    let content = match serde_json::from_slice::<SnapshotHeader>(first_line) {
+        Ok(header) if header.format == JSON_FORMAT && header.version == JSON_VERSION => content
+            .get(first_line.len() + 1..)
+            .ok_or(SnapshotError::Truncated)?,
+        Ok(header) if header.format == JSON_FORMAT => {
+            return Err(SnapshotError::UnsupportedVersion("json", header.version))
+        }
+        // written without a header, entries start right away
+        _ => content,
+    };
+    let content = content
+        .strip_suffix(b"\n")
+        .ok_or(SnapshotError::Truncated)?;
+    let (body, trailer) = match content.iter().rposition(|b| *b == b'\n') {
+        Some(i) => content.split_at(i + 1),
+        None => (&content[..0], content),
+    };
+    let trailer: SnapshotTrailer =
+        serde_json::from_slice(trailer).map_err(|_| SnapshotError::Truncated)?;
+    if trailer.bytes != body.len() {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let mut file_hasher = XxHash64::with_seed(0);
+    file_hasher.write(body);
+    if file_hasher.finish() != trailer.checksum {
+        tracing::warn!(
+            "snapshot {} checksum mismatch, corrupt entries will be skipped",
+            path.display()
+        );
+    }
+
+    let mut restored = Restored {
+        entries: Vec::with_capacity(trailer.entries),
+        skipped_corrupt: 0,
+    };
+    for line in body.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        match serde_json::from_slice::<SnapshotEntry>(line) {
+            Ok(entry) if checksum(&entry.key, &entry.value) == entry.checksum => {
+                restored.entries.push(entry)
+            }
+            _ => restored.skipped_corrupt += 1,
+        }
+    }
+
+    Ok(restored)
+}
+
+#[cfg(test)]
+pub mod snapshot_tests {
+    use crate::config::SnapshotFormat;
+    use crate::snapshot;
+    use crate::snapshot::SnapshotError;
+
+    use std::convert::TryInto;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "in-mem-cached-{}-{}.snapshot",
+            name,
+            std::process::id()
+        ))
+    }
+
+    // changes first digit of the value of given key, so JSON stays well formed
+    pub fn corrupt_value(mut content: Vec<u8>, key: &str) -> Vec<u8> {
+        let entry = format!(r#"{{"key":"{}","value":["#, key);
+        let at = content
+            .windows(entry.len())
+            .position(|w| w == entry.as_bytes())
+            .unwrap()
+            + entry.len();
+        content[at] = if content[at] == b'1' { b'2' } else { b'1' };
+        content
+    }
+
+    fn write_entries(name: &str, format: SnapshotFormat) -> PathBuf {
+        let path = snapshot_path(name);
+        let entries = [("a", "first"), ("b", "second"), ("c", "third")];
+        let written = snapshot::write(
+            &path,
+            format,
+            entries
+                .iter()
+                .map(|(k, v)| (*k, v.as_bytes(), Duration::from_secs(60))),
+        )
+        .unwrap();
+        assert_eq!(written, 3);
+        path
+    }
+
+    fn assert_restored_entries(restored: &snapshot::Restored) {
+        assert_eq!(restored.skipped_corrupt, 0);
+        assert_eq!(
+            restored
+                .entries
+                .iter()
+                .map(|e| (e.key.as_str(), e.value.as_slice(), e.ttl_remaining_ms))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a", b"first".as_ref(), 60_000),
+                ("b", b"second".as_ref(), 60_000),
+                ("c", b"third".as_ref(), 60_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let path = write_entries("round-trip", format);
+
+            let restored = snapshot::read(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            assert_restored_entries(&restored);
+        }
+    }
+
+    #[test]
+    fn json_snapshot_migrates_to_binary() {
+        let path = write_entries("migrate", SnapshotFormat::Json);
+        let json = snapshot::read(&path).unwrap();
+
+        snapshot::write(
+            &path,
+            SnapshotFormat::Binary,
+            json.entries.iter().map(|e| {
+                (
+                    e.key.as_str(),
+                    e.value.as_slice(),
+                    Duration::from_millis(e.ttl_remaining_ms),
+                )
+            }),
+        )
+        .unwrap();
+        let content = fs::read(&path).unwrap();
+        let binary = snapshot::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(content.starts_with(b"IMCSNAP\0"));
+        assert_restored_entries(&binary);
+    }
+
+    #[test]
+    fn unknown_fields_of_binary_records_are_skipped() {
+        let path = write_entries("future", SnapshotFormat::Binary);
+        let content = fs::read(&path).unwrap();
+
+        // append a field a later version could add to every record
+        let mut future = content[..9].to_vec();
+        let mut rest = &content[9..];
+        let mut records = 0u64;
+        let mut bytes = 0u64;
+        loop {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            if len == u32::MAX {
+                break;
+            }
+            let record = &rest[4..4 + len as usize];
+            future.extend_from_slice(&(len + 2).to_le_bytes());
+            future.extend_from_slice(record);
+            future.extend_from_slice(&[7, 7]);
+            rest = &rest[4 + len as usize..];
+            records += 1;
+            bytes += 4 + len as u64 + 2;
+        }
+        future.extend_from_slice(&u32::MAX.to_le_bytes());
+        future.extend_from_slice(&records.to_le_bytes());
+        future.extend_from_slice(&bytes.to_le_bytes());
+        // checksum no longer matches, which only warns
+        future.extend_from_slice(&0u64.to_le_bytes());
+        fs::write(&path, future).unwrap();
+
+        let restored = snapshot::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_restored_entries(&restored);
+    }
+
+    #[test]
+    fn corrupt_entries_are_skipped() {
+        let path = write_entries("corrupt", SnapshotFormat::Json);
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, corrupt_value(content, "b")).unwrap();
+
+        let restored = snapshot::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.skipped_corrupt, 1);
+        assert_eq!(
+            restored
+                .entries
+                .iter()
+                .map(|e| e.key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "c"]
+        );
+    }
+
+    #[test]
+    fn corrupt_binary_entries_are_skipped() {
+        let path = write_entries("corrupt-binary", SnapshotFormat::Binary);
+        let mut content = fs::read(&path).unwrap();
+        let at = content.windows(6).position(|w| w == b"second").unwrap();
+        content[at] = b'S';
+        fs::write(&path, content).unwrap();
+
+        let restored = snapshot::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.skipped_corrupt, 1);
+        assert_eq!(
+            restored
+                .entries
+                .iter()
+                .map(|e| e.key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "c"]
+        );
+    }
+
+    #[test]
+    fn truncated_snapshot_is_rejected() {
+        for format in [SnapshotFormat::Json, SnapshotFormat::Binary] {
+            let path = write_entries("truncated", format);
+            let content = fs::read(&path).unwrap();
+            for len in [0, 10, content.len() / 2, content.len() - 1] {
+                fs::write(&path, &content[..len]).unwrap();
+
+                assert!(matches!(
+                    snapshot::read(&path),
+                    Err(SnapshotError::Truncated)
+                ));
+            }
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn json_snapshot_without_header_is_read() {
+        let path = write_entries("legacy", SnapshotFormat::Json);
+        let content = fs::read(&path).unwrap();
+        let header_end = content.iter().position(|b| *b == b'\n').unwrap();
+        assert!(content.starts_with(br#"{"format":"in-mem-cached","version":1}"#));
+        fs::write(&path, &content[header_end + 1..]).unwrap();
+
+        let restored = snapshot::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_restored_entries(&restored);
+    }
+
+    #[test]
+    fn snapshot_of_unknown_version_is_rejected() {
+        let path = write_entries("version-json", SnapshotFormat::Json);
+        let content = String::from_utf8(fs::read(&path).unwrap()).unwrap();
+        fs::write(
+            &path,
+            content.replacen(r#""version":1"#, r#""version":2"#, 1),
+        )
+        .unwrap();
+
+        let err = snapshot::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(err, SnapshotError::UnsupportedVersion("json", 2)));
+        assert_eq!(
+            err.to_string(),
+            "snapshot is json format version 2, only version 1 is supported"
+        );
+
+        let path = write_entries("version-binary", SnapshotFormat::Binary);
+        let mut content = fs::read(&path).unwrap();
+        content[8] = 2;
+        fs::write(&path, content).unwrap();
+
+        let err = snapshot::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            err,
+            SnapshotError::UnsupportedVersion("binary", 2)
+        ));
+    }
+}
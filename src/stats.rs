@@ -0,0 +1,93 @@
+use std::fs;
+use std::io;
+use std::ops::Add;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+// cumulative counters that survive restarts when `stats_path` is configured
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Counters {
+    pub hits: u64,
+    pub misses: u64,
+    pub sets: u64,
+    // expirations and capacity evictions together
+    pub evictions: u64,
+}
+
+impl Add for Counters {
+    type Output = Counters;
+
+    fn add(self, other: Counters) -> Counters {
+        Counters {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            sets: self.sets + other.sets,
+            evictions: self.evictions + other.evictions,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PersistedStats {
+    // unix time in seconds counting started at, on the very first start
+    pub started_at: u64,
+    pub counters: Counters,
+}
+
+// missing file is a first start, unreadable or corrupt one is ignored
+pub fn load(path: &Path) -> Option<PersistedStats> {
+    match fs::read(path) {
+        Ok(content) => match serde_json::from_slice(&content) {
+            Ok(stats) => Some(stats),
+            Err(e) => {
+                tracing::warn!("ignoring corrupt stats file {}: {}", path.display(), e);
+                None
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            tracing::warn!("ignoring unreadable stats file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// same as snapshot, written next to the file first so that it is never left half written
+pub fn save(path: &Path, stats: &PersistedStats) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(stats)?)?;
+    fs::rename(tmp_path, path)
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use crate::stats::load;
+    use crate::stats::save;
+    use crate::stats::Counters;
+    use crate::stats::PersistedStats;
+
+    #[test]
+    fn stats_are_saved_and_loaded_back() {
+        let path = std::env::temp_dir().join(format!("in-mem-cached-{}.stats", std::process::id()));
+        let stats = PersistedStats {
+            started_at: 1_600_000_000,
+            counters: Counters {
+                hits: 3,
+                misses: 2,
+                sets: 5,
+                evictions: 1,
+            },
+        };
+
+        save(&path, &stats).unwrap();
+        assert_eq!(load(&path), Some(stats));
+
+        std::fs::write(&path, b"{\"started_at\": 16").unwrap();
+        assert_eq!(load(&path), None);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(load(&path), None);
+    }
+}
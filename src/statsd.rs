@@ -0,0 +1,201 @@
+use crate::config::Config;
+use crate::service::CacheStats;
+use crate::service::ServiceMessage;
+use crate::service::ServiceQueue;
+use crate::stats::Counters;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+// same names as in `/metrics`, dot separated
+const PREFIX: &str = "in_mem_cached";
+
+// pushes cache counters to a StatsD or DogStatsD agent every `every`, see `statsd_addr`
+pub struct StatsdExporter {
+    // `host:port`, resolved on every push so that an agent moving around is followed
+    addr: String,
+    every: Duration,
+    // DogStatsD tags, e.g. `env:prod`, sent with every metric
+    tags: Vec<String>,
+    // counters as of the previous push, StatsD counters are sent as increments
+    sent: Counters,
+}
+
+impl StatsdExporter {
+    pub fn new(config: &Config) -> Option<StatsdExporter> {
+        config.statsd_addr.as_ref().map(|addr| StatsdExporter {
+            addr: addr.clone(),
+            every: config.statsd_every,
+            tags: config.statsd_tags.clone(),
+            sent: Counters::default(),
+        })
+    }
+
+    // runs until the service is gone, a push that fails is retried with the next one
+    pub async fn run(mut self, queue: ServiceQueue) {
+        // of the same family as the agent, as far as it resolves right now
+        let local = match tokio::net::lookup_host(&self.addr)
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+        {
+            Some(SocketAddr::V6(_)) => "[::]:0",
+            _ => "0.0.0.0:0",
+        };
+        let socket = match UdpSocket::bind(local).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::error!("[statsd] failed to bind a socket, not exporting: {}", e);
+                return;
+            }
+        };
+        let mut interval = tokio::time::interval(self.every);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // first tick completes right away
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let (cb, stats) = oneshot::channel();
+            if queue.send(ServiceMessage::Stats(cb).into()).is_err() {
+                return;
+            }
+            let stats = match stats.await {
+                Ok(stats) => stats,
+                Err(_) => return,
+            };
+            let lines = self.lines(&stats);
+            if let Err(e) = socket.send_to(lines.as_bytes(), &self.addr).await {
+                tracing::warn!("[statsd] failed to push to {}: {}", self.addr, e);
+                continue;
+            }
+            self.sent = stats.process;
+        }
+    }
+
+    // a single datagram, one metric per line
+    fn lines(&self, stats: &CacheStats) -> String {
+        let counters = &stats.process;
+        let metrics = [
+            ("hits", counters.hits.saturating_sub(self.sent.hits), "c"),
+            (
+                "misses",
+                counters.misses.saturating_sub(self.sent.misses),
+                "c",
+            ),
+            (
+                "evictions",
+                counters.evictions.saturating_sub(self.sent.evictions),
+                "c",
+            ),
+            ("keys_total", stats.keys_total as u64, "g"),
+        ];
+        let tags = if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", self.tags.join(","))
+        };
+        metrics
+            .iter()
+            .map(|(name, value, kind)| format!("{}.{}:{}|{}{}", PREFIX, name, value, kind, tags))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod statsd_tests {
+    use crate::config::Config;
+    use crate::config::TEST_CONFIG_SINGLE_ITEM;
+    use crate::service::service_queue;
+    use crate::service::ServiceFlags;
+    use crate::service::ServiceMessage;
+    use crate::service::TtlCacheService;
+    use crate::statsd::StatsdExporter;
+    use crate::time::REALTIME;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use tokio::net::UdpSocket;
+    use tokio::sync::oneshot;
+
+    async fn receive(agent: &UdpSocket) -> Vec<String> {
+        let mut buf = [0; 1024];
+        let len = tokio::time::timeout(Duration::from_secs(1), agent.recv(&mut buf))
+            .await
+            .expect("nothing was pushed")
+            .unwrap();
+        String::from_utf8(buf[..len].to_vec())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn counters_are_pushed_as_increments_on_every_interval() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            capacity: None,
+            statsd_addr: Some(agent.local_addr().unwrap().to_string()),
+            statsd_every: Duration::from_millis(100),
+            statsd_tags: vec![String::from("env:test")],
+            ..TEST_CONFIG_SINGLE_ITEM
+        };
+        let exporter = StatsdExporter::new(&config).unwrap();
+        let (tx, rx) = service_queue();
+        let flags = Arc::new(ServiceFlags::new(&config));
+        tokio::spawn(async move {
+            TtlCacheService::new(config, rx, flags, &REALTIME)
+                .run()
+                .await
+        });
+
+        for key in ["a", "b"].iter() {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Write(key.to_string(), b"value".to_vec(), cb).into())
+                .unwrap();
+            assert_eq!(res.await.unwrap(), Ok(()));
+        }
+        for key in ["a", "a", "missing"].iter() {
+            let (cb, res) = oneshot::channel();
+            tx.send(ServiceMessage::Read(key.to_string(), cb).into())
+                .unwrap();
+            res.await.unwrap();
+        }
+        let started = Instant::now();
+        tokio::spawn(exporter.run(tx.clone()));
+
+        assert_eq!(
+            receive(&agent).await,
+            vec![
+                "in_mem_cached.hits:2|c|#env:test",
+                "in_mem_cached.misses:1|c|#env:test",
+                "in_mem_cached.evictions:0|c|#env:test",
+                "in_mem_cached.keys_total:2|g|#env:test",
+            ]
+        );
+        // not before the first interval is over
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let (cb, res) = oneshot::channel();
+        tx.send(ServiceMessage::Read(String::from("b"), cb).into())
+            .unwrap();
+        res.await.unwrap();
+
+        // only what happened since the previous push
+        assert_eq!(
+            receive(&agent).await,
+            vec![
+                "in_mem_cached.hits:1|c|#env:test",
+                "in_mem_cached.misses:0|c|#env:test",
+                "in_mem_cached.evictions:0|c|#env:test",
+                "in_mem_cached.keys_total:2|g|#env:test",
+            ]
+        );
+    }
+}
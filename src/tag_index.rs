@@ -0,0 +1,127 @@
+use crate::auxiliary::AuxiliaryMemory;
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::mem::size_of;
+
+// tag -> keys carrying it, mirrors `CacheEntry::tags`, tags without keys are dropped right away
+pub struct TagIndex {
+    tags: HashMap<String, HashSet<String>>,
+    // (keys, tag) for every tag, smallest first, see `eviction_candidate`
+    by_size: BTreeSet<(usize, String)>,
+    // key references held
+    refs: usize,
+    // of tags, held in both `tags` and `by_size`, and keys in them
+    bytes: usize,
+    // key references held at most, see `max_tag_index_refs`
+    cap: Option<usize>,
+    // key references dropped along with the entries holding them to stay within `cap`
+    evicted: u64,
+}
+
+impl TagIndex {
+    pub fn new(cap: Option<usize>) -> TagIndex {
+        TagIndex {
+            tags: HashMap::new(),
+            by_size: BTreeSet::new(),
+            refs: 0,
+            bytes: 0,
+            cap,
+            evicted: 0,
+        }
+    }
+
+    pub fn insert(&mut self, tag: &str, key: &str) {
+        if !self.tags.contains_key(tag) {
+            self.bytes += 2 * tag.len();
+        }
+        let keys = self.tags.entry(tag.to_string()).or_default();
+        if keys.insert(key.to_string()) {
+            let len = keys.len();
+            self.by_size.remove(&(len - 1, tag.to_string()));
+            self.by_size.insert((len, tag.to_string()));
+            self.refs += 1;
+            self.bytes += key.len();
+        }
+    }
+
+    pub fn remove(&mut self, tag: &str, key: &str) {
+        if let Some(keys) = self.tags.get_mut(tag) {
+            if keys.remove(key) {
+                let len = keys.len();
+                self.by_size.remove(&(len + 1, tag.to_string()));
+                if len > 0 {
+                    self.by_size.insert((len, tag.to_string()));
+                }
+                self.refs -= 1;
+                self.bytes -= key.len();
+            }
+            if keys.is_empty() {
+                self.tags.remove(tag);
+                self.bytes -= 2 * tag.len();
+            }
+        }
+    }
+
+    pub fn keys(&self, tag: &str) -> Option<&HashSet<String>> {
+        self.tags.get(tag)
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        self.tags.contains_key(tag)
+    }
+
+    // distinct tags
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn refs(&self) -> usize {
+        self.refs
+    }
+
+    pub fn is_over_cap(&self) -> bool {
+        self.cap.map(|cap| self.refs > cap).unwrap_or(false)
+    }
+
+    // tag to go first once over the cap, the one with the fewest keys so that the
+    // fewest entries go with it, ties are broken by name, tags in `keep` are never picked,
+    // only keys of the picked tag are copied
+    pub fn eviction_candidate(&self, keep: &[String]) -> Option<(String, Vec<String>)> {
+        let (_, tag) = self.by_size.iter().find(|(_, tag)| !keep.contains(tag))?;
+        let keys = self.tags.get(tag)?.iter().cloned().collect();
+        Some((tag.clone(), keys))
+    }
+
+    pub fn count_evicted(&mut self, refs: usize) {
+        self.evicted += refs as u64;
+    }
+}
+
+impl AuxiliaryMemory for TagIndex {
+    fn entries(&self) -> usize {
+        self.refs
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes
+            + self.refs * size_of::<String>()
+            + self.tags.len()
+                * (size_of::<String>()
+                    + size_of::<HashSet<String>>()
+                    + size_of::<(usize, String)>())
+    }
+
+    fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+
+    fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
@@ -0,0 +1,100 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+// write operation of a transaction, e.g. `{"op": "set", "key": "a", "value": "1", "nx": true}`
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum TxnOp {
+    Set {
+        key: String,
+        value: String,
+        ttl_secs: Option<u64>,
+        // only set if key does not exist
+        #[serde(default)]
+        nx: bool,
+        // only set if key already exists
+        #[serde(default)]
+        xx: bool,
+    },
+    Del {
+        key: String,
+    },
+    Expire {
+        key: String,
+        ttl_secs: u64,
+    },
+    // missing key counts as 0, remaining ttl of an existing one is kept
+    Incr {
+        key: String,
+        #[serde(default = "default_incr")]
+        by: i64,
+    },
+}
+
+fn default_incr() -> i64 {
+    1
+}
+
+impl TxnOp {
+    pub fn key(&self) -> &str {
+        match self {
+            TxnOp::Set { key, .. } => key,
+            TxnOp::Del { key } => key,
+            TxnOp::Expire { key, .. } => key,
+            TxnOp::Incr { key, .. } => key,
+        }
+    }
+
+    pub fn map_key(self, f: impl FnOnce(String) -> String) -> TxnOp {
+        match self {
+            TxnOp::Set {
+                key,
+                value,
+                ttl_secs,
+                nx,
+                xx,
+            } => TxnOp::Set {
+                key: f(key),
+                value,
+                ttl_secs,
+                nx,
+                xx,
+            },
+            TxnOp::Del { key } => TxnOp::Del { key: f(key) },
+            TxnOp::Expire { key, ttl_secs } => TxnOp::Expire {
+                key: f(key),
+                ttl_secs,
+            },
+            TxnOp::Incr { key, by } => TxnOp::Incr { key: f(key), by },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxnOpStatus {
+    Ok,
+    // del or expire of a key that does not exist, does not abort the transaction
+    NotFound,
+    ConditionFailed,
+    OutOfCapacity,
+    // incr of a value that is not a 64 bit integer, or overflowing one
+    NotAnInteger,
+    // not validated because an earlier operation failed
+    Skipped,
+}
+
+impl TxnOpStatus {
+    pub fn aborts(&self) -> bool {
+        matches!(
+            self,
+            TxnOpStatus::ConditionFailed | TxnOpStatus::OutOfCapacity | TxnOpStatus::NotAnInteger
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TxnResult {
+    pub committed: bool,
+    pub results: Vec<TxnOpStatus>,
+}
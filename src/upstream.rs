@@ -0,0 +1,498 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use hyper::body::Bytes;
+use hyper::client::connect::Connected;
+use hyper::client::connect::Connection;
+use hyper::client::HttpConnector;
+use hyper::client::ResponseFuture;
+use hyper::header::HeaderMap;
+use hyper::header::HeaderName;
+use hyper::header::HeaderValue;
+use hyper::service::Service;
+use hyper::Body;
+use hyper::Client;
+use hyper::Method;
+use hyper::Request;
+use hyper::StatusCode;
+use hyper::Uri;
+use serde::Serialize;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+// headers that only make sense for a single connection, never passed through
+const HOP_BY_HOP: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+}
+
+// another instance requests are passed to, the primary of a replica or a cluster peer
+pub struct Upstream {
+    client: UpstreamClient,
+    base: String,
+    // sent along with every request, e.g. credentials the primary expects
+    headers: HeaderMap,
+    // for the whole exchange, body of the response included
+    timeout: Option<Duration>,
+    // guards `fetch` only, forwarded writes report failures to the client themselves
+    breaker: Option<CircuitBreaker>,
+}
+
+enum UpstreamClient {
+    Direct(Client<HttpConnector>),
+    Proxied(Client<ProxyConnector>),
+}
+
+impl UpstreamClient {
+    fn request(&self, request: Request<Body>) -> ResponseFuture {
+        match self {
+            UpstreamClient::Direct(client) => client.request(request),
+            UpstreamClient::Proxied(client) => client.request(request),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    // circuit breaker is open, the primary was not asked
+    Bypassed,
+    Failed(String),
+}
+
+// response of the upstream, relayed to the client as is
+pub struct Forwarded {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Upstream {
+    // `base` is scheme and authority, e.g. `http://10.0.0.1:8080`
+    pub fn new(base: &str) -> Result<Upstream, String> {
+        Upstream::with_client(base, Client::new())
+    }
+
+    // upstreams sharing a client share its connection pool
+    pub fn with_client(base: &str, client: Client<HttpConnector>) -> Result<Upstream, String> {
+        let base = base.trim_end_matches('/');
+        check_url(base)?;
+
+        Ok(Upstream {
+            client: UpstreamClient::Direct(client),
+            base: base.to_string(),
+            headers: HeaderMap::new(),
+            timeout: None,
+            breaker: None,
+        })
+    }
+
+    // primary of a replica, the client is set up with the `origin_*` settings of the config
+    pub fn origin(base: &str, config: &Config) -> Result<Upstream, String> {
+        let base = base.trim_end_matches('/');
+        check_url(base)?;
+        let mut http = HttpConnector::new();
+        http.set_connect_timeout(config.origin_connect_timeout);
+        let client = match config.origin_proxy.as_deref() {
+            Some(proxy) => {
+                let proxy = check_url(proxy.trim_end_matches('/'))?;
+                UpstreamClient::Proxied(Client::builder().build(ProxyConnector { http, proxy }))
+            }
+            None => UpstreamClient::Direct(Client::builder().build(http)),
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.origin_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("invalid origin header name {}: {}", name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| format!("invalid value of origin header {}: {}", name, e))?;
+            headers.append(name, value);
+        }
+
+        Ok(Upstream {
+            client,
+            base: base.to_string(),
+            headers,
+            timeout: config.origin_timeout,
+            breaker: config
+                .origin_failure_threshold
+                .map(|threshold| CircuitBreaker::new(threshold, config.origin_cooldown)),
+        })
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn breaker(&self) -> Option<BreakerSnapshot> {
+        self.breaker.as_ref().map(CircuitBreaker::snapshot)
+    }
+
+    fn uri(&self, path_and_query: &str) -> Result<Uri, hyper::http::Error> {
+        Ok(format!("{}{}", self.base, path_and_query).parse::<Uri>()?)
+    }
+
+    pub async fn forward(
+        &self,
+        method: Method,
+        path_and_query: &str,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Forwarded, String> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(self.uri(path_and_query).map_err(|e| e.to_string())?);
+        for (name, value) in headers.iter() {
+            if !is_hop_by_hop(name.as_str()) {
+                request = request.header(name, value);
+            }
+        }
+        if let Some(headers) = request.headers_mut() {
+            for (name, value) in self.headers.iter() {
+                headers.insert(name, value.clone());
+            }
+        }
+        let request = request.body(Body::from(body)).map_err(|e| e.to_string())?;
+
+        let exchange = async {
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| e.to_string())?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| e.to_string())?;
+
+            Ok(Forwarded {
+                status,
+                headers,
+                body,
+            })
+        };
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange)
+                .await
+                .unwrap_or_else(|_| Err(format!("no response within {:?}", timeout))),
+            None => exchange.await,
+        }
+    }
+
+    // value of the key on the upstream, `None` when it is missing there as well
+    pub async fn fetch(&self, key: &str) -> Result<Option<Vec<u8>>, FetchError> {
+        let permit = match &self.breaker {
+            Some(breaker) => match breaker.allow(Instant::now()) {
+                Some(permit) => Some(permit),
+                None => return Err(FetchError::Bypassed),
+            },
+            None => None,
+        };
+        let fetched = self
+            .forward(
+                Method::GET,
+                &format!("/get/{}", key),
+                HeaderMap::new(),
+                Bytes::new(),
+            )
+            .await
+            .and_then(|response| match response.status {
+                // upstream may answer misses with an empty 200, see `MissStatus::OkEmpty`
+                StatusCode::OK if !response.body.is_empty() => Ok(Some(response.body.to_vec())),
+                StatusCode::OK | StatusCode::NOT_FOUND => Ok(None),
+                status => Err(format!("upstream responded with {}", status)),
+            });
+        if let Some(permit) = permit {
+            permit.record(fetched.is_ok(), Instant::now());
+        }
+        fetched.map_err(FetchError::Failed)
+    }
+}
+
+fn check_url(url: &str) -> Result<Uri, String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| format!("invalid upstream url {}: {}", url, e))?;
+    if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+        return Err(format!("upstream url {} must be http://host:port", url));
+    }
+    Ok(uri)
+}
+
+// connects to the proxy whatever the request is for, hyper then sends the request with the
+// absolute url so that the proxy knows where to pass it
+#[derive(Clone)]
+struct ProxyConnector {
+    http: HttpConnector,
+    proxy: Uri,
+}
+
+impl Service<Uri> for ProxyConnector {
+    type Response = ProxiedStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<ProxiedStream, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let connecting = self.http.call(self.proxy.clone());
+        Box::pin(async move { Ok(ProxiedStream(connecting.await?)) })
+    }
+}
+
+struct ProxiedStream(TcpStream);
+
+impl Connection for ProxiedStream {
+    fn connected(&self) -> Connected {
+        self.0.connected().proxy(true)
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BreakerState {
+    Closed,
+    // primary is not asked until the cooldown is over
+    Open,
+    // cooldown is over, a single request is let through to find out if the primary is back
+    HalfOpen,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BreakerSnapshot {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    // times the breaker went open
+    pub opened: u64,
+    // fetches answered as misses without asking the primary
+    pub bypassed: u64,
+}
+
+// stops fetching from the primary for `cooldown` once `threshold` fetches in a row failed,
+// shared by all requests of the replica
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Breaker>,
+}
+
+struct Breaker {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // trial request of the half-open breaker is in flight
+    probing: bool,
+    opened: u64,
+    bypassed: u64,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            inner: Mutex::new(Breaker {
+                consecutive_failures: 0,
+                opened_at: None,
+                probing: false,
+                opened: 0,
+                bypassed: 0,
+            }),
+        }
+    }
+
+    // permit of a request that may go to the primary, its outcome has to be passed to
+    // `Permit::record`
+    pub fn allow(&self, now: Instant) -> Option<Permit<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let permit = match self.state(&inner, now) {
+            BreakerState::Closed => Some(false),
+            BreakerState::Open => None,
+            BreakerState::HalfOpen if inner.probing => None,
+            BreakerState::HalfOpen => {
+                inner.probing = true;
+                Some(true)
+            }
+        };
+        if permit.is_none() {
+            inner.bypassed += 1;
+        }
+        permit.map(|probe| Permit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    // only the trial itself ends probing, requests let through before the breaker opened
+    // may still finish while it is in flight
+    fn record(&self, succeeded: bool, probe: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        if probe {
+            inner.probing = false;
+        }
+        if succeeded {
+            inner.consecutive_failures = 0;
+            inner.opened_at = None;
+            return;
+        }
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        // failed trial opens it again right away
+        if probe || (inner.opened_at.is_none() && inner.consecutive_failures >= self.threshold) {
+            inner.opened_at = Some(now);
+            inner.opened += 1;
+        }
+    }
+
+    fn state(&self, inner: &Breaker, now: Instant) -> BreakerState {
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(at) if now.saturating_duration_since(at) < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn snapshot(&self) -> BreakerSnapshot {
+        let inner = self.inner.lock().unwrap();
+        BreakerSnapshot {
+            state: self.state(&inner, Instant::now()),
+            consecutive_failures: inner.consecutive_failures,
+            opened: inner.opened,
+            bypassed: inner.bypassed,
+        }
+    }
+}
+
+// request let through by the breaker, dropped without its outcome, e.g. when the caller
+// stopped waiting for it, it counts as failed so that a trial never stays in flight
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, succeeded: bool, now: Instant) {
+        self.recorded = true;
+        self.breaker.record(succeeded, self.probe, now);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.record(false, self.probe, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod upstream_tests {
+    use crate::upstream::BreakerState;
+    use crate::upstream::CircuitBreaker;
+
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn breaker_opens_after_failures_and_closes_after_successful_trial() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let state = |now| breaker.state(&breaker.inner.lock().unwrap(), now);
+
+        breaker.allow(at(0)).unwrap().record(false, at(0));
+        assert_eq!(state(at(0)), BreakerState::Closed);
+        // success in between resets the count
+        breaker.allow(at(0)).unwrap().record(true, at(0));
+        for _ in 0..2 {
+            breaker.allow(at(1)).unwrap().record(false, at(1));
+        }
+        assert_eq!(state(at(1)), BreakerState::Open);
+        assert!(breaker.allow(at(5)).is_none());
+
+        // only one trial once the cooldown is over, it failing opens the breaker again
+        assert_eq!(state(at(11)), BreakerState::HalfOpen);
+        let trial = breaker.allow(at(11)).unwrap();
+        assert!(breaker.allow(at(11)).is_none());
+        trial.record(false, at(12));
+        assert_eq!(state(at(12)), BreakerState::Open);
+        assert!(breaker.allow(at(21)).is_none());
+
+        breaker.allow(at(22)).unwrap().record(true, at(22));
+        assert_eq!(state(at(22)), BreakerState::Closed);
+        breaker.allow(at(22)).unwrap().record(true, at(22));
+
+        let snapshot = breaker.snapshot();
+        assert_eq!(snapshot.opened, 2);
+        assert_eq!(snapshot.bypassed, 3);
+        assert_eq!(snapshot.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn dropped_trial_counts_as_failed() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // request let through before the breaker opened finishes during the trial
+        let late = breaker.allow(at(0)).unwrap();
+        breaker.allow(at(0)).unwrap().record(false, at(0));
+        let trial = breaker.allow(at(11)).unwrap();
+        late.record(false, at(11));
+        assert!(breaker.allow(at(11)).is_none());
+
+        // the trial is given up on, the breaker opens again instead of waiting for it forever
+        drop(trial);
+        assert_eq!(breaker.snapshot().opened, 2);
+        assert!(breaker.allow(at(5)).is_none());
+        assert!(breaker.allow(at(11)).is_some());
+    }
+}